@@ -0,0 +1,117 @@
+use crate::auth::{Role, User};
+use crate::cqrs::QueryPolicy;
+use crate::read_model::{InvoiceQuery, KitchenQueueQuery, OpenTabsQuery, TabInvoice, TabStatus, WaiterTodo, WaiterTodoQuery};
+
+// What each role may read through the query bus. Waiters only see the tabs they opened, chefs
+// only the kitchen, and managers everything.
+
+pub struct OpenTabsAccess;
+
+impl QueryPolicy<OpenTabsQuery, User> for OpenTabsAccess {
+    fn permits(&self, user: &User) -> bool {
+        user.role != Role::Chef
+    }
+
+    fn scope(&self, user: &User, tabs: Vec<TabStatus>) -> Vec<TabStatus> {
+        match user.role {
+            Role::Manager => tabs,
+            _ => tabs.into_iter().filter(|tab| tab.waiter_id == user.staff_id).collect()
+        }
+    }
+}
+
+// Someone else's invoice is answered as if the table had no tab, so waiters cannot probe which
+// tables are taken.
+pub struct InvoiceAccess;
+
+impl QueryPolicy<InvoiceQuery, User> for InvoiceAccess {
+    fn permits(&self, user: &User) -> bool {
+        user.role != Role::Chef
+    }
+
+    fn scope(&self, user: &User, invoice: Option<TabInvoice>) -> Option<TabInvoice> {
+        match user.role {
+            Role::Manager => invoice,
+            _ => invoice.filter(|invoice| invoice.waiter_id == user.staff_id)
+        }
+    }
+}
+
+// Like invoices, another waiter's list comes back empty rather than refused.
+pub struct WaiterTodoAccess;
+
+impl QueryPolicy<WaiterTodoQuery, User> for WaiterTodoAccess {
+    fn permits(&self, user: &User) -> bool {
+        user.role != Role::Chef
+    }
+
+    fn scope(&self, user: &User, todo: WaiterTodo) -> WaiterTodo {
+        match user.role {
+            Role::Waiter if todo.waiter_id != user.staff_id => WaiterTodo { waiter_id: todo.waiter_id, tables: Default::default() },
+            _ => todo
+        }
+    }
+}
+
+pub struct KitchenAccess;
+
+impl QueryPolicy<KitchenQueueQuery, User> for KitchenAccess {
+    fn permits(&self, user: &User) -> bool {
+        user.role != Role::Waiter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use uuid::Uuid;
+
+    use crate::cqrs::{Checkpoint, Projection, QueryBus, QueryError};
+    use crate::domain::{Event, OrderedItem};
+    use crate::money::{Currency, Money};
+    use crate::read_model::{ChefTodoList, OpenTabs};
+    use crate::staff;
+
+    fn user(name: &str, role: Role) -> User {
+        User { name: name.to_string(), role, staff_id: staff::legacy_id(name) }
+    }
+
+    #[test]
+    fn queries_are_scoped_to_the_callers_role() {
+        let mut open_tabs = OpenTabs::new();
+        open_tabs.apply(Uuid::new_v4(), &Event::TabOpened { table_number: 1, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        let jane_tab = Uuid::new_v4();
+        open_tabs.apply(jane_tab, &Event::TabOpened { table_number: 2, waiter_id: staff::legacy_id("Jane"), waiter: "Jane".to_string() });
+        let drink = OrderedItem::new(1, "Coke".to_string(), true, Money::new(250, Currency::EUR)).with_line_id(Uuid::new_v4());
+        open_tabs.apply(jane_tab, &Event::DrinksOrdered { items: vec![drink] });
+        let open_tabs = Arc::new(RwLock::new(open_tabs));
+        let checkpoint = Arc::new(RwLock::new(Checkpoint::new()));
+        let mut queries = QueryBus::new();
+        queries.register(open_tabs.clone(), checkpoint.clone(), OpenTabsAccess);
+        queries.register(open_tabs.clone(), checkpoint.clone(), InvoiceAccess);
+        queries.register(open_tabs, checkpoint.clone(), WaiterTodoAccess);
+        queries.register(Arc::new(RwLock::new(ChefTodoList::new())), checkpoint, KitchenAccess);
+
+        let (derek, chef, manager) = (user("Derek", Role::Waiter), user("Gordon", Role::Chef), user("Mary", Role::Manager));
+        let tables = |caller: &User| queries.ask(&OpenTabsQuery, caller).unwrap().result.iter().map(|tab| tab.table_number).collect::<Vec<_>>();
+        assert_eq!(tables(&derek), vec![1]);
+        assert_eq!(tables(&manager), vec![1, 2]);
+        assert_eq!(queries.ask(&OpenTabsQuery, &chef).err(), Some(QueryError::Refused("open_tabs")));
+
+        let invoice = |caller: &User, table_number| queries.ask(&InvoiceQuery { table_number }, caller).unwrap().result.map(|invoice| invoice.table_number);
+        assert_eq!((invoice(&derek, 1), invoice(&derek, 2)), (Some(1), None));
+        assert_eq!(invoice(&manager, 2), Some(2));
+
+        let jane = user("Jane", Role::Waiter);
+        let todo = |caller: &User, waiter_id| queries.ask(&WaiterTodoQuery { waiter_id }, caller).unwrap().result.tables.into_keys().collect::<Vec<_>>();
+        assert_eq!(todo(&jane, jane.staff_id), vec![2]);
+        assert_eq!(todo(&manager, jane.staff_id), vec![2]);
+        assert!(todo(&derek, jane.staff_id).is_empty());
+        assert_eq!(queries.ask(&WaiterTodoQuery { waiter_id: jane.staff_id }, &chef).err(), Some(QueryError::Refused("waiter_todo")));
+
+        assert!(queries.ask(&KitchenQueueQuery, &chef).is_ok());
+        assert!(queries.ask(&KitchenQueueQuery, &manager).is_ok());
+        assert_eq!(queries.ask(&KitchenQueueQuery, &derek).err(), Some(QueryError::Refused("kitchen_queue")));
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::cqrs::{named_stream_id, EventEnvelope, Metadata};
+use crate::cqrs::store::{EventStore, StoreError};
+
+// Notes support staff leave on a tab while looking into it, e.g. a link to the customer's
+// complaint. They are kept in a log of their own, so the tab's events stay as they were recorded.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    // About the whole stream, or about one of its events when there is an event id.
+    Annotated { stream_id: Uuid, event_id: Option<Uuid>, note: String, ticket_url: Option<String> }
+}
+
+// An annotated stream's notes are a stream of their own, found by the annotated stream's id.
+pub fn annotations_id(stream_id: Uuid) -> Uuid {
+    named_stream_id("annotations", &stream_id.to_string())
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Annotation {
+    pub annotation_id: Uuid,
+    pub event_id: Option<Uuid>,
+    pub note: String,
+    pub ticket_url: Option<String>,
+    pub author: Option<String>,
+    pub annotated_at: SystemTime
+}
+
+impl Annotation {
+    fn from_envelope(envelope: &EventEnvelope<Event>) -> Annotation {
+        let Event::Annotated { event_id, ref note, ref ticket_url, .. } = envelope.payload;
+        Annotation {
+            annotation_id: envelope.event_id,
+            event_id,
+            note: note.clone(),
+            ticket_url: ticket_url.clone(),
+            author: envelope.acting_user.clone(),
+            annotated_at: envelope.timestamp
+        }
+    }
+}
+
+// Records a note on the stream, or on one of its events. The author is the metadata's acting
+// user. A dry run gives back the note as it would be recorded.
+pub async fn annotate(store: &dyn EventStore<Event>, stream_id: Uuid, event_id: Option<Uuid>, note: String, ticket_url: Option<String>, metadata: Metadata) -> Result<Annotation, StoreError> {
+    let annotations_id = annotations_id(stream_id);
+    if metadata.dry_run {
+        return Ok(Annotation { annotation_id: Uuid::new_v4(), event_id, note, ticket_url, author: metadata.acting_user, annotated_at: SystemTime::now() });
+    }
+    let version = store.read_stream(annotations_id).await?.version;
+    let event = Event::Annotated { stream_id, event_id, note, ticket_url };
+    let recorded = store.append(annotations_id, vec![event], version, &metadata).await?;
+    Ok(Annotation::from_envelope(&recorded[0]))
+}
+
+// The stream's notes, oldest first.
+pub async fn for_stream(store: &dyn EventStore<Event>, stream_id: Uuid) -> Result<Vec<Annotation>, StoreError> {
+    let stream = store.read_stream(annotations_id(stream_id)).await?;
+    Ok(stream.events.iter().map(Annotation::from_envelope).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::store::InMemoryEventStore;
+
+    #[tokio::test]
+    async fn annotations_are_kept_per_stream_with_their_author() {
+        let store = InMemoryEventStore::new();
+        let (tab_id, other_tab_id, event_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let metadata = Metadata::new().with_acting_user("Maria".to_string(), Uuid::new_v4());
+        annotate(&store, tab_id, None, "Guest says they were charged twice".to_string(), Some("https://support.example/1234".to_string()), metadata.clone()).await.unwrap();
+        annotate(&store, tab_id, Some(event_id), "Paid by card, not cash".to_string(), None, metadata.clone()).await.unwrap();
+        annotate(&store, tab_id, None, "Just trying".to_string(), None, metadata.with_dry_run()).await.unwrap();
+
+        let annotations = for_stream(&store, tab_id).await.unwrap();
+        assert_eq!(annotations.iter().map(|annotation| annotation.event_id).collect::<Vec<_>>(), vec![None, Some(event_id)]);
+        assert_eq!(annotations[0].ticket_url.as_deref(), Some("https://support.example/1234"));
+        assert_eq!(annotations[1].author.as_deref(), Some("Maria"));
+        assert!(for_stream(&store, other_tab_id).await.unwrap().is_empty());
+    }
+}
@@ -1,17 +1,1785 @@
-use rocket;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
-use domain::Event;
+use rocket::{Build, Data, Rocket, State};
+use rocket::data::ToByteUnit;
+use rocket::http::{ContentType, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, content, status, Responder, Response};
+use rocket::response::stream::{Event as ServerEvent, EventStream};
+use rocket::serde::json::Json;
+use serde_json::{self, Value};
+use uuid::Uuid;
 
-pub struct EventStore<T> {
-    events: Vec<T>
+use crate::access::{InvoiceAccess, KitchenAccess, OpenTabsAccess, WaiterTodoAccess};
+use crate::annotations::{self, Annotation};
+use crate::auth::{self, ApiTokens, PinError, PinSession, PinSessions, Role, User};
+use crate::backfill::{self, BackfillError, BackfillReport, PriceFix};
+use crate::config::Config;
+use crate::cqrs::{Aggregate, AggregateCommand, Answer, CacheStats, Checkpoint, CommandHandler, EventEnvelope, HandlerError, Metadata, Policy, ProcessRunner, Projection, Query, QueryBus, QueryError, QueryTiming, QueryTimings, Rebuild, Rebuildable, Repository, Span, Stage, TracedStore, Traces, Warning};
+use crate::cqrs::trace;
+use crate::cqrs::store::{EventStore, InMemorySnapshotStore, LengthPercentiles, SnapshotLoads, SnapshotStore, StoreError, StreamMetrics};
+use crate::date::{self, Date, InvalidDate};
+use crate::devices::{self, DeviceRegistry, DeviceStatus, Heartbeats};
+use crate::docs;
+use crate::domain::{self, Command, CommandError, Course, Event, LineRef, Tab};
+use crate::fiscal::{self, DailyFiscalReport, FiscalDevice, FiscalError, FiscalRegistration};
+use crate::forecast::{Forecast, SalesVelocity};
+use crate::gateway::{self, PaymentError, PaymentProvider, PaymentSetup, SettlementError};
+use crate::incident::Incidents;
+use crate::kitchen::KitchenTicket;
+use crate::locale::{self, Language};
+use crate::menu::{self, Menu, MenuItem, Nutrition};
+use crate::money::{self, Money};
+use crate::openapi;
+use crate::payments::{self, CallbackSecrets, Deduplicator, PaymentCallback};
+use crate::policy::TabPolicy;
+use crate::printing;
+use crate::push::{self, Displays};
+use crate::read_model::{Catalog, ChefTodoList, InvoiceQuery, KitchenQueueQuery, MenuChanges, OpenTabs, OpenTabsQuery, OrderRecord, ReadModelExport, Roster, SearchIndex, SearchQuery, StaffMember, StaffRegistry, TabInvoice, TabItem, TabStatus, TodoListGroup, WaiterOnShift, WaiterTodoQuery};
+use crate::receipts;
+use crate::reports::{DailySales, SalesReport, TipsPerWaiter, WaiterTips};
+use crate::retention::RetentionPolicy;
+use crate::shift::{self, Shift};
+use crate::shutdown::Shutdown;
+use crate::staff::{self, Staff};
+use crate::store_stats::{LogStats, StoreStats};
+use crate::slo::{self, CommandLatencies, LatencyObjectives, ObjectiveStatus};
+use crate::table::{self, Table};
+use crate::watchdog::{self, LateTab, Watchdog};
+#[cfg(feature = "tantivy")]
+use crate::text_search::{TextIndex, TextMatch};
+
+// Errors are answered as RFC 7807 problem details. The code is what clients match on, and the
+// title is for people, in their language.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    menu_numbers: Vec<i32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    line_ids: Vec<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incident_id: Option<Uuid>
 }
 
-pub fn launch(event_store: EventStore<Event>) {
-    let routes = routes![
+impl ApiError {
+    pub(crate) fn new(status: Status, code: &'static str, title: &'static str) -> ApiError {
+        ApiError { problem_type: format!("urn:cafe:problem:{}", code), title, status: status.code, code, menu_numbers: Vec::new(), line_ids: Vec::new(), incident_id: None }
+    }
+
+    pub(crate) fn with_menu_numbers(mut self, menu_numbers: Vec<i32>) -> ApiError {
+        self.menu_numbers = menu_numbers;
+        self
+    }
+
+    // The lines a rejection was about, by line id or menu number as the client named them.
+    pub(crate) fn with_lines(mut self, lines: Vec<LineRef>) -> ApiError {
+        for line in lines {
+            match line {
+                LineRef::Line(line_id) => self.line_ids.push(line_id),
+                LineRef::MenuNumber(menu_number) => self.menu_numbers.push(menu_number)
+            }
+        }
+        self
+    }
+
+    pub(crate) fn with_incident(mut self, incident_id: Uuid) -> ApiError {
+        self.incident_id = Some(incident_id);
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        let body = serde_json::to_string(&self).map_err(|_| Status::InternalServerError)?;
+        Response::build()
+            .status(status)
+            .header(ContentType::new("application", "problem+json"))
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiWarning {
+    pub(crate) code: &'static str,
+    pub(crate) message: &'static str
+}
+
+// Successful commands answer 200 with the recorded events, or 202 when a policy has
+// something to warn the client about.
+#[derive(Debug, Serialize)]
+pub struct CommandResponse<E> {
+    pub(crate) events: Vec<E>,
+    pub(crate) warnings: Vec<ApiWarning>
+}
+
+#[derive(Debug, Serialize)]
+pub struct OldestOpenTab {
+    tab_id: Uuid,
+    table_number: u8,
+    opened_at: SystemTime,
+    age_seconds: u64
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoreMetrics {
+    stream_count: usize,
+    event_count: usize,
+    stream_length: LengthPercentiles,
+    oldest_open_tab: Option<OldestOpenTab>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProjectionStatus {
+    name: &'static str,
+    position: usize
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServedLine {
+    pub(crate) line_id: Uuid,
+    pub(crate) quantity: u32
+}
+
+// Order lines and how many of each were served. Items named by menu number are the first of
+// them ordered, and older clients list a menu number for each one served.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServedItems {
+    #[serde(default)]
+    pub(crate) lines: Vec<ServedLine>,
+    #[serde(default)]
+    pub(crate) items: Vec<OrderLine>,
+    #[serde(default)]
+    pub(crate) menu_numbers: Option<Vec<i32>>
+}
+
+impl ServedItems {
+    fn quantities(self) -> Vec<(LineRef, u32)> {
+        let lines = self.lines.into_iter().map(|line| (LineRef::Line(line.line_id), line.quantity));
+        let items = self.items.into_iter().map(|line| (LineRef::MenuNumber(line.menu_number), line.quantity));
+        let listed = self.menu_numbers.unwrap_or_default().into_iter().map(|menu_number| (LineRef::MenuNumber(menu_number), 1));
+        lines.chain(items).chain(listed).collect()
+    }
+}
+
+// An item by its order line, or by menu number for the first of it ordered.
+fn line_ref(line_id: Option<Uuid>, menu_number: Option<i32>, language: Language) -> Result<LineRef, ApiError> {
+    line_id.map(LineRef::Line)
+        .or_else(|| menu_number.map(LineRef::MenuNumber))
+        .ok_or_else(|| ApiError::new(Status::BadRequest, "malformed_request", locale::malformed_request_message(language)))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewTab {
+    pub(crate) tab_id: Uuid,
+    pub(crate) table_number: u8
+}
+
+// Without tab ids, every tab of the outgoing waiter is handed over.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Handover {
+    pub(crate) to_waiter_id: Uuid,
+    #[serde(default)]
+    pub(crate) tab_ids: Option<Vec<Uuid>>
+}
+
+#[derive(Debug, Serialize)]
+pub struct HandedOver {
+    pub(crate) tab_ids: Vec<Uuid>,
+    pub(crate) tables: Vec<u8>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewTable {
+    table_number: u8
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewStaffMember {
+    name: String,
+    role: Role
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPin {
+    pin: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinSignIn {
+    staff_id: Uuid,
+    pin: String
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrderLine {
+    pub(crate) menu_number: i32,
+    pub(crate) quantity: u32
+}
+
+// What the guest asked for beyond the menu number goes with it to whoever prepares it. Only food
+// is ordered for a course; drinks go out as soon as they are ordered.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewOrderItem {
+    pub(crate) menu_number: i32,
+    pub(crate) quantity: u32,
+    #[serde(default)]
+    pub(crate) modifiers: Vec<String>,
+    #[serde(default)]
+    pub(crate) note: Option<String>,
+    #[serde(default)]
+    pub(crate) course: Option<Course>
+}
+
+// Only menu numbers, quantities and how to prepare them; descriptions and prices come from the
+// menu.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewOrder {
+    pub(crate) items: Vec<NewOrderItem>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewPrice {
+    price: Money
+}
+
+// Without nutrition the declaration is taken back.
+#[derive(Debug, Deserialize)]
+pub struct NewNutrition {
+    #[serde(default)]
+    nutrition: Option<Nutrition>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForcedClose {
+    reason: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VoidedReceipt {
+    reason: String
+}
+
+// A support note on a tab, or on one of its events when there is an event id.
+#[derive(Debug, Deserialize)]
+pub struct NewAnnotation {
+    note: String,
+    #[serde(default)]
+    ticket_url: Option<String>,
+    #[serde(default)]
+    event_id: Option<Uuid>
+}
+
+#[derive(Debug, Serialize)]
+pub struct TabHistory {
+    events: Vec<EventEnvelope<Event>>,
+    annotations: Vec<Annotation>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VoidedItem {
+    #[serde(default)]
+    pub(crate) line_id: Option<Uuid>,
+    #[serde(default)]
+    pub(crate) menu_number: Option<i32>,
+    pub(crate) reason: String
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TabTransfer {
+    pub(crate) table_number: u8
+}
+
+// Served without an order, by menu number and how many; they go on the tab free of charge.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UnorderedItems {
+    pub(crate) items: Vec<OrderLine>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FiredCourse {
+    pub(crate) course: Course
+}
+
+// Paid by card at the table, tip included.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Settlement {
+    pub(crate) amount: Money
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CompedItem {
+    #[serde(default)]
+    pub(crate) line_id: Option<Uuid>,
+    #[serde(default)]
+    pub(crate) menu_number: Option<i32>,
+    pub(crate) reason: String
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Discount {
+    pub(crate) percent: u8,
+    pub(crate) reason: String
+}
+
+// Dates are YYYY-MM-DD, in UTC.
+#[derive(FromForm)]
+pub struct SearchParams {
+    waiter: Option<String>,
+    item: Option<String>,
+    from: Option<String>,
+    to: Option<String>
+}
+
+// Pages hold MENU_CHANGES_LIMIT changes unless the device asks for fewer.
+#[derive(FromForm)]
+pub struct MenuChangesParams {
+    since: usize,
+    limit: Option<usize>
+}
+
+const MENU_CHANGES_LIMIT: usize = 100;
+
+#[derive(FromForm)]
+pub struct TipsParams {
+    from: Option<String>,
+    to: Option<String>
+}
+
+// Read model responses are tagged with the checkpoint they were read at, so polling clients can
+// send If-None-Match and get 304 Not Modified until another event has been applied.
+pub struct Cached<R> {
+    etag: String,
+    last_modified: Option<SystemTime>,
+    body: R
+}
+
+impl<R> Cached<R> {
+    // The checkpoint has to be read before the read model. The body may then be newer than its
+    // tag, which costs the client a download but never leaves it with a stale 304.
+    fn new(checkpoint: Checkpoint, body: R) -> Cached<R> {
+        let etag = match checkpoint.generation {
+            0 => format!("\"{}\"", checkpoint.position),
+            generation => format!("\"{}.{}\"", checkpoint.position, generation)
+        };
+        Cached { etag, last_modified: checkpoint.last_recorded_at, body }
+    }
+
+    // For bodies that depend on more than the log, such as the time of day.
+    fn varying_by(mut self, key: &str) -> Cached<R> {
+        self.etag = format!("\"{}-{}\"", self.etag.trim_matches('"'), key);
+        self
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Cached<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let last_modified = self.last_modified.map(date::http_date);
+        let not_modified = match request.headers().get_one("If-None-Match") {
+            Some(tags) => tags.split(',').any(|tag| tag.trim() == self.etag || tag.trim() == "*"),
+            None => last_modified.is_some() && request.headers().get_one("If-Modified-Since") == last_modified.as_deref()
+        };
+        let mut response = if not_modified {
+            Response::build().status(Status::NotModified).finalize()
+        } else {
+            self.body.respond_to(request)?
+        };
+        response.set_raw_header("ETag", self.etag);
+        response.set_raw_header("Cache-Control", "no-cache");
+        if let Some(last_modified) = last_modified {
+            response.set_raw_header("Last-Modified", last_modified);
+        }
+        Ok(response)
+    }
+}
+
+type CommandResult<E = Event> = Result<status::Custom<Json<CommandResponse<E>>>, ApiError>;
+
+pub(crate) fn error_code(error: &CommandError) -> &'static str {
+    use crate::domain::CommandError::*;
+
+    match *error {
+        TabNotOpen => "tab_not_open",
+        InvalidPrice => "invalid_price",
+        DrinksNotOutstanding(_) => "drinks_not_outstanding",
+        FoodNotOutstanding(_) => "food_not_outstanding",
+        ItemNotOutstanding(_) => "item_not_outstanding",
+        MustPayEnough => "must_pay_enough",
+        TabHasUnservedItems => "tab_has_unserved_items",
+        CurrencyMismatch => "currency_mismatch",
+        TabValueLimitExceeded => "tab_value_limit_exceeded",
+        TipTooHigh => "tip_too_high",
+        NotTabWaiter => "not_tab_waiter",
+        ItemNotServed(_) => "item_not_served",
+        InvalidDiscount => "invalid_discount",
+        DiscountExceedsTabValue => "discount_exceeds_tab_value",
+        CourseNotOrdered(_) => "course_not_ordered",
+        CourseAlreadyFired(_) => "course_already_fired",
+        ServeUnorderedNotAllowed(_) => "serve_unordered_not_allowed",
+        PaymentNotCaptured => "payment_not_captured",
+        PaymentNotAuthorized => "payment_not_authorized",
+        TabAlreadyOpened => "tab_already_opened"
+    }
+}
+
+fn offending_lines(error: &CommandError) -> Vec<LineRef> {
+    match *error {
+        CommandError::DrinksNotOutstanding(ref lines) | CommandError::FoodNotOutstanding(ref lines) => lines.clone(),
+        CommandError::ItemNotOutstanding(line) => vec![line],
+        CommandError::ServeUnorderedNotAllowed(ref menu_numbers) => menu_numbers.iter().map(|&menu_number| LineRef::MenuNumber(menu_number)).collect(),
+        _ => Vec::new()
+    }
+}
+
+pub(crate) fn menu_error_code(error: &menu::CommandError) -> &'static str {
+    use crate::menu::CommandError::*;
+
+    match *error {
+        MenuNumberTaken => "menu_number_taken",
+        UnknownMenuItem => "unknown_menu_item",
+        InvalidPrice => "invalid_price",
+        CurrencyMismatch => "currency_mismatch"
+    }
+}
+
+pub(crate) fn table_error_code(error: &table::CommandError) -> &'static str {
+    use crate::table::CommandError::*;
+
+    match *error {
+        TableAlreadyRegistered => "table_already_registered",
+        UnknownTable => "unknown_table",
+        TableInUse => "table_in_use",
+        TableNotInUse => "table_not_in_use"
+    }
+}
+
+pub(crate) fn shift_error_code(error: &shift::CommandError) -> &'static str {
+    use crate::shift::CommandError::*;
+
+    match *error {
+        AlreadyOnShift => "already_on_shift",
+        NotOnShift => "not_on_shift"
+    }
+}
+
+pub(crate) fn staff_error_code(error: &staff::CommandError) -> &'static str {
+    use crate::staff::CommandError::*;
+
+    match *error {
+        AlreadyRegistered => "already_registered",
+        NotRegistered => "not_registered",
+        AlreadyInactive => "already_inactive"
+    }
+}
+
+fn store_unavailable(language: Language) -> ApiError {
+    ApiError::new(Status::ServiceUnavailable, "store_unavailable", locale::store_unavailable_message(language))
+}
+
+fn ask<Q: Query>(queries: &QueryBus<User>, query: &Q, user: &User, language: Language) -> Result<Answer<Q::Result>, ApiError> {
+    queries.ask(query, user).map_err(|error| match error {
+        QueryError::Refused(_) => ApiError::new(Status::Forbidden, "forbidden", locale::forbidden_message(language)),
+        // Only launch can leave a query unhandled, so it is not the client's fault.
+        QueryError::Unhandled(_) => ApiError::new(Status::InternalServerError, "internal_error", locale::internal_error_message(language))
+    })
+}
+
+fn rejected(code: &'static str, message: &'static str) -> ApiError {
+    ApiError::new(Status::UnprocessableEntity, code, message)
+}
+
+// Rocket's own errors, from no route matching to a guard turning the request away or a body
+// that does not parse, in the same shape as ours.
+fn caught(request: &Request, status: Status, code: &'static str, message: fn(Language) -> &'static str) -> ApiError {
+    let language = Language::from_accept_language(request.headers().get_one("Accept-Language"));
+    ApiError::new(status, code, message(language))
+}
+
+#[catch(400)]
+fn bad_request(request: &Request) -> ApiError {
+    caught(request, Status::BadRequest, "malformed_request", locale::malformed_request_message)
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> ApiError {
+    caught(request, Status::Unauthorized, "unauthorized", locale::unauthorized_message)
+}
+
+#[catch(403)]
+fn forbidden(request: &Request) -> ApiError {
+    caught(request, Status::Forbidden, "forbidden", locale::forbidden_message)
+}
+
+#[catch(404)]
+fn not_found(request: &Request) -> ApiError {
+    caught(request, Status::NotFound, "not_found", locale::not_found_message)
+}
+
+#[catch(422)]
+fn unprocessable_entity(request: &Request) -> ApiError {
+    caught(request, Status::UnprocessableEntity, "malformed_request", locale::malformed_request_message)
+}
+
+#[catch(500)]
+fn internal_error(request: &Request) -> ApiError {
+    caught(request, Status::InternalServerError, "internal_error", locale::internal_error_message)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Language {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Language, ()> {
+        Outcome::Success(Language::from_accept_language(request.headers().get_one("Accept-Language")))
+    }
+}
+
+// Clients running a multi-step workflow send the same X-Correlation-Id with every request so
+// the recorded events can be traced back to it.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Metadata {
+    type Error = ();
+
+    // Clients that may retry a command, e.g. tablets on a flaky network, send the same
+    // X-Command-Id with every attempt so it is only carried out once. With ?dry_run=true the
+    // request's commands are only simulated, and answer with the events they would record.
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Metadata, ()> {
+        let metadata = match request.headers().get_one("X-Correlation-Id").map(Uuid::parse_str) {
+            Some(Ok(correlation_id)) => Metadata::correlated_with(correlation_id),
+            Some(Err(_)) => return Outcome::Error((Status::BadRequest, ())),
+            None => Metadata::new()
+        };
+        let metadata = match request.headers().get_one("X-Command-Id").map(Uuid::parse_str) {
+            Some(Ok(command_id)) => metadata.with_command_id(command_id),
+            Some(Err(_)) => return Outcome::Error((Status::BadRequest, ())),
+            None => metadata
+        };
+        match request.query_value::<bool>("dry_run") {
+            Some(Ok(true)) => Outcome::Success(metadata.with_dry_run()),
+            Some(Ok(false)) | None => Outcome::Success(metadata),
+            Some(Err(_)) => Outcome::Error((Status::BadRequest, ()))
+        }
+    }
+}
+
+// Requests without a known bearer token are turned away with 401, see auth::ApiTokens. Tokens of
+// PIN sessions on shared terminals are as good as the member's own until they expire.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<User, ()> {
+        let (tokens, sessions) = match (request.rocket().state::<ApiTokens>(), request.rocket().state::<Arc<PinSessions>>()) {
+            (Some(tokens), Some(sessions)) => (tokens, sessions),
+            _ => return Outcome::Error((Status::InternalServerError, ()))
+        };
+        let header = request.headers().get_one("Authorization");
+        match header.and_then(|header| tokens.authenticate(header).or_else(|| sessions.authenticate(header, SystemTime::now()))) {
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+// A shared terminal, signed in with a terminal token rather than anyone's own.
+pub struct Terminal(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Terminal {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Terminal, ()> {
+        let tokens = match request.rocket().state::<ApiTokens>() {
+            Some(tokens) => tokens,
+            None => return Outcome::Error((Status::InternalServerError, ()))
+        };
+        match request.headers().get_one("Authorization").and_then(|header| tokens.terminal(header)) {
+            Some(name) => Outcome::Success(Terminal(name)),
+            None => Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
+
+async fn require_role(request: &Request<'_>, role: Role) -> request::Outcome<User, ()> {
+    match request.guard::<User>().await {
+        Outcome::Success(ref user) if user.role != role => Outcome::Error((Status::Forbidden, ())),
+        outcome => outcome
+    }
+}
+
+// The provider's signature of a callback; only Stripe-style signatures so far, see
+// payments::SignatureScheme.
+pub struct CallbackSignature(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CallbackSignature {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<CallbackSignature, ()> {
+        Outcome::Success(CallbackSignature(request.headers().get_one("Stripe-Signature").map(str::to_string)))
+    }
+}
+
+// Guards for routes only one role may use; anyone else signed in gets 403.
+pub struct Waiter(User);
+pub struct Manager(User);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Waiter {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Waiter, ()> {
+        require_role(request, Role::Waiter).await.map(Waiter)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Manager {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Manager, ()> {
+        require_role(request, Role::Manager).await.map(Manager)
+    }
+}
+
+// The tab snapshots, taken as many events apart as the configuration says, and how often
+// loading had to fall back from them to a full replay.
+pub struct Snapshots {
+    store: Arc<dyn SnapshotStore<domain::State>>,
+    every: usize,
+    loads: Arc<SnapshotLoads>
+}
+
+// A panic while handling the command is answered with 500 and the incident id instead of taking
+// the worker down with it, see incident::Incidents. The command is traced under its workflow's
+// correlation id, with the code it was answered with if it failed.
+async fn respond<A: Aggregate, F>(handler: CommandHandler<'_, A>, incidents: &Incidents, latencies: &CommandLatencies, traces: &Traces, language: Language, command: A::Command, rejection: F) -> CommandResult<A::Event> where A::Command: Debug + Send, A::Event: Clone + Send + Sync + 'static, A::State: Clone + Send, F: Fn(&A::CommandError) -> ApiError {
+    let aggregate_id = command.aggregate_id();
+    let described = format!("{:?}", command);
+    let metadata = handler.metadata().cloned().unwrap_or_default();
+    let (started_at, started) = (SystemTime::now(), Instant::now());
+    let isolated = incidents.isolate(aggregate_id, &described, handler.handle_with_warnings(command)).await;
+    let command_name = described.split(|c: char| !c.is_alphanumeric()).next().unwrap_or("");
+    latencies.record(command_name, started.elapsed(), SystemTime::now());
+    let span = Span::new(Stage::Command, command_name, metadata.causation_id, started_at, started.elapsed());
+    let response = match isolated {
+        Ok(result) => answer::<A, F>(result, language, rejection),
+        Err(incident) => {
+            Err(ApiError::new(Status::InternalServerError, "internal_error", locale::internal_error_message(language)).with_incident(incident.incident_id))
+        }
+    };
+    traces.record(metadata.correlation_id, match response {
+        Ok(_) => span,
+        Err(ref error) => span.with_error(error.code.to_string())
+    });
+    response
+}
+
+fn answer<A: Aggregate, F>(result: Result<(Vec<A::Event>, Vec<Warning>), HandlerError<A::CommandError>>, language: Language, rejection: F) -> CommandResult<A::Event> where F: Fn(&A::CommandError) -> ApiError {
+    match result {
+        Ok((events, warnings)) => {
+            let status = if warnings.is_empty() { Status::Ok } else { Status::Accepted };
+            let warnings = warnings.into_iter().map(|Warning { code }| ApiWarning { code, message: locale::warning_message(code, language) }).collect();
+            Ok(status::Custom(status, Json(CommandResponse { events, warnings })))
+        },
+        Err(HandlerError::Rejected(error)) => Err(rejection(&error)),
+        Err(HandlerError::Concurrency(_)) => {
+            Err(ApiError::new(Status::Conflict, "concurrency_conflict", locale::concurrency_conflict_message(language)))
+        },
+        Err(HandlerError::Store(_)) => {
+            Err(ApiError::new(Status::ServiceUnavailable, "store_unavailable", locale::store_unavailable_message(language)))
+        }
+    }
+}
+
+// Tabs commands were handled for lately, kept hydrated so the next command need not replay them.
+type TabCache = Arc<RwLock<Repository<Tab>>>;
+
+async fn dispatch(store: &dyn EventStore<Event>, snapshots: &Snapshots, cache: &RwLock<Repository<Tab>>, policy: &TabPolicy, incidents: &Incidents, latencies: &CommandLatencies, traces: &Traces, language: Language, metadata: Metadata, command: Command) -> CommandResult {
+    let handler = CommandHandler::<Tab>::new(store).with_snapshots(snapshots.store.as_ref()).with_snapshot_every(snapshots.every).with_snapshot_loads(snapshots.loads.as_ref()).with_repository(cache).with_policy(policy).with_metadata(metadata);
+    respond(handler, incidents, latencies, traces, language, command, |error: &CommandError| rejected(error_code(error), locale::command_error_message(error, language)).with_lines(offending_lines(error))).await
+}
+
+type MenuStore = Box<dyn EventStore<menu::Event>>;
+
+// The whole menu is one stream.
+fn menu_id() -> Uuid {
+    Uuid::nil()
+}
+
+async fn dispatch_menu(store: &dyn EventStore<menu::Event>, incidents: &Incidents, latencies: &CommandLatencies, traces: &Traces, language: Language, metadata: Metadata, command: menu::Command) -> CommandResult<menu::Event> {
+    let handler = CommandHandler::<Menu>::new(store).with_metadata(metadata);
+    respond(handler, incidents, latencies, traces, language, command, |error: &menu::CommandError| rejected(menu_error_code(error), locale::menu_error_message(error, language))).await
+}
+
+type TableStore = Box<dyn EventStore<table::Event>>;
+
+async fn dispatch_table(store: &dyn EventStore<table::Event>, incidents: &Incidents, latencies: &CommandLatencies, traces: &Traces, language: Language, metadata: Metadata, command: table::Command) -> CommandResult<table::Event> {
+    let handler = CommandHandler::<Table>::new(store).with_metadata(metadata);
+    respond(handler, incidents, latencies, traces, language, command, |error: &table::CommandError| rejected(table_error_code(error), locale::table_error_message(error, language))).await
+}
+
+type ShiftStore = Box<dyn EventStore<shift::Event>>;
+
+async fn dispatch_shift(store: &dyn EventStore<shift::Event>, incidents: &Incidents, latencies: &CommandLatencies, traces: &Traces, language: Language, metadata: Metadata, command: shift::Command) -> CommandResult<shift::Event> {
+    let handler = CommandHandler::<Shift>::new(store).with_metadata(metadata);
+    respond(handler, incidents, latencies, traces, language, command, |error: &shift::CommandError| rejected(shift_error_code(error), locale::shift_error_message(error, language))).await
+}
+
+type StaffStore = Box<dyn EventStore<staff::Event>>;
+
+type ReceiptStore = Box<dyn EventStore<receipts::Event>>;
+
+// Whether the command would go through as the tab stands, so a receipt number is only issued for
+// a close that is about to happen.
+async fn would_close(store: &dyn EventStore<Event>, cache: &RwLock<Repository<Tab>>, policy: &TabPolicy, command: &Command) -> bool {
+    match CommandHandler::<Tab>::new(store).with_repository(cache).load(command.aggregate_id()).await {
+        Ok((state, _)) => policy.check(&state, command).is_ok() && Tab::decide(&state, command.clone()).is_ok(),
+        Err(_) => false
+    }
+}
+
+// Voids the number issued for a close that was then turned down, see receipts::void_unused.
+async fn void_unused_receipt(store: &dyn EventStore<Event>, receipt_store: &dyn EventStore<receipts::Event>, numbering: &receipts::ReceiptNumbering, tab_id: Uuid, receipt_number: &str, metadata: Metadata) {
+    if let Err(error) = receipts::void_unused(receipt_store, store, numbering, tab_id, receipt_number, metadata).await {
+        eprintln!("failed to void receipt {} of tab {} that was not closed: {}", receipt_number, tab_id, error);
+    }
+}
+
+async fn dispatch_staff(store: &dyn EventStore<staff::Event>, incidents: &Incidents, latencies: &CommandLatencies, traces: &Traces, language: Language, metadata: Metadata, command: staff::Command) -> CommandResult<staff::Event> {
+    let handler = CommandHandler::<Staff>::new(store).with_metadata(metadata);
+    respond(handler, incidents, latencies, traces, language, command, |error: &staff::CommandError| rejected(staff_error_code(error), locale::staff_error_message(error, language))).await
+}
+
+// The signed-in user as the staff registry knows them, if they still work here.
+fn active_staff(registry: &RwLock<StaffRegistry>, user: &User, language: Language) -> Result<StaffMember, ApiError> {
+    registry.read().unwrap().active_member(user.staff_id).cloned()
+        .ok_or_else(|| ApiError::new(Status::Forbidden, "not_on_staff", locale::not_on_staff_message(language)))
+}
+
+// The tab is opened for the signed-in waiter, who has to be active staff and on shift, on a
+// registered table nobody else is seated at.
+#[post("/tabs", format = "application/json", data = "<tab>")]
+async fn open_tab(tab: Json<NewTab>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, tables: &State<TableStore>, shifts: &State<ShiftStore>, registry: &State<Arc<RwLock<StaffRegistry>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let NewTab { tab_id, table_number } = tab.into_inner();
+    let member = active_staff(registry, &waiter.0, language)?;
+    let (shift, _) = CommandHandler::<Shift>::new(shifts.as_ref()).load(member.staff_id).await.map_err(|_| store_unavailable(language))?;
+    if !shift.is_on_shift() {
+        let error = shift::CommandError::NotOnShift;
+        return Err(rejected(shift_error_code(&error), locale::shift_error_message(&error, language)));
+    }
+    let metadata = metadata.with_acting_user(waiter.0.name.clone(), waiter.0.staff_id);
+    let table_id = table::table_id(table_number);
+    // The guests are seated under an id of their own, as the table is freed again if the tab
+    // cannot be opened and a retry has to seat them anew. A retry of a tab that was opened finds
+    // them seated already and only gets the earlier answer.
+    let (table, _) = CommandHandler::<Table>::new(tables.as_ref()).load(table_id).await.map_err(|_| store_unavailable(language))?;
+    let retry = match metadata.command_id {
+        Some(command_id) if table.tab_id() == Some(tab_id) => {
+            let stream = store.read_stream(tab_id).await.map_err(|_| store_unavailable(language))?;
+            stream.events.iter().any(|envelope| envelope.causation_id == command_id)
+        },
+        _ => false
+    };
+    if !retry {
+        let metadata = metadata.follow_up().with_acting_user(waiter.0.name.clone(), waiter.0.staff_id);
+        dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata, table::Command::SeatGuests(table_id, tab_id)).await?;
+    }
+    let opened = dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.clone(), Command::OpenTab(tab_id, table_number, member.staff_id, member.name)).await;
+    if opened.is_err() && !retry {
+        // Frees the table again rather than leave it held by a tab that was never opened.
+        let metadata = metadata.follow_up().with_acting_user(waiter.0.name, waiter.0.staff_id);
+        let _ = dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata, table::Command::ClearTable(table_id)).await;
+    }
+    opened
+}
+
+// Callbacks are far smaller; anything bigger is not from a provider.
+const CALLBACK_LIMIT: u64 = 64 * 1024;
+
+// The body is read raw so the signature is checked against exactly what the provider signed.
+// Providers redeliver callbacks until they get a 2xx, so one that has been handled already is
+// answered 200 without events. Rejected payments are not retried either; anything else frees
+// the callback for the next delivery. Where receipts are numbered, the number is issued just
+// before the tab is closed, under the callback's correlation id, and voided if the close is
+// turned down. A dry run neither claims the callback nor issues a number.
+#[post("/payments/<provider>/callback", format = "application/json", data = "<data>")]
+async fn payment_callback(provider: String, data: Data<'_>, signature: CallbackSignature, secrets: &State<CallbackSecrets>, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, receipt_store: &State<ReceiptStore>, config: &State<Config>, deduplicator: &State<Deduplicator>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let invalid_callback = || ApiError::new(Status::BadRequest, "invalid_callback", locale::invalid_callback_message(language));
+    let body = data.open(CALLBACK_LIMIT.bytes()).into_bytes().await.map_err(|_| invalid_callback())?.into_inner();
+    secrets.verify(&provider, signature.0.as_deref(), &body, SystemTime::now()).map_err(|_| ApiError::new(Status::Unauthorized, "invalid_signature", locale::invalid_signature_message(language)))?;
+    let PaymentCallback { event_id, tab_id, amount } = serde_json::from_slice(&body).map_err(|_| invalid_callback())?;
+
+    let unavailable = |_| store_unavailable(language);
+    if !metadata.dry_run && !deduplicator.claim(&provider, &event_id, &metadata).await.map_err(unavailable)? {
+        return Ok(status::Custom(Status::Ok, Json(CommandResponse { events: Vec::new(), warnings: Vec::new() })));
+    }
+    let receipt_number = match config.receipts {
+        Some(ref numbering) if !metadata.dry_run && would_close(store.as_ref(), cache, policy, &Command::CloseTab(tab_id, amount, None)).await => {
+            let issued = receipts::issue(receipt_store.as_ref(), numbering, tab_id, metadata.follow_up()).await;
+            match issued {
+                Ok(receipt_number) => Some(receipt_number),
+                Err(error) => {
+                    deduplicator.release(&provider, &event_id, &metadata).await.map_err(unavailable)?;
+                    return Err(unavailable(error));
+                }
+            }
+        },
+        _ => None
+    };
+    let closed = dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.clone(), Command::CloseTab(tab_id, amount, receipt_number.clone())).await;
+    if let Err(ref error) = closed {
+        if error.status == Status::UnprocessableEntity.code {
+            if let (Some(numbering), Some(receipt_number)) = (&config.receipts, &receipt_number) {
+                void_unused_receipt(store.as_ref(), receipt_store.as_ref(), numbering, tab_id, receipt_number, metadata.follow_up()).await;
+            }
+        } else if !metadata.dry_run {
+            deduplicator.release(&provider, &event_id, &metadata).await.map_err(unavailable)?;
+        }
+    }
+    closed
+}
+
+fn payment_error(error: &PaymentError, language: Language) -> ApiError {
+    match *error {
+        PaymentError::Declined(_) => rejected("payment_declined", locale::payment_declined_message(language)),
+        PaymentError::Unavailable(_) => ApiError::new(Status::ServiceUnavailable, "payment_provider_unavailable", locale::payment_provider_unavailable_message(language))
+    }
+}
+
+// Settles the tab through the payment gateway, see gateway::settle: the amount is authorized
+// and captured before the tab is closed, and each step is recorded on the tab, a failed one too.
+// A dry run, and a tab that would not close for the amount, never reach the provider; both
+// answer with what closing would do. A receipt number issued for a close that is then turned
+// down is voided. Not found without a gateway in the configuration.
+#[post("/tabs/<id>/settlement", format = "application/json", data = "<settlement>")]
+async fn settle_tab(id: Uuid, settlement: Json<Settlement>, waiter: Waiter, gateway: &State<Option<Arc<dyn PaymentProvider>>>, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, receipt_store: &State<ReceiptStore>, config: &State<Config>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let gateway = gateway.as_ref().ok_or_else(|| ApiError::new(Status::NotFound, "not_found", locale::not_found_message(language)))?;
+    let (name, staff_id) = (waiter.0.name, waiter.0.staff_id);
+    let metadata = metadata.with_acting_user(name.clone(), staff_id);
+    let amount = settlement.into_inner().amount;
+    if metadata.dry_run || !would_close(store.as_ref(), cache, policy, &Command::CloseTab(id, amount, None)).await {
+        return dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.with_dry_run(), Command::CloseTab(id, amount, None)).await;
+    }
+
+    let warnings = Mutex::new(Vec::new());
+    let record = |metadata: Metadata, command: Command| {
+        let (metadata, warnings) = (metadata.with_acting_user(name.clone(), staff_id), &warnings);
+        async move {
+            let receipt_number = match command {
+                Command::CloseTab(_, _, Some(ref receipt_number)) => Some(receipt_number.clone()),
+                _ => None
+            };
+            let recorded = dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.clone(), command).await;
+            if let (Err(ref error), Some(numbering), Some(receipt_number)) = (&recorded, &config.receipts, &receipt_number) {
+                if error.status == Status::UnprocessableEntity.code {
+                    void_unused_receipt(store.as_ref(), receipt_store.as_ref(), numbering, id, receipt_number, metadata.follow_up()).await;
+                }
+            }
+            let status::Custom(_, Json(recorded)) = recorded?;
+            warnings.lock().unwrap().extend(recorded.warnings);
+            Ok(recorded.events)
+        }
+    };
+    let issue_receipt = |metadata: Metadata| async move {
+        match config.receipts {
+            Some(ref numbering) => receipts::issue(receipt_store.as_ref(), numbering, id, metadata).await.map(Some).map_err(|_| store_unavailable(language)),
+            None => Ok(None)
+        }
+    };
+    match gateway::settle(gateway.as_ref(), id, amount, &metadata, record, issue_receipt).await {
+        Ok(events) => {
+            let warnings = warnings.into_inner().unwrap();
+            let status = if warnings.is_empty() { Status::Ok } else { Status::Accepted };
+            Ok(status::Custom(status, Json(CommandResponse { events, warnings })))
+        },
+        Err(SettlementError::Payment(error)) => Err(payment_error(&error, language)),
+        Err(SettlementError::Recording(error)) => Err(error)
+    }
+}
 
+#[post("/shifts/start")]
+async fn start_shift(waiter: Waiter, shifts: &State<ShiftStore>, registry: &State<Arc<RwLock<StaffRegistry>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<shift::Event> {
+    let member = active_staff(registry, &waiter.0, language)?;
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch_shift(shifts.as_ref(), incidents, latencies, traces, language, metadata, shift::Command::StartShift(member.staff_id, member.name)).await
+}
+
+#[post("/shifts/end")]
+async fn end_shift(waiter: Waiter, shifts: &State<ShiftStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<shift::Event> {
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch_shift(shifts.as_ref(), incidents, latencies, traces, language, metadata, shift::Command::EndShift(waiter.0.staff_id)).await
+}
+
+// At shift change the outgoing waiter hands their open tabs to a waiter on shift, along with the
+// tables they were assigned. Each tab is handed over on its own, under the request's correlation
+// id, so the trail shows who handed which tab to whom. A handover cut short by a failure is
+// finished by sending it again; tabs already handed over are no longer the waiter's to hand.
+#[post("/shifts/handover", format = "application/json", data = "<handover>")]
+async fn hand_over(handover: Json<Handover>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, shifts: &State<ShiftStore>, open_tabs: &State<Arc<RwLock<OpenTabs>>>, roster: &State<Arc<RwLock<Roster>>>, registry: &State<Arc<RwLock<StaffRegistry>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> Result<Json<HandedOver>, ApiError> {
+    let Handover { to_waiter_id, tab_ids } = handover.into_inner();
+    let from_waiter_id = waiter.0.staff_id;
+    let successor = registry.read().unwrap().active_member(to_waiter_id).cloned().ok_or_else(|| {
+        let error = staff::CommandError::NotRegistered;
+        rejected(staff_error_code(&error), locale::staff_error_message(&error, language))
+    })?;
+    let (shift, _) = CommandHandler::<Shift>::new(shifts.as_ref()).load(to_waiter_id).await.map_err(|_| store_unavailable(language))?;
+    if !shift.is_on_shift() {
+        let error = shift::CommandError::NotOnShift;
+        return Err(rejected(shift_error_code(&error), locale::shift_error_message(&error, language)));
+    }
+
+    let tabs: Vec<TabStatus> = open_tabs.read().unwrap().tabs_of_waiter(from_waiter_id).into_iter()
+        .filter(|tab| tab_ids.as_ref().is_none_or(|ids| ids.contains(&tab.tab_id)))
+        .collect();
+    let metadata = metadata.with_acting_user(waiter.0.name.clone(), from_waiter_id);
+    for tab in &tabs {
+        dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.clone(), Command::ReassignWaiter(tab.tab_id, from_waiter_id, to_waiter_id, successor.name.clone())).await?;
+    }
+
+    // Both waiters' shifts may take several of these, so they are not under the client's command
+    // id, which would have the second one answered as a retry of the first.
+    let assigned = roster.read().unwrap().waiter(from_waiter_id).map(|waiter| waiter.tables.clone()).unwrap_or_default();
+    let mut tables: Vec<u8> = tabs.iter().map(|tab| tab.table_number).filter(|table_number| assigned.contains(table_number)).collect();
+    tables.sort();
+    tables.dedup();
+    for &table_number in &tables {
+        let metadata = metadata.follow_up().with_acting_user(waiter.0.name.clone(), from_waiter_id);
+        dispatch_shift(shifts.as_ref(), incidents, latencies, traces, language, metadata.clone(), shift::Command::ReleaseTable(from_waiter_id, table_number)).await?;
+        dispatch_shift(shifts.as_ref(), incidents, latencies, traces, language, metadata, shift::Command::AssignToTable(to_waiter_id, table_number)).await?;
+    }
+    Ok(Json(HandedOver { tab_ids: tabs.into_iter().map(|tab| tab.tab_id).collect(), tables }))
+}
+
+// Waiters are addressed by staff id, as listed by GET /waiters.
+#[post("/waiters/<waiter_id>/tables", format = "application/json", data = "<table>")]
+async fn assign_table(waiter_id: Uuid, table: Json<NewTable>, manager: Manager, shifts: &State<ShiftStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<shift::Event> {
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_shift(shifts.as_ref(), incidents, latencies, traces, language, metadata, shift::Command::AssignToTable(waiter_id, table.into_inner().table_number)).await
+}
+
+#[get("/waiters")]
+fn list_waiters(_user: User, roster: &State<Arc<RwLock<Roster>>>) -> Json<Vec<WaiterOnShift>> {
+    Json(roster.read().unwrap().waiters())
+}
+
+#[get("/staff")]
+fn list_staff(_manager: Manager, registry: &State<Arc<RwLock<StaffRegistry>>>) -> Json<Vec<StaffMember>> {
+    Json(registry.read().unwrap().members())
+}
+
+// The staff id is in the StaffRegistered event of the response; tokens of anyone sharing a name
+// with someone registered before them need it as their staff_id.
+#[post("/staff", format = "application/json", data = "<member>")]
+async fn register_staff(member: Json<NewStaffMember>, manager: Manager, staff: &State<StaffStore>, registry: &State<Arc<RwLock<StaffRegistry>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<staff::Event> {
+    let NewStaffMember { name, role } = member.into_inner();
+    let staff_id = registry.read().unwrap().id_for(&name);
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_staff(staff.as_ref(), incidents, latencies, traces, language, metadata, staff::Command::Register(staff_id, name, role)).await
+}
+
+#[post("/staff/<staff_id>/deactivate")]
+async fn deactivate_staff(staff_id: Uuid, manager: Manager, staff: &State<StaffStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<staff::Event> {
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_staff(staff.as_ref(), incidents, latencies, traces, language, metadata, staff::Command::Deactivate(staff_id)).await
+}
+
+// Staff sign in on shared terminals with this PIN, see sign_in_with_pin. Only its hash is recorded.
+#[put("/staff/<staff_id>/pin", format = "application/json", data = "<pin>")]
+async fn set_pin(staff_id: Uuid, pin: Json<NewPin>, manager: Manager, staff: &State<StaffStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<staff::Event> {
+    let pin = pin.into_inner().pin;
+    if !auth::is_valid_pin(&pin) {
+        return Err(rejected("invalid_pin_format", locale::invalid_pin_format_message(language)));
+    }
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_staff(staff.as_ref(), incidents, latencies, traces, language, metadata, staff::Command::SetPin(staff_id, auth::hash_pin(staff_id, &pin))).await
+}
+
+// Shared terminals sign staff in by PIN instead of giving everyone a token of their own. The
+// session's token then acts as the member, so their commands are recorded under their staff id.
+// Unknown staff, staff who left and staff without a PIN all get the same answer as a wrong PIN.
+#[post("/pin-sessions", format = "application/json", data = "<sign_in>")]
+fn sign_in_with_pin(sign_in: Json<PinSignIn>, terminal: Terminal, registry: &State<Arc<RwLock<StaffRegistry>>>, sessions: &State<Arc<PinSessions>>, language: Language) -> Result<Json<PinSession>, ApiError> {
+    let PinSignIn { staff_id, pin } = sign_in.into_inner();
+    let registry = registry.read().unwrap();
+    let member = registry.active_member(staff_id).ok_or_else(|| ApiError::new(Status::Unauthorized, "invalid_pin", locale::invalid_pin_message(language)))?;
+    let user = User { name: member.name.clone(), role: member.role, staff_id };
+    sessions.sign_in(user, terminal.0, registry.pin_hash(staff_id), &pin, SystemTime::now()).map(Json).map_err(|error| match error {
+        PinError::Wrong => ApiError::new(Status::Unauthorized, "invalid_pin", locale::invalid_pin_message(language)),
+        PinError::LockedOut => ApiError::new(Status::TooManyRequests, "pin_locked", locale::pin_locked_message(language))
+    })
+}
+
+#[post("/tables", format = "application/json", data = "<table>")]
+async fn register_table(table: Json<NewTable>, manager: Manager, tables: &State<TableStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<table::Event> {
+    let table_number = table.into_inner().table_number;
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata, table::Command::RegisterTable(table::table_id(table_number), table_number)).await
+}
+
+// Once the guests have left, so the table can be given to a new tab.
+#[post("/tables/<table_number>/clear")]
+async fn clear_table(table_number: u8, waiter: Waiter, tables: &State<TableStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<table::Event> {
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata, table::Command::ClearTable(table::table_id(table_number))).await
+}
+
+// Waiters send menu numbers and quantities; what was ordered and at what price is taken from the
+// menu as it stands.
+#[post("/tabs/<id>/orders", format = "application/json", data = "<order>")]
+async fn place_order(id: Uuid, order: Json<NewOrder>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, catalog: &State<Arc<RwLock<Catalog>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let order = order.into_inner().items;
+    let lines: Vec<(i32, u32)> = order.iter().map(|line| (line.menu_number, line.quantity)).collect();
+    let items = catalog.read().unwrap().resolve(&lines).map_err(|menu_number| {
+        let error = menu::CommandError::UnknownMenuItem;
+        rejected(menu_error_code(&error), locale::menu_error_message(&error, language)).with_menu_numbers(vec![menu_number])
+    })?;
+    // Lines ordered none of are not resolved, so the rest pair up with the items in order.
+    let items = order.into_iter().filter(|line| line.quantity > 0).zip(items).map(|(line, item)| {
+        let modifiers = line.modifiers.into_iter().map(|modifier| modifier.trim().to_string()).filter(|modifier| !modifier.is_empty()).collect();
+        let note = line.note.map(|note| note.trim().to_string()).filter(|note| !note.is_empty());
+        let course = line.course.filter(|_| !item.is_drink());
+        item.with_modifiers(modifiers).with_note(note).with_course(course)
+    }).collect();
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::PlaceOrder(id, items)).await
+}
+
+#[post("/tabs/<id>/served-drinks", format = "application/json", data = "<served>")]
+async fn mark_drinks_served(id: Uuid, served: Json<ServedItems>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::MarkDrinksServed(id, served.into_inner().quantities())).await
+}
+
+#[post("/tabs/<id>/served-food", format = "application/json", data = "<served>")]
+async fn mark_food_served(id: Uuid, served: Json<ServedItems>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::MarkFoodServed(id, served.into_inner().quantities())).await
+}
+
+#[post("/tabs/<id>/unordered-items", format = "application/json", data = "<served>")]
+async fn serve_unordered(id: Uuid, served: Json<UnorderedItems>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, catalog: &State<Arc<RwLock<Catalog>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let lines: Vec<(i32, u32)> = served.into_inner().items.iter().map(|line| (line.menu_number, line.quantity)).collect();
+    let items = catalog.read().unwrap().resolve(&lines).map_err(|menu_number| {
+        let error = menu::CommandError::UnknownMenuItem;
+        rejected(menu_error_code(&error), locale::menu_error_message(&error, language)).with_menu_numbers(vec![menu_number])
+    })?;
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::ServeUnordered(id, items)).await
+}
+
+#[post("/tabs/<id>/voided-items", format = "application/json", data = "<voided>")]
+async fn void_item(id: Uuid, voided: Json<VoidedItem>, manager: Manager, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let VoidedItem { line_id, menu_number, reason } = voided.into_inner();
+    let line = line_ref(line_id, menu_number, language)?;
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::VoidOrderedItem(id, line, reason)).await
+}
+
+#[post("/tabs/<id>/fired-courses", format = "application/json", data = "<fired>")]
+async fn fire_course(id: Uuid, fired: Json<FiredCourse>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let metadata = metadata.with_acting_user(waiter.0.name, waiter.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::FireCourse(id, fired.into_inner().course)).await
+}
+
+// The new table is taken before the tab moves, so a table with a tab of its own turns the
+// transfer down; the table moved from is freed once the tab has left it.
+#[post("/tabs/<id>/transfer", format = "application/json", data = "<transfer>")]
+async fn transfer_tab(id: Uuid, transfer: Json<TabTransfer>, waiter: Waiter, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, tables: &State<TableStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let table_number = transfer.into_inner().table_number;
+    let metadata = metadata.with_acting_user(waiter.0.name.clone(), waiter.0.staff_id);
+    dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata.clone(), table::Command::SeatGuests(table::table_id(table_number), id)).await?;
+    let transferred = dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.clone(), Command::TransferTab(id, table_number)).await;
+    // Not under the client's command id, like freeing the table of a tab that failed to open.
+    let freed = match transferred {
+        Ok(ref response) => response.1.0.events.iter().find_map(|event| match *event {
+            Event::TabTransferred { from_table_number, .. } => Some(from_table_number),
+            _ => None
+        }),
+        Err(_) => Some(table_number)
+    };
+    if let Some(freed) = freed {
+        let metadata = metadata.follow_up().with_acting_user(waiter.0.name, waiter.0.staff_id);
+        let _ = dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata, table::Command::ClearTable(table::table_id(freed))).await;
+    }
+    transferred
+}
+
+#[post("/tabs/<id>/comps", format = "application/json", data = "<comped>")]
+async fn comp_item(id: Uuid, comped: Json<CompedItem>, manager: Manager, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let CompedItem { line_id, menu_number, reason } = comped.into_inner();
+    let line = line_ref(line_id, menu_number, language)?;
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::CompItem(id, line, reason)).await
+}
+
+#[post("/tabs/<id>/discounts", format = "application/json", data = "<discount>")]
+async fn apply_discount(id: Uuid, discount: Json<Discount>, manager: Manager, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let Discount { percent, reason } = discount.into_inner();
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata, Command::ApplyDiscount(id, percent, reason)).await
+}
+
+#[get("/menu")]
+fn list_menu(_user: User, catalog: &State<Arc<RwLock<Catalog>>>, checkpoint: &State<MenuCheckpoint>) -> Cached<Json<Vec<MenuItem>>> {
+    let checkpoint = *checkpoint.0.read().unwrap();
+    Cached::new(checkpoint, Json(catalog.read().unwrap().items()))
+}
+
+#[get("/menu/changes?<params..>")]
+async fn menu_changes(params: MenuChangesParams, _user: User, store: &State<MenuStore>, language: Language) -> Result<Option<Json<MenuChanges>>, ApiError> {
+    let limit = params.limit.unwrap_or(MENU_CHANGES_LIMIT).clamp(1, MENU_CHANGES_LIMIT);
+    MenuChanges::since(store.as_ref(), menu_id(), params.since, limit).await.map(|changes| changes.map(Json)).map_err(|_| store_unavailable(language))
+}
+
+#[post("/menu/items", format = "application/json", data = "<item>")]
+async fn add_menu_item(item: Json<MenuItem>, manager: Manager, store: &State<MenuStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<menu::Event> {
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_menu(store.as_ref(), incidents, latencies, traces, language, metadata, menu::Command::AddMenuItem(menu_id(), item.into_inner())).await
+}
+
+#[put("/menu/items/<menu_number>/price", format = "application/json", data = "<price>")]
+async fn change_price(menu_number: i32, price: Json<NewPrice>, manager: Manager, store: &State<MenuStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<menu::Event> {
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_menu(store.as_ref(), incidents, latencies, traces, language, metadata, menu::Command::ChangePrice(menu_id(), menu_number, price.into_inner().price)).await
+}
+
+#[put("/menu/items/<menu_number>/nutrition", format = "application/json", data = "<nutrition>")]
+async fn declare_nutrition(menu_number: i32, nutrition: Json<NewNutrition>, manager: Manager, store: &State<MenuStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<menu::Event> {
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_menu(store.as_ref(), incidents, latencies, traces, language, metadata, menu::Command::DeclareNutrition(menu_id(), menu_number, nutrition.into_inner().nutrition)).await
+}
+
+#[delete("/menu/items/<menu_number>")]
+async fn retire_menu_item(menu_number: i32, manager: Manager, store: &State<MenuStore>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult<menu::Event> {
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    dispatch_menu(store.as_ref(), incidents, latencies, traces, language, metadata, menu::Command::RetireItem(menu_id(), menu_number)).await
+}
+
+// Devices listed in Devices.toml call this every so often; see devices::Heartbeats.
+#[post("/devices/<id>/heartbeat")]
+fn heartbeat(id: Uuid, heartbeats: &State<Arc<Heartbeats>>) -> Option<Status> {
+    if heartbeats.beat(id, SystemTime::now()) { Some(Status::NoContent) } else { None }
+}
+
+#[get("/admin/devices")]
+fn list_devices(_manager: Manager, heartbeats: &State<Arc<Heartbeats>>) -> Json<Vec<DeviceStatus>> {
+    Json(heartbeats.statuses(SystemTime::now()))
+}
+
+// Burn rates of the latency objectives in Latency.toml; see slo::CommandLatencies.
+#[get("/admin/latency")]
+fn latency_objectives(_manager: Manager, latencies: &State<Arc<CommandLatencies>>) -> Json<Vec<ObjectiveStatus>> {
+    Json(latencies.statuses(SystemTime::now()))
+}
+
+// Reads the whole tab log, so it is for the admin dashboard only.
+#[get("/admin/streams")]
+async fn stream_metrics(_manager: Manager, store: &State<Box<dyn EventStore<Event>>>, open_tabs: &State<Arc<RwLock<OpenTabs>>>, language: Language) -> Result<Json<StoreMetrics>, ApiError> {
+    let metrics = StreamMetrics::read(store.as_ref()).await.map_err(|_| store_unavailable(language))?;
+    let now = SystemTime::now();
+    let oldest_open_tab = open_tabs.read().unwrap().tabs().into_iter()
+        .filter_map(|tab| metrics.first_recorded_at(tab.tab_id).map(|opened_at| (tab, opened_at)))
+        .min_by_key(|&(_, opened_at)| opened_at)
+        .map(|(tab, opened_at)| OldestOpenTab {
+            tab_id: tab.tab_id,
+            table_number: tab.table_number,
+            opened_at,
+            age_seconds: now.duration_since(opened_at).map(|age| age.as_secs()).unwrap_or(0)
+        });
+    Ok(Json(StoreMetrics {
+        stream_count: metrics.stream_count(),
+        event_count: metrics.event_count(),
+        stream_length: metrics.length_percentiles(),
+        oldest_open_tab
+    }))
+}
+
+// Reads every log whole, so it is for operators planning compaction and archival only.
+#[get("/admin/store/stats")]
+async fn store_stats(_manager: Manager, stats: &State<StoreStats>, language: Language) -> Result<Json<Vec<LogStats>>, ApiError> {
+    stats.report(SystemTime::now()).await.map(Json).map_err(|_| store_unavailable(language))
+}
+
+// Everything a workflow led to, e.g. to find out why the kitchen never got a ticket. The id is the
+// X-Correlation-Id the client sent, or else the one recorded with the workflow's events.
+#[get("/admin/traces/<correlation_id>")]
+fn workflow_trace(correlation_id: Uuid, _manager: Manager, traces: &State<Arc<Traces>>) -> Option<Json<Vec<Span>>> {
+    let spans = traces.for_correlation(correlation_id);
+    if spans.is_empty() { None } else { Some(Json(spans)) }
+}
+
+#[get("/admin/queries")]
+fn query_timings(_manager: Manager, timings: &State<Arc<QueryTimings>>) -> Json<Vec<QueryTiming>> {
+    Json(timings.report())
+}
+
+// The tabs of the report at closing time that are still open, see watchdog::Watchdog. Always
+// empty without a closing hour in the configuration.
+#[get("/admin/late-tabs")]
+fn late_tabs(_manager: Manager, watchdog: &State<Option<Arc<Watchdog>>>, open_tabs: &State<Arc<RwLock<OpenTabs>>>) -> Json<Vec<LateTab>> {
+    let open_tabs = open_tabs.read().unwrap();
+    Json(watchdog.as_ref().map(|watchdog| watchdog.late_tabs(&open_tabs)).unwrap_or_default())
+}
+
+// A manager's override for a tab nobody is going to pay, e.g. one still open after closing time.
+// Its table is freed along with it.
+#[post("/admin/tabs/<id>/force-close", format = "application/json", data = "<closed>")]
+async fn force_close_tab(id: Uuid, closed: Json<ForcedClose>, manager: Manager, store: &State<Box<dyn EventStore<Event>>>, snapshots: &State<Snapshots>, cache: &State<TabCache>, policy: &State<TabPolicy>, tables: &State<TableStore>, open_tabs: &State<Arc<RwLock<OpenTabs>>>, incidents: &State<Incidents>, latencies: &State<Arc<CommandLatencies>>, traces: &State<Arc<Traces>>, language: Language, metadata: Metadata) -> CommandResult {
+    let table_number = open_tabs.read().unwrap().tabs().into_iter().find(|tab| tab.tab_id == id).map(|tab| tab.table_number);
+    let metadata = metadata.with_acting_user(manager.0.name.clone(), manager.0.staff_id);
+    let closed = dispatch(store.as_ref(), snapshots, cache, policy, incidents, latencies, traces, language, metadata.clone(), Command::ForceCloseTab(id, closed.into_inner().reason)).await?;
+    if let Some(table_number) = table_number {
+        let metadata = metadata.follow_up().with_acting_user(manager.0.name, manager.0.staff_id);
+        let _ = dispatch_table(tables.as_ref(), incidents, latencies, traces, language, metadata, table::Command::ClearTable(table::table_id(table_number))).await;
+    }
+    Ok(closed)
+}
+
+type AnnotationStore = Box<dyn EventStore<annotations::Event>>;
+
+// The tab's events as recorded, with what support staff noted on them, for looking into a
+// complaint.
+#[get("/admin/tabs/<id>/history")]
+async fn tab_history(id: Uuid, _manager: Manager, store: &State<Box<dyn EventStore<Event>>>, annotation_store: &State<AnnotationStore>, language: Language) -> Result<Option<Json<TabHistory>>, ApiError> {
+    let stream = store.read_stream(id).await.map_err(|_| store_unavailable(language))?;
+    if stream.events.is_empty() {
+        return Ok(None);
+    }
+    let annotations = annotations::for_stream(annotation_store.as_ref(), id).await.map_err(|_| store_unavailable(language))?;
+    Ok(Some(Json(TabHistory { events: stream.events, annotations })))
+}
+
+// Notes are kept apart from the tab's events, see annotations::annotate. Not found unless the
+// tab, and the event when there is one, was recorded.
+#[post("/admin/tabs/<id>/annotations", format = "application/json", data = "<annotation>")]
+async fn annotate_tab(id: Uuid, annotation: Json<NewAnnotation>, manager: Manager, store: &State<Box<dyn EventStore<Event>>>, annotation_store: &State<AnnotationStore>, language: Language, metadata: Metadata) -> Result<status::Created<Json<Annotation>>, ApiError> {
+    let NewAnnotation { note, ticket_url, event_id } = annotation.into_inner();
+    if note.trim().is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "malformed_request", locale::malformed_request_message(language)));
+    }
+    let stream = store.read_stream(id).await.map_err(|_| store_unavailable(language))?;
+    let recorded = match event_id {
+        Some(event_id) => stream.events.iter().any(|envelope| envelope.event_id == event_id),
+        None => !stream.events.is_empty()
+    };
+    if !recorded {
+        return Err(ApiError::new(Status::NotFound, "not_found", locale::not_found_message(language)));
+    }
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    let annotation = annotations::annotate(annotation_store.as_ref(), id, event_id, note, ticket_url, metadata).await.map_err(|error| match error {
+        StoreError::Concurrency(_) => ApiError::new(Status::Conflict, "concurrency_conflict", locale::concurrency_conflict_message(language)),
+        _ => store_unavailable(language)
+    })?;
+    Ok(status::Created::new(format!("/api/admin/tabs/{}/history", id)).body(Json(annotation)))
+}
+
+type FiscalStore = Box<dyn EventStore<fiscal::Event>>;
+
+fn fiscal_error(error: FiscalError, language: Language) -> ApiError {
+    match error {
+        FiscalError::Unavailable(_) => ApiError::new(Status::ServiceUnavailable, "fiscal_device_unavailable", locale::fiscal_device_unavailable_message(language)),
+        FiscalError::Rejected(_) => rejected("fiscal_request_rejected", locale::fiscal_request_rejected_message(language))
+    }
+}
+
+// The fiscal device's totals for the day, see fiscal::FiscalDevice. Not found without a device
+// in the configuration.
+#[get("/admin/fiscal/reports/<date>")]
+async fn fiscal_report(date: String, _manager: Manager, device: &State<Option<Arc<dyn FiscalDevice>>>, language: Language) -> Result<Json<DailyFiscalReport>, ApiError> {
+    let device = device.as_ref().ok_or_else(|| ApiError::new(Status::NotFound, "not_found", locale::not_found_message(language)))?;
+    let date: Date = date.parse().map_err(|_| ApiError::new(Status::BadRequest, "invalid_date", locale::invalid_date_message(language)))?;
+    device.daily_report(date).await.map(Json).map_err(|error| fiscal_error(error, language))
+}
+
+// Voids a receipt registered with the fiscal device, e.g. one closed on the wrong tab. The tab
+// stays closed; the void is recorded in the fiscal log.
+#[post("/admin/fiscal/receipts/<receipt_number>/void", format = "application/json", data = "<voided>")]
+async fn void_receipt(receipt_number: String, voided: Json<VoidedReceipt>, manager: Manager, device: &State<Option<Arc<dyn FiscalDevice>>>, fiscal_store: &State<FiscalStore>, language: Language, metadata: Metadata) -> Result<Status, ApiError> {
+    let device = device.as_ref().ok_or_else(|| ApiError::new(Status::NotFound, "not_found", locale::not_found_message(language)))?;
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    fiscal::void(device.as_ref(), fiscal_store.as_ref(), &receipt_number, &voided.reason, metadata).await
+        .map(|_| Status::NoContent)
+        .map_err(|error| fiscal_error(error, language))
+}
+
+#[get("/admin/cache")]
+fn cache_stats(_manager: Manager, cache: &State<TabCache>) -> Json<CacheStats> {
+    Json(cache.read().unwrap().stats())
+}
+
+#[get("/admin/projections")]
+fn list_projections(_manager: Manager, projections: &State<Projections>) -> Json<Vec<ProjectionStatus>> {
+    Json(projections.0.iter().map(|(&name, projection)| ProjectionStatus { name, position: projection.position() }).collect())
+}
+
+#[derive(Deserialize)]
+pub struct PriceBackfill {
+    fixes: Vec<PriceFix>,
+    reason: String,
+    #[serde(default)]
+    dry_run: bool
+}
+
+// Corrects what tabs were charged once a pricing bug is found, see backfill::correct_prices.
+#[post("/admin/backfill/prices", format = "application/json", data = "<backfill>")]
+async fn backfill_prices(backfill: Json<PriceBackfill>, manager: Manager, store: &State<Box<dyn EventStore<Event>>>, language: Language, metadata: Metadata) -> Result<Json<BackfillReport>, ApiError> {
+    let PriceBackfill { fixes, reason, dry_run } = backfill.into_inner();
+    let dry_run = dry_run || metadata.dry_run;
+    let metadata = metadata.with_acting_user(manager.0.name, manager.0.staff_id);
+    backfill::correct_prices(store.as_ref(), &fixes, &reason, metadata, dry_run).await.map(Json).map_err(|error| match error {
+        BackfillError::MixedCurrencies => rejected("currency_mismatch", locale::command_error_message(&CommandError::CurrencyMismatch, language)),
+        BackfillError::Handler(_, HandlerError::Rejected(error)) => rejected(error_code(&error), locale::command_error_message(&error, language)),
+        BackfillError::Handler(_, HandlerError::Concurrency(_)) => ApiError::new(Status::Conflict, "concurrency_conflict", locale::concurrency_conflict_message(language)),
+        BackfillError::Store(_) | BackfillError::Handler(_, HandlerError::Store(_)) => store_unavailable(language)
+    })
+}
+
+// Replays the whole tab log into a fresh copy of the read model, e.g. once a bug in it is fixed.
+#[post("/admin/projections/<name>/rebuild")]
+async fn rebuild_projection(name: String, _manager: Manager, projections: &State<Projections>, store: &State<Box<dyn EventStore<Event>>>, checkpoint: &State<Arc<RwLock<Checkpoint>>>, language: Language) -> Result<Option<Json<ProjectionStatus>>, ApiError> {
+    let (&name, projection) = match projections.0.get_key_value(name.as_str()) {
+        Some(found) => found,
+        None => return Ok(None)
+    };
+    let position = projection.rebuild(store.as_ref()).await.map_err(|_| store_unavailable(language))?;
+    checkpoint.write().unwrap().generation += 1;
+    Ok(Some(Json(ProjectionStatus { name, position })))
+}
+
+#[get("/kitchen/todo")]
+fn kitchen_todo(user: User, queries: &State<QueryBus<User>>, language: Language) -> Result<Cached<Json<Vec<TodoListGroup>>>, ApiError> {
+    let answer = ask(queries, &KitchenQueueQuery, &user, language)?;
+    Ok(Cached::new(answer.checkpoint, Json(answer.result)))
+}
+
+#[get("/tabs")]
+fn list_open_tabs(user: User, queries: &State<QueryBus<User>>, language: Language) -> Result<Cached<Json<Vec<TabStatus>>>, ApiError> {
+    let answer = ask(queries, &OpenTabsQuery, &user, language)?;
+    Ok(Cached::new(answer.checkpoint, Json(answer.result)))
+}
+
+#[get("/tables/<table_number>/invoice")]
+fn table_invoice(table_number: u8, user: User, queries: &State<QueryBus<User>>, catalog: &State<Arc<RwLock<Catalog>>>, config: &State<Config>, language: Language) -> Result<Option<Cached<Json<TabInvoice>>>, ApiError> {
+    let Answer { checkpoint, result } = ask(queries, &InvoiceQuery { table_number }, &user, language)?;
+    Ok(result.map(|invoice| {
+        let invoice = invoice.with_tax(config.tax_basis_points());
+        let invoice = if config.nutrition_on_invoices { invoice.with_nutrition(&catalog.read().unwrap()) } else { invoice };
+        Cached::new(checkpoint, Json(invoice))
+    }))
+}
+
+// Replays the tab's events and then sends each one as it is appended, so displays need not poll.
+// Each is its envelope, named by the event type and with the stream version as its id. Waiters
+// only get the tabs they hold, and others are not found, as with invoices.
+#[get("/tabs/<id>/events/stream")]
+async fn tab_event_stream(id: Uuid, user: User, store: &State<Box<dyn EventStore<Event>>>, language: Language, mut shutdown: rocket::Shutdown) -> Result<Option<EventStream![]>, ApiError> {
+    if user.role == Role::Chef {
+        return Err(ApiError::new(Status::Forbidden, "forbidden", locale::forbidden_message(language)));
+    }
+    let stream = store.read_stream(id).await.map_err(|_| store_unavailable(language))?;
+    let holder = stream.events.iter().rev().find_map(|envelope| match envelope.payload {
+        Event::TabOpened { waiter_id, .. } | Event::WaiterReassigned { waiter_id, .. } => Some(waiter_id),
+        _ => None
+    });
+    match holder {
+        Some(waiter_id) if user.role == Role::Manager || waiter_id == user.staff_id => {},
+        _ => return Ok(None)
+    }
+    // The tab's history comes first, then its new events; the store lets go of the listener once
+    // the stream is dropped.
+    let mut events = store.listen_to(id).await.map_err(|_| store_unavailable(language))?;
+    Ok(Some(EventStream! {
+        loop {
+            // Ends the stream on shutdown rather than hold it up until the grace period runs out.
+            let envelope = rocket::tokio::select! {
+                envelope = events.recv() => match envelope {
+                    Some(envelope) => envelope,
+                    None => break
+                },
+                _ = &mut shutdown => break
+            };
+            yield ServerEvent::json(&envelope).event(envelope.event_type.clone()).id(envelope.version.to_string());
+        }
+    }))
+}
+
+#[get("/waiters/<waiter_id>/todo")]
+fn waiter_todo(waiter_id: Uuid, user: User, queries: &State<QueryBus<User>>, language: Language) -> Result<Cached<Json<BTreeMap<u8, Vec<TabItem>>>>, ApiError> {
+    let answer = ask(queries, &WaiterTodoQuery { waiter_id }, &user, language)?;
+    Ok(Cached::new(answer.checkpoint, Json(answer.result.tables)))
+}
+
+type ExportResult = Result<Option<Json<ReadModelExport>>, ApiError>;
+
+async fn export(store: &dyn EventStore<Event>, position: Option<usize>, language: Language) -> ExportResult {
+    ReadModelExport::at(store, position).await.map(|export| export.map(Json)).map_err(|_| store_unavailable(language))
+}
+
+#[get("/export")]
+async fn export_now(_manager: Manager, store: &State<Box<dyn EventStore<Event>>>, language: Language) -> ExportResult {
+    export(store.as_ref(), None, language).await
+}
+
+#[get("/export/<position>")]
+async fn export_at(position: usize, _manager: Manager, store: &State<Box<dyn EventStore<Event>>>, language: Language) -> ExportResult {
+    export(store.as_ref(), Some(position), language).await
+}
+
+fn parse_date(date: Option<String>) -> Result<Option<Date>, InvalidDate> {
+    date.map_or(Ok(None), |date| date.parse().map(Some))
+}
+
+#[get("/search?<params..>")]
+fn search(params: SearchParams, _manager: Manager, index: &State<Arc<RwLock<SearchIndex>>>, checkpoint: &State<Arc<RwLock<Checkpoint>>>, language: Language) -> Result<Cached<Json<Vec<OrderRecord>>>, ApiError> {
+    let invalid_date = |_| ApiError::new(Status::BadRequest, "invalid_date", locale::invalid_date_message(language));
+    let query = SearchQuery {
+        waiter: params.waiter,
+        item: params.item,
+        from: parse_date(params.from).map_err(&invalid_date)?,
+        to: parse_date(params.to).map_err(&invalid_date)?
+    };
+    let checkpoint = *checkpoint.read().unwrap();
+    Ok(Cached::new(checkpoint, Json(index.read().unwrap().search(&query))))
+}
+
+#[get("/docs/domain")]
+fn domain_docs() -> (ContentType, String) {
+    (ContentType::new("text", "markdown"), docs::domain_catalog())
+}
+
+#[get("/openapi.json")]
+fn openapi_document() -> Json<Value> {
+    Json(openapi::document())
+}
+
+#[get("/docs/api")]
+fn api_docs() -> content::RawHtml<String> {
+    content::RawHtml(openapi::swagger_ui())
+}
+
+#[get("/reports/sales/<date>")]
+fn sales_report(date: String, _manager: Manager, sales: &State<Arc<RwLock<SalesReport>>>, checkpoint: &State<Arc<RwLock<Checkpoint>>>, language: Language) -> Result<Cached<Json<DailySales>>, ApiError> {
+    let date: Date = date.parse().map_err(|_| ApiError::new(Status::BadRequest, "invalid_date", locale::invalid_date_message(language)))?;
+    let checkpoint = *checkpoint.read().unwrap();
+    Ok(Cached::new(checkpoint, Json(sales.read().unwrap().on(date))))
+}
+
+#[get("/reports/tips?<params..>")]
+fn tips_report(params: TipsParams, _manager: Manager, tips: &State<Arc<RwLock<TipsPerWaiter>>>, checkpoint: &State<Arc<RwLock<Checkpoint>>>, language: Language) -> Result<Cached<Json<Vec<WaiterTips>>>, ApiError> {
+    let invalid_date = |_| ApiError::new(Status::BadRequest, "invalid_date", locale::invalid_date_message(language));
+    let from = parse_date(params.from).map_err(&invalid_date)?;
+    let to = parse_date(params.to).map_err(&invalid_date)?;
+    let checkpoint = *checkpoint.read().unwrap();
+    Ok(Cached::new(checkpoint, Json(tips.read().unwrap().report(from, to))))
+}
+
+#[get("/reports/forecast")]
+fn prep_forecast(_user: User, velocity: &State<Arc<RwLock<SalesVelocity>>>, checkpoint: &State<Arc<RwLock<Checkpoint>>>) -> Cached<Json<Forecast>> {
+    let checkpoint = *checkpoint.read().unwrap();
+    let forecast = velocity.read().unwrap().next_service(SystemTime::now());
+    let service = format!("{}-{:?}", forecast.date, forecast.daypart);
+    Cached::new(checkpoint, Json(forecast)).varying_by(&service)
+}
+
+#[cfg(feature = "tantivy")]
+#[derive(FromForm)]
+pub struct TextSearchParams {
+    q: String
+}
+
+#[cfg(feature = "tantivy")]
+#[get("/search/text?<params..>")]
+fn search_text(params: TextSearchParams, _manager: Manager, index: &State<Arc<RwLock<TextIndex>>>) -> Json<Vec<TextMatch>> {
+    Json(index.read().unwrap().search(&params.q, 20).expect("failed to search the text index"))
+}
+
+#[cfg(feature = "tantivy")]
+async fn with_text_search(rocket: Rocket<Build>, event_store: &dyn EventStore<Event>) -> Rocket<Build> {
+    let text_index = Arc::new(RwLock::new(TextIndex::new().expect("failed to create text index")));
+    event_store.subscribe(text_index.clone()).await.expect("failed to load text index");
+    rocket.mount("/api/", routes![search_text]).manage(text_index)
+}
+
+#[cfg(not(feature = "tantivy"))]
+async fn with_text_search(rocket: Rocket<Build>, _: &dyn EventStore<Event>) -> Rocket<Build> {
+    rocket
+}
+
+// How many of the latest workflows are kept traced.
+const TRACED_WORKFLOWS: usize = 1000;
+
+// How long food may wait to be served before the kitchen ticket flags it as running late.
+const KITCHEN_TICKET_MINUTES: u64 = 15;
+
+// Managed apart from the tab log's checkpoint, which has the same type.
+pub struct MenuCheckpoint(Arc<RwLock<Checkpoint>>);
+
+// The tab log's read models that can be rebuilt, by the name the admin API knows them by.
+pub struct Projections(BTreeMap<&'static str, Arc<dyn Rebuild<Event>>>);
+
+impl Projections {
+    async fn subscribe<P>(&mut self, store: &dyn EventStore<Event>, name: &'static str, projection: &Arc<RwLock<P>>, traces: &Arc<Traces>) where P: Projection<Event> + Default + Send + Sync + 'static {
+        let rebuildable = Arc::new(RwLock::new(Rebuildable::new(projection.clone())));
+        store.subscribe(trace::traced(name, rebuildable.clone(), traces)).await.unwrap_or_else(|error| panic!("failed to load {}: {}", name, error));
+        self.0.insert(name, rebuildable);
+    }
+}
+
+// Commands are handled and the logs read on Rocket's runtime, which the stores' I/O, the
+// watchers and the ticket runner share.
+pub fn launch(config: Config) {
+    rocket::execute(serve(config))
+}
+
+// The logs are opened as the configuration says, see config::Config; the read models are rebuilt
+// from them on startup. Payment callbacks, fiscal registrations and device, latency and closing
+// time alerts are only written.
+async fn serve(config: Config) {
+    money::set_json_format(config.money_format);
+    money::set_default_currency(config.currency);
+    let event_store = config.open_tab_log().await;
+    let menu_store: Arc<dyn EventStore<menu::Event>> = config.open_log("menu").into();
+    let table_store: Arc<dyn EventStore<table::Event>> = config.open_log("tables").into();
+    let shift_store: Arc<dyn EventStore<shift::Event>> = config.open_log("shifts").into();
+    let staff_store: Arc<dyn EventStore<staff::Event>> = config.open_log("staff").into();
+    let payment_store: Arc<dyn EventStore<payments::Event>> = config.open_log("payments").into();
+    let alert_store: Arc<dyn EventStore<devices::Event>> = config.open_log("device_alerts").into();
+    let latency_alert_store: Arc<dyn EventStore<slo::Event>> = config.open_log("latency_alerts").into();
+    let closing_alert_store: Arc<dyn EventStore<watchdog::Event>> = config.open_log("closing_alerts").into();
+    let receipt_store: Arc<dyn EventStore<receipts::Event>> = config.open_log("receipts").into();
+    let fiscal_store: Arc<dyn EventStore<fiscal::Event>> = config.open_log("fiscal").into();
+    let annotation_store: Arc<dyn EventStore<annotations::Event>> = config.open_log("annotations").into();
+    let traces = Arc::new(Traces::new(TRACED_WORKFLOWS));
+    let event_store: Arc<dyn EventStore<Event>> = Arc::new(TracedStore::new(event_store, traces.clone()));
+    let shutdown = Shutdown::new()
+        .with_store("tabs", event_store.clone())
+        .with_store("menu", menu_store.clone())
+        .with_store("tables", table_store.clone())
+        .with_store("shifts", shift_store.clone())
+        .with_store("staff", staff_store.clone())
+        .with_store("payments", payment_store.clone())
+        .with_store("device_alerts", alert_store.clone())
+        .with_store("latency_alerts", latency_alert_store.clone())
+        .with_store("closing_alerts", closing_alert_store.clone())
+        .with_store("receipts", receipt_store.clone())
+        .with_store("fiscal", fiscal_store.clone())
+        .with_store("annotations", annotation_store.clone());
+    let snapshot_store: Arc<dyn SnapshotStore<domain::State>> = Arc::new(InMemorySnapshotStore::new());
+    let snapshot_loads = Arc::new(SnapshotLoads::new());
+    let retention = RetentionPolicy::load_or_default("Retention.toml").expect("failed to read Retention.toml");
+    let store_stats = StoreStats::new()
+        .with_tab_store(config.backend_of("tabs"), event_store.clone(), snapshot_store.clone(), snapshot_loads.clone(), config.snapshot_every, retention)
+        .with_store("menu", config.backend_of("menu"), menu_store.clone())
+        .with_store("tables", config.backend_of("tables"), table_store.clone())
+        .with_store("shifts", config.backend_of("shifts"), shift_store.clone())
+        .with_store("staff", config.backend_of("staff"), staff_store.clone())
+        .with_store("payments", config.backend_of("payments"), payment_store.clone())
+        .with_store("device_alerts", config.backend_of("device_alerts"), alert_store.clone())
+        .with_store("latency_alerts", config.backend_of("latency_alerts"), latency_alert_store.clone())
+        .with_store("closing_alerts", config.backend_of("closing_alerts"), closing_alert_store.clone())
+        .with_store("receipts", config.backend_of("receipts"), receipt_store.clone())
+        .with_store("fiscal", config.backend_of("fiscal"), fiscal_store.clone())
+        .with_store("annotations", config.backend_of("annotations"), annotation_store.clone());
+    let policy = TabPolicy::load_or_default("Policy.toml").expect("failed to read Policy.toml");
+    let tokens = ApiTokens::load_or_default("Tokens.toml").expect("failed to read Tokens.toml");
+    let callback_secrets = CallbackSecrets::load_or_default("Payments.toml").expect("failed to read Payments.toml");
+    let incidents = Incidents::open("incidents.log").expect("failed to open incidents.log");
+    let heartbeats = Arc::new(Heartbeats::new(DeviceRegistry::load_or_default("Devices.toml").expect("failed to read Devices.toml")));
+    devices::watch(heartbeats.clone(), Box::new(alert_store));
+    let latencies = Arc::new(CommandLatencies::new(LatencyObjectives::load_or_default("Latency.toml").expect("failed to read Latency.toml")));
+    slo::watch(latencies.clone(), Box::new(latency_alert_store));
+    let open_tabs = Arc::new(RwLock::new(OpenTabs::new()));
+    let chef_todo_list = Arc::new(RwLock::new(ChefTodoList::new()));
+    let search_index = Arc::new(RwLock::new(SearchIndex::new()));
+    let sales_velocity = Arc::new(RwLock::new(SalesVelocity::new()));
+    let tips = Arc::new(RwLock::new(TipsPerWaiter::new()));
+    let sales = Arc::new(RwLock::new(SalesReport::new()));
+    let mut projections = Projections(BTreeMap::new());
+    projections.subscribe(event_store.as_ref(), "open_tabs", &open_tabs, &traces).await;
+    projections.subscribe(event_store.as_ref(), "chef_todo_list", &chef_todo_list, &traces).await;
+    projections.subscribe(event_store.as_ref(), "search_index", &search_index, &traces).await;
+    projections.subscribe(event_store.as_ref(), "sales_velocity", &sales_velocity, &traces).await;
+    projections.subscribe(event_store.as_ref(), "tips", &tips, &traces).await;
+    projections.subscribe(event_store.as_ref(), "sales", &sales, &traces).await;
+    let watchdog = config.closing_hour.map(|closing_hour| Arc::new(Watchdog::new(closing_hour)));
+    if let Some(ref watchdog) = watchdog {
+        watchdog::watch(watchdog.clone(), open_tabs.clone(), Box::new(closing_alert_store));
+    }
+    let checkpoint = Arc::new(RwLock::new(Checkpoint::new()));
+    event_store.subscribe(checkpoint.clone()).await.expect("failed to load checkpoint");
+    let query_timings = Arc::new(QueryTimings::new());
+    let mut queries = QueryBus::new().with_middleware(Box::new(query_timings.clone()));
+    queries.register(open_tabs.clone(), checkpoint.clone(), OpenTabsAccess);
+    queries.register(open_tabs.clone(), checkpoint.clone(), InvoiceAccess);
+    queries.register(open_tabs.clone(), checkpoint.clone(), WaiterTodoAccess);
+    queries.register(chef_todo_list.clone(), checkpoint.clone(), KitchenAccess);
+    let catalog = Arc::new(RwLock::new(Catalog::new()));
+    menu_store.subscribe(catalog.clone()).await.expect("failed to load the menu");
+    let roster = Arc::new(RwLock::new(Roster::new()));
+    shift_store.subscribe(roster.clone()).await.expect("failed to load the roster");
+    let staff_registry = Arc::new(RwLock::new(StaffRegistry::new()));
+    staff_store.subscribe(staff_registry.clone()).await.expect("failed to load the staff registry");
+    let menu_checkpoint = Arc::new(RwLock::new(Checkpoint::new()));
+    menu_store.subscribe(menu_checkpoint.clone()).await.expect("failed to load menu checkpoint");
+    let cache: TabCache = Arc::new(RwLock::new(Repository::new(config.tab_cache_capacity)));
+    event_store.subscribe(cache.clone()).await.expect("failed to load the tab cache");
+    let snapshots = Snapshots { store: snapshot_store, every: config.snapshot_every, loads: snapshot_loads };
+    let displays = Arc::new(Displays::new(open_tabs.clone(), chef_todo_list.clone()));
+    let events = event_store.listen().await.expect("failed to listen to the event store");
+    let sessions = Arc::new(PinSessions::new());
+    let (display_tokens, display_sessions) = (tokens.clone(), sessions.clone());
+    let authenticate = Box::new(move |header: &str| display_tokens.authenticate(header).or_else(|| display_sessions.authenticate(header, SystemTime::now())));
+    if let Err(error) = push::spawn(&config.push_address, events, displays, traces.clone(), authenticate) {
+        shutdown.exit(Some(format!("the display push server cannot listen on {}: {}", config.push_address, error))).await;
+    }
+    let tickets = ProcessRunner::new(KitchenTicket::new(Duration::from_secs(KITCHEN_TICKET_MINUTES * 60)), Box::new(InMemorySnapshotStore::new()));
+    let (ticket_store, ticket_cache) = (event_store.clone(), cache.clone());
+    tickets.spawn(event_store.listen().await.expect("failed to listen to the event store"), Duration::from_secs(30), move |command, metadata| {
+        let (store, cache) = (ticket_store.clone(), ticket_cache.clone());
+        async move {
+            // Food served in the meantime gets the flag turned down, which is fine.
+            let _ = CommandHandler::<Tab>::new(store.as_ref()).with_repository(&cache).with_metadata(metadata).handle(command).await;
+        }
+    });
+    let fiscal_device = config.fiscal.as_ref().map(|setup| setup.open().expect("failed to open the fiscal device"));
+    if let Some(ref device) = fiscal_device {
+        let registration = ProcessRunner::new(FiscalRegistration, Box::new(InMemorySnapshotStore::new()));
+        let (device, registered) = (device.clone(), fiscal_store.clone());
+        registration.spawn(event_store.listen().await.expect("failed to listen to the event store"), Duration::from_secs(30), move |command, metadata| {
+            let (device, store) = (device.clone(), registered.clone());
+            async move {
+                let fiscal::Command::RegisterReceipt(receipt) = command;
+                let receipt_number = receipt.receipt_number.clone();
+                if let Err(error) = fiscal::register(device.as_ref(), store.as_ref(), receipt, metadata).await {
+                    eprintln!("failed to record the fiscal registration of receipt {}: {}", receipt_number, error);
+                }
+            }
+        });
+    }
+    if let Some(ref setup) = config.printer {
+        printing::spawn(event_store.listen().await.expect("failed to listen to the event store"), setup.open(), SystemTime::now());
+    }
+    let payment_gateway = config.payment_gateway.as_ref().map(PaymentSetup::open);
+
+    let routes = routes![
+        open_tab,
+        payment_callback,
+        settle_tab,
+        start_shift,
+        end_shift,
+        hand_over,
+        assign_table,
+        list_waiters,
+        list_staff,
+        register_staff,
+        deactivate_staff,
+        set_pin,
+        sign_in_with_pin,
+        register_table,
+        clear_table,
+        place_order,
+        mark_drinks_served,
+        mark_food_served,
+        void_item,
+        serve_unordered,
+        fire_course,
+        transfer_tab,
+        comp_item,
+        apply_discount,
+        list_menu,
+        menu_changes,
+        add_menu_item,
+        change_price,
+        retire_menu_item,
+        declare_nutrition,
+        kitchen_todo,
+        list_open_tabs,
+        table_invoice,
+        waiter_todo,
+        tab_event_stream,
+        export_now,
+        export_at,
+        search,
+        sales_report,
+        tips_report,
+        prep_forecast,
+        heartbeat,
+        list_devices,
+        latency_objectives,
+        workflow_trace,
+        stream_metrics,
+        store_stats,
+        list_projections,
+        query_timings,
+        cache_stats,
+        late_tabs,
+        force_close_tab,
+        tab_history,
+        annotate_tab,
+        fiscal_report,
+        void_receipt,
+        rebuild_projection,
+        backfill_prices,
+        domain_docs,
+        openapi_document,
+        api_docs
     ];
-    rocket::ignite()
+    let event_store: Box<dyn EventStore<Event>> = Box::new(event_store);
+    let rocket = with_text_search(rocket::build(), event_store.as_ref()).await
         .mount("/api/", routes)
+        .register("/", catchers![bad_request, unauthorized, forbidden, not_found, unprocessable_entity, internal_error])
         .manage(event_store)
-        .launch();
+        .manage(Box::new(menu_store) as MenuStore)
+        .manage(Box::new(table_store) as TableStore)
+        .manage(Box::new(shift_store) as ShiftStore)
+        .manage(Box::new(staff_store) as StaffStore)
+        .manage(Box::new(receipt_store) as ReceiptStore)
+        .manage(Box::new(fiscal_store) as FiscalStore)
+        .manage(Box::new(annotation_store) as AnnotationStore)
+        .manage(fiscal_device)
+        .manage(payment_gateway)
+        .manage(Deduplicator::new(Box::new(payment_store)))
+        .manage(callback_secrets)
+        .manage(snapshots)
+        .manage(store_stats)
+        .manage(cache)
+        .manage(watchdog)
+        .manage(policy)
+        .manage(config)
+        .manage(tokens)
+        .manage(sessions)
+        .manage(incidents)
+        .manage(heartbeats)
+        .manage(latencies)
+        .manage(traces)
+        .manage(open_tabs)
+        .manage(chef_todo_list)
+        .manage(search_index)
+        .manage(sales_velocity)
+        .manage(tips)
+        .manage(projections)
+        .manage(sales)
+        .manage(checkpoint)
+        .manage(queries)
+        .manage(query_timings)
+        .manage(catalog)
+        .manage(roster)
+        .manage(staff_registry)
+        .manage(MenuCheckpoint(menu_checkpoint));
+    // Rocket stops on SIGTERM or Ctrl-C once the requests in flight are answered, so nothing is
+    // written after the logs are closed.
+    let launched = rocket.launch().await;
+    shutdown.exit(launched.err().map(|error| error.to_string())).await;
 }
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::policy::PolicyError;
+use crate::staff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Waiter,
+    Chef,
+    Manager
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub role: Role,
+    pub staff_id: Uuid
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+struct ApiToken {
+    token: String,
+    user: String,
+    role: Role,
+    #[serde(default)]
+    staff_id: Option<Uuid>
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+struct TerminalToken {
+    token: String,
+    name: String
+}
+
+// Who may call the API, read from TOML. Clients send the token as `Authorization: Bearer <token>`.
+//
+//     [[tokens]]
+//     token = "d3c1f0a4e1b8"
+//     user = "Derek"
+//     role = "waiter"
+//
+//     [[terminals]]
+//     token = "7be05d2c94a1"
+//     name = "Bar till"
+//
+// Staff are told apart by the id the staff registry gave them. Tokens without one are for
+// whoever holds the legacy id of the name, see staff::legacy_id. Terminal tokens are nobody's;
+// they only let a shared terminal sign staff in by PIN, see PinSessions.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ApiTokens {
+    tokens: Vec<ApiToken>,
+    terminals: Vec<TerminalToken>
+}
+
+impl ApiTokens {
+    pub fn from_toml(source: &str) -> Result<ApiTokens, PolicyError> {
+        toml::from_str(source).map_err(PolicyError::Parse)
+    }
+
+    // Without the file nobody can call the guarded routes.
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<ApiTokens, PolicyError> {
+        let mut source = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => ApiTokens::from_toml(&source),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(ApiTokens::default()),
+            Err(error) => Err(PolicyError::Io(error))
+        }
+    }
+
+    pub fn user(&self, token: &str) -> Option<User> {
+        self.tokens.iter()
+            .find(|api_token| api_token.token == token)
+            .map(|api_token| User {
+                name: api_token.user.clone(),
+                role: api_token.role,
+                staff_id: api_token.staff_id.unwrap_or_else(|| staff::legacy_id(&api_token.user))
+            })
+    }
+
+    pub fn authenticate(&self, header: &str) -> Option<User> {
+        bearer_token(header).and_then(|token| self.user(token))
+    }
+
+    // The name of the terminal the header's token belongs to.
+    pub fn terminal(&self, header: &str) -> Option<String> {
+        let token = bearer_token(header)?;
+        self.terminals.iter()
+            .find(|terminal| terminal.token == token)
+            .map(|terminal| terminal.name.clone())
+    }
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("Bearer") => Some(token.trim()),
+        _ => None
+    }
+}
+
+// How long a PIN sign-in lasts. Terminals are shared, so it is about as long as taking an order.
+const PIN_SESSION_SECONDS: u64 = 5 * 60;
+
+// Wrong PINs in a row before the member is locked out, and for how long.
+const PIN_ATTEMPTS: u32 = 5;
+const PIN_LOCKOUT_SECONDS: u64 = 5 * 60;
+
+// PINs are four to eight digits.
+pub fn is_valid_pin(pin: &str) -> bool {
+    (4..=8).contains(&pin.len()) && pin.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+// Salted with the staff id, so two members with the same PIN are not given away by their hashes.
+pub fn hash_pin(staff_id: Uuid, pin: &str) -> String {
+    let digest = Sha256::new().chain_update(staff_id.as_bytes()).chain_update(pin.as_bytes()).finalize();
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PinSession {
+    pub token: String,
+    pub staff_id: Uuid,
+    pub terminal: String,
+    pub expires_at: SystemTime
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinError {
+    Wrong,
+    LockedOut
+}
+
+// Staff signed in by PIN on a shared terminal. Each sign-in gets a token of its own that acts as
+// the member, with the role they were registered with, until it expires. Sessions are only kept
+// in memory, so a restart signs everyone out.
+#[derive(Debug, Default)]
+pub struct PinSessions {
+    sessions: Mutex<HashMap<String, (User, SystemTime)>>,
+    failures: Mutex<HashMap<Uuid, (u32, SystemTime)>>
+}
+
+impl PinSessions {
+    pub fn new() -> PinSessions {
+        PinSessions::default()
+    }
+
+    // The PIN hash is the member's from the staff registry, None if they have not been given one.
+    pub fn sign_in(&self, user: User, terminal: String, pin_hash: Option<&str>, pin: &str, now: SystemTime) -> Result<PinSession, PinError> {
+        let mut failures = self.failures.lock().unwrap();
+        let lockout = Duration::from_secs(PIN_LOCKOUT_SECONDS);
+        let failed = match failures.get(&user.staff_id) {
+            Some(&(count, last)) if count >= PIN_ATTEMPTS && now < last + lockout => return Err(PinError::LockedOut),
+            Some(&(count, _)) if count < PIN_ATTEMPTS => count,
+            _ => 0
+        };
+        if pin_hash != Some(hash_pin(user.staff_id, pin).as_str()) {
+            failures.insert(user.staff_id, (failed + 1, now));
+            return Err(PinError::Wrong);
+        }
+        failures.remove(&user.staff_id);
+
+        let session = PinSession { token: Uuid::new_v4().simple().to_string(), staff_id: user.staff_id, terminal, expires_at: now + Duration::from_secs(PIN_SESSION_SECONDS) };
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, &mut (_, expires_at)| expires_at > now);
+        sessions.insert(session.token.clone(), (user, session.expires_at));
+        Ok(session)
+    }
+
+    pub fn user(&self, token: &str, now: SystemTime) -> Option<User> {
+        match self.sessions.lock().unwrap().get(token) {
+            Some(&(ref user, expires_at)) if expires_at > now => Some(user.clone()),
+            _ => None
+        }
+    }
+
+    pub fn authenticate(&self, header: &str, now: SystemTime) -> Option<User> {
+        bearer_token(header).and_then(|token| self.user(token, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_tokens_identify_users() {
+        let tokens = ApiTokens::from_toml("[[tokens]]\ntoken = \"abc\"\nuser = \"Derek\"\nrole = \"waiter\"\n").unwrap();
+        let derek = User { name: "Derek".to_string(), role: Role::Waiter, staff_id: staff::legacy_id("Derek") };
+        assert_eq!(tokens.authenticate("Bearer abc"), Some(derek.clone()));
+        assert_eq!(tokens.authenticate("bearer abc"), Some(derek));
+        assert_eq!(tokens.authenticate("Bearer abd"), None);
+        assert_eq!(tokens.authenticate("Basic abc"), None);
+        assert_eq!(tokens.authenticate("abc"), None);
+
+        let staff_id = Uuid::new_v4();
+        let tokens = ApiTokens::from_toml(&format!("[[tokens]]\ntoken = \"abc\"\nuser = \"Derek\"\nrole = \"waiter\"\nstaff_id = \"{}\"\n", staff_id)).unwrap();
+        assert_eq!(tokens.user("abc").map(|user| user.staff_id), Some(staff_id));
+    }
+
+    #[test]
+    fn terminal_tokens_are_not_users() {
+        let tokens = ApiTokens::from_toml("[[terminals]]\ntoken = \"till\"\nname = \"Bar till\"\n").unwrap();
+        assert_eq!(tokens.terminal("Bearer till"), Some("Bar till".to_string()));
+        assert_eq!(tokens.authenticate("Bearer till"), None);
+        assert_eq!(tokens.terminal("Bearer abc"), None);
+    }
+
+    #[test]
+    fn pin_sessions_expire_and_wrong_pins_lock_out() {
+        let derek = User { name: "Derek".to_string(), role: Role::Waiter, staff_id: staff::legacy_id("Derek") };
+        let pin_hash = hash_pin(derek.staff_id, "1234");
+        let sessions = PinSessions::new();
+        let now = SystemTime::now();
+
+        let session = sessions.sign_in(derek.clone(), "Bar till".to_string(), Some(&pin_hash), "1234", now).unwrap();
+        assert_eq!(sessions.authenticate(&format!("Bearer {}", session.token), now), Some(derek.clone()));
+        assert_eq!(sessions.user(&session.token, session.expires_at), None);
+        assert_eq!(sessions.sign_in(derek.clone(), "Bar till".to_string(), None, "1234", now), Err(PinError::Wrong));
+
+        for _ in 1..PIN_ATTEMPTS {
+            assert_eq!(sessions.sign_in(derek.clone(), "Bar till".to_string(), Some(&pin_hash), "4321", now), Err(PinError::Wrong));
+        }
+        assert_eq!(sessions.sign_in(derek.clone(), "Bar till".to_string(), Some(&pin_hash), "1234", now), Err(PinError::LockedOut));
+        let later = now + Duration::from_secs(PIN_LOCKOUT_SECONDS);
+        assert!(sessions.sign_in(derek, "Bar till".to_string(), Some(&pin_hash), "1234", later).is_ok());
+    }
+
+    #[test]
+    fn pins_are_digits_hashed_per_member() {
+        assert!(is_valid_pin("0451") && is_valid_pin("12345678"));
+        assert!(!is_valid_pin("123") && !is_valid_pin("123456789") && !is_valid_pin("12a4"));
+        assert!(hash_pin(staff::legacy_id("Derek"), "1234") != hash_pin(staff::legacy_id("Jane"), "1234"));
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::cqrs::{CommandHandler, EventEnvelope, HandlerError, Metadata};
+use crate::cqrs::store::{EventStore, StoreError};
+use crate::domain::{Command, CommandError, Event, OrderedItem, PriceCorrection, Tab};
+use crate::money::{Currency, Money};
+use crate::read_model::{self, TabItem};
+
+// A price that was charged for a menu item by mistake and what it should have been.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PriceFix {
+    pub menu_number: i32,
+    pub charged: Money,
+    pub correct: Money
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AffectedTab {
+    pub tab_id: Uuid,
+    pub table_number: u8,
+    pub items_corrected: usize,
+    pub adjustment: Money
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackfillReport {
+    pub dry_run: bool,
+    pub tabs: Vec<AffectedTab>,
+    pub total_correction: Money
+}
+
+#[derive(Debug)]
+pub enum BackfillError {
+    // The fixes have to be in one currency, for the total to mean anything.
+    MixedCurrencies,
+    Store(StoreError),
+    Handler(Uuid, HandlerError<CommandError>)
+}
+
+impl From<StoreError> for BackfillError {
+    fn from(error: StoreError) -> BackfillError {
+        BackfillError::Store(error)
+    }
+}
+
+// A tab as far as the backfill cares: who it is and what was served at which price.
+#[derive(Default)]
+struct Replayed {
+    table_number: u8,
+    drinks: Vec<TabItem>,
+    food: Vec<TabItem>,
+    served: Vec<TabItem>
+}
+
+impl Replayed {
+    fn apply(&mut self, event: &Event) {
+        match *event {
+            Event::TabOpened { table_number, .. } | Event::TabTransferred { table_number, .. } => self.table_number = table_number,
+            Event::DrinksOrdered { ref items } => self.drinks.extend(items.iter().flat_map(OrderedItem::units).map(|item| TabItem::from(&item))),
+            Event::FoodOrdered { ref items } => self.food.extend(items.iter().flat_map(OrderedItem::units).map(|item| TabItem::from(&item))),
+            Event::DrinksServed { ref line_ids, .. } => read_model::move_items(&mut self.drinks, &mut self.served, line_ids),
+            Event::FoodServed { ref line_ids, .. } => read_model::move_items(&mut self.food, &mut self.served, line_ids),
+            Event::ItemVoided { line_id, .. } => {
+                if let Some(index) = self.drinks.iter().position(|item| item.line_id == line_id) {
+                    self.drinks.remove(index);
+                } else if let Some(index) = self.food.iter().position(|item| item.line_id == line_id) {
+                    self.food.remove(index);
+                }
+            },
+            Event::ServedPriceCorrected { menu_number, charged, correct, count, .. } => read_model::reprice(&mut self.served, menu_number, charged, correct, count),
+            // Given away, so there is no price left to correct.
+            Event::ItemComped { line_id, .. } => {
+                if let Some(index) = self.served.iter().position(|item| item.line_id == line_id) {
+                    self.served.remove(index);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // Items corrected before are served at the correct price by now, so running the backfill
+    // again corrects nothing twice.
+    fn corrections(&self, fixes: &[PriceFix], reason: &str) -> Vec<PriceCorrection> {
+        fixes.iter()
+            .map(|fix| PriceCorrection {
+                menu_number: fix.menu_number,
+                charged: fix.charged,
+                correct: fix.correct,
+                count: self.served.iter().filter(|item| item.menu_number == fix.menu_number && item.price == fix.charged).count(),
+                reason: reason.to_string()
+            })
+            .filter(|correction| correction.count > 0)
+            .collect()
+    }
+}
+
+// Replays every tab and corrects the served value of those charged one of the wrong prices, with
+// a ServedPriceCorrected event per price; history itself is never changed. All corrections of
+// one run share the metadata's correlation id. A dry run only reports what would be corrected.
+pub async fn correct_prices(store: &dyn EventStore<Event>, fixes: &[PriceFix], reason: &str, metadata: Metadata, dry_run: bool) -> Result<BackfillReport, BackfillError> {
+    let currency = fixes.first().map_or_else(Currency::default, |fix| fix.charged.currency());
+    if fixes.iter().any(|fix| fix.charged.currency() != currency || fix.correct.currency() != currency) {
+        return Err(BackfillError::MixedCurrencies);
+    }
+
+    let mut order = Vec::new();
+    let mut tabs: HashMap<Uuid, Replayed> = HashMap::new();
+    for EventEnvelope { stream_id, payload, .. } in store.read_all().await? {
+        tabs.entry(stream_id).or_insert_with(|| {
+            order.push(stream_id);
+            Replayed::default()
+        }).apply(&payload);
+    }
+
+    let mut report = BackfillReport { dry_run, tabs: Vec::new(), total_correction: Money::zero(currency) };
+    for tab_id in order {
+        let tab = &tabs[&tab_id];
+        let corrections = tab.corrections(fixes, reason);
+        if corrections.is_empty() {
+            continue;
+        }
+        let items_corrected = corrections.iter().map(|correction| correction.count).sum();
+        let adjustment = corrections.iter().fold(Money::zero(currency), |total, correction| total + (correction.correct - correction.charged) * correction.count as i64);
+        if !dry_run {
+            CommandHandler::<Tab>::new(store)
+                .with_metadata(metadata.clone())
+                .handle(Command::CorrectServedPrices(tab_id, corrections)).await
+                .map_err(|error| BackfillError::Handler(tab_id, error))?;
+        }
+        report.total_correction += adjustment;
+        report.tabs.push(AffectedTab { tab_id, table_number: tab.table_number, items_corrected, adjustment });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::store::InMemoryEventStore;
+    use crate::domain::{LineRef, OrderedItem};
+    use crate::staff;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    async fn served_tab(store: &dyn EventStore<Event>, table_number: u8, price: Money) -> Uuid {
+        let tab_id = Uuid::new_v4();
+        let handler = CommandHandler::<Tab>::new(store);
+        let coffee = OrderedItem::new(1, "Coffee".to_string(), true, price);
+        handler.handle(Command::OpenTab(tab_id, table_number, staff::legacy_id("Derek"), "Derek".to_string())).await.unwrap();
+        handler.handle(Command::PlaceOrder(tab_id, vec![coffee.clone(), coffee])).await.unwrap();
+        handler.handle(Command::MarkDrinksServed(tab_id, vec![(LineRef::MenuNumber(1), 1), (LineRef::MenuNumber(1), 1)])).await.unwrap();
+        tab_id
+    }
+
+    #[tokio::test]
+    async fn tabs_charged_the_wrong_price_are_corrected_once() {
+        let store = InMemoryEventStore::new();
+        let overcharged = served_tab(&store, 1, eur(300)).await;
+        served_tab(&store, 2, eur(250)).await;
+        let fixes = vec![PriceFix { menu_number: 1, charged: eur(300), correct: eur(250) }];
+
+        let dry_run = correct_prices(&store, &fixes, "Price typo", Metadata::new(), true).await.unwrap();
+        assert_eq!(dry_run.tabs, vec![AffectedTab { tab_id: overcharged, table_number: 1, items_corrected: 2, adjustment: eur(-100) }]);
+        assert_eq!(dry_run.total_correction, eur(-100));
+        let history = store.read_all().await.unwrap().len();
+
+        let report = correct_prices(&store, &fixes, "Price typo", Metadata::new(), false).await.unwrap();
+        assert_eq!(report.tabs, dry_run.tabs);
+        assert_eq!(store.read_all().await.unwrap().len(), history + 1);
+        let (state, _) = CommandHandler::<Tab>::new(&store).load(overcharged).await.unwrap();
+        assert_eq!(state.served_items_value(), eur(500));
+
+        assert_eq!(correct_prices(&store, &fixes, "Price typo", Metadata::new(), false).await.unwrap().tabs, vec![]);
+
+        let mixed = vec![PriceFix { menu_number: 1, charged: eur(300), correct: Money::new(250, Currency::USD) }];
+        assert!(matches!(correct_prices(&store, &mixed, "Price typo", Metadata::new(), true).await, Err(BackfillError::MixedCurrencies)));
+    }
+}
@@ -0,0 +1,24 @@
+extern crate cafe;
+
+use std::env;
+use std::process;
+
+use cafe::config::Config;
+use cafe::replay::Replay;
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let replay = Replay::parse(&args).unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(2);
+    });
+    let config = Config::load().expect("failed to read the configuration");
+    match replay.run(&config).await {
+        Ok(output) => println!("{}", output),
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    }
+}
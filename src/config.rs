@@ -0,0 +1,207 @@
+use std::env;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use toml::{self, Value};
+
+use crate::cqrs::store::{EventStore, FileEventStore, InMemoryEventStore, Upcasters};
+#[cfg(feature = "postgres")]
+use crate::cqrs::store::PostgresEventStore;
+use crate::domain::{self, Event};
+use crate::fiscal::FiscalSetup;
+use crate::gateway::PaymentSetup;
+use crate::money::{Currency, JsonFormat};
+use crate::policy::PolicyError;
+use crate::printing::PrinterSetup;
+use crate::receipts::ReceiptNumbering;
+
+// Environment variables starting with this override the settings of the same name, e.g.
+// CAFE_DATABASE_URL overrides database_url.
+const ENV_PREFIX: &str = "CAFE_";
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    Memory,
+    File,
+    Postgres
+}
+
+// How the server is set up, from the cafe table of Rocket.toml's global section, with
+// environment variables taking precedence:
+//
+//     [global.cafe]
+//     store = "postgres"
+//     database_url = "postgres://cafe@localhost/cafe"
+//     snapshot_every = 100
+//     tab_cache_capacity = 1000
+//     closing_hour = 23
+//     currency = "EUR"
+//     tax_rate_percent = 20
+//     nutrition_on_invoices = true
+//     money_format = "formatted"
+//     push_address = "0.0.0.0:8001"
+//
+// The file store keeps each log in a directory of its own under data_dir. A snapshot_every of 0
+// turns tab snapshots off, and a tab_cache_capacity of 0 the cache of hydrated tabs. Prices
+// include tax at tax_rate_percent, which invoices then show, along with the nutrition the menu
+// declares for the items served if nutrition_on_invoices is set. Tabs still open at closing_hour, in
+// UTC, are reported to the managers; without it nobody watches. Kitchen and waiter displays connect
+// to push_address, see push::spawn. Receipts are only numbered with a
+// [global.cafe.receipts] table, see receipts::ReceiptNumbering, and registered with a fiscal
+// device with a [global.cafe.fiscal] table as well, see fiscal::FiscalSetup. Food is printed in
+// the kitchen with a [global.cafe.printer] table, see printing::PrinterSetup. Tabs are only settled
+// by card at the table with a [global.cafe.payment_gateway] table, see gateway::PaymentSetup.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub store: StoreBackend,
+    pub data_dir: PathBuf,
+    pub database_url: Option<String>,
+    pub snapshot_every: usize,
+    pub tab_cache_capacity: usize,
+    pub closing_hour: Option<u8>,
+    pub currency: Currency,
+    pub tax_rate_percent: f64,
+    pub nutrition_on_invoices: bool,
+    pub money_format: JsonFormat,
+    pub push_address: String,
+    pub receipts: Option<ReceiptNumbering>,
+    pub fiscal: Option<FiscalSetup>,
+    pub printer: Option<PrinterSetup>,
+    pub payment_gateway: Option<PaymentSetup>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            store: StoreBackend::Memory,
+            data_dir: PathBuf::from("data"),
+            database_url: None,
+            snapshot_every: 100,
+            tab_cache_capacity: 1000,
+            closing_hour: None,
+            currency: Currency::EUR,
+            tax_rate_percent: 0.0,
+            nutrition_on_invoices: false,
+            money_format: JsonFormat::Plain,
+            push_address: "0.0.0.0:8001".to_string(),
+            receipts: None,
+            fiscal: None,
+            printer: None,
+            payment_gateway: None
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, PolicyError> {
+        let mut source = String::new();
+        match File::open("Rocket.toml").and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => {},
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {},
+            Err(error) => return Err(PolicyError::Io(error))
+        }
+        Config::from_sources(&source, env::vars())
+    }
+
+    // Environment values are taken as numbers where they parse as one.
+    pub fn from_sources<I: IntoIterator<Item = (String, String)>>(rocket_toml: &str, vars: I) -> Result<Config, PolicyError> {
+        let rocket: Value = toml::from_str(rocket_toml).map_err(PolicyError::Parse)?;
+        let mut settings = match rocket.get("global").and_then(|global| global.get("cafe")) {
+            Some(Value::Table(cafe)) => cafe.clone(),
+            _ => toml::value::Table::new()
+        };
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                settings.insert(key.to_lowercase(), env_value(value));
+            }
+        }
+        Value::Table(settings).try_into().map_err(PolicyError::Parse)
+    }
+
+    // Whole basis points, so tax is worked out in integers like the rest of the money.
+    pub fn tax_basis_points(&self) -> i64 {
+        (self.tax_rate_percent * 100.0).round() as i64
+    }
+
+    // Where the log of the given name is kept, as the logs are opened below.
+    pub fn backend_of(&self, log: &str) -> StoreBackend {
+        match self.store {
+            StoreBackend::Postgres if log == "tabs" => StoreBackend::Postgres,
+            StoreBackend::Memory => StoreBackend::Memory,
+            _ => StoreBackend::File
+        }
+    }
+
+    // The tab log goes to the configured backend. Its Postgres schema has room for one log only,
+    // so with Postgres the other logs are kept in files.
+    pub async fn open_tab_log(&self) -> Box<dyn EventStore<Event>> {
+        match self.store {
+            StoreBackend::Postgres => self.connect().await,
+            _ => self.open_log_with_upcasters("tabs", domain::upcasters())
+        }
+    }
+
+    pub fn open_log<T>(&self, name: &str) -> Box<dyn EventStore<T>> where T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static {
+        self.open_log_with_upcasters(name, Upcasters::new())
+    }
+
+    fn open_log_with_upcasters<T>(&self, name: &str, upcasters: Upcasters) -> Box<dyn EventStore<T>> where T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static {
+        match self.store {
+            StoreBackend::Memory => Box::new(InMemoryEventStore::new()),
+            StoreBackend::File | StoreBackend::Postgres => {
+                let directory = self.data_dir.join(name);
+                Box::new(FileEventStore::open_with_upcasters(&directory, upcasters).unwrap_or_else(|error| panic!("failed to open {}: {:?}", directory.display(), error)))
+            }
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    async fn connect(&self) -> Box<dyn EventStore<Event>> {
+        let url = self.database_url.as_ref().expect("database_url is needed for the postgres store");
+        Box::new(PostgresEventStore::connect_with_upcasters(url, domain::upcasters()).await.unwrap_or_else(|error| panic!("failed to connect to the event store: {:?}", error)))
+    }
+
+    #[cfg(not(feature = "postgres"))]
+    async fn connect(&self) -> Box<dyn EventStore<Event>> {
+        panic!("the postgres store needs the postgres feature")
+    }
+}
+
+fn env_value(value: String) -> Value {
+    value.parse().map(Value::Integer)
+        .or_else(|_| value.parse().map(Value::Float))
+        .unwrap_or(Value::String(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_rocket_toml() {
+        let rocket_toml = "[global]\naddress = \"0.0.0.0\"\n\n[global.cafe]\nstore = \"file\"\nsnapshot_every = 50\ncurrency = \"GBP\"\n";
+        let vars = vec![
+            ("CAFE_SNAPSHOT_EVERY".to_string(), "0".to_string()),
+            ("CAFE_TAX_RATE_PERCENT".to_string(), "5.5".to_string()),
+            ("CAFE_DATABASE_URL".to_string(), "postgres://cafe@localhost/cafe".to_string()),
+            ("ROCKET_PORT".to_string(), "8080".to_string())
+        ];
+        let config = Config::from_sources(rocket_toml, vars).unwrap();
+        assert_eq!(config.store, StoreBackend::File);
+        assert_eq!(config.snapshot_every, 0);
+        assert_eq!(config.currency, Currency::GBP);
+        assert_eq!(config.tax_basis_points(), 550);
+        assert_eq!(config.database_url, Some("postgres://cafe@localhost/cafe".to_string()));
+        assert_eq!(config.data_dir, PathBuf::from("data"));
+
+        assert_eq!(Config::from_sources("", Vec::new()).unwrap(), Config::default());
+        assert!(Config::from_sources("", vec![("CAFE_STORE".to_string(), "redis".to_string())]).is_err());
+        let postgres = Config::from_sources("", vec![("CAFE_STORE".to_string(), "postgres".to_string())]).unwrap();
+        assert_eq!((postgres.backend_of("tabs"), postgres.backend_of("menu"), config.backend_of("tabs")), (StoreBackend::Postgres, StoreBackend::File, StoreBackend::File));
+    }
+}
@@ -0,0 +1,765 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+pub mod process;
+pub mod query;
+pub mod rebuild;
+pub mod repository;
+pub mod store;
+pub mod testing;
+pub mod trace;
+
+pub use self::process::{ProcessManager, ProcessRunner};
+pub use self::query::{Answer, Query, QueryBus, QueryError, QueryHandler, QueryMiddleware, QueryPolicy, QueryTiming, QueryTimings};
+pub use self::rebuild::{Rebuild, Rebuildable};
+pub use self::repository::{CacheStats, Repository};
+pub use self::trace::{Span, Stage, TracedProjection, TracedStore, Traces};
+
+use self::store::{ConcurrencyError, Enrichment, EventStore, Snapshot, SnapshotLoads, SnapshotStore, StoreError};
+
+pub trait AggregateCommand {
+    fn aggregate_id(&self) -> Uuid;
+}
+
+// For aggregates known by a name rather than an id: the same name always gives the same stream,
+// on every server. The name is hashed with FNV-1a.
+pub fn named_stream_id(namespace: &str, name: &str) -> Uuid {
+    let mut hash: u128 = 0x6c62_272e_07bb_0142_62b8_2175_6295_c58d;
+    for byte in namespace.bytes().chain(Some(0)).chain(name.bytes()) {
+        hash ^= u128::from(byte);
+        hash = hash.wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b);
+    }
+    Uuid::from_u128(hash)
+}
+
+pub trait Aggregate {
+    type Command: AggregateCommand;
+    type CommandError;
+    type State;
+    type Event;
+    fn initial_state() -> Self::State;
+    fn decide(state: &Self::State, command: Self::Command) -> Result<Vec<Self::Event>, Self::CommandError>;
+    fn evolve(state: &mut Self::State, event: Self::Event);
+
+    // How many events apart CommandHandler should snapshot the state, if it is given a
+    // SnapshotStore. Worth it for aggregates with long streams.
+    fn snapshot_every() -> Option<usize> {
+        None
+    }
+
+    // Kept with every snapshot. Loading does not start from a snapshot of another schema version;
+    // the stream is replayed in full and snapshotted again. To be bumped whenever State changes
+    // shape.
+    fn snapshot_schema_version() -> u32 {
+        1
+    }
+}
+
+pub trait Invariants {
+    fn check_invariants(&self) -> Result<(), String>;
+}
+
+// Wraps an aggregate so its state invariants are checked after every event even in release
+// builds, e.g. CommandHandler::<Checked<Tab>> on a staging server.
+pub struct Checked<A>(PhantomData<A>);
+
+impl<A: Aggregate> Aggregate for Checked<A> where A::State: Invariants {
+    type Command = A::Command;
+    type CommandError = A::CommandError;
+    type State = A::State;
+    type Event = A::Event;
+
+    fn initial_state() -> A::State {
+        A::initial_state()
+    }
+
+    fn decide(state: &A::State, command: A::Command) -> Result<Vec<A::Event>, A::CommandError> {
+        A::decide(state, command)
+    }
+
+    fn evolve(state: &mut A::State, event: A::Event) {
+        A::evolve(state, event);
+        if let Err(violation) = state.check_invariants() {
+            panic!("invariant violated: {}", violation);
+        }
+    }
+
+    fn snapshot_every() -> Option<usize> {
+        A::snapshot_every()
+    }
+
+    fn snapshot_schema_version() -> u32 {
+        A::snapshot_schema_version()
+    }
+}
+
+// Advisory outcome of a policy: the command goes through, but the client should be told.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Warning {
+    pub code: &'static str
+}
+
+// Business rules that are configured rather than compiled into the aggregate. Checked
+// against the current state before the command reaches decide.
+pub trait Policy<A: Aggregate>: Send + Sync {
+    fn check(&self, state: &A::State, command: &A::Command) -> Result<(), A::CommandError>;
+
+    fn warnings(&self, _state: &A::State, _command: &A::Command) -> Vec<Warning> {
+        Vec::new()
+    }
+}
+
+// What the store records around every event. The correlation id is shared by everything
+// that happened because of one outside request; the causation id is the id of the command
+// or event that directly led to this one. The acting user is whoever issued the command, if
+// anyone signed in did, by name and staff id. The position is the event's place in the whole log, across streams;
+// it only ever goes up but may skip numbers. The schema version is the shape the payload was
+// stored in, see store::Upcaster, and the enrichment is whatever the deployment added on
+// append, see store::Enricher.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventEnvelope<E> {
+    pub event_id: Uuid,
+    pub stream_id: Uuid,
+    pub version: usize,
+    #[serde(default)]
+    pub position: usize,
+    pub event_type: String,
+    pub schema_version: u32,
+    pub timestamp: SystemTime,
+    pub correlation_id: Uuid,
+    pub causation_id: Uuid,
+    #[serde(default)]
+    pub acting_user: Option<String>,
+    #[serde(default)]
+    pub acting_staff_id: Option<Uuid>,
+    #[serde(default)]
+    pub enrichment: Enrichment,
+    pub payload: E
+}
+
+// The command id is set when the client names its command, so that a retried command can be
+// told from a new one. It is recorded as the causation id of the command's events. Commands of a
+// dry run are only simulated, see CommandHandler::simulate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub correlation_id: Uuid,
+    pub causation_id: Uuid,
+    pub acting_user: Option<String>,
+    pub acting_staff_id: Option<Uuid>,
+    pub command_id: Option<Uuid>,
+    pub dry_run: bool
+}
+
+impl Metadata {
+    // Starts a new workflow, with the command as its own cause.
+    pub fn new() -> Metadata {
+        let command_id = Uuid::new_v4();
+        Metadata { correlation_id: command_id, causation_id: command_id, acting_user: None, acting_staff_id: None, command_id: None, dry_run: false }
+    }
+
+    pub fn correlated_with(correlation_id: Uuid) -> Metadata {
+        Metadata { correlation_id, causation_id: Uuid::new_v4(), acting_user: None, acting_staff_id: None, command_id: None, dry_run: false }
+    }
+
+    // For a command issued in reaction to an event, e.g. by a process manager. Nobody is acting
+    // then, the system is.
+    pub fn caused_by<E>(envelope: &EventEnvelope<E>) -> Metadata {
+        Metadata { correlation_id: envelope.correlation_id, causation_id: envelope.event_id, acting_user: None, acting_staff_id: None, command_id: None, dry_run: false }
+    }
+
+    // A further command of the same request: in its workflow, but not under the client's command
+    // id, which is for the command the client sent. A dry run stays one.
+    pub fn follow_up(&self) -> Metadata {
+        Metadata { dry_run: self.dry_run, ..Metadata::correlated_with(self.correlation_id) }
+    }
+
+    pub fn with_acting_user(mut self, user: String, staff_id: Uuid) -> Metadata {
+        self.acting_user = Some(user);
+        self.acting_staff_id = Some(staff_id);
+        self
+    }
+
+    pub fn with_command_id(mut self, command_id: Uuid) -> Metadata {
+        self.causation_id = command_id;
+        self.command_id = Some(command_id);
+        self
+    }
+
+    pub fn with_dry_run(mut self) -> Metadata {
+        self.dry_run = true;
+        self
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata::new()
+    }
+}
+
+// Query side: read models kept up to date from appended events instead of replaying
+// streams on every request.
+pub trait Projection<E> {
+    fn apply(&mut self, stream_id: Uuid, event: &E);
+
+    // Overridden by projections that need more than the payload, e.g. when it happened.
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<E>) {
+        self.apply(envelope.stream_id, &envelope.payload);
+    }
+
+    // Subscribers nobody consumes any more, such as listeners whose receiver was dropped, say so
+    // and are let go by the store.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+// For projections that need to know when each event happened. Without an envelope there is no
+// recorded time, so the event is taken to happen now.
+pub trait TimedProjection {
+    type Event;
+
+    fn apply_at(&mut self, stream_id: Uuid, event: &Self::Event, timestamp: SystemTime);
+}
+
+impl<P: TimedProjection> Projection<P::Event> for P {
+    fn apply(&mut self, stream_id: Uuid, event: &P::Event) {
+        self.apply_at(stream_id, event, SystemTime::now());
+    }
+
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<P::Event>) {
+        self.apply_at(envelope.stream_id, &envelope.payload, envelope.timestamp);
+    }
+}
+
+// How far the read models have got: the number of events applied so far and when the last one
+// was recorded. Subscribed after the read models, it never runs ahead of them. The generation
+// goes up whenever a read model is rebuilt, as the same position may then read differently.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Checkpoint {
+    pub position: usize,
+    pub last_recorded_at: Option<SystemTime>,
+    pub generation: usize
+}
+
+impl Checkpoint {
+    pub fn new() -> Checkpoint {
+        Checkpoint::default()
+    }
+}
+
+impl<E> Projection<E> for Checkpoint {
+    fn apply(&mut self, _: Uuid, _: &E) {
+        self.position += 1;
+    }
+
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<E>) {
+        self.position += 1;
+        self.last_recorded_at = Some(envelope.timestamp);
+    }
+}
+
+pub struct ProjectionRegistry<E> {
+    projections: Vec<Arc<RwLock<dyn Projection<E> + Send + Sync>>>
+}
+
+impl<E> ProjectionRegistry<E> {
+    pub fn new() -> ProjectionRegistry<E> {
+        ProjectionRegistry { projections: Vec::new() }
+    }
+
+    pub fn register(&mut self, projection: Arc<RwLock<dyn Projection<E> + Send + Sync>>) {
+        self.projections.push(projection);
+    }
+
+    pub fn notify(&mut self, envelopes: &[EventEnvelope<E>]) {
+        self.projections.retain(|projection| !projection.read().unwrap().is_closed());
+        for projection in &self.projections {
+            let mut projection = projection.write().unwrap();
+            for envelope in envelopes {
+                projection.apply_envelope(envelope);
+            }
+        }
+    }
+}
+
+impl<E> Default for ProjectionRegistry<E> {
+    fn default() -> ProjectionRegistry<E> {
+        ProjectionRegistry::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerError<E> {
+    Rejected(E),
+    Concurrency(ConcurrencyError),
+    Store(String)
+}
+
+impl<E> From<StoreError> for HandlerError<E> {
+    fn from(error: StoreError) -> HandlerError<E> {
+        match error {
+            StoreError::Concurrency(error) => HandlerError::Concurrency(error),
+            StoreError::Backend(message) => HandlerError::Store(message),
+            StoreError::Closed => HandlerError::Store(StoreError::Closed.to_string())
+        }
+    }
+}
+
+pub struct CommandHandler<'a, A: Aggregate + 'a> where A::Event: Send + 'static, A::State: 'a {
+    store: &'a dyn EventStore<A::Event>,
+    snapshots: Option<&'a dyn SnapshotStore<A::State>>,
+    snapshot_every: Option<usize>,
+    snapshot_loads: Option<&'a SnapshotLoads>,
+    policy: Option<&'a dyn Policy<A>>,
+    repository: Option<&'a RwLock<Repository<A>>>,
+    metadata: Option<Metadata>,
+    aggregate: PhantomData<A>
+}
+
+// The handler's futures are Send, so commands can be handled on any of Rocket's workers while
+// the store does its I/O.
+impl<'a, A: Aggregate> CommandHandler<'a, A> where A::Command: Send, A::Event: Clone + Send + Sync + 'static, A::State: Clone + Send {
+    pub fn new(store: &'a dyn EventStore<A::Event>) -> CommandHandler<'a, A> {
+        CommandHandler { store, snapshots: None, snapshot_every: None, snapshot_loads: None, policy: None, repository: None, metadata: None, aggregate: PhantomData }
+    }
+
+    pub fn with_snapshots(mut self, snapshots: &'a dyn SnapshotStore<A::State>) -> CommandHandler<'a, A> {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    // In place of the aggregate's own snapshot_every, e.g. from the deployment's configuration.
+    pub fn with_snapshot_every(mut self, every: usize) -> CommandHandler<'a, A> {
+        self.snapshot_every = Some(every);
+        self
+    }
+
+    // Counts the loads that start from a snapshot, and the ones that fall back to a full replay.
+    pub fn with_snapshot_loads(mut self, loads: &'a SnapshotLoads) -> CommandHandler<'a, A> {
+        self.snapshot_loads = Some(loads);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: &'a dyn Policy<A>) -> CommandHandler<'a, A> {
+        self.policy = Some(policy);
+        self
+    }
+
+    // Loads through the repository's cache and keeps it up to date with what gets appended.
+    pub fn with_repository(mut self, repository: &'a RwLock<Repository<A>>) -> CommandHandler<'a, A> {
+        self.repository = Some(repository);
+        self
+    }
+
+    // Without metadata every handled command starts a workflow of its own.
+    pub fn with_metadata(mut self, metadata: Metadata) -> CommandHandler<'a, A> {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    pub async fn load(&self, aggregate_id: Uuid) -> Result<(A::State, usize), StoreError> {
+        self.load_handled(aggregate_id, None).await.map(|(state, version, _)| (state, version))
+    }
+
+    // Also returns the events the command with the given id already led to, if it was handled
+    // before. Commands from before the snapshot loaded from are recognised as long as it
+    // remembers them, see HANDLED_IN_SNAPSHOT.
+    async fn load_handled(&self, aggregate_id: Uuid, command_id: Option<Uuid>) -> Result<(A::State, usize, Vec<A::Event>), StoreError> {
+        if let Some(cached) = self.repository.and_then(|repository| repository.write().unwrap().get(aggregate_id, command_id)) {
+            return Ok(cached);
+        }
+        let snapshot = match self.snapshots {
+            Some(snapshots) => snapshots.load(aggregate_id)?,
+            None => None
+        };
+        // The state of a snapshot of another schema version may not mean what it meant when it
+        // was taken, so none of it is trusted.
+        let stale = snapshot.as_ref().is_some_and(|snapshot| snapshot.schema_version != A::snapshot_schema_version());
+        if let (Some(loads), Some(_)) = (self.snapshot_loads, &snapshot) {
+            loads.record(stale);
+        }
+        let (mut state, after, stream) = match snapshot.filter(|_| !stale) {
+            Some(snapshot) => {
+                // A retry of a command the snapshot remembers is answered from the events it led
+                // to, so those are read again. The cache is told about all of them.
+                let read_back = snapshot.handled.iter()
+                    .find(|&&(id, _)| self.repository.is_some() || command_id == Some(id))
+                    .map_or(snapshot.version, |&(_, version)| version);
+                (snapshot.state, snapshot.version, self.store.read_stream_after(aggregate_id, read_back).await?)
+            },
+            None => (A::initial_state(), 0, self.store.read_stream(aggregate_id).await?)
+        };
+        let mut handled: HashMap<Uuid, Vec<A::Event>> = HashMap::new();
+        let mut remembered = Vec::new();
+        for envelope in stream.events {
+            if self.repository.is_some() || command_id == Some(envelope.causation_id) {
+                handled.entry(envelope.causation_id).or_default().push(envelope.payload.clone());
+            }
+            if envelope.version > after {
+                remember(&mut remembered, &envelope);
+                A::evolve(&mut state, envelope.payload);
+            }
+        }
+        let answer = command_id.and_then(|id| handled.get(&id).cloned()).unwrap_or_default();
+        // Taken afresh, so the next load starts from it again. Failing to save only costs the
+        // same replay next time.
+        if let (true, Some(snapshots)) = (stale, self.snapshots) {
+            let _ = snapshots.save(aggregate_id, Snapshot { version: stream.version, schema_version: A::snapshot_schema_version(), state: state.clone(), handled: remembered });
+        }
+        if let Some(repository) = self.repository {
+            repository.write().unwrap().insert(aggregate_id, state.clone(), stream.version, handled);
+        }
+        Ok((state, stream.version, answer))
+    }
+
+    pub async fn handle(&self, command: A::Command) -> Result<Vec<A::Event>, HandlerError<A::CommandError>> {
+        self.handle_with_warnings(command).await.map(|(events, _)| events)
+    }
+
+    // Commands with dry run metadata are simulated instead.
+    pub async fn handle_with_warnings(&self, command: A::Command) -> Result<(Vec<A::Event>, Vec<Warning>), HandlerError<A::CommandError>> {
+        if self.metadata.as_ref().is_some_and(|metadata| metadata.dry_run) {
+            return self.simulate(command).await;
+        }
+        let aggregate_id = command.aggregate_id();
+        let command_id = self.metadata.as_ref().and_then(|metadata| metadata.command_id);
+        let (state, version, handled) = self.load_handled(aggregate_id, command_id).await?;
+        // A retry gets the answer the command got the first time instead of being decided again.
+        if !handled.is_empty() {
+            return Ok((handled, Vec::new()));
+        }
+        let (events, warnings) = self.decide(&state, command)?;
+        let metadata = self.metadata.clone().unwrap_or_default();
+        let recorded = match self.store.append(aggregate_id, events.clone(), version, &metadata).await {
+            Ok(recorded) => recorded,
+            Err(error) => {
+                // Whatever got there first, the cached state is behind it.
+                if let (StoreError::Concurrency(_), Some(repository)) = (&error, self.repository) {
+                    repository.write().unwrap().remove(aggregate_id);
+                }
+                return Err(error.into());
+            }
+        };
+        if let Some(repository) = self.repository {
+            let mut repository = repository.write().unwrap();
+            for envelope in &recorded {
+                repository.apply_envelope(envelope);
+            }
+        }
+        self.save_snapshot(aggregate_id, state, version, &events).await;
+        Ok((events, warnings))
+    }
+
+    // The events the command would be recorded with, or why it would be rejected, as the aggregate
+    // stands, without recording anything. Lets clients ask e.g. whether a tab can be closed.
+    pub async fn simulate(&self, command: A::Command) -> Result<(Vec<A::Event>, Vec<Warning>), HandlerError<A::CommandError>> {
+        let command_id = self.metadata.as_ref().and_then(|metadata| metadata.command_id);
+        let (state, _, handled) = self.load_handled(command.aggregate_id(), command_id).await?;
+        if !handled.is_empty() {
+            return Ok((handled, Vec::new()));
+        }
+        self.decide(&state, command)
+    }
+
+    fn decide(&self, state: &A::State, command: A::Command) -> Result<(Vec<A::Event>, Vec<Warning>), HandlerError<A::CommandError>> {
+        let warnings = match self.policy {
+            Some(policy) => {
+                policy.check(state, &command).map_err(HandlerError::Rejected)?;
+                policy.warnings(state, &command)
+            },
+            None => Vec::new()
+        };
+        let events = A::decide(state, command).map_err(HandlerError::Rejected)?;
+        Ok((events, warnings))
+    }
+
+    // Snapshots whenever the new events cross a multiple of snapshot_every. The events are
+    // already stored, so failing to save a snapshot only costs a longer load next time. The
+    // commands handled since the previous snapshot are added to the ones it remembers.
+    async fn save_snapshot(&self, aggregate_id: Uuid, mut state: A::State, version: usize, events: &[A::Event]) {
+        let (snapshots, every) = match (self.snapshots, self.snapshot_every.or_else(A::snapshot_every)) {
+            (Some(snapshots), Some(every)) if every > 0 => (snapshots, every),
+            _ => return
+        };
+        let new_version = version + events.len();
+        if new_version / every == version / every {
+            return;
+        }
+        let previous = snapshots.load(aggregate_id).ok().flatten()
+            .filter(|previous| previous.schema_version == A::snapshot_schema_version() && previous.version <= version);
+        let (after, mut handled) = previous.map_or((0, Vec::new()), |previous| (previous.version, previous.handled));
+        let stream = match self.store.read_stream_after(aggregate_id, after).await {
+            Ok(stream) => stream,
+            Err(_) => return
+        };
+        for envelope in stream.events.iter().filter(|envelope| envelope.version <= new_version) {
+            remember(&mut handled, envelope);
+        }
+        for event in events {
+            A::evolve(&mut state, event.clone());
+        }
+        let _ = snapshots.save(aggregate_id, Snapshot { version: new_version, schema_version: A::snapshot_schema_version(), state, handled });
+    }
+}
+
+// How many of the latest commands a snapshot remembers, so retries of them are still recognised
+// when the stream is loaded from it.
+const HANDLED_IN_SNAPSHOT: usize = 100;
+
+fn remember<E>(handled: &mut Vec<(Uuid, usize)>, envelope: &EventEnvelope<E>) {
+    if handled.last().is_some_and(|&(id, _)| id == envelope.causation_id) {
+        return;
+    }
+    handled.push((envelope.causation_id, envelope.version - 1));
+    if handled.len() > HANDLED_IN_SNAPSHOT {
+        handled.remove(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::store::{InMemoryEventStore, InMemorySnapshotStore};
+    use crate::domain::{Command, CommandError, Event, LineRef, OrderedItem, Tab};
+    use crate::money::{Currency, Money};
+    use crate::staff;
+
+    #[tokio::test]
+    async fn handled_events_are_stored_in_the_aggregate_stream() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let events = CommandHandler::<Tab>::new(&store).handle(Command::OpenTab(tab_id, 42, staff::legacy_id("Derek"), "Derek".to_string())).await;
+        let expected = vec![Event::TabOpened { table_number: 42, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() }];
+        assert_eq!(events, Ok(expected.clone()));
+        let stored: Vec<Event> = store.read_stream(tab_id).await.unwrap().events.into_iter().map(|envelope| envelope.payload).collect();
+        assert_eq!(stored, expected);
+    }
+
+    #[tokio::test]
+    async fn handled_events_carry_the_command_metadata() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let metadata = Metadata::correlated_with(Uuid::new_v4()).with_acting_user("Derek".to_string(), staff::legacy_id("Derek"));
+        CommandHandler::<Tab>::new(&store).with_metadata(metadata.clone()).handle(Command::OpenTab(tab_id, 42, staff::legacy_id("Derek"), "Derek".to_string())).await.unwrap();
+        let envelope = store.read_stream(tab_id).await.unwrap().events.remove(0);
+        assert_eq!((envelope.stream_id, envelope.version), (tab_id, 1));
+        assert_eq!((envelope.correlation_id, envelope.causation_id), (metadata.correlation_id, metadata.causation_id));
+        assert_eq!((envelope.acting_user.clone(), envelope.acting_staff_id), (Some("Derek".to_string()), Some(staff::legacy_id("Derek"))));
+
+        let follow_up = Metadata::caused_by(&envelope);
+        assert_eq!((follow_up.correlation_id, follow_up.causation_id, follow_up.acting_user, follow_up.acting_staff_id), (metadata.correlation_id, envelope.event_id, None, None));
+    }
+
+    #[tokio::test]
+    async fn retried_commands_are_answered_with_the_events_they_led_to() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        CommandHandler::<Tab>::new(&store).handle(Command::OpenTab(tab_id, 42, staff::legacy_id("Derek"), "Derek".to_string())).await.unwrap();
+        let order = || Command::PlaceOrder(tab_id, vec![OrderedItem::new(1, "Coffee".to_string(), true, Money::new(250, Currency::EUR))]);
+        let metadata = Metadata::new().with_command_id(Uuid::new_v4());
+
+        let placed = CommandHandler::<Tab>::new(&store).with_metadata(metadata.clone()).handle(order()).await.unwrap();
+        let retried = CommandHandler::<Tab>::new(&store).with_metadata(metadata).handle(order()).await.unwrap();
+        assert_eq!(retried, placed);
+        assert_eq!(store.read_stream(tab_id).await.unwrap().version, 2);
+
+        let another = Metadata::new().with_command_id(Uuid::new_v4());
+        CommandHandler::<Tab>::new(&store).with_metadata(another).handle(order()).await.unwrap();
+        assert_eq!(store.read_stream(tab_id).await.unwrap().version, 3);
+    }
+
+    #[tokio::test]
+    async fn commands_are_decided_against_stored_state() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let handler = CommandHandler::<Tab>::new(&store);
+        let result = handler.handle(Command::MarkDrinksServed(tab_id, vec![(LineRef::MenuNumber(1), 1)])).await;
+        assert_eq!(result, Err(HandlerError::Rejected(CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(1)]))));
+        assert_eq!(store.read_stream(tab_id).await.unwrap().version, 0);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_are_decided_without_being_recorded() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let open = Command::OpenTab(tab_id, 42, staff::legacy_id("Derek"), "Derek".to_string());
+        let opened = vec![Event::TabOpened { table_number: 42, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() }];
+        assert_eq!(CommandHandler::<Tab>::new(&store).simulate(open.clone()).await, Ok((opened.clone(), Vec::new())));
+        let dry_run = CommandHandler::<Tab>::new(&store).with_metadata(Metadata::new().with_dry_run());
+        assert_eq!(dry_run.handle(open.clone()).await, Ok(opened));
+        assert_eq!(store.read_stream(tab_id).await.unwrap().version, 0);
+
+        CommandHandler::<Tab>::new(&store).handle(open).await.unwrap();
+        assert_eq!(dry_run.handle(Command::MarkDrinksServed(tab_id, vec![(LineRef::MenuNumber(1), 1)])).await, Err(HandlerError::Rejected(CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(1)]))));
+        assert_eq!(store.read_stream(tab_id).await.unwrap().version, 1);
+    }
+
+    struct EventCount(usize);
+
+    impl Projection<Event> for EventCount {
+        fn apply(&mut self, _: Uuid, _: &Event) {
+            self.0 += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn projections_are_notified_of_appended_events() {
+        let count = Arc::new(RwLock::new(EventCount(0)));
+        let store = InMemoryEventStore::new();
+        store.subscribe(count.clone()).await.unwrap();
+        let tab_id = Uuid::new_v4();
+        let handler = CommandHandler::<Tab>::new(&store);
+        handler.handle(Command::OpenTab(tab_id, 42, staff::legacy_id("Derek"), "Derek".to_string())).await.unwrap();
+        assert_eq!(count.read().unwrap().0, 1);
+        let _ = handler.handle(Command::MarkFoodServed(tab_id, vec![(LineRef::MenuNumber(1), 1)])).await;
+        assert_eq!(count.read().unwrap().0, 1);
+    }
+
+    struct Counter;
+
+    struct Add(Uuid, i64);
+
+    impl AggregateCommand for Add {
+        fn aggregate_id(&self) -> Uuid {
+            self.0
+        }
+    }
+
+    impl Aggregate for Counter {
+        type Command = Add;
+        type CommandError = ();
+        type State = i64;
+        type Event = i64;
+
+        fn initial_state() -> i64 {
+            0
+        }
+
+        fn decide(_: &i64, command: Add) -> Result<Vec<i64>, ()> {
+            Ok(vec![command.1])
+        }
+
+        fn evolve(state: &mut i64, event: i64) {
+            *state += event;
+        }
+
+        fn snapshot_every() -> Option<usize> {
+            Some(2)
+        }
+    }
+
+    #[tokio::test]
+    async fn state_is_snapshotted_every_few_events() {
+        let store = InMemoryEventStore::new();
+        let snapshots = InMemorySnapshotStore::new();
+        let id = Uuid::new_v4();
+        let handler = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots);
+        handler.handle(Add(id, 1)).await.unwrap();
+        assert_eq!(snapshots.load(id), Ok(None));
+        handler.handle(Add(id, 2)).await.unwrap();
+        handler.handle(Add(id, 3)).await.unwrap();
+        let snapshot = snapshots.load(id).unwrap().unwrap();
+        assert_eq!((snapshot.version, snapshot.schema_version, snapshot.state, snapshot.handled.len()), (2, 1, 3, 2));
+        assert_eq!(handler.load(id).await, Ok((6, 3)));
+    }
+
+    #[tokio::test]
+    async fn snapshot_frequency_can_be_set_per_handler() {
+        let store = InMemoryEventStore::new();
+        let snapshots = InMemorySnapshotStore::new();
+        let id = Uuid::new_v4();
+        let handler = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).with_snapshot_every(3);
+        handler.handle(Add(id, 1)).await.unwrap();
+        handler.handle(Add(id, 2)).await.unwrap();
+        assert_eq!(snapshots.load(id), Ok(None));
+        handler.handle(Add(id, 3)).await.unwrap();
+        assert_eq!(snapshots.load(id).unwrap().map(|snapshot| (snapshot.version, snapshot.state)), Some((3, 6)));
+
+        let other = Uuid::new_v4();
+        let never = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).with_snapshot_every(0);
+        never.handle(Add(other, 1)).await.unwrap();
+        never.handle(Add(other, 2)).await.unwrap();
+        assert_eq!(snapshots.load(other), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn cached_streams_are_not_read_again() {
+        let store = InMemoryEventStore::new();
+        let repository = RwLock::new(Repository::<Counter>::new(10));
+        let id = Uuid::new_v4();
+        let metadata = Metadata::new().with_command_id(Uuid::new_v4());
+        let handler = CommandHandler::<Counter>::new(&store).with_repository(&repository).with_metadata(metadata);
+        handler.handle(Add(id, 1)).await.unwrap();
+        assert_eq!(handler.handle(Add(id, 1)).await, Ok(vec![1]));
+        assert_eq!(handler.load(id).await, Ok((1, 1)));
+        assert_eq!((repository.read().unwrap().stats().hits, repository.read().unwrap().stats().misses), (2, 1));
+
+        // Appended behind the cache's back, so the next command conflicts and drops the tab.
+        store.append(id, vec![10], 1, &Metadata::new()).await.unwrap();
+        let other = CommandHandler::<Counter>::new(&store).with_repository(&repository);
+        assert!(matches!(other.handle(Add(id, 2)).await, Err(HandlerError::Concurrency(_))));
+        assert_eq!(other.handle(Add(id, 2)).await, Ok(vec![2]));
+        assert_eq!(other.load(id).await, Ok((13, 3)));
+    }
+
+    #[tokio::test]
+    async fn loading_starts_from_the_latest_snapshot() {
+        let store = InMemoryEventStore::new();
+        let snapshots = InMemorySnapshotStore::new();
+        let id = Uuid::new_v4();
+        store.append(id, vec![1, 2, 3], 0, &Metadata::new()).await.unwrap();
+        snapshots.save(id, Snapshot { version: 2, schema_version: 1, state: 100, handled: Vec::new() }).unwrap();
+        assert_eq!(CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).load(id).await, Ok((103, 3)));
+        assert_eq!(CommandHandler::<Counter>::new(&store).load(id).await, Ok((6, 3)));
+    }
+
+    #[tokio::test]
+    async fn snapshots_of_another_schema_are_replayed_over_and_taken_again() {
+        let store = InMemoryEventStore::new();
+        let snapshots = InMemorySnapshotStore::new();
+        let loads = SnapshotLoads::new();
+        let id = Uuid::new_v4();
+        let metadata = Metadata::new();
+        store.append(id, vec![1, 2, 3], 0, &metadata).await.unwrap();
+        snapshots.save(id, Snapshot { version: 3, schema_version: 0, state: 100, handled: Vec::new() }).unwrap();
+        let handler = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).with_snapshot_loads(&loads);
+        assert_eq!(handler.load(id).await, Ok((6, 3)));
+        assert_eq!(snapshots.load(id), Ok(Some(Snapshot { version: 3, schema_version: 1, state: 6, handled: vec![(metadata.causation_id, 0)] })));
+        assert_eq!(handler.load(id).await, Ok((6, 3)));
+        assert_eq!((loads.loads(), loads.fallbacks()), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn retries_are_recognised_across_snapshots() {
+        let store = InMemoryEventStore::new();
+        let snapshots = InMemorySnapshotStore::new();
+        let id = Uuid::new_v4();
+        let retried = Metadata::new().with_command_id(Uuid::new_v4());
+        CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).with_metadata(retried.clone()).handle(Add(id, 1)).await.unwrap();
+        let handler = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots);
+        for amount in 2..=5 {
+            handler.handle(Add(id, amount)).await.unwrap();
+        }
+        assert_eq!(snapshots.load(id).unwrap().map(|snapshot| (snapshot.version, snapshot.handled.len())), Some((4, 4)));
+
+        let retry = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).with_metadata(retried.clone());
+        assert_eq!(retry.handle(Add(id, 1)).await, Ok(vec![1]));
+        assert_eq!(handler.load(id).await, Ok((15, 5)));
+
+        // A cache filled from the snapshot knows about it too.
+        let repository = RwLock::new(Repository::<Counter>::new(10));
+        let cached = CommandHandler::<Counter>::new(&store).with_snapshots(&snapshots).with_repository(&repository);
+        assert_eq!(cached.load(id).await, Ok((15, 5)));
+        assert_eq!(cached.with_metadata(retried).handle(Add(id, 1)).await, Ok(vec![1]));
+        assert_eq!(handler.load(id).await, Ok((15, 5)));
+    }
+}
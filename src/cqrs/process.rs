@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time;
+use uuid::Uuid;
+
+use super::{EventEnvelope, Metadata};
+use super::store::{Snapshot, SnapshotStore, StoreError};
+
+// Reacts to events by issuing commands, e.g. to follow up on something that has not happened in
+// time. There is one process per stream it reacts to, with state of its own.
+pub trait ProcessManager {
+    type Event;
+    type Command;
+    type State: Default;
+
+    fn react(&self, state: &mut Self::State, envelope: &EventEnvelope<Self::Event>) -> Vec<Self::Command>;
+
+    // Called every so often for processes that are not finished, to act on deadlines. Anything
+    // issued here should come back as an event for react, so it is not issued again.
+    fn wake(&self, _stream_id: Uuid, _state: &Self::State, _now: SystemTime) -> Vec<Self::Command> {
+        Vec::new()
+    }
+
+    fn is_finished(&self, state: &Self::State) -> bool;
+}
+
+struct Running<S> {
+    snapshot: Snapshot<S>,
+    // Follow-up commands belong to the workflow of the event last reacted to.
+    metadata: Metadata
+}
+
+// Feeds events to a process manager and collects the commands it issues. Process state is saved
+// in a SnapshotStore at the version of the last event reacted to, so events replayed when the
+// runner starts again are not reacted to twice.
+pub struct ProcessRunner<P: ProcessManager> {
+    manager: P,
+    states: Box<dyn SnapshotStore<P::State>>,
+    running: HashMap<Uuid, Running<P::State>>
+}
+
+impl<P: ProcessManager> ProcessRunner<P> where P::State: Clone {
+    pub fn new(manager: P, states: Box<dyn SnapshotStore<P::State>>) -> ProcessRunner<P> {
+        ProcessRunner { manager, states, running: HashMap::new() }
+    }
+
+    pub fn handle(&mut self, envelope: &EventEnvelope<P::Event>) -> Result<Vec<(P::Command, Metadata)>, StoreError> {
+        let stream_id = envelope.stream_id;
+        let mut snapshot = match self.running.remove(&stream_id) {
+            Some(running) => running.snapshot,
+            // Process state can not be replayed without reacting again, so it is not versioned.
+            None => self.states.load(stream_id)?.unwrap_or_else(|| Snapshot { version: 0, schema_version: 0, state: P::State::default(), handled: Vec::new() })
+        };
+
+        let mut commands = Vec::new();
+        if envelope.version > snapshot.version {
+            commands = self.manager.react(&mut snapshot.state, envelope);
+            snapshot.version = envelope.version;
+            self.states.save(stream_id, snapshot.clone())?;
+        }
+
+        let metadata = Metadata::caused_by(envelope);
+        if !self.manager.is_finished(&snapshot.state) {
+            self.running.insert(stream_id, Running { snapshot, metadata: metadata.clone() });
+        }
+        Ok(commands.into_iter().map(|command| (command, metadata.clone())).collect())
+    }
+
+    pub fn wake(&self, now: SystemTime) -> Vec<(P::Command, Metadata)> {
+        let mut commands = Vec::new();
+        for (&stream_id, running) in &self.running {
+            for command in self.manager.wake(stream_id, &running.snapshot.state, now) {
+                commands.push((command, running.metadata.clone()));
+            }
+        }
+        commands
+    }
+
+    // Runs as a task of its own, off EventStore::listen, so commands are never dispatched
+    // while the store is notifying its subscribers. Processes are only woken once the runner has
+    // caught up with the events.
+    pub fn spawn<F, D>(mut self, mut events: UnboundedReceiver<EventEnvelope<P::Event>>, wake_every: Duration, dispatch: F)
+        where P: Send + 'static, P::Event: Send + 'static, P::Command: Send, P::State: Send, F: Fn(P::Command, Metadata) -> D + Send + 'static, D: Future<Output = ()> + Send {
+        tokio::spawn(async move {
+            loop {
+                let commands = match time::timeout(wake_every, events.recv()).await {
+                    Ok(Some(envelope)) => self.handle(&envelope).unwrap_or_else(|error| {
+                        eprintln!("process manager failed to handle event {}: {}", envelope.event_id, error);
+                        Vec::new()
+                    }),
+                    Ok(None) => break,
+                    Err(_) => self.wake(SystemTime::now())
+                };
+                for (command, metadata) in commands {
+                    dispatch(command, metadata).await;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::store::{EventStore, InMemoryEventStore, InMemorySnapshotStore};
+
+    // Asks for a reminder once a stream has gone three events without a zero.
+    struct Reminder;
+
+    impl ProcessManager for Reminder {
+        type Event = i32;
+        type Command = &'static str;
+        type State = usize;
+
+        fn react(&self, state: &mut usize, envelope: &EventEnvelope<i32>) -> Vec<&'static str> {
+            *state = if envelope.payload == 0 { 0 } else { *state + 1 };
+            if *state == 3 { vec!["remind"] } else { vec![] }
+        }
+
+        fn wake(&self, _: Uuid, state: &usize, _: SystemTime) -> Vec<&'static str> {
+            if *state > 0 { vec!["nudge"] } else { vec![] }
+        }
+
+        fn is_finished(&self, state: &usize) -> bool {
+            *state == 0
+        }
+    }
+
+    #[tokio::test]
+    async fn processes_react_once_to_each_event() {
+        let store = InMemoryEventStore::new();
+        let stream_id = Uuid::new_v4();
+        let metadata = Metadata::new();
+        let envelopes = store.append(stream_id, vec![1, 2, 3], 0, &metadata).await.unwrap();
+
+        let mut runner = ProcessRunner::new(Reminder, Box::new(InMemorySnapshotStore::new()));
+        let issued: Vec<_> = envelopes.iter().flat_map(|envelope| runner.handle(envelope).unwrap()).collect();
+        assert_eq!(issued.len(), 1);
+        assert_eq!(issued[0].0, "remind");
+        assert_eq!((issued[0].1.correlation_id, issued[0].1.causation_id), (metadata.correlation_id, envelopes[2].event_id));
+        assert_eq!(runner.handle(&envelopes[2]).unwrap(), vec![]);
+        assert_eq!(runner.wake(SystemTime::now()).len(), 1);
+
+        let finished = store.append(stream_id, vec![0], 3, &metadata).await.unwrap();
+        runner.handle(&finished[0]).unwrap();
+        assert_eq!(runner.wake(SystemTime::now()), vec![]);
+    }
+}
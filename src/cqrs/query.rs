@@ -0,0 +1,200 @@
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use super::Checkpoint;
+
+// A question for a read model. The name is what middleware knows it by.
+pub trait Query: Any {
+    type Result;
+
+    const NAME: &'static str;
+}
+
+pub trait QueryHandler<Q: Query> {
+    fn handle(&self, query: &Q) -> Q::Result;
+}
+
+// Who may ask a query and how much of the answer they get to see, the query side's
+// counterpart of Policy. Every query is registered with one, so no read model can be asked
+// around it.
+pub trait QueryPolicy<Q: Query, C>: Send + Sync {
+    fn permits(&self, caller: &C) -> bool;
+
+    fn scope(&self, _caller: &C, result: Q::Result) -> Q::Result {
+        result
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryError {
+    // Nothing was registered for the query, which is a bug in whoever set up the bus.
+    Unhandled(&'static str),
+    Refused(&'static str)
+}
+
+// The result along with the checkpoint of the log it was answered from, read before the read
+// model so the answer is never older than its checkpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer<R> {
+    pub checkpoint: Checkpoint,
+    pub result: R
+}
+
+// Runs around every query the bus dispatches.
+pub trait QueryMiddleware: Send + Sync {
+    fn before(&self, _query: &'static str) -> Result<(), QueryError> {
+        Ok(())
+    }
+
+    fn after(&self, _query: &'static str, _elapsed: Duration) {}
+}
+
+type Dispatch<Q, C> = Box<dyn Fn(&Q, &C) -> Result<Answer<<Q as Query>::Result>, QueryError> + Send + Sync>;
+
+// The query side's counterpart of CommandHandler: read models register as handlers of the
+// queries they answer, and callers ask the bus without knowing which read model that is. C is
+// whoever is asking, as the policies know them.
+pub struct QueryBus<C> {
+    handlers: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    middleware: Vec<Box<dyn QueryMiddleware>>,
+    callers: PhantomData<fn(&C)>
+}
+
+impl<C: 'static> QueryBus<C> {
+    pub fn new() -> QueryBus<C> {
+        QueryBus { handlers: HashMap::new(), middleware: Vec::new(), callers: PhantomData }
+    }
+
+    // A later registration for the same query replaces the earlier one.
+    pub fn register<Q, H, P>(&mut self, handler: Arc<RwLock<H>>, checkpoint: Arc<RwLock<Checkpoint>>, policy: P) where Q: Query, H: QueryHandler<Q> + Send + Sync + 'static, P: QueryPolicy<Q, C> + 'static {
+        let dispatch: Dispatch<Q, C> = Box::new(move |query, caller| {
+            if !policy.permits(caller) {
+                return Err(QueryError::Refused(Q::NAME));
+            }
+            let checkpoint = *checkpoint.read().unwrap();
+            let result = handler.read().unwrap().handle(query);
+            Ok(Answer { checkpoint, result: policy.scope(caller, result) })
+        });
+        self.handlers.insert(TypeId::of::<Q>(), Box::new(dispatch));
+    }
+
+    pub fn with_middleware(mut self, middleware: Box<dyn QueryMiddleware>) -> QueryBus<C> {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn ask<Q: Query>(&self, query: &Q, caller: &C) -> Result<Answer<Q::Result>, QueryError> {
+        let dispatch = self.handlers.get(&TypeId::of::<Q>())
+            .and_then(|dispatch| dispatch.downcast_ref::<Dispatch<Q, C>>())
+            .ok_or(QueryError::Unhandled(Q::NAME))?;
+        for middleware in &self.middleware {
+            middleware.before(Q::NAME)?;
+        }
+        let started = Instant::now();
+        let answer = dispatch(query, caller)?;
+        let elapsed = started.elapsed();
+        for middleware in &self.middleware {
+            middleware.after(Q::NAME, elapsed);
+        }
+        Ok(answer)
+    }
+}
+
+impl<C: 'static> Default for QueryBus<C> {
+    fn default() -> QueryBus<C> {
+        QueryBus::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QueryTiming {
+    pub query: &'static str,
+    pub count: u64,
+    pub total_micros: u64,
+    pub slowest_micros: u64
+}
+
+// Counts the queries answered and how long they took, per query.
+#[derive(Debug, Default)]
+pub struct QueryTimings {
+    timings: Mutex<BTreeMap<&'static str, QueryTiming>>
+}
+
+impl QueryTimings {
+    pub fn new() -> QueryTimings {
+        QueryTimings::default()
+    }
+
+    pub fn report(&self) -> Vec<QueryTiming> {
+        self.timings.lock().unwrap().values().cloned().collect()
+    }
+}
+
+impl QueryMiddleware for Arc<QueryTimings> {
+    fn after(&self, query: &'static str, elapsed: Duration) {
+        let micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        let mut timings = self.timings.lock().unwrap();
+        let timing = timings.entry(query).or_insert(QueryTiming { query, count: 0, total_micros: 0, slowest_micros: 0 });
+        timing.count += 1;
+        timing.total_micros += micros;
+        timing.slowest_micros = timing.slowest_micros.max(micros);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Greeting(&'static str);
+
+    impl Query for Greeting {
+        type Result = String;
+
+        const NAME: &'static str = "greeting";
+    }
+
+    struct Greeter;
+
+    impl QueryHandler<Greeting> for Greeter {
+        fn handle(&self, query: &Greeting) -> String {
+            format!("Hello, {}!", query.0)
+        }
+    }
+
+    // Strangers are not greeted, and the manager is greeted loudly.
+    struct Manners;
+
+    impl QueryPolicy<Greeting, &'static str> for Manners {
+        fn permits(&self, caller: &&'static str) -> bool {
+            *caller != "stranger"
+        }
+
+        fn scope(&self, caller: &&'static str, result: String) -> String {
+            if *caller == "manager" { result.to_uppercase() } else { result }
+        }
+    }
+
+    #[test]
+    fn queries_are_answered_by_their_handler() {
+        let timings = Arc::new(QueryTimings::new());
+        let mut bus = QueryBus::new().with_middleware(Box::new(timings.clone()));
+        assert_eq!(bus.ask(&Greeting("Derek"), &"waiter"), Err(QueryError::Unhandled("greeting")));
+
+        let checkpoint = Checkpoint { position: 3, last_recorded_at: None, generation: 0 };
+        bus.register(Arc::new(RwLock::new(Greeter)), Arc::new(RwLock::new(checkpoint)), Manners);
+        assert_eq!(bus.ask(&Greeting("Derek"), &"waiter"), Ok(Answer { checkpoint, result: "Hello, Derek!".to_string() }));
+        bus.ask(&Greeting("Jane"), &"waiter").unwrap();
+        assert_eq!(timings.report().iter().map(|timing| (timing.query, timing.count)).collect::<Vec<_>>(), vec![("greeting", 2)]);
+    }
+
+    #[test]
+    fn policies_decide_who_is_answered_and_with_what() {
+        let mut bus = QueryBus::new();
+        bus.register(Arc::new(RwLock::new(Greeter)), Arc::new(RwLock::new(Checkpoint::new())), Manners);
+        assert_eq!(bus.ask(&Greeting("Derek"), &"stranger"), Err(QueryError::Refused("greeting")));
+        assert_eq!(bus.ask(&Greeting("Derek"), &"manager").map(|answer| answer.result), Ok("HELLO, DEREK!".to_string()));
+    }
+}
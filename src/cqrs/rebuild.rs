@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use super::{EventEnvelope, Projection};
+use super::store::{EventStore, StoreError};
+
+// Subscribed in place of a projection, it passes events on and keeps count of them, and can
+// rebuild the projection from the log without taking it offline: a fresh copy is replayed on the
+// side, caught up with whatever arrived meanwhile and swapped in under the lock, so readers see
+// the old copy or the new one and never a half-built one.
+pub struct Rebuildable<P, E> {
+    projection: Arc<RwLock<P>>,
+    // The checkpoint: events applied since the store was subscribed to, or since the last rebuild.
+    position: usize,
+    // Events applied while a rebuild replays the log.
+    arrived: Option<Vec<EventEnvelope<E>>>
+}
+
+impl<P, E> Rebuildable<P, E> {
+    pub fn new(projection: Arc<RwLock<P>>) -> Rebuildable<P, E> {
+        Rebuildable { projection, position: 0, arrived: None }
+    }
+}
+
+impl<P: Projection<E>, E: Clone> Projection<E> for Rebuildable<P, E> {
+    fn apply(&mut self, stream_id: Uuid, event: &E) {
+        self.projection.write().unwrap().apply(stream_id, event);
+        self.position += 1;
+    }
+
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<E>) {
+        self.projection.write().unwrap().apply_envelope(envelope);
+        self.position += 1;
+        if let Some(ref mut arrived) = self.arrived {
+            arrived.push(envelope.clone());
+        }
+    }
+}
+
+#[async_trait]
+pub trait Rebuild<E: Send + 'static>: Send + Sync {
+    fn position(&self) -> usize;
+
+    // Returns the number of events the new copy was built from.
+    async fn rebuild(&self, store: &dyn EventStore<E>) -> Result<usize, StoreError>;
+}
+
+// Relies on the stores notifying subscribers before the events can be read back, which all of
+// them do under their lock. An event the replay did not see has therefore arrived by the time of
+// the swap.
+#[async_trait]
+impl<P, E> Rebuild<E> for RwLock<Rebuildable<P, E>> where P: Projection<E> + Default + Send + Sync, E: Clone + Send + Sync + 'static {
+    fn position(&self) -> usize {
+        self.read().unwrap().position
+    }
+
+    async fn rebuild(&self, store: &dyn EventStore<E>) -> Result<usize, StoreError> {
+        self.write().unwrap().arrived = Some(Vec::new());
+        let events = match store.read_all().await {
+            Ok(events) => events,
+            Err(error) => {
+                self.write().unwrap().arrived = None;
+                return Err(error);
+            }
+        };
+        let mut fresh = P::default();
+        for envelope in &events {
+            fresh.apply_envelope(envelope);
+        }
+
+        let mut live = self.write().unwrap();
+        let arrived = live.arrived.take().unwrap_or_default();
+        let arrived_ids: HashSet<Uuid> = arrived.iter().map(|envelope| envelope.event_id).collect();
+        let replayed: HashSet<Uuid> = events.iter().map(|envelope| envelope.event_id).filter(|id| arrived_ids.contains(id)).collect();
+        let mut position = events.len();
+        for envelope in arrived.iter().filter(|envelope| !replayed.contains(&envelope.event_id)) {
+            fresh.apply_envelope(envelope);
+            position += 1;
+        }
+        *live.projection.write().unwrap() = fresh;
+        live.position = position;
+        Ok(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Metadata;
+    use crate::cqrs::store::InMemoryEventStore;
+
+    // Sums the events, once buggy enough to double them.
+    #[derive(Default)]
+    struct Sum(i32, bool);
+
+    impl Projection<i32> for Sum {
+        fn apply(&mut self, _: Uuid, event: &i32) {
+            self.0 += if self.1 { event * 2 } else { *event };
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilt_projections_replace_the_live_copy() {
+        let store = InMemoryEventStore::new();
+        let stream_id = Uuid::new_v4();
+        store.append(stream_id, vec![1, 2], 0, &Metadata::new()).await.unwrap();
+
+        let sum = Arc::new(RwLock::new(Sum(0, true)));
+        let rebuildable = Arc::new(RwLock::new(Rebuildable::new(sum.clone())));
+        store.subscribe(rebuildable.clone()).await.unwrap();
+        store.append(stream_id, vec![3], 2, &Metadata::new()).await.unwrap();
+        assert_eq!((sum.read().unwrap().0, rebuildable.position()), (12, 3));
+
+        assert_eq!(rebuildable.rebuild(&store).await, Ok(3));
+        assert_eq!(sum.read().unwrap().0, 6);
+        store.append(stream_id, vec![4], 3, &Metadata::new()).await.unwrap();
+        assert_eq!((sum.read().unwrap().0, rebuildable.position()), (10, 4));
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use super::{Aggregate, EventEnvelope, Projection};
+
+struct Cached<S, E> {
+    state: S,
+    version: usize,
+    // The events each command led to, by causation id, so retries are answered from the cache too.
+    handled: HashMap<Uuid, Vec<E>>,
+    last_used: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64
+}
+
+// Hydrated state of the streams commands were last handled for, so a busy tab is not read and
+// replayed from the store on every command. CommandHandler::with_repository loads through it.
+// Subscribed to the store, entries follow every append, whoever made it; an append the cache
+// cannot follow drops the stream, and so does a concurrency conflict. The least recently used
+// stream makes room once there are capacity of them.
+pub struct Repository<A: Aggregate> {
+    capacity: usize,
+    entries: HashMap<Uuid, Cached<A::State, A::Event>>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64
+}
+
+impl<A: Aggregate> Repository<A> where A::State: Clone, A::Event: Clone {
+    pub fn new(capacity: usize) -> Repository<A> {
+        Repository { capacity, entries: HashMap::new(), clock: 0, hits: 0, misses: 0, evictions: 0 }
+    }
+
+    // The state, its version and the events the given command already led to.
+    pub fn get(&mut self, stream_id: Uuid, command_id: Option<Uuid>) -> Option<(A::State, usize, Vec<A::Event>)> {
+        self.clock += 1;
+        match self.entries.get_mut(&stream_id) {
+            Some(cached) => {
+                self.hits += 1;
+                cached.last_used = self.clock;
+                let handled = command_id.and_then(|id| cached.handled.get(&id).cloned()).unwrap_or_default();
+                Some((cached.state.clone(), cached.version, handled))
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, stream_id: Uuid, state: A::State, version: usize, handled: HashMap<Uuid, Vec<A::Event>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.entries.contains_key(&stream_id) && self.entries.len() >= self.capacity {
+            // A linear scan, which is fine for the few hundred tabs a café has open.
+            if let Some(oldest) = self.entries.iter().min_by_key(|&(_, cached)| cached.last_used).map(|(&id, _)| id) {
+                self.entries.remove(&oldest);
+                self.evictions += 1;
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(stream_id, Cached { state, version, handled, last_used: self.clock });
+    }
+
+    pub fn remove(&mut self, stream_id: Uuid) {
+        self.entries.remove(&stream_id);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { capacity: self.capacity, entries: self.entries.len(), hits: self.hits, misses: self.misses, evictions: self.evictions }
+    }
+}
+
+impl<A: Aggregate> Projection<A::Event> for Repository<A> where A::State: Clone, A::Event: Clone {
+    // Without the version there is no telling whether the entry is behind.
+    fn apply(&mut self, stream_id: Uuid, _: &A::Event) {
+        self.remove(stream_id);
+    }
+
+    // Events already applied are skipped, as CommandHandler applies its own appends too.
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<A::Event>) {
+        let follows = match self.entries.get_mut(&envelope.stream_id) {
+            Some(cached) if envelope.version <= cached.version => true,
+            Some(cached) if envelope.version == cached.version + 1 => {
+                A::evolve(&mut cached.state, envelope.payload.clone());
+                cached.version = envelope.version;
+                cached.handled.entry(envelope.causation_id).or_default().push(envelope.payload.clone());
+                true
+            },
+            Some(_) => false,
+            None => true
+        };
+        if !follows {
+            self.remove(envelope.stream_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use crate::cqrs::{AggregateCommand, Metadata};
+    use crate::cqrs::store::{EventStore, InMemoryEventStore};
+
+    struct Counter;
+
+    struct Add(Uuid, i64);
+
+    impl AggregateCommand for Add {
+        fn aggregate_id(&self) -> Uuid {
+            self.0
+        }
+    }
+
+    impl Aggregate for Counter {
+        type Command = Add;
+        type CommandError = ();
+        type State = i64;
+        type Event = i64;
+
+        fn initial_state() -> i64 {
+            0
+        }
+
+        fn decide(_: &i64, command: Add) -> Result<Vec<i64>, ()> {
+            Ok(vec![command.1])
+        }
+
+        fn evolve(state: &mut i64, event: i64) {
+            *state += event;
+        }
+    }
+
+    #[test]
+    fn least_recently_used_streams_make_room() {
+        let mut repository = Repository::<Counter>::new(2);
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        repository.insert(first, 1, 1, HashMap::new());
+        repository.insert(second, 2, 1, HashMap::new());
+        assert_eq!(repository.get(first, None), Some((1, 1, vec![])));
+        repository.insert(third, 3, 1, HashMap::new());
+
+        assert_eq!(repository.get(second, None), None);
+        assert_eq!(repository.get(first, None), Some((1, 1, vec![])));
+        assert_eq!(repository.get(third, None), Some((3, 1, vec![])));
+        assert_eq!(repository.stats(), CacheStats { capacity: 2, entries: 2, hits: 3, misses: 1, evictions: 1 });
+    }
+
+    #[tokio::test]
+    async fn entries_follow_appends_they_can_and_drop_the_rest() {
+        let store = InMemoryEventStore::new();
+        let repository = Arc::new(RwLock::new(Repository::<Counter>::new(10)));
+        store.subscribe(repository.clone()).await.unwrap();
+        let (followed, missed) = (Uuid::new_v4(), Uuid::new_v4());
+        store.append(missed, vec![1], 0, &Metadata::new()).await.unwrap();
+        repository.write().unwrap().insert(followed, 0, 0, HashMap::new());
+        repository.write().unwrap().insert(missed, 0, 0, HashMap::new());
+
+        let metadata = Metadata::new().with_command_id(Uuid::new_v4());
+        store.append(followed, vec![1, 2], 0, &metadata).await.unwrap();
+        store.append(missed, vec![2], 1, &Metadata::new()).await.unwrap();
+
+        let mut repository = repository.write().unwrap();
+        assert_eq!(repository.get(followed, metadata.command_id), Some((3, 2, vec![1, 2])));
+        assert_eq!(repository.get(missed, None), None);
+    }
+}
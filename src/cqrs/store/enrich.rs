@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::cqrs::EventEnvelope;
+
+// Free-form facts about an event that the domain does not know about, keyed by name.
+pub type Enrichment = BTreeMap<String, String>;
+
+// Adds deployment-specific facts to events as they are appended, e.g. the shift id, a weather
+// tag or the campaign running at the time. Plain functions and closures will do.
+pub trait Enricher<T>: Send + Sync {
+    fn enrich(&self, envelope: &EventEnvelope<T>, enrichment: &mut Enrichment);
+}
+
+impl<T, F> Enricher<T> for F where F: Fn(&EventEnvelope<T>, &mut Enrichment) + Send + Sync {
+    fn enrich(&self, envelope: &EventEnvelope<T>, enrichment: &mut Enrichment) {
+        self(envelope, enrichment)
+    }
+}
+
+// The enrichers a store runs on append, in the order they were registered, so later ones see
+// and may overwrite what earlier ones added.
+pub struct Enrichers<T> {
+    enrichers: Vec<Box<dyn Enricher<T>>>
+}
+
+impl<T> Enrichers<T> {
+    pub fn new() -> Enrichers<T> {
+        Enrichers { enrichers: Vec::new() }
+    }
+
+    pub fn register(&mut self, enricher: Box<dyn Enricher<T>>) {
+        self.enrichers.push(enricher);
+    }
+
+    pub fn enrich(&self, envelopes: &mut [EventEnvelope<T>]) {
+        for envelope in envelopes {
+            let mut enrichment = mem::take(&mut envelope.enrichment);
+            for enricher in &self.enrichers {
+                enricher.enrich(envelope, &mut enrichment);
+            }
+            envelope.enrichment = enrichment;
+        }
+    }
+}
+
+impl<T> Default for Enrichers<T> {
+    fn default() -> Enrichers<T> {
+        Enrichers::new()
+    }
+}
@@ -0,0 +1,434 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::task::{self, JoinError};
+use uuid::Uuid;
+
+use crate::cqrs::{EventEnvelope, Metadata};
+use super::{envelop, ConcurrencyError, Enrichers, EventStore, EventStream, InMemoryEventStore, StoreError, Subscriber, Upcasters};
+
+#[derive(Debug)]
+pub enum FileStoreError {
+    Concurrency(ConcurrencyError),
+    Io(io::Error),
+    Serialization(serde_json::Error)
+}
+
+impl From<io::Error> for FileStoreError {
+    fn from(error: io::Error) -> FileStoreError {
+        FileStoreError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for FileStoreError {
+    fn from(error: serde_json::Error) -> FileStoreError {
+        FileStoreError::Serialization(error)
+    }
+}
+
+impl From<FileStoreError> for StoreError {
+    fn from(error: FileStoreError) -> StoreError {
+        match error {
+            FileStoreError::Concurrency(error) => StoreError::Concurrency(error),
+            FileStoreError::Io(error) => StoreError::Backend(error.to_string()),
+            FileStoreError::Serialization(error) => StoreError::Backend(error.to_string())
+        }
+    }
+}
+
+// One line in a stream file. The position is global across all streams so read_all can
+// restore the original append order on startup.
+#[derive(Deserialize, Serialize)]
+struct Line<E> {
+    position: usize,
+    envelope: E
+}
+
+// Appends events as newline-delimited JSON to <directory>/<stream id>.ndjson and keeps
+// everything in memory for reads. Every append is synced to disk before it is applied.
+pub struct FileEventStore<T> {
+    directory: PathBuf,
+    memory: Arc<InMemoryEventStore<T>>,
+    upcasters: Upcasters,
+    enrichers: Enrichers<T>,
+    write_lock: Arc<Mutex<usize>>,
+    closed: AtomicBool
+}
+
+impl<T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static> FileEventStore<T> {
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<FileEventStore<T>, FileStoreError> {
+        FileEventStore::open_with_upcasters(directory, Upcasters::new())
+    }
+
+    pub fn open_with_upcasters<P: AsRef<Path>>(directory: P, upcasters: Upcasters) -> Result<FileEventStore<T>, FileStoreError> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+
+        let mut lines = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let stream_id = match stream_id(&path) {
+                Some(stream_id) => stream_id,
+                None => continue
+            };
+            for (index, line) in read_lines(&path)?.into_iter().enumerate() {
+                lines.push((stream_id, index == 0, line));
+            }
+        }
+        lines.sort_by_key(|(_, _, line)| line.position);
+
+        // Purged streams leave gaps, so the next position comes after the last one used.
+        let memory = InMemoryEventStore::new();
+        let next_position = lines.last().map_or(0, |(_, _, line)| line.position + 1);
+        for (stream_id, is_first, line) in lines {
+            // A purged stream starts with its tombstone, which carries on from the purged version.
+            // Otherwise the in-memory store can only fail here if the files disagree about versions.
+            let expected_version = if is_first { 0 } else { line.envelope.version - 1 };
+            let mut envelope = upcasters.read(line.envelope)?;
+            envelope.position = line.position;
+            if let Err(StoreError::Concurrency(error)) = memory.record(stream_id, vec![envelope], expected_version) {
+                return Err(FileStoreError::Concurrency(error));
+            }
+        }
+
+        Ok(FileEventStore { directory, memory: Arc::new(memory), upcasters, enrichers: Enrichers::new(), write_lock: Arc::new(Mutex::new(next_position)), closed: AtomicBool::new(false) })
+    }
+
+    pub fn with_enrichers(mut self, enrichers: Enrichers<T>) -> FileEventStore<T> {
+        self.enrichers = enrichers;
+        self
+    }
+
+    fn stream_path(&self, stream_id: Uuid) -> PathBuf {
+        self.directory.join(format!("{}.ndjson", stream_id))
+    }
+
+    // Takes the write lock and checks the stream is where the caller expects it.
+    async fn lock_stream(&self, stream_id: Uuid, expected_version: usize) -> Result<OwnedMutexGuard<usize>, StoreError> {
+        let next_position = self.write_lock.clone().lock_owned().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoreError::Closed);
+        }
+
+        let current_version = self.memory.read_stream(stream_id).await?.version;
+        if current_version != expected_version {
+            return Err(StoreError::Concurrency(ConcurrencyError { stream_id, expected_version, current_version }));
+        }
+        Ok(next_position)
+    }
+}
+
+// The writes are done on tokio's blocking threads, holding the write lock, and they run to the
+// end even if whoever appended stops waiting, so the log in memory never falls behind the files.
+#[async_trait]
+impl<T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static> EventStore<T> for FileEventStore<T> {
+    async fn append(&self, stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let mut next_position = self.lock_stream(stream_id, expected_version).await?;
+        let mut envelopes = envelop(stream_id, events, expected_version, metadata, SystemTime::now(), &self.upcasters, &self.enrichers)?;
+        for (offset, envelope) in envelopes.iter_mut().enumerate() {
+            envelope.position = *next_position + offset;
+        }
+        let buffer = lines(&envelopes, *next_position).map_err(FileStoreError::from)?;
+        let (directory, path, memory) = (self.directory.clone(), self.stream_path(stream_id), self.memory.clone());
+        task::spawn_blocking(move || {
+            write(&directory, &path, &buffer).map_err(FileStoreError::Io)?;
+            *next_position += envelopes.len();
+            memory.record(stream_id, envelopes, expected_version)
+        }).await.map_err(interrupted)?
+    }
+
+    async fn purge_stream(&self, stream_id: Uuid, expected_version: usize, tombstone: T, metadata: &Metadata) -> Result<EventEnvelope<T>, StoreError> {
+        let mut next_position = self.lock_stream(stream_id, expected_version).await?;
+        let mut tombstone = envelop(stream_id, vec![tombstone], expected_version, metadata, SystemTime::now(), &self.upcasters, &self.enrichers)?.remove(0);
+        tombstone.position = *next_position;
+        let buffer = lines(slice::from_ref(&tombstone), *next_position).map_err(FileStoreError::from)?;
+        let (directory, path, memory) = (self.directory.clone(), self.stream_path(stream_id), self.memory.clone());
+        task::spawn_blocking(move || {
+            overwrite(&directory, &path, &buffer).map_err(FileStoreError::Io)?;
+            *next_position += 1;
+            memory.replace(stream_id, tombstone, expected_version)
+        }).await.map_err(interrupted)?
+    }
+
+    async fn read_stream(&self, stream_id: Uuid) -> Result<EventStream<T>, StoreError> {
+        self.memory.read_stream(stream_id).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        self.memory.read_all().await
+    }
+
+    async fn read_all_from(&self, position: usize, max_count: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        self.memory.read_all_from(position, max_count).await
+    }
+
+    async fn subscribe(&self, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        self.memory.subscribe(subscriber).await
+    }
+
+    async fn subscribe_to_stream(&self, stream_id: Uuid, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        self.memory.subscribe_to_stream(stream_id, subscriber).await
+    }
+
+    async fn stored_bytes(&self) -> Result<Option<u64>, StoreError> {
+        let directory = self.directory.clone();
+        let bytes = task::spawn_blocking(move || stream_files_size(&directory)).await.map_err(interrupted)?.map_err(FileStoreError::Io)?;
+        Ok(Some(bytes))
+    }
+
+    // Every append is synced before it returns, so waiting for the one in flight is all the
+    // flushing there is; the directory is synced once more for streams created just before.
+    async fn close(&self) -> Result<(), StoreError> {
+        let _next_position = self.write_lock.lock().await;
+        self.closed.store(true, Ordering::SeqCst);
+        let directory = self.directory.clone();
+        Ok(task::spawn_blocking(move || sync_directory(&directory)).await.map_err(interrupted)?.map_err(FileStoreError::Io)?)
+    }
+}
+
+fn interrupted(error: JoinError) -> StoreError {
+    StoreError::Backend(error.to_string())
+}
+
+fn lines<T: Serialize>(envelopes: &[EventEnvelope<T>], first_position: usize) -> Result<Vec<u8>, serde_json::Error> {
+    let mut buffer = Vec::new();
+    for (offset, envelope) in envelopes.iter().enumerate() {
+        serde_json::to_writer(&mut buffer, &Line { position: first_position + offset, envelope })?;
+        buffer.push(b'\n');
+    }
+    Ok(buffer)
+}
+
+fn write(directory: &Path, path: &Path, buffer: &[u8]) -> io::Result<()> {
+    let is_new = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(buffer)?;
+    file.sync_data()?;
+    if is_new {
+        sync_directory(directory)?;
+    }
+    Ok(())
+}
+
+// The tombstone is written next to the stream and renamed over it, so a crash leaves either
+// the whole stream or only the tombstone.
+fn overwrite(directory: &Path, path: &Path, buffer: &[u8]) -> io::Result<()> {
+    let temporary_path = path.with_extension("ndjson.tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(buffer)?;
+    file.sync_data()?;
+    fs::rename(&temporary_path, path)?;
+    sync_directory(directory)
+}
+
+fn stream_files_size(directory: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if stream_id(&entry.path()).is_some() {
+            bytes += entry.metadata()?.len();
+        }
+    }
+    Ok(bytes)
+}
+
+fn stream_id(path: &Path) -> Option<Uuid> {
+    if path.extension().is_none_or(|extension| extension != "ndjson") {
+        return None;
+    }
+    path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| Uuid::parse_str(stem).ok())
+}
+
+// A crash in the middle of an append can leave the last line without its newline. That
+// append was never acknowledged, so the partial line is cut off before anything new is written.
+fn read_lines(path: &Path) -> Result<Vec<Line<EventEnvelope<Value>>>, FileStoreError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut lines = Vec::new();
+    let mut buffer = String::new();
+    let mut complete_length = 0;
+    loop {
+        buffer.clear();
+        if reader.read_line(&mut buffer)? == 0 {
+            break;
+        }
+        if !buffer.ends_with('\n') {
+            OpenOptions::new().write(true).open(path)?.set_len(complete_length)?;
+            break;
+        }
+        complete_length += buffer.len() as u64;
+        lines.push(serde_json::from_str(&buffer)?);
+    }
+    Ok(lines)
+}
+
+#[cfg(unix)]
+fn sync_directory(directory: &Path) -> io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::cqrs::store::Upcaster;
+    use crate::domain::Event;
+    use crate::staff;
+
+    fn scratch_directory() -> PathBuf {
+        env::temp_dir().join(format!("cafe-file-store-{}", Uuid::new_v4()))
+    }
+
+    fn payloads(envelopes: Vec<EventEnvelope<i32>>) -> Vec<(Uuid, i32)> {
+        envelopes.into_iter().map(|envelope| (envelope.stream_id, envelope.payload)).collect()
+    }
+
+    #[tokio::test]
+    async fn replays_events_on_open() {
+        let directory = scratch_directory();
+        let metadata = Metadata::new();
+        let tab1 = Uuid::new_v4();
+        let tab2 = Uuid::new_v4();
+        let recorded = {
+            let store = FileEventStore::open(&directory).unwrap();
+            store.append(tab1, vec![1, 2], 0, &metadata).await.unwrap();
+            store.append(tab2, vec![3], 0, &metadata).await.unwrap();
+            store.append(tab1, vec![4], 2, &metadata).await.unwrap()
+        };
+        let store: FileEventStore<i32> = FileEventStore::open(&directory).unwrap();
+        let stream = store.read_stream(tab1).await.unwrap();
+        assert_eq!(stream.version, 3);
+        assert_eq!(stream.events[2], recorded[0]);
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab1, 1), (tab1, 2), (tab2, 3), (tab1, 4)]);
+        assert_eq!(payloads(store.read_all_from(2, 10).await.unwrap()), vec![(tab2, 3), (tab1, 4)]);
+        let appended = store.append(tab2, vec![5], 1, &metadata).await.unwrap();
+        assert_eq!((appended[0].version, appended[0].position), (2, 4));
+        let files = fs::metadata(store.stream_path(tab1)).unwrap().len() + fs::metadata(store.stream_path(tab2)).unwrap().len();
+        assert_eq!(store.stored_bytes().await.unwrap(), Some(files));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_appends_on_stale_version() {
+        let directory = scratch_directory();
+        let metadata = Metadata::new();
+        let tab = Uuid::new_v4();
+        let store = FileEventStore::open(&directory).unwrap();
+        store.append(tab, vec![1], 0, &metadata).await.unwrap();
+        match store.append(tab, vec![2], 0, &metadata).await {
+            Err(StoreError::Concurrency(error)) => assert_eq!(error.current_version, 1),
+            other => panic!("expected a concurrency error, got {:?}", other)
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_torn_last_line() {
+        let directory = scratch_directory();
+        let metadata = Metadata::new();
+        let tab = Uuid::new_v4();
+        {
+            let store = FileEventStore::open(&directory).unwrap();
+            store.append(tab, vec![1], 0, &metadata).await.unwrap();
+        }
+        let mut file = OpenOptions::new().append(true).open(directory.join(format!("{}.ndjson", tab))).unwrap();
+        file.write_all(b"{\"position\":1,\"envel").unwrap();
+        {
+            let store: FileEventStore<i32> = FileEventStore::open(&directory).unwrap();
+            assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab, 1)]);
+            store.append(tab, vec![2], 1, &metadata).await.unwrap();
+        }
+        let store: FileEventStore<i32> = FileEventStore::open(&directory).unwrap();
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab, 1), (tab, 2)]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn purged_stream_stays_purged_after_reopening() {
+        let directory = scratch_directory();
+        let metadata = Metadata::new();
+        let tab1 = Uuid::new_v4();
+        let tab2 = Uuid::new_v4();
+        {
+            let store = FileEventStore::open(&directory).unwrap();
+            store.append(tab1, vec![1, 2], 0, &metadata).await.unwrap();
+            store.append(tab2, vec![3], 0, &metadata).await.unwrap();
+            store.purge_stream(tab1, 2, 0, &metadata).await.unwrap();
+        }
+        let store: FileEventStore<i32> = FileEventStore::open(&directory).unwrap();
+        assert_eq!(store.read_stream(tab1).await.unwrap().version, 3);
+        store.append(tab2, vec![4], 1, &metadata).await.unwrap();
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab2, 3), (tab1, 0), (tab2, 4)]);
+        let store: FileEventStore<i32> = FileEventStore::open(&directory).unwrap();
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab2, 3), (tab1, 0), (tab2, 4)]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // TabOpened v1 called the table number "table".
+    struct TableRenamed;
+
+    impl Upcaster for TableRenamed {
+        fn event_type(&self) -> &str {
+            "tab_opened"
+        }
+
+        fn version(&self) -> u32 {
+            1
+        }
+
+        fn upcast(&self, mut payload: Value) -> Value {
+            if let Some(table) = payload.as_object_mut().and_then(|fields| fields.remove("table")) {
+                payload["table_number"] = table;
+            }
+            payload
+        }
+    }
+
+    #[tokio::test]
+    async fn old_events_are_upcast_on_open() {
+        let directory = scratch_directory();
+        let tab = Uuid::new_v4();
+        fs::create_dir_all(&directory).unwrap();
+        let envelope = EventEnvelope {
+            event_id: Uuid::new_v4(),
+            stream_id: tab,
+            version: 1,
+            position: 0,
+            event_type: "tab_opened".to_string(),
+            schema_version: 1,
+            timestamp: SystemTime::now(),
+            correlation_id: Uuid::new_v4(),
+            causation_id: Uuid::new_v4(),
+            acting_user: None,
+            acting_staff_id: None,
+            enrichment: Default::default(),
+            payload: json!({ "type": "tab_opened", "table": 42, "waiter_id": staff::legacy_id("Derek"), "waiter": "Derek" })
+        };
+        let line = serde_json::to_string(&Line { position: 0, envelope }).unwrap();
+        fs::write(directory.join(format!("{}.ndjson", tab)), line + "\n").unwrap();
+
+        let mut upcasters = Upcasters::new();
+        upcasters.register(Box::new(TableRenamed));
+        let store: FileEventStore<Event> = FileEventStore::open_with_upcasters(&directory, upcasters).unwrap();
+        let stored = store.read_stream(tab).await.unwrap().events.remove(0);
+        assert_eq!(stored.payload, Event::TabOpened { table_number: 42, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        assert_eq!(stored.schema_version, 1);
+        let appended = store.append(tab, vec![Event::TabOpened { table_number: 7, waiter_id: staff::legacy_id("Jane"), waiter: "Jane".to_string() }], 1, &Metadata::new()).await.unwrap();
+        assert_eq!(appended[0].schema_version, 2);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
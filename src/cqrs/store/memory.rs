@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::slice;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::cqrs::{EventEnvelope, Metadata, ProjectionRegistry};
+use super::{envelop, ConcurrencyError, Enrichers, EventStore, EventStream, StoreError, StreamOnly, Subscriber, Upcasters};
+
+pub struct InMemoryEventStore<T> {
+    inner: RwLock<Log<T>>,
+    enrichers: Enrichers<T>
+}
+
+struct Log<T> {
+    events: Vec<EventEnvelope<T>>,
+    streams: HashMap<Uuid, Vec<usize>>,
+    projections: ProjectionRegistry<T>,
+    next_position: usize,
+    closed: bool
+}
+
+impl<T: Clone> InMemoryEventStore<T> {
+    pub fn new() -> InMemoryEventStore<T> {
+        InMemoryEventStore {
+            inner: RwLock::new(Log {
+                events: Vec::new(),
+                streams: HashMap::new(),
+                projections: ProjectionRegistry::new(),
+                next_position: 0,
+                closed: false
+            }),
+            enrichers: Enrichers::new()
+        }
+    }
+
+    pub fn with_enrichers(mut self, enrichers: Enrichers<T>) -> InMemoryEventStore<T> {
+        self.enrichers = enrichers;
+        self
+    }
+
+    // Appends envelopes that were already recorded elsewhere, e.g. replayed from disk, at the
+    // positions they were given there.
+    pub(super) fn record(&self, stream_id: Uuid, envelopes: Vec<EventEnvelope<T>>, expected_version: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let mut log = self.inner.write().unwrap();
+        log.check_version(stream_id, expected_version)?;
+        Ok(log.record(envelopes))
+    }
+
+    pub(super) fn replace(&self, stream_id: Uuid, tombstone: EventEnvelope<T>, expected_version: usize) -> Result<EventEnvelope<T>, StoreError> {
+        let mut log = self.inner.write().unwrap();
+        log.check_version(stream_id, expected_version)?;
+        Ok(log.replace(stream_id, tombstone))
+    }
+}
+
+impl<T: Clone> Log<T> {
+    fn record(&mut self, envelopes: Vec<EventEnvelope<T>>) -> Vec<EventEnvelope<T>> {
+        // Projections are updated while the log is still locked so they see events in log order.
+        self.projections.notify(&envelopes);
+
+        for envelope in &envelopes {
+            self.push(envelope.clone());
+        }
+
+        envelopes
+    }
+
+    // Drops every event of the stream and leaves the tombstone in their place, at the end of the log.
+    fn replace(&mut self, stream_id: Uuid, tombstone: EventEnvelope<T>) -> EventEnvelope<T> {
+        self.projections.notify(slice::from_ref(&tombstone));
+
+        self.events.retain(|envelope| envelope.stream_id != stream_id);
+        self.streams.clear();
+        for (index, envelope) in self.events.iter().enumerate() {
+            self.streams.entry(envelope.stream_id).or_default().push(index);
+        }
+        self.push(tombstone.clone());
+
+        tombstone
+    }
+
+    fn assign_positions(&self, envelopes: &mut [EventEnvelope<T>]) {
+        for (offset, envelope) in envelopes.iter_mut().enumerate() {
+            envelope.position = self.next_position + offset;
+        }
+    }
+}
+
+impl<T> Log<T> {
+    // Streams keep counting after a purge, so the version is the last one recorded rather than
+    // the number of events.
+    fn version(&self, stream_id: Uuid) -> usize {
+        self.streams.get(&stream_id)
+            .and_then(|positions| positions.last())
+            .map_or(0, |&position| self.events[position].version)
+    }
+
+    fn check_version(&self, stream_id: Uuid, expected_version: usize) -> Result<(), StoreError> {
+        if self.closed {
+            return Err(StoreError::Closed);
+        }
+        let current_version = self.version(stream_id);
+        if current_version != expected_version {
+            return Err(StoreError::Concurrency(ConcurrencyError { stream_id, expected_version, current_version }));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, envelope: EventEnvelope<T>) {
+        self.next_position = self.next_position.max(envelope.position + 1);
+        self.streams.entry(envelope.stream_id).or_default().push(self.events.len());
+        self.events.push(envelope);
+    }
+}
+
+impl<T: Clone> Default for InMemoryEventStore<T> {
+    fn default() -> InMemoryEventStore<T> {
+        InMemoryEventStore::new()
+    }
+}
+
+// Nothing is read back from storage, so there is nothing to upcast and every event is at the
+// first version of its schema.
+#[async_trait]
+impl<T: Clone + Serialize + Send + Sync + 'static> EventStore<T> for InMemoryEventStore<T> {
+    async fn append(&self, stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let mut envelopes = envelop(stream_id, events, expected_version, metadata, SystemTime::now(), &Upcasters::new(), &self.enrichers)?;
+        let mut log = self.inner.write().unwrap();
+        log.check_version(stream_id, expected_version)?;
+        log.assign_positions(&mut envelopes);
+        Ok(log.record(envelopes))
+    }
+
+    async fn purge_stream(&self, stream_id: Uuid, expected_version: usize, tombstone: T, metadata: &Metadata) -> Result<EventEnvelope<T>, StoreError> {
+        let mut tombstone = envelop(stream_id, vec![tombstone], expected_version, metadata, SystemTime::now(), &Upcasters::new(), &self.enrichers)?;
+        let mut log = self.inner.write().unwrap();
+        log.check_version(stream_id, expected_version)?;
+        log.assign_positions(&mut tombstone);
+        Ok(log.replace(stream_id, tombstone.remove(0)))
+    }
+
+    async fn read_stream(&self, stream_id: Uuid) -> Result<EventStream<T>, StoreError> {
+        let log = self.inner.read().unwrap();
+        let events: Vec<EventEnvelope<T>> = match log.streams.get(&stream_id) {
+            Some(positions) => positions.iter().map(|&position| log.events[position].clone()).collect(),
+            None => Vec::new()
+        };
+        Ok(EventStream { version: log.version(stream_id), events })
+    }
+
+    async fn read_all(&self) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        Ok(self.inner.read().unwrap().events.clone())
+    }
+
+    // The log is kept in position order, purged streams only leaving gaps.
+    async fn read_all_from(&self, position: usize, max_count: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let log = self.inner.read().unwrap();
+        let start = log.events.partition_point(|envelope| envelope.position < position);
+        Ok(log.events[start..].iter().take(max_count).cloned().collect())
+    }
+
+    async fn subscribe(&self, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        let mut log = self.inner.write().unwrap();
+        {
+            let mut projection = subscriber.write().unwrap();
+            for envelope in &log.events {
+                projection.apply_envelope(envelope);
+            }
+        }
+        log.projections.register(subscriber);
+        Ok(())
+    }
+
+    async fn subscribe_to_stream(&self, stream_id: Uuid, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        let mut log = self.inner.write().unwrap();
+        {
+            let mut projection = subscriber.write().unwrap();
+            for &position in log.streams.get(&stream_id).into_iter().flatten() {
+                projection.apply_envelope(&log.events[position]);
+            }
+        }
+        log.projections.register(Arc::new(RwLock::new(StreamOnly { stream_id, subscriber })));
+        Ok(())
+    }
+
+    // Appends hold the log locked, so once it is ours none is in flight.
+    async fn close(&self) -> Result<(), StoreError> {
+        self.inner.write().unwrap().closed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::store::Enrichment;
+
+    fn payloads(envelopes: Vec<EventEnvelope<i32>>) -> Vec<(Uuid, i32)> {
+        envelopes.into_iter().map(|envelope| (envelope.stream_id, envelope.payload)).collect()
+    }
+
+    #[tokio::test]
+    async fn streams_are_kept_apart() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab1 = Uuid::new_v4();
+        let tab2 = Uuid::new_v4();
+        store.append(tab1, vec![1, 2], 0, &metadata).await.unwrap();
+        store.append(tab2, vec![3], 0, &metadata).await.unwrap();
+        store.append(tab1, vec![4], 2, &metadata).await.unwrap();
+        let stream = store.read_stream(tab1).await.unwrap();
+        assert_eq!(stream.version, 3);
+        assert_eq!(payloads(stream.events), vec![(tab1, 1), (tab1, 2), (tab1, 4)]);
+        assert_eq!(payloads(store.read_stream(tab2).await.unwrap().events), vec![(tab2, 3)]);
+        assert_eq!(store.read_stream(Uuid::new_v4()).await, Ok(EventStream { version: 0, events: Vec::new() }));
+    }
+
+    #[tokio::test]
+    async fn read_all_returns_events_in_append_order() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab1 = Uuid::new_v4();
+        let tab2 = Uuid::new_v4();
+        store.append(tab1, vec![1], 0, &metadata).await.unwrap();
+        store.append(tab2, vec![2], 0, &metadata).await.unwrap();
+        store.append(tab1, vec![3], 1, &metadata).await.unwrap();
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab1, 1), (tab2, 2), (tab1, 3)]);
+    }
+
+    #[tokio::test]
+    async fn read_all_is_paged_by_position() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab1 = Uuid::new_v4();
+        let tab2 = Uuid::new_v4();
+        store.append(tab1, vec![1, 2], 0, &metadata).await.unwrap();
+        store.append(tab2, vec![3], 0, &metadata).await.unwrap();
+        store.append(tab1, vec![4], 2, &metadata).await.unwrap();
+        assert_eq!(store.read_all().await.unwrap().iter().map(|envelope| envelope.position).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+
+        let page = store.read_all_from(0, 3).await.unwrap();
+        assert_eq!(payloads(page.clone()), vec![(tab1, 1), (tab1, 2), (tab2, 3)]);
+        let next = page.last().unwrap().position + 1;
+        assert_eq!(payloads(store.read_all_from(next, 3).await.unwrap()), vec![(tab1, 4)]);
+        assert_eq!(store.read_all_from(next + 1, 3).await, Ok(vec![]));
+
+        store.purge_stream(tab1, 3, 0, &metadata).await.unwrap();
+        let after_purge = store.read_all_from(0, 10).await.unwrap();
+        assert_eq!(after_purge.iter().map(|envelope| envelope.position).collect::<Vec<_>>(), vec![2, 4]);
+    }
+
+    #[tokio::test]
+    async fn listeners_catch_up_then_receive_new_events() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab = Uuid::new_v4();
+        store.append(tab, vec![1], 0, &metadata).await.unwrap();
+        let mut listener = store.listen().await.unwrap();
+        store.append(tab, vec![2, 3], 1, &metadata).await.unwrap();
+        let mut received = Vec::new();
+        while let Ok(envelope) = listener.try_recv() {
+            received.push(envelope);
+        }
+        assert_eq!(payloads(received), vec![(tab, 1), (tab, 2), (tab, 3)]);
+
+        drop(listener);
+        store.append(tab, vec![4], 3, &metadata).await.unwrap();
+        assert!(store.inner.read().unwrap().projections.projections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stream_listeners_get_only_their_stream() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let (tab, other) = (Uuid::new_v4(), Uuid::new_v4());
+        store.append(tab, vec![1], 0, &metadata).await.unwrap();
+        store.append(other, vec![10], 0, &metadata).await.unwrap();
+        let mut listener = store.listen_to(tab).await.unwrap();
+        store.append(other, vec![11], 1, &metadata).await.unwrap();
+        store.append(tab, vec![2], 1, &metadata).await.unwrap();
+        let mut received = Vec::new();
+        while let Ok(envelope) = listener.try_recv() {
+            received.push(envelope);
+        }
+        assert_eq!(payloads(received), vec![(tab, 1), (tab, 2)]);
+
+        drop(listener);
+        store.append(tab, vec![3], 2, &metadata).await.unwrap();
+        assert!(store.inner.read().unwrap().projections.projections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn append_returns_recorded_envelopes() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab = Uuid::new_v4();
+        store.append(tab, vec![1, 2], 0, &metadata).await.unwrap();
+        let recorded = store.append(tab, vec![3], 2, &metadata).await.unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!((recorded[0].version, recorded[0].correlation_id), (3, metadata.correlation_id));
+        assert_eq!(store.read_stream(tab).await.unwrap().events[2], recorded[0]);
+    }
+
+    #[tokio::test]
+    async fn enrichers_run_in_order_on_append() {
+        let mut enrichers = Enrichers::new();
+        enrichers.register(Box::new(|_: &EventEnvelope<i32>, enrichment: &mut Enrichment| {
+            enrichment.insert("shift".to_string(), "morning".to_string());
+            enrichment.insert("campaign".to_string(), "none".to_string());
+        }));
+        enrichers.register(Box::new(|envelope: &EventEnvelope<i32>, enrichment: &mut Enrichment| {
+            if envelope.payload > 1 {
+                enrichment.insert("campaign".to_string(), "happy-hour".to_string());
+            }
+        }));
+        let store = InMemoryEventStore::new().with_enrichers(enrichers);
+        let recorded = store.append(Uuid::new_v4(), vec![1, 2], 0, &Metadata::new()).await.unwrap();
+        let campaigns: Vec<&str> = recorded.iter().map(|envelope| envelope.enrichment["campaign"].as_str()).collect();
+        assert_eq!(campaigns, vec!["none", "happy-hour"]);
+        assert_eq!(recorded[1].enrichment["shift"], "morning");
+        assert_eq!(store.read_all().await.unwrap(), recorded);
+    }
+
+    #[tokio::test]
+    async fn append_on_stale_version_is_rejected() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab = Uuid::new_v4();
+        let version = store.read_stream(tab).await.unwrap().version;
+        store.append(tab, vec![1], version, &metadata).await.unwrap();
+        let error = ConcurrencyError { stream_id: tab, expected_version: 0, current_version: 1 };
+        assert_eq!(store.append(tab, vec![2], version, &metadata).await, Err(StoreError::Concurrency(error)));
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab, 1)]);
+    }
+
+    #[tokio::test]
+    async fn purged_stream_keeps_only_the_tombstone() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab1 = Uuid::new_v4();
+        let tab2 = Uuid::new_v4();
+        store.append(tab1, vec![1, 2], 0, &metadata).await.unwrap();
+        store.append(tab2, vec![3], 0, &metadata).await.unwrap();
+        let tombstone = store.purge_stream(tab1, 2, 0, &metadata).await.unwrap();
+        assert_eq!(tombstone.version, 3);
+        assert_eq!(store.read_stream(tab1).await, Ok(EventStream { version: 3, events: vec![tombstone] }));
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab2, 3), (tab1, 0)]);
+        assert_eq!(store.append(tab2, vec![4], 1, &metadata).await.unwrap()[0].version, 2);
+        assert!(store.purge_stream(tab2, 1, 0, &metadata).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_store_turns_writes_away_but_still_reads() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab = Uuid::new_v4();
+        store.append(tab, vec![1], 0, &metadata).await.unwrap();
+        store.close().await.unwrap();
+        assert_eq!(store.append(tab, vec![2], 1, &metadata).await, Err(StoreError::Closed));
+        assert_eq!(store.purge_stream(tab, 1, 0, &metadata).await, Err(StoreError::Closed));
+        assert_eq!(payloads(store.read_all().await.unwrap()), vec![(tab, 1)]);
+    }
+}
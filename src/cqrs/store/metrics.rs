@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::cqrs::EventEnvelope;
+use super::{EventStore, StoreError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LengthPercentiles {
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    pub max: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct StreamSummary {
+    length: usize,
+    first_recorded_at: SystemTime
+}
+
+// How many streams there are, how long they get and how old they are, for capacity planning and
+// for tuning how often snapshots are taken. Read from the whole log, so it is a maintenance
+// query and not something to ask on every request. Purged streams count as their tombstone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamMetrics {
+    streams: HashMap<Uuid, StreamSummary>
+}
+
+impl StreamMetrics {
+    pub async fn read<T: Send + 'static>(store: &dyn EventStore<T>) -> Result<StreamMetrics, StoreError> {
+        Ok(StreamMetrics::from_events(&store.read_all().await?))
+    }
+
+    pub fn from_events<T>(events: &[EventEnvelope<T>]) -> StreamMetrics {
+        let mut streams = HashMap::new();
+        for envelope in events {
+            streams.entry(envelope.stream_id)
+                .or_insert(StreamSummary { length: 0, first_recorded_at: envelope.timestamp })
+                .length += 1;
+        }
+        StreamMetrics { streams }
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn event_count(&self) -> usize {
+        self.streams.values().map(|stream| stream.length).sum()
+    }
+
+    // Nearest-rank percentiles; all zero for an empty store.
+    pub fn length_percentiles(&self) -> LengthPercentiles {
+        let mut lengths: Vec<usize> = self.streams.values().map(|stream| stream.length).collect();
+        lengths.sort();
+        let rank = |percent: usize| {
+            if lengths.is_empty() { 0 } else { lengths[(percent * lengths.len()).div_ceil(100) - 1] }
+        };
+        LengthPercentiles { p50: rank(50), p90: rank(90), p99: rank(99), max: rank(100) }
+    }
+
+    pub fn first_recorded_at(&self, stream_id: Uuid) -> Option<SystemTime> {
+        self.streams.get(&stream_id).map(|stream| stream.first_recorded_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Metadata;
+    use crate::cqrs::store::InMemoryEventStore;
+
+    #[tokio::test]
+    async fn streams_are_counted_and_measured() {
+        let store = InMemoryEventStore::new();
+        assert_eq!(StreamMetrics::read(&store).await.unwrap().length_percentiles(), LengthPercentiles { p50: 0, p90: 0, p99: 0, max: 0 });
+
+        let metadata = Metadata::new();
+        let mut first = None;
+        for length in 1..11 {
+            let stream_id = Uuid::new_v4();
+            let recorded = store.append(stream_id, vec![0; length], 0, &metadata).await.unwrap();
+            first = first.or(Some((stream_id, recorded[0].timestamp)));
+        }
+
+        let metrics = StreamMetrics::read(&store).await.unwrap();
+        assert_eq!((metrics.stream_count(), metrics.event_count()), (10, 55));
+        assert_eq!(metrics.length_percentiles(), LengthPercentiles { p50: 5, p90: 9, p99: 10, max: 10 });
+        let (stream_id, recorded_at) = first.unwrap();
+        assert_eq!(metrics.first_recorded_at(stream_id), Some(recorded_at));
+        assert_eq!(metrics.first_recorded_at(Uuid::new_v4()), None);
+    }
+}
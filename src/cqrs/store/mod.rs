@@ -0,0 +1,242 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+use crate::cqrs::{EventEnvelope, Metadata, Projection};
+
+mod enrich;
+mod file;
+mod memory;
+mod metrics;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod snapshot;
+mod upcast;
+
+pub use self::enrich::{Enricher, Enrichers, Enrichment};
+pub use self::file::FileEventStore;
+pub use self::memory::InMemoryEventStore;
+pub use self::metrics::{LengthPercentiles, StreamMetrics};
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresEventStore;
+pub use self::snapshot::{InMemorySnapshotStore, Snapshot, SnapshotLoads, SnapshotStore};
+pub use self::upcast::{Upcaster, Upcasters};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConcurrencyError {
+    pub stream_id: Uuid,
+    pub expected_version: usize,
+    pub current_version: usize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StoreError {
+    Concurrency(ConcurrencyError),
+    Backend(String),
+    // The store was closed on shutdown and takes no more writes.
+    Closed
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Concurrency(ref error) => write!(f, "stream {} is at version {}, expected {}", error.stream_id, error.current_version, error.expected_version),
+            StoreError::Backend(ref message) => write!(f, "event store failure: {}", message),
+            StoreError::Closed => write!(f, "the event store is closed")
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventStream<T> {
+    pub version: usize,
+    pub events: Vec<EventEnvelope<T>>
+}
+
+pub type Subscriber<T> = Arc<RwLock<dyn Projection<T> + Send + Sync>>;
+
+// The version of a stream is the number of events ever appended to it, so a new stream is at
+// version 0. Stores backed by a database or files do their I/O without holding up the executor,
+// so commands can be handled on Rocket's workers.
+#[async_trait]
+pub trait EventStore<T: Send + 'static>: Send + Sync {
+    // Appends only if nobody else has written to the stream since the caller read it at
+    // expected_version. Returns the events as they were recorded.
+    async fn append(&self, stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata) -> Result<Vec<EventEnvelope<T>>, StoreError>;
+
+    // Removes every event of the stream and records the tombstone as its only event, at the
+    // next version so stale writers are still turned away. Meant for retention, not for undo.
+    async fn purge_stream(&self, stream_id: Uuid, expected_version: usize, tombstone: T, metadata: &Metadata) -> Result<EventEnvelope<T>, StoreError>;
+
+    async fn read_stream(&self, stream_id: Uuid) -> Result<EventStream<T>, StoreError>;
+
+    // Only the events after the given version, e.g. the version of a snapshot.
+    async fn read_stream_after(&self, stream_id: Uuid, version: usize) -> Result<EventStream<T>, StoreError> {
+        let mut stream = self.read_stream(stream_id).await?;
+        stream.events.retain(|envelope| envelope.version > version);
+        Ok(stream)
+    }
+
+    async fn read_all(&self) -> Result<Vec<EventEnvelope<T>>, StoreError>;
+
+    // At most max_count events in log order, starting with the first at or after the position,
+    // so consumers can catch up a page at a time from the position after the last one they saw.
+    async fn read_all_from(&self, position: usize, max_count: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        Ok(self.read_all().await?.into_iter().filter(|envelope| envelope.position >= position).take(max_count).collect())
+    }
+
+    // Feeds the subscriber everything already stored, then every event appended from now on,
+    // in log order. Projections are applied in memory as events are recorded, so they are
+    // never awaited.
+    async fn subscribe(&self, subscriber: Subscriber<T>) -> Result<(), StoreError>;
+
+    // Like subscribe, for the events of one stream only. Stores that can read a stream on its
+    // own replay just that instead of the whole log.
+    async fn subscribe_to_stream(&self, stream_id: Uuid, subscriber: Subscriber<T>) -> Result<(), StoreError> where T: Sync {
+        self.subscribe(Arc::new(RwLock::new(StreamOnly { stream_id, subscriber }))).await
+    }
+
+    // The same feed as a stream, for consumers running beside the API such as process
+    // managers or websocket broadcasters. The history comes first so they can catch up before
+    // going live; dropping the receiver ends the subscription. Consumers on a thread of their
+    // own take events with blocking_recv.
+    async fn listen(&self) -> Result<UnboundedReceiver<EventEnvelope<T>>, StoreError> where T: Clone + Sync {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribe(Arc::new(RwLock::new(Listener { sender }))).await?;
+        Ok(receiver)
+    }
+
+    // Likewise for one stream, e.g. a tab a display follows.
+    async fn listen_to(&self, stream_id: Uuid) -> Result<UnboundedReceiver<EventEnvelope<T>>, StoreError> where T: Clone + Sync {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribe_to_stream(stream_id, Arc::new(RwLock::new(Listener { sender }))).await?;
+        Ok(receiver)
+    }
+
+    // How much room the log takes where it is kept; None for stores that keep it in memory only.
+    async fn stored_bytes(&self) -> Result<Option<u64>, StoreError> {
+        Ok(None)
+    }
+
+    // Waits for appends in flight, makes sure everything written so far is durable and turns
+    // away appends and purges from then on. Reads and subscriptions keep working.
+    async fn close(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+// A store shared between the API and something running beside it, such as a process runner
+// that has to issue commands of its own.
+#[async_trait]
+impl<T: Send + 'static, S: EventStore<T> + ?Sized> EventStore<T> for Arc<S> {
+    async fn append(&self, stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        (**self).append(stream_id, events, expected_version, metadata).await
+    }
+
+    async fn purge_stream(&self, stream_id: Uuid, expected_version: usize, tombstone: T, metadata: &Metadata) -> Result<EventEnvelope<T>, StoreError> {
+        (**self).purge_stream(stream_id, expected_version, tombstone, metadata).await
+    }
+
+    async fn read_stream(&self, stream_id: Uuid) -> Result<EventStream<T>, StoreError> {
+        (**self).read_stream(stream_id).await
+    }
+
+    async fn read_stream_after(&self, stream_id: Uuid, version: usize) -> Result<EventStream<T>, StoreError> {
+        (**self).read_stream_after(stream_id, version).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        (**self).read_all().await
+    }
+
+    async fn read_all_from(&self, position: usize, max_count: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        (**self).read_all_from(position, max_count).await
+    }
+
+    async fn subscribe(&self, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        (**self).subscribe(subscriber).await
+    }
+
+    async fn subscribe_to_stream(&self, stream_id: Uuid, subscriber: Subscriber<T>) -> Result<(), StoreError> where T: Sync {
+        (**self).subscribe_to_stream(stream_id, subscriber).await
+    }
+
+    async fn stored_bytes(&self) -> Result<Option<u64>, StoreError> {
+        (**self).stored_bytes().await
+    }
+
+    async fn close(&self) -> Result<(), StoreError> {
+        (**self).close().await
+    }
+}
+
+struct Listener<T> {
+    sender: UnboundedSender<EventEnvelope<T>>
+}
+
+impl<T: Clone> Projection<T> for Listener<T> {
+    // Stores always deliver whole envelopes.
+    fn apply(&mut self, _: Uuid, _: &T) {}
+
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<T>) {
+        let _ = self.sender.send(envelope.clone());
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+// Passes on the events of one stream to the subscriber.
+pub(crate) struct StreamOnly<T> {
+    pub(crate) stream_id: Uuid,
+    pub(crate) subscriber: Subscriber<T>
+}
+
+impl<T> Projection<T> for StreamOnly<T> {
+    fn apply(&mut self, stream_id: Uuid, event: &T) {
+        if stream_id == self.stream_id {
+            self.subscriber.write().unwrap().apply(stream_id, event);
+        }
+    }
+
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<T>) {
+        if envelope.stream_id == self.stream_id {
+            self.subscriber.write().unwrap().apply_envelope(envelope);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.subscriber.read().unwrap().is_closed()
+    }
+}
+
+fn envelop<T: Serialize>(stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata, timestamp: SystemTime, upcasters: &Upcasters, enrichers: &Enrichers<T>) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+    let mut envelopes = Vec::with_capacity(events.len());
+    for (offset, payload) in events.into_iter().enumerate() {
+        let event_type = upcast::event_type(&payload).map_err(|error| StoreError::Backend(error.to_string()))?;
+        envelopes.push(EventEnvelope {
+            event_id: Uuid::new_v4(),
+            stream_id,
+            version: expected_version + offset + 1,
+            // Given by the store once it knows where the events go.
+            position: 0,
+            schema_version: upcasters.current_version(&event_type),
+            event_type,
+            timestamp,
+            correlation_id: metadata.correlation_id,
+            causation_id: metadata.causation_id,
+            acting_user: metadata.acting_user.clone(),
+            acting_staff_id: metadata.acting_staff_id,
+            enrichment: Enrichment::new(),
+            payload
+        });
+    }
+    enrichers.enrich(&mut envelopes);
+    Ok(envelopes)
+}
@@ -0,0 +1,353 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use tokio::sync::Mutex;
+use tokio_postgres::{self as postgres, Client, NoTls, Row};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::Json;
+use uuid::Uuid;
+
+use crate::cqrs::{EventEnvelope, Metadata, ProjectionRegistry};
+use super::{envelop, ConcurrencyError, Enrichers, Enrichment, EventStore, EventStream, StoreError, StreamOnly, Subscriber, Upcasters};
+
+const COLUMNS: &str = "event_id, stream_id, version, event_type, schema_version, recorded_at, correlation_id, causation_id, acting_user, enrichment, payload, position, acting_staff_id";
+
+// Versions start at 1 within a stream and the version of a stream is the highest one recorded.
+// The unique constraint is what stops two writers from appending the same version.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        position BIGSERIAL PRIMARY KEY,
+        event_id UUID NOT NULL UNIQUE,
+        stream_id UUID NOT NULL,
+        version INTEGER NOT NULL,
+        event_type TEXT NOT NULL,
+        schema_version INTEGER NOT NULL,
+        payload JSONB NOT NULL,
+        recorded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        correlation_id UUID NOT NULL,
+        causation_id UUID NOT NULL,
+        acting_user TEXT,
+        acting_staff_id UUID,
+        enrichment JSONB NOT NULL DEFAULT '{}',
+        UNIQUE (stream_id, version)
+    );
+    ALTER TABLE events ADD COLUMN IF NOT EXISTS acting_user TEXT;
+    ALTER TABLE events ADD COLUMN IF NOT EXISTS acting_staff_id UUID;
+    ALTER TABLE events ADD COLUMN IF NOT EXISTS enrichment JSONB NOT NULL DEFAULT '{}'";
+
+#[derive(Debug)]
+pub enum PostgresStoreError {
+    Database(postgres::Error),
+    Serialization(serde_json::Error)
+}
+
+impl From<postgres::Error> for PostgresStoreError {
+    fn from(error: postgres::Error) -> PostgresStoreError {
+        PostgresStoreError::Database(error)
+    }
+}
+
+impl From<serde_json::Error> for PostgresStoreError {
+    fn from(error: serde_json::Error) -> PostgresStoreError {
+        PostgresStoreError::Serialization(error)
+    }
+}
+
+impl From<PostgresStoreError> for StoreError {
+    fn from(error: PostgresStoreError) -> StoreError {
+        match error {
+            PostgresStoreError::Database(error) => StoreError::Backend(error.to_string()),
+            PostgresStoreError::Serialization(error) => StoreError::Backend(error.to_string())
+        }
+    }
+}
+
+impl From<postgres::Error> for StoreError {
+    fn from(error: postgres::Error) -> StoreError {
+        StoreError::Backend(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(error: serde_json::Error) -> StoreError {
+        StoreError::Backend(error.to_string())
+    }
+}
+
+// Subscribers only live in memory, so they are fed the whole log when they subscribe.
+pub struct PostgresEventStore<T> {
+    client: Mutex<Client>,
+    projections: RwLock<ProjectionRegistry<T>>,
+    upcasters: Upcasters,
+    enrichers: Enrichers<T>,
+    closed: AtomicBool,
+    events: PhantomData<T>
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync> PostgresEventStore<T> {
+    pub async fn connect(url: &str) -> Result<PostgresEventStore<T>, PostgresStoreError> {
+        PostgresEventStore::connect_with_upcasters(url, Upcasters::new()).await
+    }
+
+    // The connection is driven by a task of its own, which ends when the store is dropped.
+    pub async fn connect_with_upcasters(url: &str, upcasters: Upcasters) -> Result<PostgresEventStore<T>, PostgresStoreError> {
+        let (client, connection) = postgres::connect(url, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(error) = connection.await {
+                eprintln!("lost the connection to the event store: {}", error);
+            }
+        });
+        client.batch_execute(SCHEMA).await?;
+        Ok(PostgresEventStore { client: Mutex::new(client), projections: RwLock::new(ProjectionRegistry::new()), upcasters, enrichers: Enrichers::new(), closed: AtomicBool::new(false), events: PhantomData })
+    }
+
+    pub fn with_enrichers(mut self, enrichers: Enrichers<T>) -> PostgresEventStore<T> {
+        self.enrichers = enrichers;
+        self
+    }
+}
+
+#[async_trait]
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> EventStore<T> for PostgresEventStore<T> {
+    async fn append(&self, stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let mut envelopes = envelop(stream_id, events, expected_version, metadata, SystemTime::now(), &self.upcasters, &self.enrichers)?;
+        let mut payloads = Vec::with_capacity(envelopes.len());
+        for envelope in &envelopes {
+            payloads.push(serde_json::to_value(&envelope.payload)?);
+        }
+
+        let mut client = self.client.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoreError::Closed);
+        }
+        let current_version = stream_version(&client, stream_id).await?;
+        if current_version != expected_version {
+            return Err(StoreError::Concurrency(ConcurrencyError { stream_id, expected_version, current_version }));
+        }
+
+        // The database clock is the one read back later, so it wins over the one in envelop.
+        match insert(&mut client, &mut envelopes, &payloads, false).await {
+            Ok(()) => {},
+            Err(ref error) if error.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                let current_version = stream_version(&client, stream_id).await?;
+                return Err(StoreError::Concurrency(ConcurrencyError { stream_id, expected_version, current_version }));
+            },
+            Err(error) => return Err(error.into())
+        }
+
+        self.projections.write().unwrap().notify(&envelopes);
+        Ok(envelopes)
+    }
+
+    async fn purge_stream(&self, stream_id: Uuid, expected_version: usize, tombstone: T, metadata: &Metadata) -> Result<EventEnvelope<T>, StoreError> {
+        let mut envelopes = envelop(stream_id, vec![tombstone], expected_version, metadata, SystemTime::now(), &self.upcasters, &self.enrichers)?;
+        let payload = serde_json::to_value(&envelopes[0].payload)?;
+
+        let mut client = self.client.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(StoreError::Closed);
+        }
+        let current_version = stream_version(&client, stream_id).await?;
+        if current_version != expected_version {
+            return Err(StoreError::Concurrency(ConcurrencyError { stream_id, expected_version, current_version }));
+        }
+
+        insert(&mut client, &mut envelopes, &[payload], true).await?;
+
+        self.projections.write().unwrap().notify(&envelopes);
+        Ok(envelopes.remove(0))
+    }
+
+    async fn read_stream(&self, stream_id: Uuid) -> Result<EventStream<T>, StoreError> {
+        self.read_stream_after(stream_id, 0).await
+    }
+
+    async fn read_stream_after(&self, stream_id: Uuid, version: usize) -> Result<EventStream<T>, StoreError> {
+        let events = read_stream_after(&*self.client.lock().await, &self.upcasters, stream_id, version).await?;
+        let version = events.last().map_or(version, |envelope: &EventEnvelope<T>| envelope.version);
+        Ok(EventStream { version, events })
+    }
+
+    async fn read_all(&self) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        read_all(&*self.client.lock().await, &self.upcasters).await
+    }
+
+    async fn read_all_from(&self, position: usize, max_count: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let client = self.client.lock().await;
+        let query = format!("SELECT {} FROM events WHERE position >= $1 ORDER BY position LIMIT $2", COLUMNS);
+        let mut events = Vec::new();
+        for row in client.query(query.as_str(), &[&(position as i64), &(max_count as i64)]).await? {
+            events.push(self.upcasters.read(envelope(&row))?);
+        }
+        Ok(events)
+    }
+
+    // The table with its indexes and whatever is stored out of line.
+    async fn stored_bytes(&self) -> Result<Option<u64>, StoreError> {
+        let row = self.client.lock().await.query_one("SELECT pg_total_relation_size('events')", &[]).await?;
+        let bytes: i64 = row.get(0);
+        Ok(Some(bytes as u64))
+    }
+
+    // The client stays locked until the subscriber is registered, so no append can slip in
+    // between the replay and the first notification.
+    async fn subscribe(&self, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        let client = self.client.lock().await;
+        let history = read_all::<T>(&client, &self.upcasters).await?;
+        {
+            let mut projection = subscriber.write().unwrap();
+            for envelope in &history {
+                projection.apply_envelope(envelope);
+            }
+        }
+        self.projections.write().unwrap().register(subscriber);
+        Ok(())
+    }
+
+    async fn subscribe_to_stream(&self, stream_id: Uuid, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        let client = self.client.lock().await;
+        let history = read_stream_after::<T>(&client, &self.upcasters, stream_id, 0).await?;
+        {
+            let mut projection = subscriber.write().unwrap();
+            for envelope in &history {
+                projection.apply_envelope(envelope);
+            }
+        }
+        self.projections.write().unwrap().register(Arc::new(RwLock::new(StreamOnly { stream_id, subscriber })));
+        Ok(())
+    }
+
+    // Every append commits before it returns; the connection itself is closed when the store is
+    // dropped.
+    async fn close(&self) -> Result<(), StoreError> {
+        let _client = self.client.lock().await;
+        self.closed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+async fn read_all<T: DeserializeOwned>(client: &Client, upcasters: &Upcasters) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+    let query = format!("SELECT {} FROM events ORDER BY position", COLUMNS);
+    let mut events = Vec::new();
+    for row in client.query(query.as_str(), &[]).await? {
+        events.push(upcasters.read(envelope(&row))?);
+    }
+    Ok(events)
+}
+
+async fn read_stream_after<T: DeserializeOwned>(client: &Client, upcasters: &Upcasters, stream_id: Uuid, version: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+    let query = format!("SELECT {} FROM events WHERE stream_id = $1 AND version > $2 ORDER BY version", COLUMNS);
+    let mut events = Vec::new();
+    for row in client.query(query.as_str(), &[&stream_id, &(version as i32)]).await? {
+        events.push(upcasters.read(envelope(&row))?);
+    }
+    Ok(events)
+}
+
+fn envelope(row: &Row) -> EventEnvelope<Value> {
+    let version: i32 = row.get(2);
+    let schema_version: i32 = row.get(4);
+    let Json(enrichment): Json<Enrichment> = row.get(9);
+    let position: i64 = row.get(11);
+    EventEnvelope {
+        event_id: row.get(0),
+        stream_id: row.get(1),
+        version: version as usize,
+        position: position as usize,
+        event_type: row.get(3),
+        schema_version: schema_version as u32,
+        timestamp: row.get(5),
+        correlation_id: row.get(6),
+        causation_id: row.get(7),
+        acting_user: row.get(8),
+        acting_staff_id: row.get(12),
+        enrichment,
+        payload: row.get(10)
+    }
+}
+
+async fn stream_version(client: &Client, stream_id: Uuid) -> Result<usize, postgres::Error> {
+    let row = client.query_one("SELECT COALESCE(MAX(version), 0) FROM events WHERE stream_id = $1", &[&stream_id]).await?;
+    let version: i32 = row.get(0);
+    Ok(version as usize)
+}
+
+// Fills in the positions the database gave the events and when it recorded them. With replace
+// the rest of the stream is deleted in the same transaction.
+async fn insert<T>(client: &mut Client, envelopes: &mut [EventEnvelope<T>], payloads: &[Value], replace: bool) -> Result<(), postgres::Error> {
+    let transaction = client.transaction().await?;
+    if replace {
+        transaction.execute("DELETE FROM events WHERE stream_id = $1", &[&envelopes[0].stream_id]).await?;
+    }
+    for (envelope, payload) in envelopes.iter_mut().zip(payloads) {
+        let version = envelope.version as i32;
+        let schema_version = envelope.schema_version as i32;
+        let row = transaction.query_one(
+            "INSERT INTO events (event_id, stream_id, version, event_type, schema_version, payload, correlation_id, causation_id, acting_user, acting_staff_id, enrichment)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING position, recorded_at",
+            &[&envelope.event_id, &envelope.stream_id, &version, &envelope.event_type, &schema_version, payload, &envelope.correlation_id, &envelope.causation_id, &envelope.acting_user, &envelope.acting_staff_id, &Json(&envelope.enrichment)]
+        ).await?;
+        let position: i64 = row.get(0);
+        envelope.position = position as usize;
+        envelope.timestamp = row.get(1);
+    }
+    transaction.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Needs a scratch database, e.g.
+    // CAFE_TEST_DATABASE_URL=postgres://localhost/cafe_test cargo test --features postgres -- --ignored
+    async fn store() -> PostgresEventStore<Value> {
+        let url = env::var("CAFE_TEST_DATABASE_URL").expect("CAFE_TEST_DATABASE_URL is not set");
+        PostgresEventStore::connect(&url).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn appends_and_reads_streams() {
+        let store = store().await;
+        let stream_id = Uuid::new_v4();
+        let events = vec![json!({ "type": "tab_opened" }), json!({ "type": "drinks_ordered" })];
+        let recorded = store.append(stream_id, events.clone(), 0, &Metadata::new()).await.unwrap();
+        let stream = store.read_stream(stream_id).await.unwrap();
+        assert_eq!(stream.version, 2);
+        assert_eq!(stream.events, recorded);
+        assert_eq!(store.read_stream_after(stream_id, 1).await.unwrap(), EventStream { version: 2, events: recorded[1..].to_vec() });
+        assert_eq!(stream.events.into_iter().map(|envelope| envelope.payload).collect::<Vec<_>>(), events);
+        assert!(store.stored_bytes().await.unwrap() > Some(0));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn rejects_appends_on_stale_version() {
+        let store = store().await;
+        let stream_id = Uuid::new_v4();
+        let metadata = Metadata::new();
+        store.append(stream_id, vec![json!({ "type": "tab_opened" })], 0, &metadata).await.unwrap();
+        match store.append(stream_id, vec![json!({ "type": "tab_opened" })], 0, &metadata).await {
+            Err(StoreError::Concurrency(error)) => assert_eq!(error, ConcurrencyError { stream_id, expected_version: 0, current_version: 1 }),
+            other => panic!("expected a concurrency error, got {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn purged_stream_keeps_only_the_tombstone() {
+        let store = store().await;
+        let stream_id = Uuid::new_v4();
+        let metadata = Metadata::new();
+        store.append(stream_id, vec![json!({ "type": "tab_opened" }), json!({ "type": "tab_closed" })], 0, &metadata).await.unwrap();
+        let tombstone = store.purge_stream(stream_id, 2, json!({ "type": "tab_purged" }), &metadata).await.unwrap();
+        assert_eq!(store.read_stream(stream_id).await.unwrap(), EventStream { version: 3, events: vec![tombstone] });
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use uuid::Uuid;
+
+use super::StoreError;
+
+// Aggregate state as of a stream version, so loading only has to replay what came after. The
+// schema version is the aggregate's when the snapshot was taken, see
+// Aggregate::snapshot_schema_version. Handled are the causation ids of the latest commands up to
+// the snapshot, oldest first, each with the stream version its events were appended after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot<S> {
+    pub version: usize,
+    pub schema_version: u32,
+    pub state: S,
+    pub handled: Vec<(Uuid, usize)>
+}
+
+pub trait SnapshotStore<S>: Send + Sync {
+    fn load(&self, stream_id: Uuid) -> Result<Option<Snapshot<S>>, StoreError>;
+
+    // Only the latest snapshot of a stream is kept.
+    fn save(&self, stream_id: Uuid, snapshot: Snapshot<S>) -> Result<(), StoreError>;
+}
+
+pub struct InMemorySnapshotStore<S> {
+    snapshots: RwLock<HashMap<Uuid, Snapshot<S>>>
+}
+
+impl<S> InMemorySnapshotStore<S> {
+    pub fn new() -> InMemorySnapshotStore<S> {
+        InMemorySnapshotStore { snapshots: RwLock::new(HashMap::new()) }
+    }
+}
+
+impl<S> Default for InMemorySnapshotStore<S> {
+    fn default() -> InMemorySnapshotStore<S> {
+        InMemorySnapshotStore::new()
+    }
+}
+
+impl<S: Clone + Send + Sync> SnapshotStore<S> for InMemorySnapshotStore<S> {
+    fn load(&self, stream_id: Uuid) -> Result<Option<Snapshot<S>>, StoreError> {
+        Ok(self.snapshots.read().unwrap().get(&stream_id).cloned())
+    }
+
+    fn save(&self, stream_id: Uuid, snapshot: Snapshot<S>) -> Result<(), StoreError> {
+        let mut snapshots = self.snapshots.write().unwrap();
+        // Two handlers can race to save; the older snapshot must not win. One of another schema
+        // is replaced whatever its version.
+        if snapshots.get(&stream_id).is_none_or(|current| current.version < snapshot.version || current.schema_version != snapshot.schema_version) {
+            snapshots.insert(stream_id, snapshot);
+        }
+        Ok(())
+    }
+}
+
+// How often loading started from a snapshot, and how often it found one of another schema
+// version and replayed the stream in full instead. Counted since the server started.
+#[derive(Debug, Default)]
+pub struct SnapshotLoads {
+    loads: AtomicUsize,
+    fallbacks: AtomicUsize
+}
+
+impl SnapshotLoads {
+    pub fn new() -> SnapshotLoads {
+        SnapshotLoads::default()
+    }
+
+    pub fn record(&self, fell_back: bool) {
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        if fell_back {
+            self.fallbacks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn loads(&self) -> usize {
+        self.loads.load(Ordering::Relaxed)
+    }
+
+    pub fn fallbacks(&self) -> usize {
+        self.fallbacks.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,143 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use crate::cqrs::EventEnvelope;
+
+// Migrates stored payloads of one event type from one schema version to the next, so old
+// history can be read into the current shape of the event without being rewritten.
+pub trait Upcaster: Send + Sync {
+    fn event_type(&self) -> &str;
+
+    // The version this upcaster reads; it produces the one after.
+    fn version(&self) -> u32;
+
+    fn upcast(&self, payload: Value) -> Value;
+}
+
+// The upcasters a store runs on read. An event type is at version 1 until it gets its first
+// upcaster, and each upcaster moves it up by one.
+#[derive(Default)]
+pub struct Upcasters {
+    upcasters: Vec<Box<dyn Upcaster>>
+}
+
+impl Upcasters {
+    pub fn new() -> Upcasters {
+        Upcasters::default()
+    }
+
+    pub fn register(&mut self, upcaster: Box<dyn Upcaster>) {
+        self.upcasters.push(upcaster);
+    }
+
+    pub fn current_version(&self, event_type: &str) -> u32 {
+        self.upcasters.iter()
+            .filter(|upcaster| upcaster.event_type() == event_type)
+            .map(|upcaster| upcaster.version() + 1)
+            .max()
+            .unwrap_or(1)
+    }
+
+    pub fn upcast(&self, event_type: &str, mut version: u32, mut payload: Value) -> Value {
+        while let Some(upcaster) = self.upcasters.iter().find(|upcaster| upcaster.event_type() == event_type && upcaster.version() == version) {
+            payload = upcaster.upcast(payload);
+            version += 1;
+        }
+        payload
+    }
+
+    // Reads a stored envelope into the current shape of its event. The envelope keeps the
+    // schema version it was stored with.
+    pub fn read<T: DeserializeOwned>(&self, envelope: EventEnvelope<Value>) -> Result<EventEnvelope<T>, serde_json::Error> {
+        let payload = serde_json::from_value(self.upcast(&envelope.event_type, envelope.schema_version, envelope.payload))?;
+        Ok(EventEnvelope {
+            event_id: envelope.event_id,
+            stream_id: envelope.stream_id,
+            version: envelope.version,
+            position: envelope.position,
+            event_type: envelope.event_type,
+            schema_version: envelope.schema_version,
+            timestamp: envelope.timestamp,
+            correlation_id: envelope.correlation_id,
+            causation_id: envelope.causation_id,
+            acting_user: envelope.acting_user,
+            acting_staff_id: envelope.acting_staff_id,
+            enrichment: envelope.enrichment,
+            payload
+        })
+    }
+}
+
+// Events are tagged enums, so the type is the tag. Payloads without one share the empty type.
+pub fn event_type<T: Serialize>(event: &T) -> Result<String, serde_json::Error> {
+    let payload = serde_json::to_value(event)?;
+    Ok(payload.get("type").and_then(Value::as_str).unwrap_or("").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TabOpened v1 had the table number as a string.
+    struct TableNumberAsInteger;
+
+    impl Upcaster for TableNumberAsInteger {
+        fn event_type(&self) -> &str {
+            "tab_opened"
+        }
+
+        fn version(&self) -> u32 {
+            1
+        }
+
+        fn upcast(&self, mut payload: Value) -> Value {
+            let table_number = payload["table_number"].as_str().and_then(|number| number.parse::<u8>().ok()).unwrap_or(0);
+            payload["table_number"] = json!(table_number);
+            payload
+        }
+    }
+
+    // TabOpened v2 named the waiter "server".
+    struct ServerIsWaiter;
+
+    impl Upcaster for ServerIsWaiter {
+        fn event_type(&self) -> &str {
+            "tab_opened"
+        }
+
+        fn version(&self) -> u32 {
+            2
+        }
+
+        fn upcast(&self, mut payload: Value) -> Value {
+            if let Some(server) = payload.as_object_mut().and_then(|fields| fields.remove("server")) {
+                payload["waiter"] = server;
+            }
+            payload
+        }
+    }
+
+    fn upcasters() -> Upcasters {
+        let mut upcasters = Upcasters::new();
+        upcasters.register(Box::new(ServerIsWaiter));
+        upcasters.register(Box::new(TableNumberAsInteger));
+        upcasters
+    }
+
+    #[test]
+    fn event_types_without_upcasters_are_at_version_1() {
+        assert_eq!(upcasters().current_version("tab_opened"), 3);
+        assert_eq!(upcasters().current_version("tab_closed"), 1);
+    }
+
+    #[test]
+    fn old_payloads_are_upcast_through_every_later_version() {
+        let v1 = json!({ "type": "tab_opened", "table_number": "42", "server": "Derek" });
+        let v2 = json!({ "type": "tab_opened", "table_number": 42, "server": "Derek" });
+        let v3 = json!({ "type": "tab_opened", "table_number": 42, "waiter": "Derek" });
+        assert_eq!(upcasters().upcast("tab_opened", 1, v1), v3);
+        assert_eq!(upcasters().upcast("tab_opened", 2, v2), v3);
+        assert_eq!(upcasters().upcast("tab_opened", 3, v3.clone()), v3);
+    }
+}
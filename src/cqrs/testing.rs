@@ -0,0 +1,50 @@
+use std::fmt::Debug;
+
+use crate::cqrs::Aggregate;
+
+// Given-when-then specs for aggregates, as in the Edument tutorial:
+//
+//     Scenario::<Tab>::new()
+//         .given(vec![Event::TabOpened { table_number: 42, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() }])
+//         .when(Command::CloseTab(tab_id, eur(0), None))
+//         .then(vec![Event::TabClosed { amount_paid: eur(0), order_value: eur(0), tip_value: eur(0), receipt_number: None }]);
+pub struct Scenario<A: Aggregate> {
+    state: A::State
+}
+
+pub struct Outcome<A: Aggregate> {
+    result: Result<Vec<A::Event>, A::CommandError>
+}
+
+impl<A: Aggregate> Scenario<A> {
+    pub fn new() -> Scenario<A> {
+        Scenario { state: A::initial_state() }
+    }
+
+    pub fn given(mut self, events: Vec<A::Event>) -> Scenario<A> {
+        for event in events {
+            A::evolve(&mut self.state, event);
+        }
+        self
+    }
+
+    pub fn when(self, command: A::Command) -> Outcome<A> {
+        Outcome { result: A::decide(&self.state, command) }
+    }
+}
+
+impl<A: Aggregate> Default for Scenario<A> {
+    fn default() -> Scenario<A> {
+        Scenario::new()
+    }
+}
+
+impl<A: Aggregate> Outcome<A> where A::Event: Debug + PartialEq, A::CommandError: Debug + PartialEq {
+    pub fn then(self, expected: Vec<A::Event>) {
+        assert_eq!(self.result, Ok(expected));
+    }
+
+    pub fn then_err(self, expected: A::CommandError) {
+        assert_eq!(self.result, Err(expected));
+    }
+}
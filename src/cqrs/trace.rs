@@ -0,0 +1,231 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use super::{EventEnvelope, Metadata, Projection};
+use super::store::{EventStore, EventStream, StoreError, Subscriber};
+
+// Where on the write path a span was recorded, in the order a command goes through them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Command,
+    Append,
+    Projection,
+    Notification
+}
+
+// One step taken because of a command. The cause is what led to it, as in the envelope: a
+// command and its append name the command, the read models and displays updated afterwards
+// name the event. The effects are what came of it, e.g. the events appended or the displays
+// pushed to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Span {
+    pub stage: Stage,
+    pub name: String,
+    pub cause_id: Uuid,
+    pub started_at: SystemTime,
+    pub elapsed_micros: u64,
+    pub effects: Vec<String>,
+    pub error: Option<String>
+}
+
+impl Span {
+    pub fn new(stage: Stage, name: &str, cause_id: Uuid, started_at: SystemTime, elapsed: Duration) -> Span {
+        let elapsed_micros = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        Span { stage, name: name.to_string(), cause_id, started_at, elapsed_micros, effects: Vec::new(), error: None }
+    }
+
+    pub fn with_effects(mut self, effects: Vec<String>) -> Span {
+        self.effects = effects;
+        self
+    }
+
+    pub fn with_error(mut self, error: String) -> Span {
+        self.error = Some(error);
+        self
+    }
+}
+
+// The spans of the latest workflows, by correlation id, so a ticket the kitchen never got can
+// be followed from the command to the display. Kept in memory only; the oldest workflow is
+// forgotten once there are more than capacity of them. Events from before the traces were
+// started, i.e. the history replayed on startup, are not traced.
+pub struct Traces {
+    capacity: usize,
+    started_at: SystemTime,
+    traces: Mutex<(VecDeque<Uuid>, HashMap<Uuid, Vec<Span>>)>
+}
+
+impl Traces {
+    pub fn new(capacity: usize) -> Traces {
+        Traces { capacity, started_at: SystemTime::now(), traces: Mutex::new((VecDeque::new(), HashMap::new())) }
+    }
+
+    pub fn record(&self, correlation_id: Uuid, span: Span) {
+        let mut traces = self.traces.lock().unwrap();
+        let (ref mut order, ref mut spans) = *traces;
+        if !spans.contains_key(&correlation_id) {
+            order.push_back(correlation_id);
+            if order.len() > self.capacity {
+                if let Some(forgotten) = order.pop_front() {
+                    spans.remove(&forgotten);
+                }
+            }
+        }
+        spans.entry(correlation_id).or_default().push(span);
+    }
+
+    // In the order the spans started, stage breaking ties so a command comes before its append.
+    pub fn for_correlation(&self, correlation_id: Uuid) -> Vec<Span> {
+        let mut spans = self.traces.lock().unwrap().1.get(&correlation_id).cloned().unwrap_or_default();
+        spans.sort_by_key(|span| (span.started_at, span.stage));
+        spans
+    }
+
+    pub fn is_live<E>(&self, envelope: &EventEnvelope<E>) -> bool {
+        envelope.timestamp >= self.started_at
+    }
+}
+
+// Records every append and purge as a span. Stores notify their subscribers while appending, so
+// the read models' spans fall within it.
+pub struct TracedStore<T: Send + 'static> {
+    store: Box<dyn EventStore<T>>,
+    traces: Arc<Traces>
+}
+
+impl<T: Send + 'static> TracedStore<T> {
+    pub fn new(store: Box<dyn EventStore<T>>, traces: Arc<Traces>) -> TracedStore<T> {
+        TracedStore { store, traces }
+    }
+
+    fn record(&self, stream_id: Uuid, metadata: &Metadata, started_at: SystemTime, started: Instant, result: &Result<Vec<EventEnvelope<T>>, StoreError>) {
+        let span = Span::new(Stage::Append, &stream_id.to_string(), metadata.causation_id, started_at, started.elapsed());
+        let span = match *result {
+            Ok(ref envelopes) => span.with_effects(envelopes.iter().map(|envelope| format!("{} {}", envelope.event_type, envelope.event_id)).collect()),
+            Err(ref error) => span.with_error(error.to_string())
+        };
+        self.traces.record(metadata.correlation_id, span);
+    }
+}
+
+#[async_trait]
+impl<T: Send + 'static> EventStore<T> for TracedStore<T> {
+    async fn append(&self, stream_id: Uuid, events: Vec<T>, expected_version: usize, metadata: &Metadata) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        let (started_at, started) = (SystemTime::now(), Instant::now());
+        let appended = self.store.append(stream_id, events, expected_version, metadata).await;
+        self.record(stream_id, metadata, started_at, started, &appended);
+        appended
+    }
+
+    async fn purge_stream(&self, stream_id: Uuid, expected_version: usize, tombstone: T, metadata: &Metadata) -> Result<EventEnvelope<T>, StoreError> {
+        let (started_at, started) = (SystemTime::now(), Instant::now());
+        let purged = self.store.purge_stream(stream_id, expected_version, tombstone, metadata).await.map(|envelope| vec![envelope]);
+        self.record(stream_id, metadata, started_at, started, &purged);
+        purged.map(|mut envelopes| envelopes.remove(0))
+    }
+
+    async fn read_stream(&self, stream_id: Uuid) -> Result<EventStream<T>, StoreError> {
+        self.store.read_stream(stream_id).await
+    }
+
+    async fn read_stream_after(&self, stream_id: Uuid, version: usize) -> Result<EventStream<T>, StoreError> {
+        self.store.read_stream_after(stream_id, version).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        self.store.read_all().await
+    }
+
+    async fn read_all_from(&self, position: usize, max_count: usize) -> Result<Vec<EventEnvelope<T>>, StoreError> {
+        self.store.read_all_from(position, max_count).await
+    }
+
+    async fn subscribe(&self, subscriber: Subscriber<T>) -> Result<(), StoreError> {
+        self.store.subscribe(subscriber).await
+    }
+
+    async fn subscribe_to_stream(&self, stream_id: Uuid, subscriber: Subscriber<T>) -> Result<(), StoreError> where T: Sync {
+        self.store.subscribe_to_stream(stream_id, subscriber).await
+    }
+
+    async fn stored_bytes(&self) -> Result<Option<u64>, StoreError> {
+        self.store.stored_bytes().await
+    }
+
+    async fn close(&self) -> Result<(), StoreError> {
+        self.store.close().await
+    }
+}
+
+// Subscribed in place of a read model, it passes events on and records a span for each.
+pub struct TracedProjection<E> {
+    name: &'static str,
+    projection: Subscriber<E>,
+    traces: Arc<Traces>
+}
+
+impl<E> TracedProjection<E> {
+    pub fn new(name: &'static str, projection: Subscriber<E>, traces: Arc<Traces>) -> TracedProjection<E> {
+        TracedProjection { name, projection, traces }
+    }
+}
+
+impl<E> Projection<E> for TracedProjection<E> {
+    fn apply(&mut self, stream_id: Uuid, event: &E) {
+        self.projection.write().unwrap().apply(stream_id, event);
+    }
+
+    fn apply_envelope(&mut self, envelope: &EventEnvelope<E>) {
+        let (started_at, started) = (SystemTime::now(), Instant::now());
+        self.projection.write().unwrap().apply_envelope(envelope);
+        if self.traces.is_live(envelope) {
+            self.traces.record(envelope.correlation_id, Span::new(Stage::Projection, self.name, envelope.event_id, started_at, started.elapsed()));
+        }
+    }
+}
+
+// Wraps a read model for subscribing with its updates traced.
+pub fn traced<E: 'static, P>(name: &'static str, projection: Arc<RwLock<P>>, traces: &Arc<Traces>) -> Subscriber<E> where P: Projection<E> + Send + Sync + 'static {
+    Arc::new(RwLock::new(TracedProjection::new(name, projection, traces.clone())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Checkpoint;
+    use crate::cqrs::store::InMemoryEventStore;
+
+    #[tokio::test]
+    async fn a_command_is_followed_to_the_read_models() {
+        let traces = Arc::new(Traces::new(2));
+        let store = TracedStore::new(Box::new(InMemoryEventStore::new()), traces.clone());
+        let checkpoint = Arc::new(RwLock::new(Checkpoint::new()));
+        store.subscribe(traced("checkpoint", checkpoint.clone(), &traces)).await.unwrap();
+
+        let metadata = Metadata::new();
+        let stream_id = Uuid::new_v4();
+        let appended = store.append(stream_id, vec![1, 2], 0, &metadata).await.unwrap();
+        assert_eq!(checkpoint.read().unwrap().position, 2);
+
+        let spans = traces.for_correlation(metadata.correlation_id);
+        assert_eq!(spans.iter().map(|span| span.stage).collect::<Vec<_>>(), vec![Stage::Append, Stage::Projection, Stage::Projection]);
+        assert_eq!(spans[0].cause_id, metadata.causation_id);
+        assert_eq!(spans[0].effects.len(), 2);
+        assert_eq!(spans[1].cause_id, appended[0].event_id);
+        assert_eq!(spans[2].cause_id, appended[1].event_id);
+
+        let failed = store.append(stream_id, vec![3], 0, &metadata).await;
+        assert!(failed.is_err());
+        assert!(traces.for_correlation(metadata.correlation_id).last().unwrap().error.is_some());
+
+        // Only the latest two workflows are kept.
+        store.append(Uuid::new_v4(), vec![4], 0, &Metadata::new()).await.unwrap();
+        store.append(Uuid::new_v4(), vec![5], 0, &Metadata::new()).await.unwrap();
+        assert_eq!(traces.for_correlation(metadata.correlation_id), vec![]);
+    }
+}
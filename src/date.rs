@@ -0,0 +1,144 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Serializer};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// A calendar day in UTC, written as YYYY-MM-DD. Enough for reports and searches by date
+// without pulling in a date library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    days_since_epoch: i64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidDate(pub String);
+
+impl Date {
+    pub fn from_ymd(year: i64, month: u32, day: u32) -> Option<Date> {
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        // Days from civil, counting years from March so the leap day comes last.
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = if year >= 0 { year } else { year - 399 } / 400;
+        let year_of_era = year - era * 400;
+        let month_from_march = (i64::from(month) + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        Some(Date { days_since_epoch: era * 146_097 + day_of_era - 719_468 })
+    }
+
+    // Times before 1970 are not expected and map to the epoch.
+    pub fn of(time: SystemTime) -> Date {
+        let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        Date { days_since_epoch: (seconds / SECONDS_PER_DAY) as i64 }
+    }
+
+    pub fn start(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.days_since_epoch.max(0) as u64 * SECONDS_PER_DAY)
+    }
+
+    pub fn next(&self) -> Date {
+        Date { days_since_epoch: self.days_since_epoch + 1 }
+    }
+
+    pub fn previous(&self) -> Date {
+        Date { days_since_epoch: self.days_since_epoch - 1 }
+    }
+
+    pub fn ymd(&self) -> (i64, u32, u32) {
+        let days = self.days_since_epoch + 719_468;
+        let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+        let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year, month, day)
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// The date format of HTTP headers, e.g. Sun, 06 Nov 1994 08:49:37 GMT.
+pub fn http_date(time: SystemTime) -> String {
+    let date = Date::of(time);
+    let (year, month, day) = date.ymd();
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) % SECONDS_PER_DAY;
+    let weekday = WEEKDAYS[date.days_since_epoch.rem_euclid(7) as usize];
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT", weekday, day, MONTHS[month as usize - 1], year, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+impl FromStr for Date {
+    type Err = InvalidDate;
+
+    fn from_str(source: &str) -> Result<Date, InvalidDate> {
+        let parts: Vec<&str> = source.split('-').collect();
+        let date = match parts.as_slice() {
+            [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => {
+                match (year.parse(), month.parse(), day.parse()) {
+                    (Ok(year), Ok(month), Ok(day)) => Date::from_ymd(year, month, day),
+                    _ => None
+                }
+            },
+            _ => None
+        };
+        date.ok_or_else(|| InvalidDate(source.to_string()))
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_iso_dates() {
+        for source in &["1970-01-01", "2000-02-29", "2017-12-31", "2024-03-01"] {
+            assert_eq!(source.parse::<Date>().unwrap().to_string(), *source);
+        }
+        assert!("2017-02-29".parse::<Date>().is_err());
+        assert!("2017-13-01".parse::<Date>().is_err());
+        assert!("17-1-1".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn converts_to_and_from_time() {
+        let date: Date = "2017-06-15".parse().unwrap();
+        assert_eq!(date.start(), UNIX_EPOCH + Duration::from_secs(1_497_484_800));
+        assert_eq!(Date::of(date.start() + Duration::from_secs(SECONDS_PER_DAY - 1)), date);
+        assert_eq!(Date::of(date.next().start()), "2017-06-16".parse().unwrap());
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(784_111_777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time;
+use uuid::Uuid;
+
+use crate::cqrs::Metadata;
+use crate::cqrs::store::EventStore;
+use crate::policy::PolicyError;
+
+// How often the watcher looks for silent kitchen displays.
+const WATCH_INTERVAL_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceKind {
+    KitchenDisplay,
+    Pos,
+    Printer
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+struct Device {
+    id: Uuid,
+    name: String,
+    kind: DeviceKind
+}
+
+// The devices expected to send heartbeats, read from TOML. Service hours are UTC hours, from
+// opening up to but not including closing; without them kitchen displays are never alerted on.
+//
+//     stale_after_seconds = 90
+//     service_hours = [8, 22]
+//
+//     [[devices]]
+//     id = "6a0b7c55-2d6e-4f0e-9a57-2f2f5c3e8a10"
+//     name = "Kitchen pass"
+//     kind = "kitchen_display"
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DeviceRegistry {
+    devices: Vec<Device>,
+    stale_after_seconds: u64,
+    service_hours: Option<(u8, u8)>
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> DeviceRegistry {
+        DeviceRegistry { devices: Vec::new(), stale_after_seconds: 90, service_hours: None }
+    }
+}
+
+impl DeviceRegistry {
+    pub fn from_toml(source: &str) -> Result<DeviceRegistry, PolicyError> {
+        toml::from_str(source).map_err(PolicyError::Parse)
+    }
+
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<DeviceRegistry, PolicyError> {
+        let mut source = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => DeviceRegistry::from_toml(&source),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(DeviceRegistry::default()),
+            Err(error) => Err(PolicyError::Io(error))
+        }
+    }
+
+    fn in_service(&self, time: SystemTime) -> bool {
+        let hour = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) % (24 * 60 * 60) / (60 * 60);
+        match self.service_hours {
+            Some((opens, closes)) => u64::from(opens) <= hour && hour < u64::from(closes),
+            None => false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    Stale
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeviceStatus {
+    pub id: Uuid,
+    pub name: String,
+    pub kind: DeviceKind,
+    pub last_seen: Option<SystemTime>,
+    pub presence: Presence
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    KitchenDisplaySilent { name: String, last_seen: Option<SystemTime> }
+}
+
+// When each registered device was last heard from. Kept in memory only: after a restart every
+// device is stale until its next heartbeat.
+pub struct Heartbeats {
+    registry: DeviceRegistry,
+    last_seen: Mutex<HashMap<Uuid, SystemTime>>,
+    alerted: Mutex<HashSet<Uuid>>
+}
+
+impl Heartbeats {
+    pub fn new(registry: DeviceRegistry) -> Heartbeats {
+        Heartbeats { registry, last_seen: Mutex::new(HashMap::new()), alerted: Mutex::new(HashSet::new()) }
+    }
+
+    // False for devices that are not registered.
+    pub fn beat(&self, device_id: Uuid, now: SystemTime) -> bool {
+        if !self.registry.devices.iter().any(|device| device.id == device_id) {
+            return false;
+        }
+        self.last_seen.lock().unwrap().insert(device_id, now);
+        self.alerted.lock().unwrap().remove(&device_id);
+        true
+    }
+
+    pub fn statuses(&self, now: SystemTime) -> Vec<DeviceStatus> {
+        let last_seen = self.last_seen.lock().unwrap();
+        self.registry.devices.iter().map(|device| {
+            let seen = last_seen.get(&device.id).cloned();
+            let presence = if self.is_stale(seen, now) { Presence::Stale } else { Presence::Online };
+            DeviceStatus { id: device.id, name: device.name.clone(), kind: device.kind, last_seen: seen, presence }
+        }).collect()
+    }
+
+    // Kitchen displays gone stale during service hours, each reported once until it is heard
+    // from again.
+    pub fn silent_kitchen_displays(&self, now: SystemTime) -> Vec<(Uuid, Event)> {
+        if !self.registry.in_service(now) {
+            return Vec::new();
+        }
+        let last_seen = self.last_seen.lock().unwrap();
+        let mut alerted = self.alerted.lock().unwrap();
+        self.registry.devices.iter()
+            .filter(|device| device.kind == DeviceKind::KitchenDisplay)
+            .filter(|device| self.is_stale(last_seen.get(&device.id).cloned(), now))
+            .filter(|device| alerted.insert(device.id))
+            .map(|device| (device.id, Event::KitchenDisplaySilent { name: device.name.clone(), last_seen: last_seen.get(&device.id).cloned() }))
+            .collect()
+    }
+
+    fn is_stale(&self, last_seen: Option<SystemTime>, now: SystemTime) -> bool {
+        match last_seen.map(|seen| now.duration_since(seen)) {
+            Some(Ok(silence)) => silence > Duration::from_secs(self.registry.stale_after_seconds),
+            Some(Err(_)) => false,
+            None => true
+        }
+    }
+}
+
+// Records an alert in the device's stream whenever a kitchen display goes silent.
+pub fn watch(heartbeats: Arc<Heartbeats>, alerts: Box<dyn EventStore<Event>>) {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(Duration::from_secs(WATCH_INTERVAL_SECONDS)).await;
+            for (device_id, alert) in heartbeats.silent_kitchen_displays(SystemTime::now()) {
+                let recorded = match alerts.read_stream(device_id).await {
+                    Ok(stream) => alerts.append(device_id, vec![alert], stream.version, &Metadata::new()).await,
+                    Err(error) => Err(error)
+                };
+                if let Err(error) = recorded {
+                    eprintln!("failed to record device alert: {}", error);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISPLAY: &str = "6a0b7c55-2d6e-4f0e-9a57-2f2f5c3e8a10";
+    const TILL: &str = "0d8f3d1e-61c5-4a57-8f0c-3b3f4e0f5a21";
+
+    fn registry() -> DeviceRegistry {
+        let source = format!("service_hours = [8, 22]\n\n[[devices]]\nid = \"{}\"\nname = \"Kitchen pass\"\nkind = \"kitchen_display\"\n\n[[devices]]\nid = \"{}\"\nname = \"Till\"\nkind = \"pos\"\n", DISPLAY, TILL);
+        DeviceRegistry::from_toml(&source).unwrap()
+    }
+
+    fn at(hour: u64, seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(hour * 60 * 60 + seconds)
+    }
+
+    #[test]
+    fn devices_go_stale_without_heartbeats() {
+        let heartbeats = Heartbeats::new(registry());
+        let display = Uuid::parse_str(DISPLAY).unwrap();
+        assert!(heartbeats.beat(display, at(9, 0)));
+        assert!(!heartbeats.beat(Uuid::new_v4(), at(9, 0)));
+
+        let presence = |now| heartbeats.statuses(now).iter().map(|status| status.presence).collect::<Vec<_>>();
+        assert_eq!(presence(at(9, 60)), vec![Presence::Online, Presence::Stale]);
+        assert_eq!(presence(at(9, 91)), vec![Presence::Stale, Presence::Stale]);
+    }
+
+    #[test]
+    fn silent_kitchen_displays_are_alerted_once_during_service() {
+        let heartbeats = Heartbeats::new(registry());
+        let display = Uuid::parse_str(DISPLAY).unwrap();
+        heartbeats.beat(display, at(7, 0));
+        assert_eq!(heartbeats.silent_kitchen_displays(at(7, 600)), vec![]);
+
+        let alert = Event::KitchenDisplaySilent { name: "Kitchen pass".to_string(), last_seen: Some(at(7, 0)) };
+        assert_eq!(heartbeats.silent_kitchen_displays(at(9, 0)), vec![(display, alert)]);
+        assert_eq!(heartbeats.silent_kitchen_displays(at(9, 10)), vec![]);
+
+        heartbeats.beat(display, at(9, 20));
+        assert_eq!(heartbeats.silent_kitchen_displays(at(9, 60)), vec![]);
+        assert_eq!(heartbeats.silent_kitchen_displays(at(9, 200)).len(), 1);
+    }
+}
@@ -0,0 +1,471 @@
+use std::fmt::{Debug, Write};
+
+use serde::Serialize;
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+use crate::api::{error_code, menu_error_code, shift_error_code, staff_error_code, table_error_code};
+use crate::auth::Role;
+use crate::domain::{self, Course, LineRef, OrderedItem};
+use crate::locale::{self, Language};
+use crate::menu::{self, MenuItem, Nutrition};
+use crate::money::{Currency, Money};
+use crate::receipts;
+use crate::shift;
+use crate::staff;
+use crate::table;
+
+// What one aggregate takes and records. Events are shown as an example of the JSON the logs and
+// the API carry them in, so the shapes come from the serde model itself.
+struct Section {
+    name: &'static str,
+    commands: Vec<(String, &'static str)>,
+    events: Vec<(Value, &'static str)>,
+    errors: Vec<(&'static str, &'static str)>
+}
+
+// Catalog of the commands, events and errors of every aggregate, as Markdown for integrators.
+// The descriptions are matched over each enum without a catch-all, so a new variant does not
+// build until it is documented here too.
+pub fn domain_catalog() -> String {
+    let sections = vec![tab_section(), menu_section(), table_section(), shift_section(), staff_section(), receipt_section()];
+    let mut markdown = String::from("# Domain catalog\n");
+    for section in sections {
+        write!(markdown, "\n## {}\n\n### Commands\n\n", section.name).unwrap();
+        for (name, description) in section.commands {
+            writeln!(markdown, "- `{}`: {}", name, description).unwrap();
+        }
+        markdown.push_str("\n### Events\n");
+        for (example, description) in section.events {
+            let name = example["type"].as_str().unwrap_or("").to_string();
+            let json = serde_json::to_string_pretty(&example).unwrap();
+            write!(markdown, "\n#### `{}`\n\n{}\n\n```json\n{}\n```\n", name, description, json).unwrap();
+        }
+        markdown.push_str("\n### Errors\n\n| Code | Message |\n| --- | --- |\n");
+        for (code, message) in section.errors {
+            writeln!(markdown, "| `{}` | {} |", code, message).unwrap();
+        }
+    }
+    markdown
+}
+
+fn variant_name<T: Debug>(value: &T) -> String {
+    let debug = format!("{:?}", value);
+    debug.split(|c: char| !c.is_alphanumeric()).next().unwrap_or("").to_string()
+}
+
+fn commands<T: Debug>(examples: Vec<T>, describe: fn(&T) -> &'static str) -> Vec<(String, &'static str)> {
+    examples.iter().map(|command| (variant_name(command), describe(command))).collect()
+}
+
+fn events<T: Serialize>(examples: Vec<T>, describe: fn(&T) -> &'static str) -> Vec<(Value, &'static str)> {
+    examples.iter().map(|event| (serde_json::to_value(event).unwrap(), describe(event))).collect()
+}
+
+fn errors<T>(all: Vec<T>, code: fn(&T) -> &'static str, message: fn(&T, Language) -> &'static str) -> Vec<(&'static str, &'static str)> {
+    all.iter().map(|error| (code(error), message(error, Language::English))).collect()
+}
+
+fn eur(amount_minor: i64) -> Money {
+    Money::new(amount_minor, Currency::EUR)
+}
+
+fn sample_id() -> Uuid {
+    Uuid::from_u128(0x9b1deb4d_3b7d_4bad_9bdd_2b0d7b3dcb6d)
+}
+
+// The order lines of the sample coffee and soup.
+pub(crate) fn sample_line_ids() -> (Uuid, Uuid) {
+    (Uuid::from_u128(0x3f2c6a1e_8d4b_4c7e_a5f0_1b9e7d2c4a61), Uuid::from_u128(0x7a9d4e2b_1c6f_4b3a_9e8d_5f2a6c1b7d92))
+}
+
+fn tab_section() -> Section {
+    use crate::domain::Command::*;
+    use crate::domain::CommandError::*;
+
+    let id = sample_id();
+    Section {
+        name: "Tab",
+        commands: commands(vec![
+            OpenTab(id, 5, staff::legacy_id("Derek"), "Derek".to_string()),
+            PlaceOrder(id, vec![]),
+            MarkDrinksServed(id, vec![]),
+            MarkFoodServed(id, vec![]),
+            ServeUnordered(id, vec![]),
+            FlagLateFood(id, vec![]),
+            VoidOrderedItem(id, LineRef::MenuNumber(1), String::new()),
+            CloseTab(id, eur(0), None),
+            CloseTabSplit(id, vec![], None),
+            AuthorizePayment(id, "auth_000001".to_string(), eur(800)),
+            CapturePayment(id, "auth_000001".to_string(), "cap_000001".to_string()),
+            FailPayment(id, eur(800), String::new()),
+            CorrectServedPrices(id, vec![]),
+            ReassignWaiter(id, staff::legacy_id("Derek"), staff::legacy_id("Jane"), "Jane".to_string()),
+            ForceCloseTab(id, String::new()),
+            CompItem(id, LineRef::MenuNumber(1), String::new()),
+            ApplyDiscount(id, 10, String::new()),
+            FireCourse(id, Course::Main),
+            TransferTab(id, 7)
+        ], describe_tab_command),
+        events: events(tab_event_examples(), describe_tab_event),
+        errors: errors(vec![
+            TabNotOpen,
+            InvalidPrice,
+            DrinksNotOutstanding(vec![LineRef::MenuNumber(1)]),
+            FoodNotOutstanding(vec![LineRef::MenuNumber(1)]),
+            ItemNotOutstanding(LineRef::MenuNumber(1)),
+            MustPayEnough,
+            TabHasUnservedItems,
+            CurrencyMismatch,
+            TabValueLimitExceeded,
+            TipTooHigh,
+            NotTabWaiter,
+            ItemNotServed(LineRef::MenuNumber(1)),
+            InvalidDiscount,
+            DiscountExceedsTabValue,
+            CourseNotOrdered(Course::Main),
+            CourseAlreadyFired(Course::Main),
+            ServeUnorderedNotAllowed(vec![90]),
+            PaymentNotCaptured,
+            PaymentNotAuthorized,
+            TabAlreadyOpened
+        ], error_code, locale::command_error_message)
+    }
+}
+
+// One of every tab event, also the examples the OpenAPI document describes them by.
+pub(crate) fn tab_event_examples() -> Vec<domain::Event> {
+    use crate::domain::Event::*;
+
+    let (coffee, soup) = sample_line_ids();
+    let water = Uuid::from_u128(0x5e8b2c7d_4a1f_4d9e_b3c6_8f0a2d5e9b14);
+    vec![
+        TabOpened { table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() },
+        DrinksOrdered { items: vec![OrderedItem::new(1, "Coffee".to_string(), true, eur(250)).with_quantity(2).with_modifiers(vec!["Oat milk".to_string()]).with_line_id(coffee)] },
+        FoodOrdered { items: vec![OrderedItem::new(2, "Soup".to_string(), false, eur(450)).with_note(Some("Nut allergy".to_string())).with_course(Some(Course::Main)).with_line_id(soup)] },
+        DrinksServed { line_ids: vec![coffee, coffee], menu_numbers: vec![1, 1] },
+        FoodServed { line_ids: vec![soup], menu_numbers: vec![2] },
+        UnorderedItemsServed { items: vec![OrderedItem::new(90, "House water".to_string(), true, eur(0)).with_quantity(4).with_line_id(water)] },
+        FoodRunningLate { menu_numbers: vec![2] },
+        ItemVoided { line_id: coffee, menu_number: 1, reason: "Spilled".to_string() },
+        TabClosedPartially { payer: "Jane".to_string(), amount_paid: eur(400) },
+        TabClosed { amount_paid: eur(800), order_value: eur(700), tip_value: eur(100), receipt_number: Some("TLN-000042".to_string()) },
+        TabPurged { event_count: 7, amount_paid: eur(800), order_value: eur(700), tip_value: eur(100), receipt_number: Some("TLN-000042".to_string()) },
+        ServedPriceCorrected { menu_number: 1, charged: eur(250), correct: eur(200), count: 1, reason: "Happy hour was not applied".to_string() },
+        WaiterReassigned { from_waiter_id: staff::legacy_id("Derek"), waiter_id: staff::legacy_id("Jane"), waiter: "Jane".to_string() },
+        TabForceClosed { reason: "Guests left without paying".to_string(), unpaid_value: eur(700) },
+        ItemComped { line_id: soup, menu_number: 2, value: eur(450), reason: "Soup was cold".to_string() },
+        DiscountApplied { percent: 10, amount: eur(25), reason: "Regulars".to_string() },
+        TabTransferred { from_table_number: 5, table_number: 7 },
+        CourseFired { course: Course::Main },
+        PaymentAuthorized { authorization_id: "auth_000001".to_string(), amount: eur(800) },
+        PaymentCaptured { authorization_id: "auth_000001".to_string(), capture_id: "cap_000001".to_string(), amount: eur(800) },
+        PaymentFailed { amount: eur(800), reason: "payment declined: insufficient funds".to_string() }
+    ]
+}
+
+fn describe_tab_command(command: &domain::Command) -> &'static str {
+    use crate::domain::Command::*;
+
+    match *command {
+        OpenTab(..) => "Opens a tab for the guests at a table, looked after by a waiter on shift, known by their staff id.",
+        PlaceOrder(..) => "Orders drinks and food from the menu onto the tab, each item on an order line of its own with any modifiers and preparation note. Food ordered for a course waits until the course is fired.",
+        MarkDrinksServed(..) => "Marks ordered drinks as served, by order line and quantity. An item named by menu number is the first of it ordered.",
+        MarkFoodServed(..) => "Marks ordered food as served, by order line and quantity. An item named by menu number is the first of it ordered.",
+        ServeUnordered(..) => "Puts items served without an order on the tab free of charge, e.g. house water or bread; only items the policy allows.",
+        FlagLateFood(..) => "Flags food that has waited too long; issued by the kitchen ticket, not by clients.",
+        VoidOrderedItem(..) => "Takes an item that has not been served off the tab, by order line or menu number, with a reason.",
+        CloseTab(..) => "Closes the tab once everything is served; anything paid over the order value is a tip.",
+        CloseTabSplit(..) => "Closes the tab with the bill split between several payers.",
+        AuthorizePayment(..) => "Records a payment the gateway authorized; issued while settling the tab, not by clients. The tab can not close until it is captured.",
+        CapturePayment(..) => "Records the capture of the authorized payment; issued while settling the tab, not by clients.",
+        FailPayment(..) => "Records a payment that was declined or could not be made, with why; issued while settling the tab, not by clients.",
+        CorrectServedPrices(..) => "Corrects prices charged by mistake for served items; issued by the price backfill, not by clients.",
+        ReassignWaiter(..) => "Hands the tab over from its waiter to another, by staff id; issued for each tab of a shift handover.",
+        ForceCloseTab(..) => "Closes a tab left open after closing time without payment, with the reason; a manager's override.",
+        CompItem(..) => "Gives a served item away, by order line or menu number, with the reason; a manager's override.",
+        ApplyDiscount(..) => "Takes a percentage off the items served so far, with the reason; a manager's override. The discounts together can not exceed the served value.",
+        FireCourse(..) => "Sends the food held for a course (starter, main or dessert) to the kitchen; food of the course ordered later goes straight there.",
+        TransferTab(..) => "Moves the tab to another table with its guests; the new table has to be free."
+    }
+}
+
+fn describe_tab_event(event: &domain::Event) -> &'static str {
+    use crate::domain::Event::*;
+
+    match *event {
+        TabOpened { .. } => "A tab was opened for a table, with the staff id and name of its waiter.",
+        DrinksOrdered { .. } => "Drinks were ordered, each on an order line, with how many of each, at the prices of the menu at the time, and any modifiers or preparation note. Orders recorded without a quantity are for one of each.",
+        FoodOrdered { .. } => "Food was ordered, each on an order line, with how many of each, at the prices of the menu at the time, and any modifiers, preparation note or course. Orders recorded without a quantity are for one of each.",
+        DrinksServed { .. } => "Drinks were served, with the order line and menu number of each one.",
+        FoodServed { .. } => "Food was served, with the order line and menu number of each one.",
+        UnorderedItemsServed { .. } => "Items were served without an order, each on an order line of its own at no charge. Not a comp: nothing ordered was given away.",
+        FoodRunningLate { .. } => "Food has waited too long to be served. Changes nothing on the tab.",
+        ItemVoided { .. } => "An item that had not been served was taken off the tab.",
+        TabClosedPartially { .. } => "One payer paid their share of a split bill.",
+        TabClosed { .. } => "The tab was paid in full and closed.",
+        TabPurged { .. } => "Left behind when retention removes a closed tab's history; keeps the totals for reporting.",
+        ServedPriceCorrected { .. } => "Served items had been charged the wrong price; the served value is corrected from now on.",
+        WaiterReassigned { .. } => "The tab was handed over to another waiter, who gets its tip when it is paid.",
+        TabForceClosed { .. } => "A manager closed the tab without payment; anything unserved was dropped.",
+        ItemComped { .. } => "A served item was given away; its value is no longer charged.",
+        DiscountApplied { .. } => "A percentage was taken off the items served so far; the amount is taken off the order value.",
+        TabTransferred { .. } => "The tab moved to another table with its guests.",
+        CourseFired { .. } => "The waiter sent a course to the kitchen; its food can be prepared from now on.",
+        PaymentAuthorized { .. } => "The payment gateway authorized the amount, under its authorization id. The tab can not close until it is captured.",
+        PaymentCaptured { .. } => "The authorized amount was captured, under the gateway's capture id; the tab can be closed.",
+        PaymentFailed { .. } => "A payment was declined or could not be made, with why; an authorization awaiting capture is given up."
+    }
+}
+
+fn menu_section() -> Section {
+    use crate::menu::Command::*;
+    use crate::menu::CommandError::*;
+    use crate::menu::Event::*;
+
+    let id = sample_id();
+    let nutrition = Nutrition { kcal: 5, protein_grams: Some(0), carbohydrate_grams: Some(1), fat_grams: Some(0) };
+    let item = MenuItem { menu_number: 1, description: "Coffee".to_string(), is_drink: true, price: eur(250), nutrition: None };
+    Section {
+        name: "Menu",
+        commands: commands(vec![
+            AddMenuItem(id, item.clone()),
+            ChangePrice(id, 1, eur(0)),
+            RetireItem(id, 1),
+            DeclareNutrition(id, 1, Some(nutrition))
+        ], describe_menu_command),
+        events: events(vec![
+            MenuItemAdded { item },
+            PriceChanged { menu_number: 1, price: eur(280) },
+            ItemRetired { menu_number: 1 },
+            NutritionDeclared { menu_number: 1, nutrition: Some(nutrition) }
+        ], describe_menu_event),
+        errors: errors(vec![
+            MenuNumberTaken,
+            UnknownMenuItem,
+            InvalidPrice,
+            CurrencyMismatch
+        ], menu_error_code, locale::menu_error_message)
+    }
+}
+
+fn describe_menu_command(command: &menu::Command) -> &'static str {
+    use crate::menu::Command::*;
+
+    match *command {
+        AddMenuItem(..) => "Adds an item to the menu under a menu number not used before.",
+        ChangePrice(..) => "Changes the price of an item for orders from now on.",
+        RetireItem(..) => "Takes an item off the menu.",
+        DeclareNutrition(..) => "Declares the calories and nutrients of a serving of an item, or takes the declaration back."
+    }
+}
+
+fn describe_menu_event(event: &menu::Event) -> &'static str {
+    use crate::menu::Event::*;
+
+    match *event {
+        MenuItemAdded { .. } => "An item was added to the menu.",
+        PriceChanged { .. } => "The price of an item changed.",
+        ItemRetired { .. } => "An item was taken off the menu.",
+        NutritionDeclared { .. } => "The nutrition of an item was declared, or taken back."
+    }
+}
+
+fn table_section() -> Section {
+    use crate::table::Command::*;
+    use crate::table::CommandError::*;
+    use crate::table::Event::*;
+
+    let id = sample_id();
+    Section {
+        name: "Table",
+        commands: commands(vec![
+            RegisterTable(id, 5),
+            SeatGuests(id, id),
+            ClearTable(id)
+        ], describe_table_command),
+        events: events(vec![
+            TableRegistered { table_number: 5 },
+            TableOccupied { tab_id: id },
+            TableCleared
+        ], describe_table_event),
+        errors: errors(vec![
+            TableAlreadyRegistered,
+            UnknownTable,
+            TableInUse,
+            TableNotInUse
+        ], table_error_code, locale::table_error_message)
+    }
+}
+
+fn describe_table_command(command: &table::Command) -> &'static str {
+    use crate::table::Command::*;
+
+    match *command {
+        RegisterTable(..) => "Registers a table by its number.",
+        SeatGuests(..) => "Seats guests at a free table; done when a tab is opened.",
+        ClearTable(..) => "Frees the table for the next guests."
+    }
+}
+
+fn describe_table_event(event: &table::Event) -> &'static str {
+    use crate::table::Event::*;
+
+    match *event {
+        TableRegistered { .. } => "A table was registered.",
+        TableOccupied { .. } => "Guests were seated at the table, on the given tab.",
+        TableCleared => "The table was freed."
+    }
+}
+
+fn shift_section() -> Section {
+    use crate::shift::Command::*;
+    use crate::shift::CommandError::*;
+    use crate::shift::Event::*;
+
+    let id = sample_id();
+    Section {
+        name: "Shift",
+        commands: commands(vec![
+            StartShift(id, "Derek".to_string()),
+            EndShift(id),
+            AssignToTable(id, 5),
+            ReleaseTable(id, 5)
+        ], describe_shift_command),
+        events: events(vec![
+            ShiftStarted { waiter: "Derek".to_string() },
+            AssignedToTable { table_number: 5 },
+            ReleasedTable { table_number: 5 },
+            ShiftEnded
+        ], describe_shift_event),
+        errors: errors(vec![
+            AlreadyOnShift,
+            NotOnShift
+        ], shift_error_code, locale::shift_error_message)
+    }
+}
+
+fn describe_shift_command(command: &shift::Command) -> &'static str {
+    use crate::shift::Command::*;
+
+    match *command {
+        StartShift(..) => "Starts a waiter's shift.",
+        EndShift(..) => "Ends a waiter's shift.",
+        AssignToTable(..) => "Assigns a waiter on shift to a table.",
+        ReleaseTable(..) => "Takes a table off a waiter on shift, when it is handed over to another."
+    }
+}
+
+fn describe_shift_event(event: &shift::Event) -> &'static str {
+    use crate::shift::Event::*;
+
+    match *event {
+        ShiftStarted { .. } => "A waiter's shift started.",
+        AssignedToTable { .. } => "A waiter was assigned to a table.",
+        ReleasedTable { .. } => "A waiter handed a table over before their shift ended.",
+        ShiftEnded => "A waiter's shift ended, and with it their table assignments."
+    }
+}
+
+fn staff_section() -> Section {
+    use crate::staff::Command::*;
+    use crate::staff::CommandError::*;
+    use crate::staff::Event::*;
+
+    let id = sample_id();
+    Section {
+        name: "Staff",
+        commands: commands(vec![
+            Register(id, "Derek".to_string(), Role::Waiter),
+            Deactivate(id),
+            SetPin(id, "<hash>".to_string())
+        ], describe_staff_command),
+        events: events(vec![
+            StaffRegistered { staff_id: id, name: "Derek".to_string(), role: Role::Waiter },
+            StaffDeactivated,
+            PinSet { pin_hash: "<hash>".to_string() }
+        ], describe_staff_event),
+        errors: errors(vec![
+            AlreadyRegistered,
+            NotRegistered,
+            AlreadyInactive
+        ], staff_error_code, locale::staff_error_message)
+    }
+}
+
+fn describe_staff_command(command: &staff::Command) -> &'static str {
+    use crate::staff::Command::*;
+
+    match *command {
+        Register(..) => "Registers a member of staff with their name and role.",
+        Deactivate(..) => "Marks a member of staff as having left; they can no longer open tabs or start shifts.",
+        SetPin(..) => "Gives an active member of staff the PIN they sign in with on shared terminals."
+    }
+}
+
+fn describe_staff_event(event: &staff::Event) -> &'static str {
+    use crate::staff::Event::*;
+
+    match *event {
+        StaffRegistered { .. } => "A member of staff was registered, under the staff id their tabs and shifts are filed by.",
+        StaffDeactivated => "A member of staff left. They stay in the registry so their history keeps a name.",
+        PinSet { .. } => "A member of staff was given a new PIN, stored hashed."
+    }
+}
+
+// Issuing or voiding a number can not fail, so there are no errors to list.
+fn receipt_section() -> Section {
+    use crate::receipts::Command::*;
+    use crate::receipts::Event::*;
+
+    let id = sample_id();
+    Section {
+        name: "Receipt sequence",
+        commands: commands(vec![IssueReceipt(receipts::sequence_id("old-town"), id), VoidReceipt(receipts::sequence_id("old-town"), id)], describe_receipt_command),
+        events: events(vec![ReceiptIssued { number: 42, tab_id: id }, ReceiptVoided { number: 42, tab_id: id }], describe_receipt_event),
+        errors: Vec::new()
+    }
+}
+
+fn describe_receipt_command(command: &receipts::Command) -> &'static str {
+    use crate::receipts::Command::*;
+
+    match *command {
+        IssueReceipt(..) => "Issues the next receipt number of a location to a tab about to be closed; a tab that has one already keeps it.",
+        VoidReceipt(..) => "Voids the number a tab was issued when its close was turned down; a tab without one is left alone."
+    }
+}
+
+fn describe_receipt_event(event: &receipts::Event) -> &'static str {
+    use crate::receipts::Event::*;
+
+    match *event {
+        ReceiptIssued { .. } => "A receipt number was issued to a tab. Numbers count up from 1 at each location without gaps.",
+        ReceiptVoided { .. } => "A receipt number was voided as its tab was not closed with it. The tab is issued a new one when it is closed."
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_shows_every_example_once() {
+        let catalog = domain_catalog();
+        for section in [tab_section(), menu_section(), table_section(), shift_section(), staff_section(), receipt_section()] {
+            let mut names: Vec<String> = section.commands.iter().map(|(name, _)| name.clone())
+                .chain(section.events.iter().map(|(example, _)| example["type"].as_str().unwrap().to_string()))
+                .collect();
+            let count = names.len();
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), count, "{} documents a variant twice", section.name);
+        }
+        assert!(catalog.contains("- `OpenTab`: "));
+        assert!(catalog.contains("#### `tab_opened`"));
+        assert!(catalog.contains("\"table_number\": 5"));
+        assert!(catalog.contains("| `tab_not_open` | "));
+        assert!(catalog.contains("| `not_on_shift` | "));
+        assert!(catalog.contains("#### `staff_registered`"));
+    }
+}
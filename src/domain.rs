@@ -1,45 +1,236 @@
-use cqrs::Aggregate;
+use serde_json::Value;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::cqrs::{named_stream_id, Aggregate, AggregateCommand, Invariants};
+use crate::cqrs::store::{Upcaster, Upcasters};
+use crate::money::{Currency, Money};
+use crate::staff::{self, WaiterId};
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum Command {
-    OpenTab(Uuid, u8, String),
+    // The waiter's name as the staff registry has it, kept on the tab for the read models.
+    OpenTab(Uuid, u8, WaiterId, String),
+    // Each item is given a line id of its own.
     PlaceOrder(Uuid, Vec<OrderedItem>),
-    MarkDrinksServed(Uuid, Vec<i32>),
-    MarkFoodServed(Uuid, Vec<i32>)
+    // Order lines with how many of each were served.
+    MarkDrinksServed(Uuid, Vec<(LineRef, u32)>),
+    MarkFoodServed(Uuid, Vec<(LineRef, u32)>),
+    // Items served without an order, e.g. house water poured as guests sit down. They go on the
+    // tab free of charge; which items may be is up to the policy.
+    ServeUnordered(Uuid, Vec<OrderedItem>),
+    FlagLateFood(Uuid, Vec<i32>),
+    VoidOrderedItem(Uuid, LineRef, String),
+    // Both with the receipt number, where receipts are numbered.
+    CloseTab(Uuid, Money, Option<String>),
+    CloseTabSplit(Uuid, Vec<PaymentShare>, Option<String>),
+    // What the payment provider answered while settling the tab, see gateway::PaymentProvider:
+    // the authorization's id and amount, the capture's id for the authorization, or why the
+    // payment failed.
+    AuthorizePayment(Uuid, String, Money),
+    CapturePayment(Uuid, String, String),
+    FailPayment(Uuid, Money, String),
+    CorrectServedPrices(Uuid, Vec<PriceCorrection>),
+    // Hands the tab over from one waiter to another, by staff id, at shift change. Only done if
+    // the tab is still the first waiter's, so two handovers can not both take it.
+    ReassignWaiter(Uuid, WaiterId, WaiterId, String),
+    // A manager's override for tabs left open after closing time, with the reason. Whatever is
+    // still unserved is dropped and nothing is paid.
+    ForceCloseTab(Uuid, String),
+    // A manager giving a served item away, with the reason.
+    CompItem(Uuid, LineRef, String),
+    // A manager taking a percentage off what has been served so far, with the reason. Items
+    // served later are charged in full.
+    ApplyDiscount(Uuid, u8, String),
+    // Sends the food held for the course to the kitchen.
+    FireCourse(Uuid, Course),
+    // Moves the tab along with its guests. That the new table is free is for the table itself to
+    // say, see table::Table.
+    TransferTab(Uuid, u8)
 }
 
-#[derive(Debug, Clone, PartialEq)]
+impl AggregateCommand for Command {
+    fn aggregate_id(&self) -> Uuid {
+        use self::Command::*;
+
+        match *self {
+            OpenTab(id, ..) | PlaceOrder(id, ..) | MarkDrinksServed(id, ..) | MarkFoodServed(id, ..) | ServeUnordered(id, ..) | FlagLateFood(id, ..) | VoidOrderedItem(id, ..) | CloseTab(id, ..) | CloseTabSplit(id, ..) | AuthorizePayment(id, ..) | CapturePayment(id, ..) | FailPayment(id, ..) | CorrectServedPrices(id, ..) | ReassignWaiter(id, ..) | ForceCloseTab(id, ..) | CompItem(id, ..) | ApplyDiscount(id, ..) | FireCourse(id, ..) | TransferTab(id, ..) => id
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 pub enum CommandError {
     TabNotOpen,
-    DrinksNotOutstanding,
-    FoodNotOutstanding
+    InvalidPrice,
+    // With the lines that were not waiting to be served.
+    DrinksNotOutstanding(Vec<LineRef>),
+    FoodNotOutstanding(Vec<LineRef>),
+    ItemNotOutstanding(LineRef),
+    MustPayEnough,
+    TabHasUnservedItems,
+    CurrencyMismatch,
+    TabValueLimitExceeded,
+    TipTooHigh,
+    NotTabWaiter,
+    ItemNotServed(LineRef),
+    // Discounts are between 1 and 100 percent.
+    InvalidDiscount,
+    // The discounts would come to more than the served items are worth.
+    DiscountExceedsTabValue,
+    // No food on the tab is held for the course.
+    CourseNotOrdered(Course),
+    CourseAlreadyFired(Course),
+    // With the menu numbers the policy does not let be served without an order.
+    ServeUnorderedNotAllowed(Vec<i32>),
+    // A payment was authorized and is yet to be captured; the tab can not be closed or paid
+    // again until it is.
+    PaymentNotCaptured,
+    // No payment awaits capture under the authorization id.
+    PaymentNotAuthorized,
+    // Tab ids are not reused, so a closed tab can not be opened again either.
+    TabAlreadyOpened
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Event {
-    TabOpened { table_number: u8, waiter: String },
+    TabOpened { table_number: u8, waiter_id: WaiterId, waiter: String },
     DrinksOrdered { items: Vec<OrderedItem> },
     FoodOrdered { items: Vec<OrderedItem> },
-    DrinksServed { menu_numbers: Vec<i32> },
-    FoodServed { menu_numbers: Vec<i32> }
+    // A line id for each one served. The menu numbers, likewise one for each, are only there to
+    // be read.
+    DrinksServed { line_ids: Vec<Uuid>, menu_numbers: Vec<i32> },
+    FoodServed { line_ids: Vec<Uuid>, menu_numbers: Vec<i32> },
+    // Served without an order, each item on a line of its own and at no charge. Kept apart from
+    // comps, which give away items that were ordered.
+    UnorderedItemsServed { items: Vec<OrderedItem> },
+    // Raised by kitchen::KitchenTicket when food has waited too long; changes nothing on the tab.
+    FoodRunningLate { menu_numbers: Vec<i32> },
+    ItemVoided { line_id: Uuid, menu_number: i32, reason: String },
+    TabClosedPartially { payer: String, amount_paid: Money },
+    // The receipt number is issued by receipts::ReceiptSequence where the deployment numbers
+    // receipts.
+    TabClosed {
+        amount_paid: Money,
+        order_value: Money,
+        tip_value: Money,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receipt_number: Option<String>
+    },
+    // Left behind when retention removes a closed tab's history; keeps the totals, and the
+    // receipt number, for reporting.
+    TabPurged {
+        event_count: usize,
+        amount_paid: Money,
+        order_value: Money,
+        tip_value: Money,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receipt_number: Option<String>
+    },
+    // Served items were charged the wrong price; see backfill. Nothing already paid changes.
+    ServedPriceCorrected { menu_number: i32, charged: Money, correct: Money, count: usize, reason: String },
+    WaiterReassigned { from_waiter_id: WaiterId, waiter_id: WaiterId, waiter: String },
+    // The unpaid value is what had been served when the tab was closed without payment.
+    TabForceClosed { reason: String, unpaid_value: Money },
+    // The value is what the item was charged at.
+    ItemComped { line_id: Uuid, menu_number: i32, value: Money, reason: String },
+    DiscountApplied { percent: u8, amount: Money, reason: String },
+    TabTransferred { from_table_number: u8, table_number: u8 },
+    CourseFired { course: Course },
+    PaymentAuthorized { authorization_id: String, amount: Money },
+    PaymentCaptured { authorization_id: String, capture_id: String, amount: Money },
+    // Declined or not reached, before or after authorization; an authorization awaiting capture
+    // is given up.
+    PaymentFailed { amount: Money, reason: String }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct State {
     tab_open: bool,
+    table_number: Option<u8>,
+    waiter_id: Option<WaiterId>,
     outstanding_drinks: Vec<OrderedItem>,
     outstanding_food: Vec<OrderedItem>,
-    served_items_value: f32 // TODO: use decimal
+    // Served items that can still be comped.
+    served_items: Vec<OrderedItem>,
+    served_items_value: Money,
+    discount_value: Money,
+    lines_ordered: usize,
+    fired_courses: Vec<Course>,
+    // The authorization id and amount of a payment awaiting capture.
+    authorized_payment: Option<(String, Money)>
 }
 
+// The price is for one; orders from before quantities were kept are one of each item. The line id
+// tells apart items ordered with the same menu number; it is nil until the order is placed.
+// Modifiers ("no ice", "extra shot") and the note are for whoever prepares the item.
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct OrderedItem {
+    #[serde(default)]
+    line_id: Uuid,
     menu_number: i32,
     description: String,
     is_drink: bool,
-    price: f32 // TODO: use decimal
+    price: Money,
+    #[serde(default = "one")]
+    quantity: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modifiers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    course: Option<Course>
+}
+
+fn one() -> u32 {
+    1
+}
+
+// An ordered item as commands name it: by its order line, or by menu number for the first line
+// of it, the way clients named items before lines had ids.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum LineRef {
+    Line(Uuid),
+    MenuNumber(i32)
+}
+
+// Food ordered for a course is held back from the kitchen until the waiter fires the course, and
+// goes to it right away once the course has been fired. Food without a course is never held.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Course {
+    Starter,
+    Main,
+    Dessert
+}
+
+// Lines are numbered in the order they were placed on the tab, so deciding an order again gives
+// its items the same ids.
+fn line_id(tab_id: Uuid, line_number: usize) -> Uuid {
+    named_stream_id("order-lines", &format!("{}/{}", tab_id, line_number))
+}
+
+// What items ordered before lines had ids are known by, see LegacyLineIds.
+pub fn legacy_line_id(menu_number: i32) -> Uuid {
+    named_stream_id("order-lines", &menu_number.to_string())
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PaymentShare {
+    pub payer: String,
+    pub amount: Money
+}
+
+// A price charged for served items by mistake, e.g. because of a bug in pricing, and what it
+// should have been, for count of the items.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct PriceCorrection {
+    pub menu_number: i32,
+    pub charged: Money,
+    pub correct: Money,
+    pub count: usize,
+    pub reason: String
 }
 
 pub struct Tab;
@@ -53,9 +244,16 @@ impl Aggregate for Tab {
     fn initial_state() -> State {
         State {
             tab_open: false,
+            table_number: None,
+            waiter_id: None,
             outstanding_drinks: Vec::new(),
             outstanding_food: Vec::new(),
-            served_items_value: 0.0
+            served_items: Vec::new(),
+            served_items_value: Money::zero(Currency::default()),
+            discount_value: Money::zero(Currency::default()),
+            lines_ordered: 0,
+            fired_courses: Vec::new(),
+            authorized_payment: None
         }
     }
 
@@ -65,9 +263,23 @@ impl Aggregate for Tab {
         use self::Event::*;
 
         match command {
-            OpenTab(_, table_number, waiter) => Ok(vec![TabOpened { table_number, waiter }]),
-            PlaceOrder(_, items) => {
-                if state.tab_open {
+            OpenTab(_, table_number, waiter_id, waiter) => {
+                if state.waiter_id.is_some() {
+                    Err(TabAlreadyOpened)
+                } else {
+                    Ok(vec![TabOpened { table_number, waiter_id, waiter }])
+                }
+            },
+            PlaceOrder(tab_id, mut items) => {
+                items.retain(|item| item.quantity > 0);
+                for (index, item) in items.iter_mut().enumerate() {
+                    item.line_id = line_id(tab_id, state.lines_ordered + index + 1);
+                }
+                if items.iter().any(|item| item.price.is_negative()) {
+                    Err(InvalidPrice)
+                } else if items.iter().any(|item| item.price.currency() != state.served_items_value.currency()) {
+                    Err(CurrencyMismatch)
+                } else if state.tab_open {
                     let (drinks, foods): (Vec<OrderedItem>, Vec<OrderedItem>) = items.into_iter().partition(|n| n.is_drink);
                     let mut events = vec![];
 
@@ -84,21 +296,143 @@ impl Aggregate for Tab {
                     Err(TabNotOpen)
                 }
             },
-            MarkDrinksServed(_, menu_numbers) => {
-                if state.are_drinks_outstanding(&menu_numbers) {
-                    Ok(vec![DrinksServed { menu_numbers: menu_numbers }])
+            MarkDrinksServed(_, served) => State::units(&state.outstanding_drinks, &served)
+                .map(|(line_ids, menu_numbers)| vec![DrinksServed { line_ids, menu_numbers }])
+                .map_err(DrinksNotOutstanding),
+            MarkFoodServed(_, served) => State::units(&state.outstanding_food, &served)
+                .map(|(line_ids, menu_numbers)| vec![FoodServed { line_ids, menu_numbers }])
+                .map_err(FoodNotOutstanding),
+            ServeUnordered(tab_id, mut items) => {
+                items.retain(|item| item.quantity > 0);
+                for (index, item) in items.iter_mut().enumerate() {
+                    item.line_id = line_id(tab_id, state.lines_ordered + index + 1);
+                    item.price = Money::zero(item.price.currency());
+                }
+                if !state.tab_open {
+                    Err(TabNotOpen)
+                } else if items.is_empty() {
+                    Ok(vec![])
+                } else {
+                    Ok(vec![UnorderedItemsServed { items }])
+                }
+            },
+            FlagLateFood(_, menu_numbers) => {
+                let late: Vec<(LineRef, u32)> = menu_numbers.iter().map(|&menu_number| (LineRef::MenuNumber(menu_number), 1)).collect();
+                State::units(&state.outstanding_food, &late)
+                    .map(|_| vec![FoodRunningLate { menu_numbers }])
+                    .map_err(FoodNotOutstanding)
+            },
+            VoidOrderedItem(_, line, reason) => {
+                if !state.tab_open {
+                    return Err(TabNotOpen);
+                }
+                let item = state.outstanding_drinks.iter().chain(state.outstanding_food.iter()).find(|item| item.is(line)).ok_or(ItemNotOutstanding(line))?;
+                Ok(vec![ItemVoided { line_id: item.line_id, menu_number: item.menu_number, reason }])
+            },
+            CloseTab(_, amount_paid, receipt_number) => state.close(amount_paid, receipt_number).map(|closed| vec![closed]),
+            CloseTabSplit(_, shares, receipt_number) => {
+                if !state.tab_open {
+                    return Err(TabNotOpen);
+                }
+                let amount_paid = PaymentShare::total(&shares, state.served_items_value.currency()).ok_or(CurrencyMismatch)?;
+                let closed = state.close(amount_paid, receipt_number)?;
+                let mut events: Vec<Event> = shares.into_iter().map(|share| TabClosedPartially { payer: share.payer, amount_paid: share.amount }).collect();
+                events.push(closed);
+                Ok(events)
+            },
+            AuthorizePayment(_, authorization_id, amount) => {
+                if !state.tab_open {
+                    Err(TabNotOpen)
+                } else if state.authorized_payment.is_some() {
+                    Err(PaymentNotCaptured)
+                } else if amount.currency() != state.served_items_value.currency() {
+                    Err(CurrencyMismatch)
+                } else {
+                    Ok(vec![PaymentAuthorized { authorization_id, amount }])
+                }
+            },
+            CapturePayment(_, authorization_id, capture_id) => match state.authorized_payment {
+                _ if !state.tab_open => Err(TabNotOpen),
+                Some((ref authorized, amount)) if *authorized == authorization_id => Ok(vec![PaymentCaptured { authorization_id, capture_id, amount }]),
+                _ => Err(PaymentNotAuthorized)
+            },
+            FailPayment(_, amount, reason) => {
+                if !state.tab_open {
+                    Err(TabNotOpen)
+                } else {
+                    Ok(vec![PaymentFailed { amount, reason }])
+                }
+            },
+            CorrectServedPrices(_, corrections) => {
+                let currency = state.served_items_value.currency();
+                if corrections.iter().any(|correction| correction.correct.is_negative()) {
+                    Err(InvalidPrice)
+                } else if corrections.iter().any(|correction| correction.charged.currency() != currency || correction.correct.currency() != currency) {
+                    Err(CurrencyMismatch)
                 } else {
-                    Err(DrinksNotOutstanding)
+                    Ok(corrections.into_iter()
+                        .filter(|correction| correction.count > 0)
+                        .map(|PriceCorrection { menu_number, charged, correct, count, reason }| ServedPriceCorrected { menu_number, charged, correct, count, reason })
+                        .collect())
                 }
             },
-            MarkFoodServed(_, menu_numbers) => {
-                if state.is_food_outstanding(&menu_numbers) {
-                    Ok(vec![FoodServed { menu_numbers: menu_numbers }])
+            ReassignWaiter(_, from_waiter_id, waiter_id, waiter) => {
+                if !state.tab_open {
+                    Err(TabNotOpen)
+                } else if state.waiter_id != Some(from_waiter_id) {
+                    Err(NotTabWaiter)
+                } else if waiter_id == from_waiter_id {
+                    Ok(vec![])
                 } else {
-                    Err(FoodNotOutstanding)
+                    Ok(vec![WaiterReassigned { from_waiter_id, waiter_id, waiter }])
                 }
             },
-            _ => Ok(vec![])
+            ForceCloseTab(_, reason) => {
+                if state.tab_open {
+                    Ok(vec![TabForceClosed { reason, unpaid_value: state.amount_due() }])
+                } else {
+                    Err(TabNotOpen)
+                }
+            },
+            CompItem(_, line, reason) => {
+                if !state.tab_open {
+                    return Err(TabNotOpen);
+                }
+                let item = state.served_items.iter().find(|item| item.is(line)).ok_or(ItemNotServed(line))?;
+                if state.discount_value > state.served_items_value - item.price {
+                    Err(DiscountExceedsTabValue)
+                } else {
+                    Ok(vec![ItemComped { line_id: item.line_id, menu_number: item.menu_number, value: item.price, reason }])
+                }
+            },
+            ApplyDiscount(_, percent, reason) => {
+                let amount = state.served_items_value.mul_ratio(i64::from(percent), 100);
+                if !state.tab_open {
+                    Err(TabNotOpen)
+                } else if percent == 0 || percent > 100 {
+                    Err(InvalidDiscount)
+                } else if state.discount_value + amount > state.served_items_value {
+                    Err(DiscountExceedsTabValue)
+                } else {
+                    Ok(vec![DiscountApplied { percent, amount, reason }])
+                }
+            },
+            FireCourse(_, course) => {
+                if !state.tab_open {
+                    Err(TabNotOpen)
+                } else if state.fired_courses.contains(&course) {
+                    Err(CourseAlreadyFired(course))
+                } else if !state.outstanding_food.iter().any(|item| item.course == Some(course)) {
+                    Err(CourseNotOrdered(course))
+                } else {
+                    Ok(vec![CourseFired { course }])
+                }
+            },
+            TransferTab(_, table_number) => match state.table_number {
+                Some(from_table_number) if state.tab_open && from_table_number != table_number => Ok(vec![TabTransferred { from_table_number, table_number }]),
+                Some(_) if state.tab_open => Ok(vec![]),
+                _ => Err(TabNotOpen)
+            }
         }
     }
 
@@ -106,182 +440,960 @@ impl Aggregate for Tab {
         use self::Event::*;
 
         match event {
-            TabOpened { .. } => state.tab_open = true,
-            DrinksOrdered { mut items } => state.outstanding_drinks.append(&mut items),
-            FoodOrdered { mut items } => state.outstanding_food.append(&mut items),
-            DrinksServed { menu_numbers } => {
-                for menu_number in menu_numbers {
-                    if let Some(index) = state.outstanding_drinks.iter().position(|x| x.menu_number == menu_number) {
-                        state.served_items_value += state.outstanding_drinks[index].price;
-                        state.outstanding_drinks.remove(index);
+            TabOpened { table_number, waiter_id, .. } => {
+                state.tab_open = true;
+                state.table_number = Some(table_number);
+                state.waiter_id = Some(waiter_id);
+            },
+            TabTransferred { table_number, .. } => state.table_number = Some(table_number),
+            WaiterReassigned { waiter_id, .. } => state.waiter_id = Some(waiter_id),
+            DrinksOrdered { mut items } => {
+                state.lines_ordered += items.len();
+                state.outstanding_drinks.append(&mut items);
+            },
+            FoodOrdered { mut items } => {
+                state.lines_ordered += items.len();
+                state.outstanding_food.append(&mut items);
+            },
+            DrinksServed { line_ids, .. } => {
+                for line_id in line_ids {
+                    if let Some(item) = State::take_one(&mut state.outstanding_drinks, line_id) {
+                        state.served_items_value += item.price;
+                        state.served_items.push(item);
                     }
                 }
             },
-            FoodServed { menu_numbers } => {
-                for menu_number in menu_numbers {
-                    if let Some(index) = state.outstanding_food.iter().position(|x| x.menu_number == menu_number) {
-                        state.served_items_value += state.outstanding_food[index].price;
-                        state.outstanding_food.remove(index);
+            FoodServed { line_ids, .. } => {
+                for line_id in line_ids {
+                    if let Some(item) = State::take_one(&mut state.outstanding_food, line_id) {
+                        state.served_items_value += item.price;
+                        state.served_items.push(item);
                     }
                 }
-            }
+            },
+            UnorderedItemsServed { items } => state.lines_ordered += items.len(),
+            ItemVoided { line_id, .. } => {
+                let _ = State::take_one(&mut state.outstanding_drinks, line_id).or_else(|| State::take_one(&mut state.outstanding_food, line_id));
+            },
+            TabClosed { .. } | TabPurged { .. } => state.tab_open = false,
+            TabForceClosed { .. } => {
+                state.tab_open = false;
+                state.outstanding_drinks.clear();
+                state.outstanding_food.clear();
+            },
+            ServedPriceCorrected { menu_number, charged, correct, count, .. } => {
+                state.served_items_value += (correct - charged) * count as i64;
+                for item in state.served_items.iter_mut().filter(|item| item.menu_number == menu_number && item.price == charged).take(count) {
+                    item.price = correct;
+                }
+            },
+            ItemComped { line_id, value, .. } => {
+                if let Some(index) = state.served_items.iter().position(|item| item.line_id == line_id) {
+                    state.served_items.remove(index);
+                }
+                state.served_items_value -= value;
+            },
+            DiscountApplied { amount, .. } => state.discount_value += amount,
+            CourseFired { course } => state.fired_courses.push(course),
+            PaymentAuthorized { authorization_id, amount } => state.authorized_payment = Some((authorization_id, amount)),
+            PaymentCaptured { .. } | PaymentFailed { .. } => state.authorized_payment = None,
             _ => {}
         }
+
+        debug_assert_eq!(state.check_invariants(), Ok(()));
+    }
+
+    // Tabs of large parties can run to hundreds of orders.
+    fn snapshot_every() -> Option<usize> {
+        Some(100)
+    }
+
+    // To be bumped with every change to State's fields, see Aggregate::snapshot_schema_version.
+    fn snapshot_schema_version() -> u32 {
+        1
+    }
+}
+
+impl Invariants for State {
+    fn check_invariants(&self) -> Result<(), String> {
+        if self.served_items_value.is_negative() {
+            return Err(format!("served items value {} is negative", self.served_items_value));
+        }
+        if self.discount_value.is_negative() {
+            return Err(format!("discount {} is negative", self.discount_value));
+        }
+        if let Some(item) = self.outstanding_drinks.iter().chain(self.outstanding_food.iter()).find(|item| item.price.is_negative()) {
+            return Err(format!("outstanding item {} has negative price {}", item.menu_number, item.price));
+        }
+        if let Some(item) = self.outstanding_drinks.iter().chain(self.outstanding_food.iter()).find(|item| item.quantity == 0) {
+            return Err(format!("outstanding item {} has none left", item.menu_number));
+        }
+        if self.outstanding_drinks.iter().any(|item| !item.is_drink) || self.outstanding_food.iter().any(|item| item.is_drink) {
+            return Err("outstanding drinks and food are mixed up".to_string());
+        }
+        if !self.tab_open && self.has_unserved_items() {
+            return Err("tab is not open but has outstanding items".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl PaymentShare {
+    // Sum of all shares, or None if any share is not in the given currency.
+    pub fn total(shares: &[PaymentShare], currency: Currency) -> Option<Money> {
+        shares.iter().try_fold(Money::zero(currency), |total, share| total.checked_add(share.amount))
+    }
+}
+
+impl OrderedItem {
+    pub fn new(menu_number: i32, description: String, is_drink: bool, price: Money) -> OrderedItem {
+        OrderedItem { line_id: Uuid::nil(), menu_number, description, is_drink, price, quantity: 1, modifiers: Vec::new(), note: None, course: None }
+    }
+
+    pub fn with_line_id(mut self, line_id: Uuid) -> OrderedItem {
+        self.line_id = line_id;
+        self
+    }
+
+    pub fn line_id(&self) -> Uuid {
+        self.line_id
+    }
+
+    pub fn with_quantity(mut self, quantity: u32) -> OrderedItem {
+        self.quantity = quantity;
+        self
+    }
+
+    pub fn with_modifiers(mut self, modifiers: Vec<String>) -> OrderedItem {
+        self.modifiers = modifiers;
+        self
+    }
+
+    pub fn with_note(mut self, note: Option<String>) -> OrderedItem {
+        self.note = note;
+        self
+    }
+
+    pub fn with_course(mut self, course: Option<Course>) -> OrderedItem {
+        self.course = course;
+        self
+    }
+
+    pub fn menu_number(&self) -> i32 {
+        self.menu_number
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn is_drink(&self) -> bool {
+        self.is_drink
+    }
+
+    pub fn price(&self) -> Money {
+        self.price
+    }
+
+    pub fn quantity(&self) -> u32 {
+        self.quantity
+    }
+
+    pub fn modifiers(&self) -> &[String] {
+        &self.modifiers
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    pub fn course(&self) -> Option<Course> {
+        self.course
+    }
+
+    pub fn total(&self) -> Money {
+        self.price * i64::from(self.quantity)
+    }
+
+    // One of the item for each of its quantity, for read models that list items one by one.
+    pub fn units(&self) -> impl Iterator<Item = OrderedItem> + '_ {
+        (0..self.quantity).map(move |_| OrderedItem { quantity: 1, ..self.clone() })
+    }
+
+    fn is(&self, line: LineRef) -> bool {
+        match line {
+            LineRef::Line(line_id) => self.line_id == line_id,
+            LineRef::MenuNumber(menu_number) => self.menu_number == menu_number
+        }
     }
 }
 
 impl State {
-    fn are_drinks_outstanding(&self, menu_numbers: &[i32]) -> bool {
-        let mut current_outstanding_drinks = self.outstanding_drinks.clone();
-
-        for menu_number in menu_numbers {
-            if let Some(index) = current_outstanding_drinks.iter().position(|x| x.menu_number == *menu_number) {
-                current_outstanding_drinks.remove(index);
-            } else {
-                return false;
-            }
+    pub fn served_items_value(&self) -> Money {
+        self.served_items_value
+    }
+
+    // What closing the tab takes: the served items less the discounts, which price corrections
+    // may have left larger than them.
+    pub fn amount_due(&self) -> Money {
+        if self.discount_value > self.served_items_value {
+            Money::zero(self.served_items_value.currency())
+        } else {
+            self.served_items_value - self.discount_value
         }
+    }
 
-        true
+    pub fn tab_value(&self) -> Money {
+        self.outstanding_drinks.iter().chain(self.outstanding_food.iter()).fold(self.served_items_value, |total, item| total + item.total())
     }
 
-    fn is_food_outstanding(&self, menu_numbers: &[i32]) -> bool {
-        let mut current_outstanding_food = self.outstanding_food.clone();
+    // The line id and menu number of each one served, the way the served events list them, or
+    // the lines left over once each is matched with one of an outstanding item, so serving two
+    // of something only ordered once leaves one over.
+    fn units(outstanding: &[OrderedItem], served: &[(LineRef, u32)]) -> Result<(Vec<Uuid>, Vec<i32>), Vec<LineRef>> {
+        let mut current_outstanding = outstanding.to_vec();
+        let (mut line_ids, mut menu_numbers, mut not_outstanding) = (Vec::new(), Vec::new(), Vec::new());
 
-        for menu_number in menu_numbers {
-            if let Some(index) = current_outstanding_food.iter().position(|x| x.menu_number == *menu_number) {
-                current_outstanding_food.remove(index);
-            } else {
-                return false;
+        for &(line, quantity) in served {
+            for _ in 0..quantity {
+                let line_id = current_outstanding.iter().find(|item| item.is(line)).map(|item| item.line_id);
+                match line_id.and_then(|line_id| State::take_one(&mut current_outstanding, line_id)) {
+                    Some(item) => {
+                        line_ids.push(item.line_id);
+                        menu_numbers.push(item.menu_number);
+                    },
+                    None => not_outstanding.push(line)
+                }
             }
         }
 
-        true
+        if not_outstanding.is_empty() { Ok((line_ids, menu_numbers)) } else { Err(not_outstanding) }
+    }
+
+    // Takes one of the first outstanding item of the line, dropping the item once none of it is
+    // left.
+    fn take_one(outstanding: &mut Vec<OrderedItem>, line_id: Uuid) -> Option<OrderedItem> {
+        let index = outstanding.iter().position(|item| item.line_id == line_id)?;
+        let item = &mut outstanding[index];
+        item.quantity -= 1;
+        let one = OrderedItem { quantity: 1, ..item.clone() };
+        if item.quantity == 0 {
+            outstanding.remove(index);
+        }
+        Some(one)
+    }
+
+    fn has_unserved_items(&self) -> bool {
+        !self.outstanding_drinks.is_empty() || !self.outstanding_food.is_empty()
+    }
+
+    fn close(&self, amount_paid: Money, receipt_number: Option<String>) -> Result<Event, CommandError> {
+        if !self.tab_open {
+            Err(CommandError::TabNotOpen)
+        } else if self.authorized_payment.is_some() {
+            Err(CommandError::PaymentNotCaptured)
+        } else if self.has_unserved_items() {
+            Err(CommandError::TabHasUnservedItems)
+        } else if amount_paid.currency() != self.served_items_value.currency() {
+            Err(CommandError::CurrencyMismatch)
+        } else if amount_paid < self.amount_due() {
+            Err(CommandError::MustPayEnough)
+        } else {
+            let order_value = self.amount_due();
+            Ok(Event::TabClosed { amount_paid, order_value, tip_value: amount_paid - order_value, receipt_number })
+        }
     }
 }
 
+// Tabs opened before the staff registry only named their waiter. The id they get is the one the
+// waiter was registered under when the registry started, see staff::legacy_id.
+struct WaiterIdFromName;
+
+impl Upcaster for WaiterIdFromName {
+    fn event_type(&self) -> &str {
+        "tab_opened"
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn upcast(&self, mut payload: Value) -> Value {
+        let waiter_id = payload["waiter"].as_str().map(staff::legacy_id);
+        if let Some(waiter_id) = waiter_id {
+            payload["waiter_id"] = json!(waiter_id);
+        }
+        payload
+    }
+}
+
+// Items ordered before lines had ids get the legacy line id of their menu number, as do the
+// serves, voids and comps of them. The same dish ordered twice then shares a line, and the first
+// of it ordered is served first, as it always was.
+struct LegacyLineIds(&'static str);
+
+impl Upcaster for LegacyLineIds {
+    fn event_type(&self) -> &str {
+        self.0
+    }
+
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn upcast(&self, mut payload: Value) -> Value {
+        let legacy = |menu_number: &Value| json!(menu_number.as_i64().map(|menu_number| legacy_line_id(menu_number as i32)));
+        match self.0 {
+            "drinks_ordered" | "food_ordered" => {
+                for item in payload["items"].as_array_mut().into_iter().flatten() {
+                    item["line_id"] = legacy(&item["menu_number"]);
+                }
+            },
+            "drinks_served" | "food_served" => {
+                let line_ids: Vec<Value> = payload["menu_numbers"].as_array().into_iter().flatten().map(legacy).collect();
+                payload["line_ids"] = json!(line_ids);
+            },
+            _ => payload["line_id"] = legacy(&payload["menu_number"])
+        }
+        payload
+    }
+}
+
+// What the tab log runs on read, for stores that keep history across schema changes.
+pub fn upcasters() -> Upcasters {
+    let mut upcasters = Upcasters::new();
+    upcasters.register(Box::new(WaiterIdFromName));
+    for event_type in ["drinks_ordered", "food_ordered", "drinks_served", "food_served", "item_voided", "item_comped"] {
+        upcasters.register(Box::new(LegacyLineIds(event_type)));
+    }
+    upcasters
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cqrs::Checked;
+    use crate::cqrs::testing::Scenario;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    fn tab_opened() -> Event {
+        Event::TabOpened { table_number: 42, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() }
+    }
+
+    // Lines in the given events are numbered after their menu number.
+    fn line(menu_number: i32) -> Uuid {
+        Uuid::from_u128(menu_number as u128)
+    }
+
+    fn item(menu_number: i32, is_drink: bool, price: Money) -> OrderedItem {
+        OrderedItem::new(menu_number, String::new(), is_drink, price).with_line_id(line(menu_number))
+    }
+
+    fn drinks_served(menu_numbers: &[i32]) -> Event {
+        Event::DrinksServed { line_ids: menu_numbers.iter().map(|&menu_number| line(menu_number)).collect(), menu_numbers: menu_numbers.to_vec() }
+    }
+
+    fn food_served(menu_numbers: &[i32]) -> Event {
+        Event::FoodServed { line_ids: menu_numbers.iter().map(|&menu_number| line(menu_number)).collect(), menu_numbers: menu_numbers.to_vec() }
+    }
+
+    fn menu_numbers(served: &[(i32, u32)]) -> Vec<(LineRef, u32)> {
+        served.iter().map(|&(menu_number, quantity)| (LineRef::MenuNumber(menu_number), quantity)).collect()
+    }
 
     #[test]
     fn can_open_a_new_tab() {
-        let state = Tab::initial_state();
-        let command = Command::OpenTab(Uuid::new_v4(), 42, "Derek".to_string());
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Ok(vec![Event::TabOpened { table_number: 42, waiter: "Derek".to_string() } ]));
+        Scenario::<Tab>::new()
+            .when(Command::OpenTab(Uuid::new_v4(), 42, staff::legacy_id("Derek"), "Derek".to_string()))
+            .then(vec![tab_opened()]);
+    }
+
+    #[test]
+    fn tabs_are_opened_only_once() {
+        let open = || Command::OpenTab(Uuid::new_v4(), 42, staff::legacy_id("Derek"), "Derek".to_string());
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(open())
+            .then_err(CommandError::TabAlreadyOpened);
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::TabClosed { amount_paid: eur(0), order_value: eur(0), tip_value: eur(0), receipt_number: None }])
+            .when(open())
+            .then_err(CommandError::TabAlreadyOpened);
+    }
+
+    #[test]
+    fn tabs_are_handed_over_only_by_their_waiter() {
+        let (derek, jane) = (staff::legacy_id("Derek"), staff::legacy_id("Jane"));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::ReassignWaiter(Uuid::new_v4(), derek, jane, "Jane".to_string()))
+            .then(vec![Event::WaiterReassigned { from_waiter_id: derek, waiter_id: jane, waiter: "Jane".to_string() }]);
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::WaiterReassigned { from_waiter_id: derek, waiter_id: jane, waiter: "Jane".to_string() }])
+            .when(Command::ReassignWaiter(Uuid::new_v4(), derek, jane, "Jane".to_string()))
+            .then_err(CommandError::NotTabWaiter);
+        Scenario::<Tab>::new()
+            .when(Command::ReassignWaiter(Uuid::new_v4(), derek, jane, "Jane".to_string()))
+            .then_err(CommandError::TabNotOpen);
+    }
+
+    #[test]
+    fn managers_can_force_close_a_tab_with_unserved_items() {
+        let soup = item(2, false, eur(450));
+        let coffee = item(1, true, eur(250));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![coffee] }, drinks_served(&[1]), Event::FoodOrdered { items: vec![soup] }])
+            .when(Command::ForceCloseTab(Uuid::new_v4(), "Guests left".to_string()))
+            .then(vec![Event::TabForceClosed { reason: "Guests left".to_string(), unpaid_value: eur(250) }]);
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::TabForceClosed { reason: "Guests left".to_string(), unpaid_value: eur(0) }])
+            .when(Command::ForceCloseTab(Uuid::new_v4(), "Guests left".to_string()))
+            .then_err(CommandError::TabNotOpen);
+    }
+
+    #[test]
+    fn tabs_move_with_their_guests() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::TransferTab(Uuid::new_v4(), 7))
+            .then(vec![Event::TabTransferred { from_table_number: 42, table_number: 7 }]);
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::TabTransferred { from_table_number: 42, table_number: 7 }])
+            .when(Command::TransferTab(Uuid::new_v4(), 7))
+            .then(vec![]);
+        Scenario::<Tab>::new()
+            .when(Command::TransferTab(Uuid::new_v4(), 7))
+            .then_err(CommandError::TabNotOpen);
+    }
+
+    #[test]
+    fn comps_and_discounts_come_off_the_order_value() {
+        let soup = item(2, false, eur(450));
+        let coffee = item(1, true, eur(250));
+        let served = vec![tab_opened(), Event::DrinksOrdered { items: vec![coffee] }, Event::FoodOrdered { items: vec![soup] }, drinks_served(&[1]), food_served(&[2])];
+        Scenario::<Tab>::new()
+            .given(served.clone())
+            .when(Command::CompItem(Uuid::new_v4(), LineRef::MenuNumber(2), "Soup was cold".to_string()))
+            .then(vec![Event::ItemComped { line_id: line(2), menu_number: 2, value: eur(450), reason: "Soup was cold".to_string() }]);
+        let comped = served.into_iter().chain(vec![Event::ItemComped { line_id: line(2), menu_number: 2, value: eur(450), reason: "Soup was cold".to_string() }]).collect::<Vec<_>>();
+        Scenario::<Tab>::new()
+            .given(comped.clone())
+            .when(Command::ApplyDiscount(Uuid::new_v4(), 10, "Regulars".to_string()))
+            .then(vec![Event::DiscountApplied { percent: 10, amount: eur(25), reason: "Regulars".to_string() }]);
+        Scenario::<Tab>::new()
+            .given(comped.into_iter().chain(vec![Event::DiscountApplied { percent: 10, amount: eur(25), reason: "Regulars".to_string() }]).collect())
+            .when(Command::CloseTab(Uuid::new_v4(), eur(300), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(300), order_value: eur(225), tip_value: eur(75), receipt_number: None }]);
+    }
+
+    #[test]
+    fn discounts_can_not_exceed_the_served_value() {
+        let coffee = item(1, true, eur(250));
+        let served = vec![tab_opened(), Event::DrinksOrdered { items: vec![coffee.clone(), coffee] }, drinks_served(&[1])];
+        Scenario::<Tab>::new()
+            .given(served.clone())
+            .when(Command::ApplyDiscount(Uuid::new_v4(), 0, "Regulars".to_string()))
+            .then_err(CommandError::InvalidDiscount);
+        Scenario::<Tab>::new()
+            .given(served.clone())
+            .when(Command::CompItem(Uuid::new_v4(), LineRef::MenuNumber(2), "Never ordered".to_string()))
+            .then_err(CommandError::ItemNotServed(LineRef::MenuNumber(2)));
+
+        let discounted = served.into_iter().chain(vec![Event::DiscountApplied { percent: 60, amount: eur(150), reason: "Regulars".to_string() }]).collect::<Vec<_>>();
+        Scenario::<Tab>::new()
+            .given(discounted.clone())
+            .when(Command::ApplyDiscount(Uuid::new_v4(), 50, "Birthday".to_string()))
+            .then_err(CommandError::DiscountExceedsTabValue);
+        Scenario::<Tab>::new()
+            .given(discounted)
+            .when(Command::CompItem(Uuid::new_v4(), LineRef::MenuNumber(1), "Spilled".to_string()))
+            .then_err(CommandError::DiscountExceedsTabValue);
     }
 
     #[test]
     fn can_not_order_with_unopened_tab() {
-        let state = Tab::initial_state();
-        let command = Command::PlaceOrder(Uuid::new_v4(), vec![ OrderedItem { menu_number: 0, description: String::new(), is_drink: true, price: 0.0 } ]);
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Err(CommandError::TabNotOpen));
+        Scenario::<Tab>::new()
+            .when(Command::PlaceOrder(Uuid::new_v4(), vec![item(0, true, eur(0))]))
+            .then_err(CommandError::TabNotOpen);
     }
 
     #[test]
     fn can_place_drinks_order() {
-        let mut state = Tab::initial_state();
-        Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: String::from("Derek") });
-        let drink1 = OrderedItem { menu_number: 0, description: String::from(""), is_drink: true, price: 0.0 };
-        let drink2 = OrderedItem { menu_number: 0, description: String::from(""), is_drink: true, price: 0.0 };
-        let command = Command::PlaceOrder(Uuid::new_v4(), vec![drink1.clone(), drink2.clone()]);
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Ok(vec![Event::DrinksOrdered { items: vec![drink1, drink2] }]));
+        let tab_id = Uuid::new_v4();
+        let drink1 = item(0, true, eur(0));
+        let drink2 = item(0, true, eur(0));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::PlaceOrder(tab_id, vec![drink1.clone(), drink2.clone()]))
+            .then(vec![Event::DrinksOrdered { items: vec![drink1.with_line_id(line_id(tab_id, 1)), drink2.with_line_id(line_id(tab_id, 2))] }]);
     }
 
     #[test]
     fn can_place_food_order() {
-        let mut state = Tab::initial_state();
-        Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: String::from("Derek") });
-        let food1 = OrderedItem { menu_number: 0, description: String::from(""), is_drink: false, price: 0.0 };
-        let food2 = OrderedItem { menu_number: 0, description: String::from(""), is_drink: false, price: 0.0 };
-        let command = Command::PlaceOrder(Uuid::new_v4(), vec![food1.clone(), food2.clone()]);
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Ok(vec![Event::FoodOrdered { items: vec![food1, food2] }]));
+        let tab_id = Uuid::new_v4();
+        let food1 = item(0, false, eur(0));
+        let food2 = item(0, false, eur(0));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::PlaceOrder(tab_id, vec![food1.clone(), food2.clone()]))
+            .then(vec![Event::FoodOrdered { items: vec![food1.with_line_id(line_id(tab_id, 1)), food2.with_line_id(line_id(tab_id, 2))] }]);
     }
 
     #[test]
     fn can_place_food_and_drink_order() {
-        let mut state = Tab::initial_state();
-        Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: String::from("Derek") });
-        let food = OrderedItem { menu_number: 0, description: String::from(""), is_drink: false, price: 0.0 };
-        let drink = OrderedItem { menu_number: 0, description: String::from(""), is_drink: true, price: 0.0 };
-        let command = Command::PlaceOrder(Uuid::new_v4(), vec![food.clone(), drink.clone()]);
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Ok(vec![Event::FoodOrdered { items: vec![food] }, Event::DrinksOrdered { items: vec![drink] }]));
+        let tab_id = Uuid::new_v4();
+        let food = item(0, false, eur(0));
+        let drink = item(0, true, eur(0));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![item(1, true, eur(0))] }])
+            .when(Command::PlaceOrder(tab_id, vec![food.clone(), drink.clone()]))
+            .then(vec![Event::FoodOrdered { items: vec![food.with_line_id(line_id(tab_id, 2))] }, Event::DrinksOrdered { items: vec![drink.with_line_id(line_id(tab_id, 3))] }]);
+    }
+
+    #[test]
+    fn modifiers_and_notes_are_ordered_with_the_item() {
+        let tab_id = Uuid::new_v4();
+        let iced_tea = item(1, true, eur(300)).with_modifiers(vec!["No ice".to_string(), "Extra lemon".to_string()]).with_quantity(2);
+        let soup = item(2, false, eur(450)).with_note(Some("Nut allergy".to_string()));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::PlaceOrder(tab_id, vec![iced_tea.clone(), soup.clone()]))
+            .then(vec![Event::FoodOrdered { items: vec![soup.clone().with_line_id(line_id(tab_id, 2))] }, Event::DrinksOrdered { items: vec![iced_tea.clone().with_line_id(line_id(tab_id, 1))] }]);
+
+        assert!(iced_tea.units().all(|unit| unit.modifiers() == iced_tea.modifiers()));
+        let ordered = serde_json::to_value(Event::FoodOrdered { items: vec![soup, item(3, false, eur(900))] }).unwrap();
+        assert_eq!((&ordered["items"][0]["note"], &ordered["items"][0]["modifiers"]), (&json!("Nut allergy"), &Value::Null));
+        assert_eq!(ordered["items"][1].get("note"), None);
+    }
+
+    #[test]
+    fn courses_are_fired_once_food_is_held_for_them() {
+        let (salad, steak) = (item(3, false, eur(650)).with_course(Some(Course::Starter)), item(7, false, eur(1800)).with_course(Some(Course::Main)));
+        let ordered = vec![tab_opened(), Event::FoodOrdered { items: vec![salad, steak] }];
+        Scenario::<Tab>::new()
+            .given(ordered.clone())
+            .when(Command::FireCourse(Uuid::new_v4(), Course::Starter))
+            .then(vec![Event::CourseFired { course: Course::Starter }]);
+        Scenario::<Tab>::new()
+            .given(ordered.iter().cloned().chain(vec![Event::CourseFired { course: Course::Starter }]).collect())
+            .when(Command::FireCourse(Uuid::new_v4(), Course::Starter))
+            .then_err(CommandError::CourseAlreadyFired(Course::Starter));
+        Scenario::<Tab>::new()
+            .given(ordered.clone())
+            .when(Command::FireCourse(Uuid::new_v4(), Course::Dessert))
+            .then_err(CommandError::CourseNotOrdered(Course::Dessert));
+        Scenario::<Tab>::new()
+            .given(ordered.into_iter().chain(vec![food_served(&[7])]).collect())
+            .when(Command::FireCourse(Uuid::new_v4(), Course::Main))
+            .then_err(CommandError::CourseNotOrdered(Course::Main));
+        Scenario::<Tab>::new()
+            .when(Command::FireCourse(Uuid::new_v4(), Course::Main))
+            .then_err(CommandError::TabNotOpen);
+    }
+
+    #[test]
+    fn unordered_items_are_served_on_lines_of_their_own_free_of_charge() {
+        let tab_id = Uuid::new_v4();
+        let (water, bread) = (item(90, true, eur(0)), item(91, false, eur(200)));
+        let coffee = item(1, true, eur(250));
+        let ordered = vec![tab_opened(), Event::DrinksOrdered { items: vec![coffee] }];
+        Scenario::<Tab>::new()
+            .given(ordered.clone())
+            .when(Command::ServeUnordered(tab_id, vec![water.clone().with_quantity(4), bread.clone()]))
+            .then(vec![Event::UnorderedItemsServed { items: vec![water.with_quantity(4).with_line_id(line_id(tab_id, 2)), item(91, false, eur(0)).with_line_id(line_id(tab_id, 3))] }]);
+        Scenario::<Tab>::new()
+            .given(ordered.into_iter().chain(vec![Event::UnorderedItemsServed { items: vec![bread.clone().with_line_id(line_id(tab_id, 2))] }, drinks_served(&[1])]).collect())
+            .when(Command::CloseTab(tab_id, eur(250), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(250), order_value: eur(250), tip_value: eur(0), receipt_number: None }]);
+        Scenario::<Tab>::new()
+            .when(Command::ServeUnordered(tab_id, vec![bread]))
+            .then_err(CommandError::TabNotOpen);
+    }
+
+    #[test]
+    fn tabs_paid_through_the_gateway_close_once_the_payment_is_captured() {
+        let coffee = item(1, true, eur(250));
+        let served = vec![tab_opened(), Event::DrinksOrdered { items: vec![coffee] }, drinks_served(&[1])];
+        let authorized = Event::PaymentAuthorized { authorization_id: "auth_1".to_string(), amount: eur(300) };
+        let pending: Vec<Event> = served.iter().cloned().chain(vec![authorized.clone()]).collect();
+        Scenario::<Tab>::new()
+            .given(served.clone())
+            .when(Command::AuthorizePayment(Uuid::new_v4(), "auth_1".to_string(), eur(300)))
+            .then(vec![authorized.clone()]);
+        Scenario::<Tab>::new()
+            .given(pending.clone())
+            .when(Command::CloseTab(Uuid::new_v4(), eur(300), None))
+            .then_err(CommandError::PaymentNotCaptured);
+        Scenario::<Tab>::new()
+            .given(pending.clone())
+            .when(Command::AuthorizePayment(Uuid::new_v4(), "auth_2".to_string(), eur(300)))
+            .then_err(CommandError::PaymentNotCaptured);
+        Scenario::<Tab>::new()
+            .given(pending.clone())
+            .when(Command::CapturePayment(Uuid::new_v4(), "auth_2".to_string(), "cap_1".to_string()))
+            .then_err(CommandError::PaymentNotAuthorized);
+        Scenario::<Tab>::new()
+            .given(pending.clone())
+            .when(Command::CapturePayment(Uuid::new_v4(), "auth_1".to_string(), "cap_1".to_string()))
+            .then(vec![Event::PaymentCaptured { authorization_id: "auth_1".to_string(), capture_id: "cap_1".to_string(), amount: eur(300) }]);
+        Scenario::<Tab>::new()
+            .given(pending.iter().cloned().chain(vec![Event::PaymentCaptured { authorization_id: "auth_1".to_string(), capture_id: "cap_1".to_string(), amount: eur(300) }]).collect())
+            .when(Command::CloseTab(Uuid::new_v4(), eur(300), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(300), order_value: eur(250), tip_value: eur(50), receipt_number: None }]);
+        Scenario::<Tab>::new()
+            .given(pending.into_iter().chain(vec![Event::PaymentFailed { amount: eur(300), reason: "card declined".to_string() }]).collect())
+            .when(Command::CapturePayment(Uuid::new_v4(), "auth_1".to_string(), "cap_1".to_string()))
+            .then_err(CommandError::PaymentNotAuthorized);
+        Scenario::<Tab>::new()
+            .given(served)
+            .when(Command::AuthorizePayment(Uuid::new_v4(), "auth_1".to_string(), Money::new(300, Currency::USD)))
+            .then_err(CommandError::CurrencyMismatch);
+    }
+
+    #[test]
+    fn the_same_dish_ordered_twice_is_served_and_voided_by_its_line() {
+        let tab_id = Uuid::new_v4();
+        let (rare, well_done) = (line_id(tab_id, 1), line_id(tab_id, 2));
+        let steaks = vec![item(7, false, eur(1800)).with_line_id(rare), item(7, false, eur(1800)).with_line_id(well_done)];
+        let ordered = vec![tab_opened(), Event::FoodOrdered { items: steaks }];
+        Scenario::<Tab>::new()
+            .given(ordered.clone())
+            .when(Command::MarkFoodServed(tab_id, vec![(LineRef::Line(well_done), 1)]))
+            .then(vec![Event::FoodServed { line_ids: vec![well_done], menu_numbers: vec![7] }]);
+        Scenario::<Tab>::new()
+            .given(ordered.clone())
+            .when(Command::MarkFoodServed(tab_id, vec![(LineRef::Line(well_done), 2)]))
+            .then_err(CommandError::FoodNotOutstanding(vec![LineRef::Line(well_done)]));
+
+        let served = ordered.into_iter().chain(vec![Event::FoodServed { line_ids: vec![well_done], menu_numbers: vec![7] }]).collect::<Vec<_>>();
+        Scenario::<Tab>::new()
+            .given(served.clone())
+            .when(Command::VoidOrderedItem(tab_id, LineRef::Line(well_done), "Sent back".to_string()))
+            .then_err(CommandError::ItemNotOutstanding(LineRef::Line(well_done)));
+        Scenario::<Tab>::new()
+            .given(served.clone())
+            .when(Command::VoidOrderedItem(tab_id, LineRef::MenuNumber(7), "Sent back".to_string()))
+            .then(vec![Event::ItemVoided { line_id: rare, menu_number: 7, reason: "Sent back".to_string() }]);
+        Scenario::<Tab>::new()
+            .given(served.into_iter().chain(vec![Event::ItemVoided { line_id: rare, menu_number: 7, reason: "Sent back".to_string() }]).collect())
+            .when(Command::CloseTab(tab_id, eur(1800), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(1800), order_value: eur(1800), tip_value: eur(0), receipt_number: None }]);
     }
 
     #[test]
     fn ordered_drinks_can_be_served() {
-        let mut state = Tab::initial_state();
-        Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: "Derek".to_string() });
-        let drink1 = OrderedItem { menu_number: 1, description: "".to_string(), is_drink: true, price: 0.0 };
-        let drink2 = OrderedItem { menu_number: 2, description: "".to_string(), is_drink: true, price: 0.0 };
-        Tab::evolve(&mut state, Event::DrinksOrdered { items: vec![drink1.clone(), drink2.clone()] });
-        let command = Command::MarkDrinksServed(Uuid::new_v4(), vec![drink1.menu_number, drink2.menu_number]);
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Ok(vec![Event::DrinksServed { menu_numbers: vec![drink1.menu_number, drink2.menu_number] }]));
+        let drink1 = item(1, true, eur(0));
+        let drink2 = item(2, true, eur(0));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![drink1, drink2] }])
+            .when(Command::MarkDrinksServed(Uuid::new_v4(), menu_numbers(&[(1, 1), (2, 1)])))
+            .then(vec![drinks_served(&[1, 2])]);
     }
 
     #[test]
     fn can_not_serve_an_unordered_drink() {
-         let mut state = Tab::initial_state();
-         Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: "Derek".to_string() });
-         let drink1 = OrderedItem { menu_number: 1, description: "".to_string(), is_drink: true, price: 0.0 };
-         let drink2 = OrderedItem { menu_number: 2, description: "".to_string(), is_drink: true, price: 0.0 };
-         Tab::evolve(&mut state, Event::DrinksOrdered { items: vec![drink1.clone()] });
-         let command = Command::MarkDrinksServed(Uuid::new_v4(), vec![drink2.menu_number]);
-         let events = Tab::decide(&state, command);
-         assert_eq!(events, Err(CommandError::DrinksNotOutstanding));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![item(1, true, eur(0))] }])
+            .when(Command::MarkDrinksServed(Uuid::new_v4(), menu_numbers(&[(2, 1)])))
+            .then_err(CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(2)]));
     }
 
     #[test]
     fn can_not_serve_an_ordered_drink_twice() {
-         let mut state = Tab::initial_state();
-         Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: "Derek".to_string() });
-         let drink = OrderedItem { menu_number: 1, description: "".to_string(), is_drink: true, price: 0.0 };
-         Tab::evolve(&mut state, Event::DrinksOrdered { items: vec![drink.clone()] });
-         Tab::evolve(&mut state, Event::DrinksServed { menu_numbers: vec![drink.menu_number] });
-         let command = Command::MarkDrinksServed(Uuid::new_v4(), vec![drink.menu_number]);
-         let events = Tab::decide(&state, command);
-         assert_eq!(events, Err(CommandError::DrinksNotOutstanding));
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::DrinksOrdered { items: vec![item(1, true, eur(0))] },
+                drinks_served(&[1])
+            ])
+            .when(Command::MarkDrinksServed(Uuid::new_v4(), menu_numbers(&[(1, 1)])))
+            .then_err(CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(1)]));
+    }
+
+    #[test]
+    fn only_the_drinks_not_outstanding_are_reported() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![item(1, true, eur(0)), item(2, true, eur(0))] }])
+            .when(Command::MarkDrinksServed(Uuid::new_v4(), menu_numbers(&[(1, 1), (3, 1), (2, 1), (1, 1)])))
+            .then_err(CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(3), LineRef::MenuNumber(1)]));
+    }
+
+    #[test]
+    fn items_ordered_in_quantity_are_served_some_at_a_time() {
+        let coffees = item(1, true, eur(250)).with_quantity(3);
+        let ordered = serde_json::to_value(Event::DrinksOrdered { items: vec![coffees.clone()] }).unwrap();
+        assert_eq!(ordered["items"][0]["quantity"], json!(3));
+        let recorded_before_quantities: OrderedItem = serde_json::from_value(json!({ "menu_number": 1, "description": "", "is_drink": true, "price": eur(250) })).unwrap();
+        assert_eq!(recorded_before_quantities.quantity(), 1);
+
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![coffees.clone()] }])
+            .when(Command::MarkDrinksServed(Uuid::new_v4(), menu_numbers(&[(1, 2)])))
+            .then(vec![drinks_served(&[1, 1])]);
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![coffees.clone()] }, drinks_served(&[1, 1])])
+            .when(Command::MarkDrinksServed(Uuid::new_v4(), menu_numbers(&[(1, 2)])))
+            .then_err(CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(1)]));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::DrinksOrdered { items: vec![coffees] }, drinks_served(&[1]), Event::ItemVoided { line_id: line(1), menu_number: 1, reason: "Spilled".to_string() }])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(250), None))
+            .then_err(CommandError::TabHasUnservedItems);
     }
 
     #[test]
     fn ordered_food_can_be_served() {
-        let mut state = Tab::initial_state();
-        Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: "Derek".to_string() });
-        let food1 = OrderedItem { menu_number: 1, description: "".to_string(), is_drink: false, price: 0.0 };
-        let food2 = OrderedItem { menu_number: 2, description: "".to_string(), is_drink: false, price: 0.0 };
-        Tab::evolve(&mut state, Event::FoodOrdered { items: vec![food1.clone(), food2.clone()] });
-        let command = Command::MarkFoodServed(Uuid::new_v4(), vec![food1.menu_number, food2.menu_number]);
-        let events = Tab::decide(&state, command);
-        assert_eq!(events, Ok(vec![Event::FoodServed { menu_numbers: vec![food1.menu_number, food2.menu_number] }]));
+        let food1 = item(1, false, eur(0));
+        let food2 = item(2, false, eur(0));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::FoodOrdered { items: vec![food1, food2] }])
+            .when(Command::MarkFoodServed(Uuid::new_v4(), menu_numbers(&[(1, 1), (2, 1)])))
+            .then(vec![food_served(&[1, 2])]);
     }
 
     #[test]
     fn can_not_serve_an_unordered_food() {
-         let mut state = Tab::initial_state();
-         Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: "Derek".to_string() });
-         let food1 = OrderedItem { menu_number: 1, description: "".to_string(), is_drink: false, price: 0.0 };
-         let food2 = OrderedItem { menu_number: 2, description: "".to_string(), is_drink: false, price: 0.0 };
-         Tab::evolve(&mut state, Event::FoodOrdered { items: vec![food1.clone()] });
-         let command = Command::MarkFoodServed(Uuid::new_v4(), vec![food2.menu_number]);
-         let events = Tab::decide(&state, command);
-         assert_eq!(events, Err(CommandError::FoodNotOutstanding));
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::FoodOrdered { items: vec![item(1, false, eur(0))] }])
+            .when(Command::MarkFoodServed(Uuid::new_v4(), menu_numbers(&[(2, 1)])))
+            .then_err(CommandError::FoodNotOutstanding(vec![LineRef::MenuNumber(2)]));
     }
 
     #[test]
     fn can_not_serve_an_ordered_food_twice() {
-         let mut state = Tab::initial_state();
-         Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter: "Derek".to_string() });
-         let food = OrderedItem { menu_number: 1, description: "".to_string(), is_drink: false, price: 0.0 };
-         Tab::evolve(&mut state, Event::FoodOrdered { items: vec![food.clone()] });
-         Tab::evolve(&mut state, Event::FoodServed { menu_numbers: vec![food.menu_number] });
-         let command = Command::MarkFoodServed(Uuid::new_v4(), vec![food.menu_number]);
-         let events = Tab::decide(&state, command);
-         assert_eq!(events, Err(CommandError::FoodNotOutstanding));
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::FoodOrdered { items: vec![item(1, false, eur(0))] },
+                food_served(&[1])
+            ])
+            .when(Command::MarkFoodServed(Uuid::new_v4(), menu_numbers(&[(1, 1)])))
+            .then_err(CommandError::FoodNotOutstanding(vec![LineRef::MenuNumber(1)]));
+    }
+
+    #[test]
+    fn only_outstanding_food_can_run_late() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::FoodOrdered { items: vec![item(1, false, eur(0))] }])
+            .when(Command::FlagLateFood(Uuid::new_v4(), vec![1]))
+            .then(vec![Event::FoodRunningLate { menu_numbers: vec![1] }]);
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::FoodOrdered { items: vec![item(1, false, eur(0))] },
+                food_served(&[1])
+            ])
+            .when(Command::FlagLateFood(Uuid::new_v4(), vec![1]))
+            .then_err(CommandError::FoodNotOutstanding(vec![LineRef::MenuNumber(1)]));
+    }
+
+    #[test]
+    fn can_close_tab_by_paying_exact_amount() {
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::FoodOrdered { items: vec![item(1, false, eur(450))] },
+                Event::DrinksOrdered { items: vec![item(2, true, eur(150))] },
+                food_served(&[1]),
+                drinks_served(&[2])
+            ])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(600), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(600), order_value: eur(600), tip_value: eur(0), receipt_number: None }]);
+    }
+
+    #[test]
+    fn can_close_tab_with_tip() {
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::DrinksOrdered { items: vec![item(1, true, eur(250))] },
+                drinks_served(&[1])
+            ])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(300), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(300), order_value: eur(250), tip_value: eur(50), receipt_number: None }]);
+    }
+
+    #[test]
+    fn corrected_prices_count_towards_what_must_be_paid() {
+        let correction = PriceCorrection { menu_number: 1, charged: eur(250), correct: eur(200), count: 1, reason: "Happy hour".to_string() };
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::DrinksOrdered { items: vec![item(1, true, eur(250))] },
+                drinks_served(&[1]),
+                Event::ServedPriceCorrected { menu_number: 1, charged: eur(250), correct: eur(200), count: 1, reason: "Happy hour".to_string() }
+            ])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(200), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(200), order_value: eur(200), tip_value: eur(0), receipt_number: None }]);
+
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::CorrectServedPrices(Uuid::new_v4(), vec![PriceCorrection { correct: Money::new(200, Currency::USD), ..correction }]))
+            .then_err(CommandError::CurrencyMismatch);
+    }
+
+    #[test]
+    fn must_pay_enough_to_close_tab() {
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::DrinksOrdered { items: vec![item(1, true, eur(250))] },
+                drinks_served(&[1])
+            ])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(200), None))
+            .then_err(CommandError::MustPayEnough);
+    }
+
+    #[test]
+    fn can_not_close_tab_with_unserved_items() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::FoodOrdered { items: vec![item(1, false, eur(450))] }])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(1000), None))
+            .then_err(CommandError::TabHasUnservedItems);
+    }
+
+    #[test]
+    fn can_not_close_tab_twice() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::TabClosed { amount_paid: eur(0), order_value: eur(0), tip_value: eur(0), receipt_number: None }])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(0), None))
+            .then_err(CommandError::TabNotOpen);
+    }
+
+    #[test]
+    fn must_pay_in_tab_currency() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::CloseTab(Uuid::new_v4(), Money::new(100, Currency::USD), None))
+            .then_err(CommandError::CurrencyMismatch);
+    }
+
+    #[test]
+    fn can_void_an_ordered_item() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), Event::FoodOrdered { items: vec![item(1, false, eur(450))] }])
+            .when(Command::VoidOrderedItem(Uuid::new_v4(), LineRef::MenuNumber(1), "Wrong table".to_string()))
+            .then(vec![Event::ItemVoided { line_id: line(1), menu_number: 1, reason: "Wrong table".to_string() }]);
+    }
+
+    #[test]
+    fn can_not_void_a_served_item() {
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::DrinksOrdered { items: vec![item(1, true, eur(250))] },
+                drinks_served(&[1])
+            ])
+            .when(Command::VoidOrderedItem(Uuid::new_v4(), LineRef::MenuNumber(1), "".to_string()))
+            .then_err(CommandError::ItemNotOutstanding(LineRef::MenuNumber(1)));
+    }
+
+    #[test]
+    fn voided_items_do_not_block_closing_the_tab() {
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::DrinksOrdered { items: vec![item(1, true, eur(250))] },
+                Event::ItemVoided { line_id: line(1), menu_number: 1, reason: "".to_string() }
+            ])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(0), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(0), order_value: eur(0), tip_value: eur(0), receipt_number: None }]);
+    }
+
+    #[test]
+    fn can_split_the_bill_between_payers() {
+        let shares = vec![PaymentShare { payer: "Anna".to_string(), amount: eur(600) }, PaymentShare { payer: "Mart".to_string(), amount: eur(500) }];
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::FoodOrdered { items: vec![item(1, false, eur(1000))] },
+                food_served(&[1])
+            ])
+            .when(Command::CloseTabSplit(Uuid::new_v4(), shares, None))
+            .then(vec![
+                Event::TabClosedPartially { payer: "Anna".to_string(), amount_paid: eur(600) },
+                Event::TabClosedPartially { payer: "Mart".to_string(), amount_paid: eur(500) },
+                Event::TabClosed { amount_paid: eur(1100), order_value: eur(1000), tip_value: eur(100), receipt_number: None }
+            ]);
+    }
+
+    #[test]
+    fn split_shares_must_cover_served_items() {
+        let shares = vec![PaymentShare { payer: "Anna".to_string(), amount: eur(500) }, PaymentShare { payer: "Mart".to_string(), amount: eur(499) }];
+        Scenario::<Tab>::new()
+            .given(vec![
+                tab_opened(),
+                Event::FoodOrdered { items: vec![item(1, false, eur(1000))] },
+                food_served(&[1])
+            ])
+            .when(Command::CloseTabSplit(Uuid::new_v4(), shares, None))
+            .then_err(CommandError::MustPayEnough);
+    }
+
+    #[test]
+    fn split_shares_must_be_in_tab_currency() {
+        let shares = vec![PaymentShare { payer: "Anna".to_string(), amount: eur(500) }, PaymentShare { payer: "Mart".to_string(), amount: Money::new(500, Currency::USD) }];
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::CloseTabSplit(Uuid::new_v4(), shares, None))
+            .then_err(CommandError::CurrencyMismatch);
+    }
+
+    #[test]
+    fn events_carry_money_in_minor_units() {
+        let closed = Event::TabClosed { amount_paid: eur(1100), order_value: eur(1000), tip_value: eur(100), receipt_number: None };
+        assert_eq!(serde_json::to_value(&closed).unwrap(), json!({
+            "type": "tab_closed",
+            "amount_paid": { "amount_minor": 1100, "currency": "EUR" },
+            "order_value": { "amount_minor": 1000, "currency": "EUR" },
+            "tip_value": { "amount_minor": 100, "currency": "EUR" }
+        }));
+    }
+
+    #[test]
+    fn tabs_opened_before_the_staff_registry_get_the_waiters_legacy_id() {
+        let v1 = json!({ "type": "tab_opened", "table_number": 42, "waiter": "Derek" });
+        let upcast: Event = serde_json::from_value(upcasters().upcast("tab_opened", 1, v1)).unwrap();
+        assert_eq!(upcast, tab_opened());
+        assert_eq!(upcasters().current_version("tab_opened"), 2);
+    }
+
+    #[test]
+    fn items_ordered_before_lines_had_ids_are_known_by_their_menu_number() {
+        let ordered = json!({ "type": "drinks_ordered", "items": [{ "menu_number": 1, "description": "", "is_drink": true, "price": eur(250) }] });
+        let served = json!({ "type": "drinks_served", "menu_numbers": [1] });
+        let ordered: Event = serde_json::from_value(upcasters().upcast("drinks_ordered", 1, ordered)).unwrap();
+        let served: Event = serde_json::from_value(upcasters().upcast("drinks_served", 1, served)).unwrap();
+        assert_eq!(ordered, Event::DrinksOrdered { items: vec![item(1, true, eur(250)).with_line_id(legacy_line_id(1))] });
+        assert_eq!(served, Event::DrinksServed { line_ids: vec![legacy_line_id(1)], menu_numbers: vec![1] });
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened(), ordered, served])
+            .when(Command::CloseTab(Uuid::new_v4(), eur(250), None))
+            .then(vec![Event::TabClosed { amount_paid: eur(250), order_value: eur(250), tip_value: eur(0), receipt_number: None }]);
+    }
+
+    #[test]
+    fn can_not_order_items_with_negative_price() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::PlaceOrder(Uuid::new_v4(), vec![item(1, true, eur(-100))]))
+            .then_err(CommandError::InvalidPrice);
+    }
+
+    #[test]
+    fn can_not_order_items_priced_in_another_currency() {
+        Scenario::<Tab>::new()
+            .given(vec![tab_opened()])
+            .when(Command::PlaceOrder(Uuid::new_v4(), vec![item(1, true, Money::new(100, Currency::GBP))]))
+            .then_err(CommandError::CurrencyMismatch);
+    }
+
+    #[test]
+    #[should_panic(expected = "tab is not open but has outstanding items")]
+    fn checked_tab_panics_on_broken_invariant() {
+        let mut state = Tab::initial_state();
+        Checked::<Tab>::evolve(&mut state, Event::DrinksOrdered { items: vec![item(1, true, eur(100))] });
     }
 }
@@ -0,0 +1,413 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::cqrs::{named_stream_id, EventEnvelope, Metadata, ProcessManager};
+use crate::cqrs::store::{EventStore, StoreError};
+use crate::date::Date;
+use crate::domain;
+use crate::money::{Currency, Money};
+
+// What the tax authority is told of a paid tab, under the receipt number it was closed with.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct FiscalReceipt {
+    pub receipt_number: String,
+    pub tab_id: Uuid,
+    pub closed_at: SystemTime,
+    pub amount_paid: Money,
+    pub order_value: Money,
+    pub tip_value: Money
+}
+
+// A day's totals as the device has them: receipts registered and voided that day, and what they
+// came to between them.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct DailyFiscalReport {
+    pub date: Date,
+    pub receipts: usize,
+    pub voided: usize,
+    pub turnover: Money
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FiscalError {
+    // The device could not be reached or written to; asking again later may work.
+    Unavailable(String),
+    // The device refused, e.g. to void a receipt it never registered.
+    Rejected(String)
+}
+
+impl fmt::Display for FiscalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FiscalError::Unavailable(ref message) => write!(f, "fiscal device unavailable: {}", message),
+            FiscalError::Rejected(ref message) => write!(f, "fiscal device rejected the request: {}", message)
+        }
+    }
+}
+
+// A fiscal printer or e-invoicing service, for markets where every receipt has to be registered
+// with the tax authority. Registering gives back the device's own id for the receipt.
+#[async_trait]
+pub trait FiscalDevice: Send + Sync {
+    async fn register_receipt(&self, receipt: &FiscalReceipt) -> Result<String, FiscalError>;
+
+    async fn void_receipt(&self, receipt_number: &str, reason: &str) -> Result<(), FiscalError>;
+
+    async fn daily_report(&self, date: Date) -> Result<DailyFiscalReport, FiscalError>;
+}
+
+// Which device receipts are registered with, from a table of the configuration:
+//
+//     [global.cafe.fiscal]
+//     device = "journal"
+//     path = "fiscal.journal"
+//
+// The mock keeps what it is sent in memory, for trying the flow out.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "device", rename_all = "snake_case")]
+pub enum FiscalSetup {
+    Mock,
+    Journal { path: PathBuf }
+}
+
+impl FiscalSetup {
+    pub fn open(&self) -> io::Result<Arc<dyn FiscalDevice>> {
+        match *self {
+            FiscalSetup::Mock => Ok(Arc::new(MockFiscalDevice::new())),
+            FiscalSetup::Journal { ref path } => Ok(Arc::new(FiscalJournal::open(path)?))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Registered { fiscal_id: String, receipt: FiscalReceipt },
+    Voided { receipt_number: String, reason: String, voided_at: SystemTime }
+}
+
+fn registered<'a>(entries: &'a [JournalEntry], receipt_number: &str) -> Option<(&'a str, &'a FiscalReceipt)> {
+    entries.iter().find_map(|entry| match *entry {
+        JournalEntry::Registered { ref fiscal_id, ref receipt } if receipt.receipt_number == receipt_number => Some((fiscal_id.as_str(), receipt)),
+        _ => None
+    })
+}
+
+fn is_voided(entries: &[JournalEntry], receipt_number: &str) -> bool {
+    entries.iter().any(|entry| matches!(*entry, JournalEntry::Voided { receipt_number: ref voided, .. } if voided == receipt_number))
+}
+
+// A receipt is registered once; registering it again gives back the id it got the first time.
+fn register_entry(entries: &[JournalEntry], receipt: &FiscalReceipt) -> (String, Option<JournalEntry>) {
+    match registered(entries, &receipt.receipt_number) {
+        Some((fiscal_id, _)) => (fiscal_id.to_string(), None),
+        None => {
+            let fiscal_id = format!("{:08}", entries.len() + 1);
+            (fiscal_id.clone(), Some(JournalEntry::Registered { fiscal_id, receipt: receipt.clone() }))
+        }
+    }
+}
+
+fn void_entry(entries: &[JournalEntry], receipt_number: &str, reason: &str) -> Result<JournalEntry, FiscalError> {
+    if registered(entries, receipt_number).is_none() {
+        return Err(FiscalError::Rejected(format!("receipt {} was never registered", receipt_number)));
+    }
+    if is_voided(entries, receipt_number) {
+        return Err(FiscalError::Rejected(format!("receipt {} is voided already", receipt_number)));
+    }
+    Ok(JournalEntry::Voided { receipt_number: receipt_number.to_string(), reason: reason.to_string(), voided_at: SystemTime::now() })
+}
+
+// Sales are counted on the day they were closed and voids on the day they were voided, so a
+// day's report does not change once the day is over.
+fn daily_report(entries: &[JournalEntry], date: Date) -> DailyFiscalReport {
+    let mut report = DailyFiscalReport { date, receipts: 0, voided: 0, turnover: Money::zero(Currency::default()) };
+    let mut turnover: Option<Money> = None;
+    for entry in entries {
+        match *entry {
+            JournalEntry::Registered { ref receipt, .. } if Date::of(receipt.closed_at) == date => {
+                report.receipts += 1;
+                turnover = Some(turnover.map_or(receipt.amount_paid, |total| total + receipt.amount_paid));
+            },
+            JournalEntry::Voided { ref receipt_number, voided_at, .. } if Date::of(voided_at) == date => {
+                report.voided += 1;
+                if let Some((_, receipt)) = registered(entries, receipt_number) {
+                    turnover = Some(turnover.map_or(-receipt.amount_paid, |total| total - receipt.amount_paid));
+                }
+            },
+            _ => {}
+        }
+    }
+    if let Some(turnover) = turnover {
+        report.turnover = turnover;
+    }
+    report
+}
+
+// Keeps what it is sent in memory. Can be told to act as if it were unplugged.
+#[derive(Default)]
+pub struct MockFiscalDevice {
+    entries: Mutex<Vec<JournalEntry>>,
+    unplugged: AtomicBool
+}
+
+impl MockFiscalDevice {
+    pub fn new() -> MockFiscalDevice {
+        MockFiscalDevice::default()
+    }
+
+    pub fn set_unplugged(&self, unplugged: bool) {
+        self.unplugged.store(unplugged, Ordering::SeqCst);
+    }
+
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    fn plugged_in(&self) -> Result<(), FiscalError> {
+        if self.unplugged.load(Ordering::SeqCst) {
+            Err(FiscalError::Unavailable("the mock device is unplugged".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl FiscalDevice for MockFiscalDevice {
+    async fn register_receipt(&self, receipt: &FiscalReceipt) -> Result<String, FiscalError> {
+        self.plugged_in()?;
+        let mut entries = self.entries.lock().unwrap();
+        let (fiscal_id, entry) = register_entry(&entries, receipt);
+        entries.extend(entry);
+        Ok(fiscal_id)
+    }
+
+    async fn void_receipt(&self, receipt_number: &str, reason: &str) -> Result<(), FiscalError> {
+        self.plugged_in()?;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = void_entry(&entries, receipt_number, reason)?;
+        entries.push(entry);
+        Ok(())
+    }
+
+    async fn daily_report(&self, date: Date) -> Result<DailyFiscalReport, FiscalError> {
+        self.plugged_in()?;
+        Ok(daily_report(&self.entries.lock().unwrap(), date))
+    }
+}
+
+// An electronic journal for markets that take fiscal records as a file: every registration and
+// void is appended as a JSON line, for the tax authority's export or a fiscal printer's driver to
+// pick up. The journal is read back on opening so receipts keep their ids across restarts.
+pub struct FiscalJournal {
+    journal: Mutex<(File, Vec<JournalEntry>)>
+}
+
+impl FiscalJournal {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FiscalJournal> {
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| serde_json::from_str(line).map_err(io::Error::from))
+                .collect::<io::Result<Vec<JournalEntry>>>()?,
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error)
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FiscalJournal { journal: Mutex::new((file, entries)) })
+    }
+
+    // The entry is only kept once it is written.
+    fn append(file: &mut File, entries: &mut Vec<JournalEntry>, entry: JournalEntry) -> Result<(), FiscalError> {
+        let line = serde_json::to_string(&entry).map_err(|error| FiscalError::Unavailable(error.to_string()))?;
+        writeln!(file, "{}", line).and_then(|_| file.sync_data()).map_err(|error| FiscalError::Unavailable(error.to_string()))?;
+        entries.push(entry);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FiscalDevice for FiscalJournal {
+    async fn register_receipt(&self, receipt: &FiscalReceipt) -> Result<String, FiscalError> {
+        let mut journal = self.journal.lock().unwrap();
+        let (ref mut file, ref mut entries) = *journal;
+        let (fiscal_id, entry) = register_entry(entries, receipt);
+        if let Some(entry) = entry {
+            FiscalJournal::append(file, entries, entry)?;
+        }
+        Ok(fiscal_id)
+    }
+
+    async fn void_receipt(&self, receipt_number: &str, reason: &str) -> Result<(), FiscalError> {
+        let mut journal = self.journal.lock().unwrap();
+        let (ref mut file, ref mut entries) = *journal;
+        let entry = void_entry(entries, receipt_number, reason)?;
+        FiscalJournal::append(file, entries, entry)
+    }
+
+    async fn daily_report(&self, date: Date) -> Result<DailyFiscalReport, FiscalError> {
+        Ok(daily_report(&self.journal.lock().unwrap().1, date))
+    }
+}
+
+// What became of each receipt, in a stream of its own found by the receipt number.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ReceiptRegistered { receipt_number: String, tab_id: Uuid, fiscal_id: String },
+    RegistrationFailed { receipt_number: String, tab_id: Uuid, error: String },
+    ReceiptVoided { receipt_number: String, reason: String }
+}
+
+pub fn receipt_id(receipt_number: &str) -> Uuid {
+    named_stream_id("fiscal", receipt_number)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    RegisterReceipt(FiscalReceipt)
+}
+
+// Registers every tab closed with a receipt number with the fiscal device. The device is only
+// asked once a receipt is recorded as registered in the fiscal log, see register, so the closes
+// replayed when the server starts are passed over, and ones that failed are tried again.
+pub struct FiscalRegistration;
+
+impl ProcessManager for FiscalRegistration {
+    type Event = domain::Event;
+    type Command = Command;
+    type State = ();
+
+    fn react(&self, _: &mut (), envelope: &EventEnvelope<domain::Event>) -> Vec<Command> {
+        match envelope.payload {
+            domain::Event::TabClosed { amount_paid, order_value, tip_value, receipt_number: Some(ref receipt_number) } => vec![Command::RegisterReceipt(FiscalReceipt {
+                receipt_number: receipt_number.clone(),
+                tab_id: envelope.stream_id,
+                closed_at: envelope.timestamp,
+                amount_paid,
+                order_value,
+                tip_value
+            })],
+            _ => Vec::new()
+        }
+    }
+
+    fn is_finished(&self, _: &()) -> bool {
+        true
+    }
+}
+
+fn is_registered(events: &[EventEnvelope<Event>]) -> bool {
+    events.iter().any(|envelope| matches!(envelope.payload, Event::ReceiptRegistered { .. }))
+}
+
+// Registers the receipt unless the log has it registered already, and records how it went.
+pub async fn register(device: &dyn FiscalDevice, store: &dyn EventStore<Event>, receipt: FiscalReceipt, metadata: Metadata) -> Result<(), StoreError> {
+    let stream_id = receipt_id(&receipt.receipt_number);
+    let stream = store.read_stream(stream_id).await?;
+    if is_registered(&stream.events) {
+        return Ok(());
+    }
+    let FiscalReceipt { ref receipt_number, tab_id, .. } = receipt;
+    let event = match device.register_receipt(&receipt).await {
+        Ok(fiscal_id) => Event::ReceiptRegistered { receipt_number: receipt_number.clone(), tab_id, fiscal_id },
+        Err(error) => Event::RegistrationFailed { receipt_number: receipt_number.clone(), tab_id, error: error.to_string() }
+    };
+    store.append(stream_id, vec![event], stream.version, &metadata).await.map(|_| ())
+}
+
+// A manager's void of a registered receipt, e.g. one closed on the wrong tab. A dry run stops
+// short of the device.
+pub async fn void(device: &dyn FiscalDevice, store: &dyn EventStore<Event>, receipt_number: &str, reason: &str, metadata: Metadata) -> Result<(), FiscalError> {
+    let stream_id = receipt_id(receipt_number);
+    let stream = store.read_stream(stream_id).await.map_err(|error| FiscalError::Unavailable(error.to_string()))?;
+    if !is_registered(&stream.events) {
+        return Err(FiscalError::Rejected(format!("receipt {} was never registered", receipt_number)));
+    }
+    if metadata.dry_run {
+        return Ok(());
+    }
+    device.void_receipt(receipt_number, reason).await?;
+    let voided = Event::ReceiptVoided { receipt_number: receipt_number.to_string(), reason: reason.to_string() };
+    store.append(stream_id, vec![voided], stream.version, &metadata).await.map(|_| ()).map_err(|error| FiscalError::Unavailable(error.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::cqrs::ProcessRunner;
+    use crate::cqrs::store::{InMemoryEventStore, InMemorySnapshotStore};
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    fn closed(receipt_number: Option<&str>) -> domain::Event {
+        domain::Event::TabClosed { amount_paid: eur(1100), order_value: eur(1000), tip_value: eur(100), receipt_number: receipt_number.map(str::to_string) }
+    }
+
+    async fn run(runner: &mut ProcessRunner<FiscalRegistration>, device: &dyn FiscalDevice, fiscal: &dyn EventStore<Event>, envelope: &EventEnvelope<domain::Event>) {
+        for (Command::RegisterReceipt(receipt), metadata) in runner.handle(envelope).unwrap() {
+            register(device, fiscal, receipt, metadata).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_tabs_are_registered_once_and_retried_after_failing() {
+        let (tabs, fiscal) = (InMemoryEventStore::new(), InMemoryEventStore::new());
+        let device = MockFiscalDevice::new();
+        let (paid, unnumbered) = (Uuid::new_v4(), Uuid::new_v4());
+        let paid_closed = tabs.append(paid, vec![closed(Some("OT-000001"))], 0, &Metadata::new()).await.unwrap();
+        let unnumbered_closed = tabs.append(unnumbered, vec![closed(None)], 0, &Metadata::new()).await.unwrap();
+
+        device.set_unplugged(true);
+        let mut runner = ProcessRunner::new(FiscalRegistration, Box::new(InMemorySnapshotStore::new()));
+        run(&mut runner, &device, &fiscal, &paid_closed[0]).await;
+        run(&mut runner, &device, &fiscal, &unnumbered_closed[0]).await;
+        let failed = fiscal.read_stream(receipt_id("OT-000001")).await.unwrap();
+        assert!(matches!(failed.events[0].payload, Event::RegistrationFailed { .. }));
+
+        // As after a restart, with the device back.
+        device.set_unplugged(false);
+        for _ in 0..2 {
+            let mut runner = ProcessRunner::new(FiscalRegistration, Box::new(InMemorySnapshotStore::new()));
+            run(&mut runner, &device, &fiscal, &paid_closed[0]).await;
+        }
+        let registered = fiscal.read_stream(receipt_id("OT-000001")).await.unwrap();
+        assert_eq!(registered.events[1].payload, Event::ReceiptRegistered { receipt_number: "OT-000001".to_string(), tab_id: paid, fiscal_id: "00000001".to_string() });
+        assert_eq!(registered.version, 2);
+        assert_eq!(device.entries().len(), 1);
+
+        assert_eq!(void(&device, &fiscal, "OT-000002", "Wrong tab", Metadata::new()).await, Err(FiscalError::Rejected("receipt OT-000002 was never registered".to_string())));
+        assert_eq!(void(&device, &fiscal, "OT-000001", "Wrong tab", Metadata::new()).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn the_journal_reports_the_day_net_of_voids() {
+        let path = env::temp_dir().join(format!("cafe-fiscal-{}", Uuid::new_v4()));
+        let now = SystemTime::now();
+        let receipt = |receipt_number: &str| FiscalReceipt { receipt_number: receipt_number.to_string(), tab_id: Uuid::new_v4(), closed_at: now, amount_paid: eur(1100), order_value: eur(1000), tip_value: eur(100) };
+        let journal = FiscalJournal::open(&path).unwrap();
+        assert_eq!(journal.register_receipt(&receipt("OT-000001")).await, Ok("00000001".to_string()));
+        assert_eq!(journal.register_receipt(&receipt("OT-000002")).await, Ok("00000002".to_string()));
+        journal.void_receipt("OT-000001", "Wrong tab").await.unwrap();
+
+        let reopened = FiscalJournal::open(&path).unwrap();
+        assert_eq!(reopened.register_receipt(&receipt("OT-000002")).await, Ok("00000002".to_string()));
+        assert!(matches!(reopened.void_receipt("OT-000001", "Again").await, Err(FiscalError::Rejected(_))));
+        let today = Date::of(now);
+        assert_eq!(reopened.daily_report(today).await, Ok(DailyFiscalReport { date: today, receipts: 2, voided: 1, turnover: eur(1100) }));
+        assert_eq!(reopened.daily_report(today.next()).await.map(|report| report.receipts), Ok(0));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use uuid::Uuid;
+
+use crate::cqrs::TimedProjection;
+use crate::date::Date;
+use crate::domain::{Event, OrderedItem};
+
+// How many past days the rate of sale is averaged over.
+const WINDOW_DAYS: usize = 28;
+
+// Services by hour of the day, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Daypart {
+    Breakfast,
+    Lunch,
+    Afternoon,
+    Dinner
+}
+
+impl Daypart {
+    pub fn at(time: SystemTime) -> Daypart {
+        let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        match seconds % (24 * 60 * 60) / (60 * 60) {
+            0..=10 => Daypart::Breakfast,
+            11..=14 => Daypart::Lunch,
+            15..=16 => Daypart::Afternoon,
+            _ => Daypart::Dinner
+        }
+    }
+
+    // The service after this one, and whether it is on the next day.
+    pub fn next(self) -> (Daypart, bool) {
+        match self {
+            Daypart::Breakfast => (Daypart::Lunch, false),
+            Daypart::Lunch => (Daypart::Afternoon, false),
+            Daypart::Afternoon => (Daypart::Dinner, false),
+            Daypart::Dinner => (Daypart::Breakfast, true)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PrepSuggestion {
+    pub menu_number: i32,
+    pub description: String,
+    pub daily_average: f64,
+    pub quantity: u32
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Forecast {
+    pub date: Date,
+    pub daypart: Daypart,
+    pub items: Vec<PrepSuggestion>
+}
+
+// Food sold per item, service and day, from which the kitchen gets how much to prepare for a
+// service: the average sold in that service over the last four weeks, rounded up.
+#[derive(Debug, Default)]
+pub struct SalesVelocity {
+    descriptions: BTreeMap<i32, String>,
+    sold: HashMap<(i32, Daypart), BTreeMap<Date, u32>>,
+    // Food still open to being voided, so a void takes back the sale it cancels.
+    unserved: HashMap<Uuid, Vec<(Uuid, Daypart, Date)>>
+}
+
+impl SalesVelocity {
+    pub fn new() -> SalesVelocity {
+        SalesVelocity::default()
+    }
+
+    // For the service after the one running at `now`.
+    pub fn next_service(&self, now: SystemTime) -> Forecast {
+        let (daypart, tomorrow) = Daypart::at(now).next();
+        let today = Date::of(now);
+        self.forecast(if tomorrow { today.next() } else { today }, daypart)
+    }
+
+    // Based on the full days before `date`.
+    pub fn forecast(&self, date: Date, daypart: Daypart) -> Forecast {
+        let mut first_day = date;
+        for _ in 0..WINDOW_DAYS {
+            first_day = first_day.previous();
+        }
+
+        let mut items = Vec::new();
+        for (&menu_number, description) in &self.descriptions {
+            let sold: u32 = self.sold.get(&(menu_number, daypart))
+                .map_or(0, |days| days.range(first_day..date).map(|(_, count)| count).sum());
+            if sold > 0 {
+                let daily_average = f64::from(sold) / WINDOW_DAYS as f64;
+                items.push(PrepSuggestion { menu_number, description: description.clone(), daily_average, quantity: daily_average.ceil() as u32 });
+            }
+        }
+        Forecast { date, daypart, items }
+    }
+
+    fn take_unserved(&mut self, tab_id: Uuid, line_id: Uuid) -> Option<(Daypart, Date)> {
+        let unserved = self.unserved.get_mut(&tab_id)?;
+        let index = unserved.iter().position(|&(line, ..)| line == line_id)?;
+        let (_, daypart, date) = unserved.remove(index);
+        Some((daypart, date))
+    }
+}
+
+impl TimedProjection for SalesVelocity {
+    type Event = Event;
+
+    fn apply_at(&mut self, tab_id: Uuid, event: &Event, timestamp: SystemTime) {
+        match *event {
+            Event::FoodOrdered { ref items } => {
+                let daypart = Daypart::at(timestamp);
+                let date = Date::of(timestamp);
+                for item in items.iter().flat_map(OrderedItem::units) {
+                    self.descriptions.insert(item.menu_number(), item.description().to_string());
+                    *self.sold.entry((item.menu_number(), daypart)).or_default().entry(date).or_insert(0) += 1;
+                    self.unserved.entry(tab_id).or_default().push((item.line_id(), daypart, date));
+                }
+            },
+            Event::FoodServed { ref line_ids, .. } => {
+                for line_id in line_ids {
+                    self.take_unserved(tab_id, *line_id);
+                }
+            },
+            Event::ItemVoided { line_id, menu_number, .. } => {
+                if let Some((daypart, date)) = self.take_unserved(tab_id, line_id) {
+                    if let Some(count) = self.sold.get_mut(&(menu_number, daypart)).and_then(|days| days.get_mut(&date)) {
+                        *count -= 1;
+                    }
+                }
+            },
+            Event::TabClosed { .. } | Event::TabForceClosed { .. } | Event::TabPurged { .. } => {
+                self.unserved.remove(&tab_id);
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::domain::OrderedItem;
+    use crate::money::{Currency, Money};
+
+    fn soup() -> OrderedItem {
+        OrderedItem::new(1, "Soup".to_string(), false, Money::new(450, Currency::EUR))
+    }
+
+    fn lunch_on(date: Date) -> SystemTime {
+        date.start() + Duration::from_secs(12 * 60 * 60)
+    }
+
+    #[test]
+    fn dayparts_follow_the_clock() {
+        let date: Date = "2017-06-15".parse().unwrap();
+        assert_eq!(Daypart::at(date.start()), Daypart::Breakfast);
+        assert_eq!(Daypart::at(lunch_on(date)), Daypart::Lunch);
+        assert_eq!(Daypart::at(date.start() + Duration::from_secs(20 * 60 * 60)), Daypart::Dinner);
+        assert_eq!(Daypart::Dinner.next(), (Daypart::Breakfast, true));
+    }
+
+    #[test]
+    fn forecast_averages_sales_over_the_window() {
+        let mut velocity = SalesVelocity::new();
+        let today: Date = "2017-06-15".parse().unwrap();
+        let mut day = today;
+        for _ in 0..WINDOW_DAYS + 5 {
+            day = day.previous();
+            let tab_id = Uuid::new_v4();
+            velocity.apply_at(tab_id, &Event::FoodOrdered { items: vec![soup(), soup()] }, lunch_on(day));
+        }
+        // Today's sales and voided orders do not count.
+        velocity.apply_at(Uuid::new_v4(), &Event::FoodOrdered { items: vec![soup()] }, lunch_on(today));
+        let (tab_id, line_id) = (Uuid::new_v4(), Uuid::new_v4());
+        velocity.apply_at(tab_id, &Event::FoodOrdered { items: vec![soup().with_line_id(line_id)] }, lunch_on(today.previous()));
+        velocity.apply_at(tab_id, &Event::ItemVoided { line_id, menu_number: 1, reason: "Sold out".to_string() }, lunch_on(today));
+
+        let forecast = velocity.forecast(today, Daypart::Lunch);
+        assert_eq!(forecast.items, vec![PrepSuggestion { menu_number: 1, description: "Soup".to_string(), daily_average: 2.0, quantity: 2 }]);
+        assert_eq!(velocity.forecast(today, Daypart::Dinner).items, vec![]);
+        assert_eq!(velocity.next_service(lunch_on(today)).daypart, Daypart::Afternoon);
+    }
+}
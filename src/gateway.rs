@@ -0,0 +1,308 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::cqrs::Metadata;
+use crate::domain::{Command, Event};
+use crate::money::Money;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaymentError {
+    // The provider turned the payment down, e.g. the card was declined.
+    Declined(String),
+    // The provider could not be reached; asking again later may work.
+    Unavailable(String)
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PaymentError::Declined(ref message) => write!(f, "payment declined: {}", message),
+            PaymentError::Unavailable(ref message) => write!(f, "payment provider unavailable: {}", message)
+        }
+    }
+}
+
+// A card terminal or payment service a tab is settled through. The amount is authorized first
+// and then captured under the authorization's id; capturing gives back the capture's own id,
+// which is what a refund is made against. An authorization that will not be captured is voided,
+// so the hold on the guest's card is released.
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    async fn authorize(&self, tab_id: Uuid, amount: Money) -> Result<String, PaymentError>;
+
+    async fn capture(&self, authorization_id: &str) -> Result<String, PaymentError>;
+
+    async fn void(&self, authorization_id: &str) -> Result<(), PaymentError>;
+
+    async fn refund(&self, capture_id: &str) -> Result<(), PaymentError>;
+}
+
+// Which provider tabs are settled through, from a table of the configuration:
+//
+//     [global.cafe.payment_gateway]
+//     provider = "mock"
+//
+// The mock approves every payment and keeps them in memory, for trying the flow out.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum PaymentSetup {
+    Mock
+}
+
+impl PaymentSetup {
+    pub fn open(&self) -> Arc<dyn PaymentProvider> {
+        match *self {
+            PaymentSetup::Mock => Arc::new(MockPaymentProvider::new())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockPayment {
+    pub authorization_id: String,
+    pub tab_id: Uuid,
+    pub amount: Money,
+    pub captured: bool,
+    pub voided: bool,
+    pub refunded: bool
+}
+
+impl MockPayment {
+    fn capture_id(&self) -> String {
+        self.authorization_id.replacen("auth", "cap", 1)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MockPaymentProvider {
+    payments: Mutex<Vec<MockPayment>>,
+    declining: AtomicBool,
+    declining_captures: AtomicBool
+}
+
+impl MockPaymentProvider {
+    pub fn new() -> MockPaymentProvider {
+        MockPaymentProvider::default()
+    }
+
+    pub fn set_declining(&self, declining: bool) {
+        self.declining.store(declining, Ordering::SeqCst);
+    }
+
+    pub fn set_declining_captures(&self, declining: bool) {
+        self.declining_captures.store(declining, Ordering::SeqCst);
+    }
+
+    pub fn payments(&self) -> Vec<MockPayment> {
+        self.payments.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for MockPaymentProvider {
+    async fn authorize(&self, tab_id: Uuid, amount: Money) -> Result<String, PaymentError> {
+        if self.declining.load(Ordering::SeqCst) {
+            return Err(PaymentError::Declined("the mock provider is declining".to_string()));
+        }
+        let mut payments = self.payments.lock().unwrap();
+        let authorization_id = format!("auth_{:06}", payments.len() + 1);
+        payments.push(MockPayment { authorization_id: authorization_id.clone(), tab_id, amount, captured: false, voided: false, refunded: false });
+        Ok(authorization_id)
+    }
+
+    // Capturing again gives back the same capture.
+    async fn capture(&self, authorization_id: &str) -> Result<String, PaymentError> {
+        let mut payments = self.payments.lock().unwrap();
+        let payment = payments.iter_mut().find(|payment| payment.authorization_id == authorization_id && !payment.voided)
+            .ok_or_else(|| PaymentError::Declined(format!("authorization {} is unknown", authorization_id)))?;
+        if self.declining_captures.load(Ordering::SeqCst) {
+            return Err(PaymentError::Declined("the mock provider is declining captures".to_string()));
+        }
+        payment.captured = true;
+        Ok(payment.capture_id())
+    }
+
+    async fn void(&self, authorization_id: &str) -> Result<(), PaymentError> {
+        let mut payments = self.payments.lock().unwrap();
+        let payment = payments.iter_mut().find(|payment| payment.authorization_id == authorization_id && !payment.captured)
+            .ok_or_else(|| PaymentError::Declined(format!("authorization {} can not be voided", authorization_id)))?;
+        payment.voided = true;
+        Ok(())
+    }
+
+    async fn refund(&self, capture_id: &str) -> Result<(), PaymentError> {
+        let mut payments = self.payments.lock().unwrap();
+        let payment = payments.iter_mut().find(|payment| payment.captured && payment.capture_id() == capture_id)
+            .ok_or_else(|| PaymentError::Declined(format!("capture {} is unknown", capture_id)))?;
+        if payment.refunded {
+            return Err(PaymentError::Declined(format!("capture {} is refunded already", capture_id)));
+        }
+        payment.refunded = true;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettlementError<E> {
+    // The payment did not go through; the failure is recorded on the tab.
+    Payment(PaymentError),
+    Recording(E)
+}
+
+// Settles the tab through the provider, recording each step with `record` as it happens. Only
+// the authorization is recorded under the request's own metadata: the capture, the close and
+// any failure are follow-ups of it, since under the same command id they would be answered as
+// retries of the authorization. A retry of a settlement that got as far as being authorized is
+// answered with what was recorded the first time. A hold that could not be recorded or captured
+// is voided, and a capture the tab then did not close on is refunded.
+pub async fn settle<E, R, F, I, G>(provider: &dyn PaymentProvider, tab_id: Uuid, amount: Money, metadata: &Metadata, record: R, issue_receipt: I) -> Result<Vec<Event>, SettlementError<E>>
+    where R: Fn(Metadata, Command) -> F, F: Future<Output = Result<Vec<Event>, E>>, I: FnOnce(Metadata) -> G, G: Future<Output = Result<Option<String>, E>>
+{
+    let record = &record;
+    let failed = |error: PaymentError, reason: String| async move {
+        record(metadata.follow_up(), Command::FailPayment(tab_id, amount, reason)).await.map_err(SettlementError::Recording)?;
+        Err(SettlementError::Payment(error))
+    };
+    let authorization_id = match provider.authorize(tab_id, amount).await {
+        Ok(authorization_id) => authorization_id,
+        Err(error) => return failed(error.clone(), error.to_string()).await
+    };
+    let authorized = Event::PaymentAuthorized { authorization_id: authorization_id.clone(), amount };
+    let mut events = match record(metadata.clone(), Command::AuthorizePayment(tab_id, authorization_id.clone(), amount)).await {
+        Ok(events) => events,
+        Err(error) => {
+            if let Err(void_error) = provider.void(&authorization_id).await {
+                eprintln!("failed to void authorization {} of tab {} that could not be recorded: {}", authorization_id, tab_id, void_error);
+            }
+            return Err(SettlementError::Recording(error));
+        }
+    };
+    if events != [authorized] {
+        if let Err(void_error) = provider.void(&authorization_id).await {
+            eprintln!("failed to void authorization {} of a retried settlement of tab {}: {}", authorization_id, tab_id, void_error);
+        }
+        return Ok(events);
+    }
+
+    let capture_id = match provider.capture(&authorization_id).await {
+        Ok(capture_id) => capture_id,
+        Err(error) => {
+            let reason = match provider.void(&authorization_id).await {
+                Ok(()) => error.to_string(),
+                Err(void_error) => format!("{}; authorization {} was not voided: {}", error, authorization_id, void_error)
+            };
+            return failed(error, reason).await;
+        }
+    };
+    let closed = async {
+        let mut recorded = record(metadata.follow_up(), Command::CapturePayment(tab_id, authorization_id.clone(), capture_id.clone())).await?;
+        let receipt_number = issue_receipt(metadata.follow_up()).await?;
+        recorded.extend(record(metadata.follow_up(), Command::CloseTab(tab_id, amount, receipt_number)).await?);
+        Ok(recorded)
+    };
+    match closed.await {
+        Ok(recorded) => {
+            events.extend(recorded);
+            Ok(events)
+        },
+        Err(error) => {
+            let reason = match provider.refund(&capture_id).await {
+                Ok(()) => "refunded, the tab was not closed".to_string(),
+                Err(refund_error) => format!("the tab was not closed and capture {} was not refunded: {}", capture_id, refund_error)
+            };
+            let _ = record(metadata.follow_up(), Command::FailPayment(tab_id, amount, reason)).await;
+            Err(SettlementError::Recording(error))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::{CommandHandler, HandlerError};
+    use crate::cqrs::store::{EventStore, InMemoryEventStore};
+    use crate::domain::{CommandError, OrderedItem, Tab};
+    use crate::money::Currency;
+    use crate::staff;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    async fn served_tab(store: &InMemoryEventStore<Event>) -> Uuid {
+        let tab_id = Uuid::new_v4();
+        let coffee = OrderedItem::new(1, "Coffee".to_string(), true, eur(250)).with_line_id(Uuid::new_v4());
+        let served = Event::DrinksServed { line_ids: vec![coffee.line_id()], menu_numbers: vec![1] };
+        let events = vec![Event::TabOpened { table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() }, Event::DrinksOrdered { items: vec![coffee] }, served];
+        store.append(tab_id, events, 0, &Metadata::new()).await.unwrap();
+        tab_id
+    }
+
+    async fn settle_in(store: &InMemoryEventStore<Event>, provider: &MockPaymentProvider, tab_id: Uuid, metadata: &Metadata) -> Result<Vec<Event>, SettlementError<HandlerError<CommandError>>> {
+        let record = |metadata: Metadata, command| async move { CommandHandler::<Tab>::new(store).with_metadata(metadata).handle(command).await };
+        settle(provider, tab_id, eur(300), metadata, record, |_| async { Ok(None) }).await
+    }
+
+    fn payloads(events: &[crate::cqrs::EventEnvelope<Event>]) -> Vec<&str> {
+        events.iter().map(|envelope| match envelope.payload {
+            Event::PaymentAuthorized { .. } => "authorized",
+            Event::PaymentCaptured { .. } => "captured",
+            Event::PaymentFailed { .. } => "failed",
+            Event::TabClosed { .. } => "closed",
+            _ => "other"
+        }).filter(|payload| *payload != "other").collect()
+    }
+
+    #[tokio::test]
+    async fn settling_under_the_clients_command_id_closes_the_tab_once() {
+        let (store, provider) = (InMemoryEventStore::new(), MockPaymentProvider::new());
+        let tab_id = served_tab(&store).await;
+        let metadata = Metadata::new().with_command_id(Uuid::new_v4());
+        let settled = settle_in(&store, &provider, tab_id, &metadata).await.unwrap();
+        assert_eq!(settled.len(), 3);
+        assert_eq!(payloads(&store.read_stream(tab_id).await.unwrap().events), vec!["authorized", "captured", "closed"]);
+
+        // The retry authorizes again before learning it is one; that hold is released.
+        assert_eq!(settle_in(&store, &provider, tab_id, &metadata).await, Ok(vec![settled[0].clone()]));
+        assert_eq!(store.read_stream(tab_id).await.unwrap().version, 6);
+        assert_eq!(provider.payments().iter().map(|payment| (payment.captured, payment.voided)).collect::<Vec<_>>(), vec![(true, false), (false, true)]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_capture_releases_the_hold_and_leaves_the_tab_open() {
+        let (store, provider) = (InMemoryEventStore::new(), MockPaymentProvider::new());
+        let tab_id = served_tab(&store).await;
+        provider.set_declining_captures(true);
+        let failed = settle_in(&store, &provider, tab_id, &Metadata::new()).await;
+        assert!(matches!(failed, Err(SettlementError::Payment(PaymentError::Declined(_)))));
+        assert_eq!(payloads(&store.read_stream(tab_id).await.unwrap().events), vec!["authorized", "failed"]);
+        assert!(provider.payments()[0].voided);
+
+        provider.set_declining_captures(false);
+        settle_in(&store, &provider, tab_id, &Metadata::new()).await.unwrap();
+        assert_eq!(payloads(&store.read_stream(tab_id).await.unwrap().events), vec!["authorized", "failed", "authorized", "captured", "closed"]);
+    }
+
+    #[tokio::test]
+    async fn the_mock_captures_what_it_authorized_and_refunds_it_once() {
+        let provider = MockPaymentProvider::new();
+        let tab_id = Uuid::new_v4();
+        let authorization_id = provider.authorize(tab_id, eur(1200)).await.unwrap();
+        let capture_id = provider.capture(&authorization_id).await.unwrap();
+        assert_eq!(provider.capture(&authorization_id).await, Ok(capture_id.clone()));
+        assert!(matches!(provider.capture("auth_999999").await, Err(PaymentError::Declined(_))));
+
+        assert_eq!(provider.refund(&capture_id).await, Ok(()));
+        assert!(matches!(provider.refund(&capture_id).await, Err(PaymentError::Declined(_))));
+        assert!(matches!(provider.void(&authorization_id).await, Err(PaymentError::Declined(_))));
+        assert_eq!(provider.payments(), vec![MockPayment { authorization_id, tab_id, amount: eur(1200), captured: true, voided: false, refunded: true }]);
+
+        provider.set_declining(true);
+        assert!(matches!(provider.authorize(tab_id, eur(500)).await, Err(PaymentError::Declined(_))));
+    }
+}
@@ -0,0 +1,88 @@
+use std::any::Any;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use futures::FutureExt;
+use uuid::Uuid;
+
+// A command that panicked while it was being handled. The incident id is handed to the client
+// so support can find the record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Incident {
+    pub incident_id: Uuid,
+    pub occurred_at: SystemTime,
+    pub aggregate_id: Uuid,
+    pub command: String,
+    pub panic: String
+}
+
+// Incidents are appended to a file as JSON lines for later analysis.
+pub struct Incidents {
+    file: Mutex<File>
+}
+
+impl Incidents {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Incidents> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Incidents { file: Mutex::new(file) })
+    }
+
+    // Runs `work` so that a panic in it only fails this command. The state it was working on is
+    // thrown away with it; aggregates are loaded afresh for every command.
+    pub async fn isolate<T, F: Future<Output = T>>(&self, aggregate_id: Uuid, command: &str, work: F) -> Result<T, Incident> {
+        AssertUnwindSafe(work).catch_unwind().await.map_err(|payload| {
+            let incident = Incident {
+                incident_id: Uuid::new_v4(),
+                occurred_at: SystemTime::now(),
+                aggregate_id,
+                command: command.to_string(),
+                panic: panic_message(payload.as_ref())
+            };
+            self.record(&incident);
+            incident
+        })
+    }
+
+    fn record(&self, incident: &Incident) {
+        let written = serde_json::to_string(incident)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner()), "{}", line));
+        if let Err(error) = written {
+            eprintln!("failed to record incident {}: {}", incident.incident_id, error);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[tokio::test]
+    async fn panics_are_recorded_as_incidents() {
+        let path = env::temp_dir().join(format!("cafe-incidents-{}", Uuid::new_v4()));
+        let incidents = Incidents::open(&path).unwrap();
+        let tab_id = Uuid::new_v4();
+        assert_eq!(incidents.isolate(tab_id, "CloseTab", async { 42 }).await, Ok(42));
+
+        let incident = incidents.isolate(tab_id, "CloseTab", async { panic!("tip overflow") as i32 }).await.unwrap_err();
+        assert_eq!((incident.aggregate_id, incident.panic.as_str()), (tab_id, "tip overflow"));
+        let recorded: Vec<Incident> = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(recorded, vec![incident]);
+        fs::remove_file(&path).unwrap();
+    }
+}
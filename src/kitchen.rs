@@ -0,0 +1,151 @@
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::cqrs::{EventEnvelope, ProcessManager};
+use crate::domain::{Command, Course, Event, OrderedItem};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Ticket {
+    line_id: Uuid,
+    menu_number: i32,
+    course: Option<Course>,
+    // Since the kitchen got the item; none while its course is held.
+    waiting_since: Option<SystemTime>,
+    flagged: bool
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TicketState {
+    waiting: Vec<Ticket>,
+    fired: Vec<Course>
+}
+
+// Flags food that has waited longer than `late_after` to be served, once per item, so the
+// kitchen and the waiter can be told. Food held for a course waits from when the course is
+// fired. Done as soon as nothing on the tab is waiting.
+pub struct KitchenTicket {
+    late_after: Duration
+}
+
+impl KitchenTicket {
+    pub fn new(late_after: Duration) -> KitchenTicket {
+        KitchenTicket { late_after }
+    }
+}
+
+fn take(waiting: &mut Vec<Ticket>, line_id: Uuid) {
+    if let Some(index) = waiting.iter().position(|ticket| ticket.line_id == line_id) {
+        waiting.remove(index);
+    }
+}
+
+impl ProcessManager for KitchenTicket {
+    type Event = Event;
+    type Command = Command;
+    type State = TicketState;
+
+    fn react(&self, state: &mut TicketState, envelope: &EventEnvelope<Event>) -> Vec<Command> {
+        match envelope.payload {
+            Event::FoodOrdered { ref items } => {
+                for item in items.iter().flat_map(OrderedItem::units) {
+                    let held = item.course().is_some_and(|course| !state.fired.contains(&course));
+                    let waiting_since = if held { None } else { Some(envelope.timestamp) };
+                    state.waiting.push(Ticket { line_id: item.line_id(), menu_number: item.menu_number(), course: item.course(), waiting_since, flagged: false });
+                }
+            },
+            Event::CourseFired { course } => {
+                state.fired.push(course);
+                for ticket in state.waiting.iter_mut().filter(|ticket| ticket.course == Some(course) && ticket.waiting_since.is_none()) {
+                    ticket.waiting_since = Some(envelope.timestamp);
+                }
+            },
+            Event::FoodServed { ref line_ids, .. } => {
+                for &line_id in line_ids {
+                    take(&mut state.waiting, line_id);
+                }
+            },
+            Event::ItemVoided { line_id, .. } => take(&mut state.waiting, line_id),
+            Event::FoodRunningLate { ref menu_numbers } => {
+                for &menu_number in menu_numbers {
+                    if let Some(ticket) = state.waiting.iter_mut().find(|ticket| ticket.menu_number == menu_number && ticket.waiting_since.is_some() && !ticket.flagged) {
+                        ticket.flagged = true;
+                    }
+                }
+            },
+            Event::TabClosed { .. } | Event::TabForceClosed { .. } | Event::TabPurged { .. } => state.waiting.clear(),
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    fn wake(&self, tab_id: Uuid, state: &TicketState, now: SystemTime) -> Vec<Command> {
+        let late: Vec<i32> = state.waiting.iter()
+            .filter(|ticket| !ticket.flagged)
+            .filter(|ticket| ticket.waiting_since.and_then(|since| now.duration_since(since).ok()).is_some_and(|waited| waited > self.late_after))
+            .map(|ticket| ticket.menu_number)
+            .collect();
+        if late.is_empty() { Vec::new() } else { vec![Command::FlagLateFood(tab_id, late)] }
+    }
+
+    fn is_finished(&self, state: &TicketState) -> bool {
+        state.waiting.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::{Metadata, ProcessRunner};
+    use crate::cqrs::store::{EventStore, InMemoryEventStore, InMemorySnapshotStore};
+    use crate::domain::OrderedItem;
+    use crate::money::{Currency, Money};
+
+    fn soup() -> OrderedItem {
+        OrderedItem::new(1, "Soup".to_string(), false, Money::new(450, Currency::EUR))
+    }
+
+    #[tokio::test]
+    async fn late_food_is_flagged_once_until_served() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let metadata = Metadata::new();
+        let mut runner = ProcessRunner::new(KitchenTicket::new(Duration::from_secs(15 * 60)), Box::new(InMemorySnapshotStore::new()));
+        let lines = [Uuid::new_v4(), Uuid::new_v4()];
+        let ordered = store.append(tab_id, vec![Event::FoodOrdered { items: vec![soup().with_line_id(lines[0]), soup().with_line_id(lines[1])] }], 0, &metadata).await.unwrap();
+        runner.handle(&ordered[0]).unwrap();
+        let ordered_at = ordered[0].timestamp;
+
+        assert_eq!(runner.wake(ordered_at + Duration::from_secs(10 * 60)), vec![]);
+        let late = runner.wake(ordered_at + Duration::from_secs(20 * 60));
+        assert_eq!(late.into_iter().map(|(command, _)| command).collect::<Vec<_>>(), vec![Command::FlagLateFood(tab_id, vec![1, 1])]);
+
+        let flagged = store.append(tab_id, vec![Event::FoodRunningLate { menu_numbers: vec![1, 1] }], 1, &metadata).await.unwrap();
+        runner.handle(&flagged[0]).unwrap();
+        assert_eq!(runner.wake(ordered_at + Duration::from_secs(30 * 60)), vec![]);
+
+        let served = store.append(tab_id, vec![Event::FoodServed { line_ids: lines.to_vec(), menu_numbers: vec![1, 1] }], 2, &metadata).await.unwrap();
+        runner.handle(&served[0]).unwrap();
+        let reordered = store.append(tab_id, vec![Event::FoodOrdered { items: vec![soup()] }], 3, &metadata).await.unwrap();
+        runner.handle(&reordered[0]).unwrap();
+        let late = runner.wake(reordered[0].timestamp + Duration::from_secs(20 * 60));
+        assert_eq!(late.into_iter().map(|(command, _)| command).collect::<Vec<_>>(), vec![Command::FlagLateFood(tab_id, vec![1])]);
+    }
+
+    #[tokio::test]
+    async fn held_food_waits_from_when_its_course_is_fired() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let metadata = Metadata::new();
+        let mut runner = ProcessRunner::new(KitchenTicket::new(Duration::from_secs(15 * 60)), Box::new(InMemorySnapshotStore::new()));
+        let ordered = store.append(tab_id, vec![Event::FoodOrdered { items: vec![soup().with_course(Some(Course::Main)).with_line_id(Uuid::new_v4())] }], 0, &metadata).await.unwrap();
+        runner.handle(&ordered[0]).unwrap();
+        assert_eq!(runner.wake(ordered[0].timestamp + Duration::from_secs(60 * 60)), vec![]);
+
+        let fired = store.append(tab_id, vec![Event::CourseFired { course: Course::Main }], 1, &metadata).await.unwrap();
+        runner.handle(&fired[0]).unwrap();
+        assert_eq!(runner.wake(fired[0].timestamp + Duration::from_secs(10 * 60)), vec![]);
+        let late = runner.wake(fired[0].timestamp + Duration::from_secs(20 * 60));
+        assert_eq!(late.into_iter().map(|(command, _)| command).collect::<Vec<_>>(), vec![Command::FlagLateFood(tab_id, vec![1])]);
+    }
+}
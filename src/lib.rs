@@ -1,14 +1,64 @@
-#![feature(plugin)]
-#![plugin(rocket_codegen)]
-
-#![cfg_attr(feature="clippy", plugin(clippy))]
+// Rocket routes take every guard and piece of managed state they use as an argument, and the
+// store and projection callbacks spell out their types where they are used. Routes answer with
+// the whole problem document when they fail.
+#![allow(clippy::too_many_arguments, clippy::type_complexity, clippy::result_large_err)]
 
+extern crate async_trait;
+extern crate futures;
+extern crate hmac;
+#[macro_use]
 extern crate rocket;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+#[cfg(feature = "tantivy")]
+extern crate tantivy;
+extern crate tokio;
+#[cfg(feature = "postgres")]
+extern crate tokio_postgres;
+extern crate toml;
+extern crate tungstenite;
 extern crate uuid;
 
 #[macro_use]
 extern crate serde_derive;
 
+pub mod access;
+pub mod annotations;
 pub mod api;
+pub mod auth;
+pub mod backfill;
+pub mod config;
 pub mod cqrs;
+pub mod date;
+pub mod devices;
+pub mod docs;
+pub mod fiscal;
 pub mod domain;
+pub mod forecast;
+pub mod gateway;
+pub mod incident;
+pub mod kitchen;
+pub mod locale;
+pub mod menu;
+pub mod money;
+pub mod openapi;
+pub mod payments;
+pub mod policy;
+pub mod printing;
+pub mod push;
+pub mod read_model;
+pub mod receipts;
+pub mod replay;
+pub mod reports;
+pub mod retention;
+pub mod shift;
+pub mod shutdown;
+pub mod staff;
+pub mod store_stats;
+pub mod slo;
+pub mod table;
+#[cfg(feature = "tantivy")]
+pub mod text_search;
+pub mod watchdog;
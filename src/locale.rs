@@ -0,0 +1,309 @@
+use crate::domain::CommandError;
+use crate::menu;
+use crate::shift;
+use crate::staff;
+use crate::table;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Default)]
+pub enum Language {
+    #[default]
+    English,
+    Estonian
+}
+
+
+impl Language {
+    fn from_tag(tag: &str) -> Option<Language> {
+        let primary = tag.split('-').next().unwrap_or("").trim().to_lowercase();
+        match primary.as_str() {
+            "en" => Some(Language::English),
+            "et" => Some(Language::Estonian),
+            _ => None
+        }
+    }
+
+    // Picks the supported language with the highest quality value from an Accept-Language
+    // header, e.g. "et-EE,et;q=0.9,en;q=0.8". Falls back to English.
+    pub fn from_accept_language(header: Option<&str>) -> Language {
+        let mut best: Option<(Language, f32)> = None;
+
+        for range in header.unwrap_or("").split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or("");
+            let quality = parts
+                .filter_map(|param| {
+                    let param = param.trim();
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok())
+                })
+                .next()
+                .unwrap_or(1.0);
+
+            if let Some(language) = Language::from_tag(tag) {
+                if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                    best = Some((language, quality));
+                }
+            }
+        }
+
+        best.map(|(language, _)| language).unwrap_or_default()
+    }
+}
+
+pub fn command_error_message(error: &CommandError, language: Language) -> &'static str {
+    use crate::domain::CommandError::*;
+    use self::Language::*;
+
+    match (language, error) {
+        (English, &TabNotOpen) => "The tab is not open.",
+        (English, &InvalidPrice) => "Prices can not be negative.",
+        (English, &DrinksNotOutstanding(_)) => "Some of these drinks are not waiting to be served.",
+        (English, &FoodNotOutstanding(_)) => "Some of this food is not waiting to be served.",
+        (English, &ItemNotOutstanding(_)) => "The item is not waiting to be served.",
+        (English, &MustPayEnough) => "The amount paid does not cover the served items.",
+        (English, &TabHasUnservedItems) => "The tab still has items that have not been served.",
+        (English, &CurrencyMismatch) => "The amount is not in the currency of the tab.",
+        (English, &TabValueLimitExceeded) => "The order would take the tab over its maximum value.",
+        (English, &TipTooHigh) => "The tip is larger than allowed.",
+        (English, &NotTabWaiter) => "The tab is not looked after by this waiter.",
+        (English, &ItemNotServed(_)) => "The item has not been served.",
+        (English, &InvalidDiscount) => "Discounts are between 1 and 100 percent.",
+        (English, &DiscountExceedsTabValue) => "The discounts would come to more than the served items are worth.",
+        (English, &CourseNotOrdered(_)) => "No food is held for this course.",
+        (English, &CourseAlreadyFired(_)) => "The course has already been sent to the kitchen.",
+        (English, &ServeUnorderedNotAllowed(_)) => "Some of these items can not be served without an order.",
+        (English, &PaymentNotCaptured) => "A payment on this tab is still waiting to be captured.",
+        (English, &PaymentNotAuthorized) => "No payment on this tab is waiting to be captured under that authorization.",
+        (English, &TabAlreadyOpened) => "A tab with this id has already been opened.",
+        (Estonian, &TabNotOpen) => "Arve ei ole avatud.",
+        (Estonian, &InvalidPrice) => "Hind ei saa olla negatiivne.",
+        (Estonian, &DrinksNotOutstanding(_)) => "Osa neist jookidest ei oota serveerimist.",
+        (Estonian, &FoodNotOutstanding(_)) => "Osa sellest toidust ei oota serveerimist.",
+        (Estonian, &ItemNotOutstanding(_)) => "See toode ei oota serveerimist.",
+        (Estonian, &MustPayEnough) => "Makstud summa ei kata serveeritud toodete väärtust.",
+        (Estonian, &TabHasUnservedItems) => "Arvel on veel serveerimata tooteid.",
+        (Estonian, &CurrencyMismatch) => "Summa ei ole arve valuutas.",
+        (Estonian, &TabValueLimitExceeded) => "Tellimusega ületaks arve lubatud maksimumsumma.",
+        (Estonian, &TipTooHigh) => "Jootraha on lubatust suurem.",
+        (Estonian, &NotTabWaiter) => "Seda arvet ei teeninda see kelner.",
+        (Estonian, &ItemNotServed(_)) => "Seda toodet ei ole serveeritud.",
+        (Estonian, &InvalidDiscount) => "Allahindlus peab olema 1 kuni 100 protsenti.",
+        (Estonian, &DiscountExceedsTabValue) => "Allahindlused ületaksid serveeritud toodete väärtuse.",
+        (Estonian, &CourseNotOrdered(_)) => "Selle käigu jaoks ei oota ükski toit.",
+        (Estonian, &CourseAlreadyFired(_)) => "See käik on juba kööki saadetud.",
+        (Estonian, &ServeUnorderedNotAllowed(_)) => "Osa neist toodetest ei saa serveerida ilma tellimuseta.",
+        (Estonian, &PaymentNotCaptured) => "Selle arve makse ootab veel kinnitamist.",
+        (Estonian, &PaymentNotAuthorized) => "Selle autoriseeringuga ei oota ükski selle arve makse kinnitamist.",
+        (Estonian, &TabAlreadyOpened) => "Selle tunnusega arve on juba avatud.",
+    }
+}
+
+pub fn menu_error_message(error: &menu::CommandError, language: Language) -> &'static str {
+    use crate::menu::CommandError::*;
+    use self::Language::*;
+
+    match (language, error) {
+        (English, &MenuNumberTaken) => "The menu number is already in use.",
+        (English, &UnknownMenuItem) => "The item is not on the menu.",
+        (English, &InvalidPrice) => "Prices can not be negative.",
+        (English, &CurrencyMismatch) => "Prices must be in the currency of the cafe.",
+        (Estonian, &MenuNumberTaken) => "See menüünumber on juba kasutusel.",
+        (Estonian, &UnknownMenuItem) => "Seda toodet ei ole menüüs.",
+        (Estonian, &InvalidPrice) => "Hind ei saa olla negatiivne.",
+        (Estonian, &CurrencyMismatch) => "Hind peab olema kohviku valuutas."
+    }
+}
+
+pub fn shift_error_message(error: &shift::CommandError, language: Language) -> &'static str {
+    use crate::shift::CommandError::*;
+    use self::Language::*;
+
+    match (language, error) {
+        (English, &AlreadyOnShift) => "The waiter is already on shift.",
+        (English, &NotOnShift) => "The waiter is not on shift.",
+        (Estonian, &AlreadyOnShift) => "Kelner on juba vahetuses.",
+        (Estonian, &NotOnShift) => "Kelner ei ole vahetuses."
+    }
+}
+
+pub fn staff_error_message(error: &staff::CommandError, language: Language) -> &'static str {
+    use crate::staff::CommandError::*;
+    use self::Language::*;
+
+    match (language, error) {
+        (English, &AlreadyRegistered) => "This member of staff is already registered.",
+        (English, &NotRegistered) => "There is no such member of staff.",
+        (English, &AlreadyInactive) => "This member of staff has already left.",
+        (Estonian, &AlreadyRegistered) => "See töötaja on juba registreeritud.",
+        (Estonian, &NotRegistered) => "Sellist töötajat ei ole.",
+        (Estonian, &AlreadyInactive) => "See töötaja on juba lahkunud."
+    }
+}
+
+pub fn table_error_message(error: &table::CommandError, language: Language) -> &'static str {
+    use crate::table::CommandError::*;
+    use self::Language::*;
+
+    match (language, error) {
+        (English, &TableAlreadyRegistered) => "The table is already registered.",
+        (English, &UnknownTable) => "There is no such table.",
+        (English, &TableInUse) => "The table already has an open tab.",
+        (English, &TableNotInUse) => "Nobody is seated at the table.",
+        (Estonian, &TableAlreadyRegistered) => "See laud on juba registreeritud.",
+        (Estonian, &UnknownTable) => "Sellist lauda ei ole.",
+        (Estonian, &TableInUse) => "Laual on juba avatud arve.",
+        (Estonian, &TableNotInUse) => "Laua taga ei istu kedagi."
+    }
+}
+
+pub fn concurrency_conflict_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The tab was changed by someone else at the same time. Please try again.",
+        Language::Estonian => "Keegi muutis arvet samal ajal. Palun proovi uuesti."
+    }
+}
+
+pub fn store_unavailable_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The tab could not be saved right now. Please try again later.",
+        Language::Estonian => "Arvet ei õnnestunud praegu salvestada. Palun proovi hiljem uuesti."
+    }
+}
+
+pub fn internal_error_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "Something went wrong on our side. Please quote the incident id when reporting it.",
+        Language::Estonian => "Meie poolel läks midagi valesti. Teatamisel palun lisa intsidendi tunnus."
+    }
+}
+
+pub fn forbidden_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "Your role does not allow this.",
+        Language::Estonian => "Sinu roll seda ei luba."
+    }
+}
+
+pub fn not_on_staff_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "You are not registered as active staff.",
+        Language::Estonian => "Sa ei ole aktiivse töötajana registreeritud."
+    }
+}
+
+pub fn unauthorized_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "Sign in with your API token first.",
+        Language::Estonian => "Logi esmalt oma API võtmega sisse."
+    }
+}
+
+pub fn not_found_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "There is nothing here.",
+        Language::Estonian => "Siin ei ole midagi."
+    }
+}
+
+pub fn malformed_request_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The request could not be read.",
+        Language::Estonian => "Päringut ei õnnestunud lugeda."
+    }
+}
+
+pub fn invalid_signature_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The callback signature is missing, wrong or too old.",
+        Language::Estonian => "Tagasikutse allkiri puudub, on vale või liiga vana."
+    }
+}
+
+pub fn invalid_callback_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The callback could not be read.",
+        Language::Estonian => "Tagasikutset ei õnnestunud lugeda."
+    }
+}
+
+pub fn invalid_pin_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The PIN is wrong.",
+        Language::Estonian => "PIN-kood on vale."
+    }
+}
+
+pub fn pin_locked_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "Too many wrong PINs. Please wait a few minutes.",
+        Language::Estonian => "Liiga palju valesid PIN-koode. Palun oota mõni minut."
+    }
+}
+
+pub fn invalid_pin_format_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "PINs are four to eight digits.",
+        Language::Estonian => "PIN-kood on neli kuni kaheksa numbrit."
+    }
+}
+
+pub fn invalid_date_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "Dates must be given as YYYY-MM-DD.",
+        Language::Estonian => "Kuupäev peab olema kujul AAAA-KK-PP."
+    }
+}
+
+pub fn fiscal_device_unavailable_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The fiscal device could not be reached. Please try again later.",
+        Language::Estonian => "Kassaseadmega ei õnnestunud ühendust saada. Palun proovi hiljem uuesti."
+    }
+}
+
+pub fn fiscal_request_rejected_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The fiscal device turned the request down, e.g. the receipt was never registered or is voided already.",
+        Language::Estonian => "Kassaseade lükkas päringu tagasi, nt tšekki pole registreeritud või on see juba tühistatud."
+    }
+}
+
+pub fn payment_declined_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The payment was declined. The tab is still open.",
+        Language::Estonian => "Makse lükati tagasi. Arve on endiselt avatud."
+    }
+}
+
+pub fn payment_provider_unavailable_message(language: Language) -> &'static str {
+    match language {
+        Language::English => "The payment provider could not be reached. The tab is still open; please try again later.",
+        Language::Estonian => "Makseteenusega ei õnnestunud ühendust saada. Arve on endiselt avatud; palun proovi hiljem uuesti."
+    }
+}
+
+pub fn warning_message(code: &str, language: Language) -> &'static str {
+    match (language, code) {
+        (Language::English, "tab_nearing_max_value") => "The tab is nearing its maximum value.",
+        (Language::Estonian, "tab_nearing_max_value") => "Arve läheneb lubatud maksimumsummale.",
+        _ => ""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_supported_language_with_highest_quality() {
+        assert_eq!(Language::from_accept_language(Some("et-EE,et;q=0.9,en;q=0.8")), Language::Estonian);
+        assert_eq!(Language::from_accept_language(Some("fr;q=1.0,en;q=0.5,et;q=0.7")), Language::Estonian);
+        assert_eq!(Language::from_accept_language(Some("et;q=0,en")), Language::English);
+    }
+
+    #[test]
+    fn falls_back_to_english() {
+        assert_eq!(Language::from_accept_language(None), Language::English);
+        assert_eq!(Language::from_accept_language(Some("fr-FR,de;q=0.8")), Language::English);
+    }
+}
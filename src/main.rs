@@ -1,3 +1,9 @@
+extern crate cafe;
+
+use cafe::api;
+use cafe::config::Config;
+
 fn main() {
-    println!("Hello, world!");
+    let config = Config::load().expect("failed to read the configuration");
+    api::launch(config);
 }
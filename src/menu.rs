@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use crate::cqrs::{Aggregate, AggregateCommand};
+use crate::domain::OrderedItem;
+use crate::money::{Currency, Money};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum Command {
+    AddMenuItem(Uuid, MenuItem),
+    ChangePrice(Uuid, i32, Money),
+    RetireItem(Uuid, i32),
+    DeclareNutrition(Uuid, i32, Option<Nutrition>)
+}
+
+impl AggregateCommand for Command {
+    fn aggregate_id(&self) -> Uuid {
+        use self::Command::*;
+
+        match *self {
+            AddMenuItem(id, ..) | ChangePrice(id, ..) | RetireItem(id, ..) | DeclareNutrition(id, ..) => id
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum CommandError {
+    MenuNumberTaken,
+    UnknownMenuItem,
+    InvalidPrice,
+    CurrencyMismatch
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    MenuItemAdded { item: MenuItem },
+    PriceChanged { menu_number: i32, price: Money },
+    ItemRetired { menu_number: i32 },
+    NutritionDeclared { menu_number: i32, nutrition: Option<Nutrition> }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct MenuItem {
+    pub menu_number: i32,
+    pub description: String,
+    pub is_drink: bool,
+    pub price: Money,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nutrition: Option<Nutrition>
+}
+
+// Per serving, for places that require calories on the menu. Only the energy is required.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Nutrition {
+    pub kcal: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protein_grams: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub carbohydrate_grams: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fat_grams: Option<u32>
+}
+
+impl MenuItem {
+    pub fn ordered(&self) -> OrderedItem {
+        OrderedItem::new(self.menu_number, self.description.clone(), self.is_drink, self.price)
+    }
+}
+
+// Menu numbers stay taken after the item is retired, so old tabs keep meaning what they said.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    on_menu: HashMap<i32, bool>
+}
+
+impl State {
+    fn is_on_menu(&self, menu_number: i32) -> bool {
+        self.on_menu.get(&menu_number).cloned().unwrap_or(false)
+    }
+}
+
+// The whole menu is a single stream; the API keeps it at the nil id.
+pub struct Menu;
+
+impl Aggregate for Menu {
+    type Command = Command;
+    type CommandError = CommandError;
+    type Event = Event;
+    type State = State;
+
+    fn initial_state() -> State {
+        State::default()
+    }
+
+    fn decide(state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
+        use self::Command::*;
+        use self::CommandError::*;
+        use self::Event::*;
+
+        match command {
+            AddMenuItem(_, item) => {
+                if state.on_menu.contains_key(&item.menu_number) {
+                    Err(MenuNumberTaken)
+                } else if item.price.is_negative() {
+                    Err(InvalidPrice)
+                } else if item.price.currency() != Currency::default() {
+                    Err(CurrencyMismatch)
+                } else {
+                    Ok(vec![MenuItemAdded { item }])
+                }
+            },
+            ChangePrice(_, menu_number, price) => {
+                if !state.is_on_menu(menu_number) {
+                    Err(UnknownMenuItem)
+                } else if price.is_negative() {
+                    Err(InvalidPrice)
+                } else if price.currency() != Currency::default() {
+                    Err(CurrencyMismatch)
+                } else {
+                    Ok(vec![PriceChanged { menu_number, price }])
+                }
+            },
+            RetireItem(_, menu_number) => {
+                if state.is_on_menu(menu_number) {
+                    Ok(vec![ItemRetired { menu_number }])
+                } else {
+                    Err(UnknownMenuItem)
+                }
+            },
+            // None takes the declaration back.
+            DeclareNutrition(_, menu_number, nutrition) => {
+                if state.is_on_menu(menu_number) {
+                    Ok(vec![NutritionDeclared { menu_number, nutrition }])
+                } else {
+                    Err(UnknownMenuItem)
+                }
+            }
+        }
+    }
+
+    fn evolve(state: &mut State, event: Event) {
+        use self::Event::*;
+
+        match event {
+            MenuItemAdded { item } => {
+                state.on_menu.insert(item.menu_number, true);
+            },
+            ItemRetired { menu_number } => {
+                state.on_menu.insert(menu_number, false);
+            },
+            PriceChanged { .. } | NutritionDeclared { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::testing::Scenario;
+    use crate::money::Currency;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    fn espresso() -> MenuItem {
+        MenuItem { menu_number: 1, description: "Espresso".to_string(), is_drink: true, price: eur(200), nutrition: None }
+    }
+
+    #[test]
+    fn can_add_menu_items() {
+        Scenario::<Menu>::new()
+            .when(Command::AddMenuItem(Uuid::nil(), espresso()))
+            .then(vec![Event::MenuItemAdded { item: espresso() }]);
+    }
+
+    #[test]
+    fn menu_numbers_are_not_reused() {
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }, Event::ItemRetired { menu_number: 1 }])
+            .when(Command::AddMenuItem(Uuid::nil(), espresso()))
+            .then_err(CommandError::MenuNumberTaken);
+    }
+
+    #[test]
+    fn can_change_the_price_of_items_on_the_menu() {
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }])
+            .when(Command::ChangePrice(Uuid::nil(), 1, eur(220)))
+            .then(vec![Event::PriceChanged { menu_number: 1, price: eur(220) }]);
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }])
+            .when(Command::ChangePrice(Uuid::nil(), 1, eur(-1)))
+            .then_err(CommandError::InvalidPrice);
+    }
+
+    // Tabs add up served items in the cafe's currency, so the menu is priced in nothing else.
+    #[test]
+    fn prices_are_in_the_cafes_currency() {
+        let in_pounds = Money::new(200, Currency::GBP);
+        Scenario::<Menu>::new()
+            .when(Command::AddMenuItem(Uuid::nil(), MenuItem { price: in_pounds, ..espresso() }))
+            .then_err(CommandError::CurrencyMismatch);
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }])
+            .when(Command::ChangePrice(Uuid::nil(), 1, in_pounds))
+            .then_err(CommandError::CurrencyMismatch);
+    }
+
+    #[test]
+    fn items_on_the_menu_can_declare_their_nutrition() {
+        let nutrition = Nutrition { kcal: 5, protein_grams: None, carbohydrate_grams: Some(1), fat_grams: None };
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }])
+            .when(Command::DeclareNutrition(Uuid::nil(), 1, Some(nutrition)))
+            .then(vec![Event::NutritionDeclared { menu_number: 1, nutrition: Some(nutrition) }]);
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }, Event::ItemRetired { menu_number: 1 }])
+            .when(Command::DeclareNutrition(Uuid::nil(), 1, None))
+            .then_err(CommandError::UnknownMenuItem);
+        assert_eq!(serde_json::to_value(MenuItem { nutrition: Some(nutrition), ..espresso() }).unwrap()["nutrition"], json!({ "kcal": 5, "carbohydrate_grams": 1 }));
+    }
+
+    #[test]
+    fn retired_items_cannot_be_changed() {
+        Scenario::<Menu>::new()
+            .given(vec![Event::MenuItemAdded { item: espresso() }, Event::ItemRetired { menu_number: 1 }])
+            .when(Command::ChangePrice(Uuid::nil(), 1, eur(220)))
+            .then_err(CommandError::UnknownMenuItem);
+        Scenario::<Menu>::new()
+            .when(Command::RetireItem(Uuid::nil(), 1))
+            .then_err(CommandError::UnknownMenuItem);
+    }
+}
@@ -0,0 +1,287 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Currency {
+    EUR,
+    GBP,
+    USD,
+    JPY
+}
+
+impl Currency {
+    pub fn minor_units(&self) -> u32 {
+        match *self {
+            Currency::JPY => 0,
+            _ => 2
+        }
+    }
+}
+
+const CURRENCIES: [Currency; 4] = [Currency::EUR, Currency::GBP, Currency::USD, Currency::JPY];
+
+// The cafe's own currency, for amounts before anything has been priced, e.g. the value served
+// on a tab that has nothing served yet. EUR unless set otherwise on launch.
+static DEFAULT_CURRENCY: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_default_currency(currency: Currency) {
+    let index = CURRENCIES.iter().position(|&known| known == currency).unwrap_or(0);
+    DEFAULT_CURRENCY.store(index, atomic::Ordering::Relaxed);
+}
+
+impl Default for Currency {
+    fn default() -> Currency {
+        CURRENCIES[DEFAULT_CURRENCY.load(atomic::Ordering::Relaxed)]
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+// How money is written as JSON, in events as well as in invoices and reports: always the amount
+// as an integer count of minor units with the currency, never as a float. Formatted adds the
+// amount as it is displayed, e.g. "12.50 EUR", for clients that only show it; it is ignored when
+// the JSON is read back. Set once on launch.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonFormat {
+    Plain,
+    Formatted
+}
+
+static FORMATTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json_format(format: JsonFormat) {
+    FORMATTED.store(format == JsonFormat::Formatted, atomic::Ordering::Relaxed);
+}
+
+pub fn json_format() -> JsonFormat {
+    if FORMATTED.load(atomic::Ordering::Relaxed) { JsonFormat::Formatted } else { JsonFormat::Plain }
+}
+
+// Amounts are kept as an integer count of the currency's minor unit (cents for EUR),
+// so sums of prices are exact. Mixing currencies in arithmetic is a bug and panics.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, Hash)]
+pub struct Money {
+    amount_minor: i64,
+    currency: Currency
+}
+
+impl Money {
+    pub fn new(amount_minor: i64, currency: Currency) -> Money {
+        Money { amount_minor, currency }
+    }
+
+    pub fn zero(currency: Currency) -> Money {
+        Money::new(0, currency)
+    }
+
+    pub fn amount_minor(&self) -> i64 {
+        self.amount_minor
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.amount_minor == 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.amount_minor < 0
+    }
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        self.amount_minor.checked_add(other.amount_minor).map(|amount| Money::new(amount, self.currency))
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        if self.currency != other.currency {
+            return None;
+        }
+        self.amount_minor.checked_sub(other.amount_minor).map(|amount| Money::new(amount, self.currency))
+    }
+
+    // Multiplies by numerator / denominator, rounding half to even (banker's rounding)
+    // to the nearest minor unit. Used for percentages, shares and tax.
+    pub fn mul_ratio(self, numerator: i64, denominator: i64) -> Money {
+        assert!(denominator != 0, "denominator must not be zero");
+        let product = i128::from(self.amount_minor) * i128::from(numerator);
+        let denominator = i128::from(denominator);
+        let mut quotient = product / denominator;
+        let remainder = product % denominator;
+        let twice_remainder = (remainder * 2).abs();
+        let positive = (product < 0) == (denominator < 0);
+        if twice_remainder > denominator.abs() || (twice_remainder == denominator.abs() && quotient % 2 != 0) {
+            quotient += if positive { 1 } else { -1 };
+        }
+        Money::new(quotient as i64, self.currency)
+    }
+
+    fn to_json(self, format: JsonFormat) -> MoneyJson {
+        let formatted = if format == JsonFormat::Formatted { Some(self.to_string()) } else { None };
+        MoneyJson { amount_minor: self.amount_minor, currency: self.currency, formatted }
+    }
+
+    fn assert_same_currency(&self, other: &Money) {
+        assert!(self.currency == other.currency, "currency mismatch: {} and {}", self.currency, other.currency);
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = self.currency.minor_units();
+        let sign = if self.amount_minor < 0 { "-" } else { "" };
+        let amount = self.amount_minor.unsigned_abs();
+        if units == 0 {
+            write!(f, "{}{} {}", sign, amount, self.currency)
+        } else {
+            let scale = 10u64.pow(units);
+            write!(f, "{}{}.{:0width$} {}", sign, amount / scale, amount % scale, self.currency, width = units as usize)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MoneyJson {
+    amount_minor: i64,
+    currency: Currency,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    formatted: Option<String>
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json(json_format()).serialize(serializer)
+    }
+}
+
+impl PartialOrd for Money {
+    fn partial_cmp(&self, other: &Money) -> Option<Ordering> {
+        if self.currency == other.currency {
+            Some(self.amount_minor.cmp(&other.amount_minor))
+        } else {
+            None
+        }
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        self.assert_same_currency(&other);
+        Money::new(self.amount_minor + other.amount_minor, self.currency)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        *self = *self + other;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        self.assert_same_currency(&other);
+        Money::new(self.amount_minor - other.amount_minor, self.currency)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        *self = *self - other;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money::new(-self.amount_minor, self.currency)
+    }
+}
+
+impl Mul<i64> for Money {
+    type Output = Money;
+
+    fn mul(self, quantity: i64) -> Money {
+        Money::new(self.amount_minor * quantity, self.currency)
+    }
+}
+
+impl<'a> Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Money {
+        let mut iter = iter.peekable();
+        let currency = iter.peek().map(|m| m.currency).unwrap_or_default();
+        iter.fold(Money::zero(currency), |total, m| total + *m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_prices_without_rounding_error() {
+        let price = Money::new(10, Currency::EUR);
+        let mut total = Money::zero(Currency::EUR);
+        for _ in 0..1000 {
+            total += price;
+        }
+        assert_eq!(total, Money::new(10000, Currency::EUR));
+    }
+
+    #[test]
+    fn can_not_mix_currencies() {
+        let euros = Money::new(100, Currency::EUR);
+        let dollars = Money::new(100, Currency::USD);
+        assert_eq!(euros.checked_add(dollars), None);
+        assert_eq!(euros.partial_cmp(&dollars), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "currency mismatch")]
+    fn adding_different_currencies_panics() {
+        let _ = Money::new(100, Currency::EUR) + Money::new(100, Currency::USD);
+    }
+
+    #[test]
+    fn ratio_rounds_half_to_even() {
+        assert_eq!(Money::new(25, Currency::EUR).mul_ratio(1, 10), Money::new(2, Currency::EUR));
+        assert_eq!(Money::new(35, Currency::EUR).mul_ratio(1, 10), Money::new(4, Currency::EUR));
+        assert_eq!(Money::new(-25, Currency::EUR).mul_ratio(1, 10), Money::new(-2, Currency::EUR));
+        assert_eq!(Money::new(1000, Currency::EUR).mul_ratio(1, 3), Money::new(333, Currency::EUR));
+        assert_eq!(Money::new(1000, Currency::EUR).mul_ratio(2, 3), Money::new(667, Currency::EUR));
+    }
+
+    #[test]
+    fn displays_in_major_units() {
+        assert_eq!(Money::new(1250, Currency::EUR).to_string(), "12.50 EUR");
+        assert_eq!(Money::new(-5, Currency::USD).to_string(), "-0.05 USD");
+        assert_eq!(Money::new(500, Currency::JPY).to_string(), "500 JPY");
+    }
+
+    #[test]
+    fn json_has_the_amount_in_minor_units() {
+        let price = Money::new(1250, Currency::EUR);
+        assert_eq!(serde_json::to_string(&price).unwrap(), r#"{"amount_minor":1250,"currency":"EUR"}"#);
+        let formatted = serde_json::to_string(&price.to_json(JsonFormat::Formatted)).unwrap();
+        assert_eq!(formatted, r#"{"amount_minor":1250,"currency":"EUR","formatted":"12.50 EUR"}"#);
+        assert_eq!(serde_json::from_str::<Money>(&formatted).unwrap(), price);
+    }
+}
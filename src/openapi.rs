@@ -0,0 +1,277 @@
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+use uuid::Uuid;
+
+use crate::api::{ApiError, ApiWarning, CommandResponse, CompedItem, Discount, FiredCourse, HandedOver, Handover, NewOrder, NewOrderItem, NewTab, OrderLine, ServedItems, ServedLine, Settlement, TabTransfer, UnorderedItems, VoidedItem};
+use crate::date::Date;
+use crate::docs;
+use crate::domain::{CommandError, Course, Event, LineRef};
+use crate::forecast::{Daypart, Forecast, PrepSuggestion};
+use crate::locale::{self, Language};
+use crate::menu::Nutrition;
+use crate::money::{Currency, Money};
+use crate::read_model::{NutritionFootnote, TabInvoice, TabItem, TabStatus, TodoListGroup, TodoListItem};
+use crate::reports::{DailySales, ItemSales, WaiterTips};
+use rocket::http::Status;
+use crate::staff;
+
+// Where a parameter goes: the path or the query string.
+#[derive(Clone, Copy)]
+enum In {
+    Path,
+    Query
+}
+
+struct Parameter {
+    name: &'static str,
+    location: In,
+    schema: Value
+}
+
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    // Who may call it; None for routes anyone can call.
+    roles: Option<&'static str>,
+    parameters: Vec<Parameter>,
+    request: Option<&'static str>,
+    response: &'static str
+}
+
+// OpenAPI 3 document of the tab, kitchen and report routes. The schemas are read off example
+// values serialized the way the routes serialize them, so they follow the types without being
+// written out by hand.
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for operation in operations() {
+        let path = paths.entry(operation.path.to_string()).or_insert_with(|| json!({}));
+        path[operation.method] = describe(&operation);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Cafe API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Commands answer with the events they recorded, or would record when sent with dry_run=true. Errors are problem details (RFC 7807); see /api/docs/domain for every error code."
+        },
+        "servers": [{ "url": "/api" }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": { "token": { "type": "http", "scheme": "bearer" } }
+        }
+    })
+}
+
+// Swagger UI, loaded from a CDN and pointed at the document.
+pub fn swagger_ui() -> String {
+    String::from(concat!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<title>Cafe API</title>\n",
+        "<link rel=\"stylesheet\" href=\"https://unpkg.com/swagger-ui-dist@5/swagger-ui.css\">\n",
+        "</head>\n<body>\n<div id=\"swagger-ui\"></div>\n",
+        "<script src=\"https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js\"></script>\n",
+        "<script>SwaggerUIBundle({ url: \"/api/openapi.json\", dom_id: \"#swagger-ui\" });</script>\n",
+        "</body>\n</html>\n"
+    ))
+}
+
+fn describe(operation: &Operation) -> Value {
+    let mut described = json!({
+        "tags": [operation.tag],
+        "summary": operation.summary,
+        "responses": {
+            "200": {
+                "description": "OK",
+                "content": { "application/json": { "schema": reference(operation.response) } }
+            },
+            "default": {
+                "description": "Problem",
+                "content": { "application/problem+json": { "schema": reference("Problem") } }
+            }
+        }
+    });
+    if let Some(roles) = operation.roles {
+        described["description"] = json!(format!("For {}.", roles));
+        described["security"] = json!([{ "token": [] }]);
+    }
+    if !operation.parameters.is_empty() {
+        described["parameters"] = operation.parameters.iter().map(|parameter| {
+            let (location, required) = match parameter.location {
+                In::Path => ("path", true),
+                In::Query => ("query", false)
+            };
+            json!({ "name": parameter.name, "in": location, "required": required, "schema": parameter.schema })
+        }).collect();
+    }
+    if let Some(request) = operation.request {
+        described["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": reference(request) } }
+        });
+    }
+    described
+}
+
+fn reference(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn path(name: &'static str, schema: Value) -> Parameter {
+    Parameter { name, location: In::Path, schema }
+}
+
+fn query(name: &'static str, schema: Value) -> Parameter {
+    Parameter { name, location: In::Query, schema }
+}
+
+fn operations() -> Vec<Operation> {
+    let tab_id = || path("id", json!({ "type": "string", "format": "uuid" }));
+    let date = || json!({ "type": "string", "format": "date" });
+    // Commands only simulated, answering with the events they would record.
+    let dry_run = || query("dry_run", json!({ "type": "boolean" }));
+    vec![
+        Operation { method: "post", path: "/tabs", tag: "tabs", summary: "Open a tab for a table", roles: Some("waiters on shift"), parameters: vec![dry_run()], request: Some("NewTab"), response: "TabCommandResponse" },
+        Operation { method: "get", path: "/tabs", tag: "tabs", summary: "Open tabs; waiters only see their own", roles: Some("waiters and managers"), parameters: vec![], request: None, response: "TabStatusList" },
+        Operation { method: "post", path: "/tabs/{id}/orders", tag: "tabs", summary: "Order from the menu", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("NewOrder"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/served-drinks", tag: "tabs", summary: "Mark drinks served", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("ServedItems"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/served-food", tag: "tabs", summary: "Mark food served", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("ServedItems"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/unordered-items", tag: "tabs", summary: "Put items served without an order on the tab free of charge", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("UnorderedItems"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/voided-items", tag: "tabs", summary: "Void an item that has not been served", roles: Some("managers"), parameters: vec![tab_id(), dry_run()], request: Some("VoidedItem"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/fired-courses", tag: "tabs", summary: "Send the food held for a course to the kitchen", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("FiredCourse"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/transfer", tag: "tabs", summary: "Move a tab to a free table with its guests", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("TabTransfer"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/comps", tag: "tabs", summary: "Give a served item away", roles: Some("managers"), parameters: vec![tab_id(), dry_run()], request: Some("CompedItem"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/discounts", tag: "tabs", summary: "Take a percentage off the items served so far", roles: Some("managers"), parameters: vec![tab_id(), dry_run()], request: Some("Discount"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/tabs/{id}/settlement", tag: "tabs", summary: "Pay the tab by card through the payment gateway and close it", roles: Some("waiters"), parameters: vec![tab_id(), dry_run()], request: Some("Settlement"), response: "TabCommandResponse" },
+        Operation { method: "post", path: "/shifts/handover", tag: "tabs", summary: "Hand open tabs and their tables over to another waiter at shift change", roles: Some("waiters"), parameters: vec![], request: Some("Handover"), response: "HandedOver" },
+        Operation { method: "get", path: "/tables/{table_number}/invoice", tag: "tabs", summary: "Invoice of the tab open at a table", roles: Some("waiters and managers"), parameters: vec![path("table_number", json!({ "type": "integer" }))], request: None, response: "TabInvoice" },
+        Operation { method: "get", path: "/waiters/{waiter_id}/todo", tag: "tabs", summary: "Items a waiter has to serve, by table", roles: Some("waiters, for themselves, and managers"), parameters: vec![path("waiter_id", json!({ "type": "string", "format": "uuid" }))], request: None, response: "WaiterTodoList" },
+        Operation { method: "get", path: "/kitchen/todo", tag: "kitchen", summary: "Food still to be cooked, oldest order first", roles: Some("chefs and managers"), parameters: vec![], request: None, response: "KitchenTodoList" },
+        Operation { method: "get", path: "/reports/sales/{date}", tag: "reports", summary: "Sales of a day", roles: Some("managers"), parameters: vec![path("date", date())], request: None, response: "DailySales" },
+        Operation { method: "get", path: "/reports/tips", tag: "reports", summary: "Tips per waiter", roles: Some("managers"), parameters: vec![query("from", date()), query("to", date())], request: None, response: "WaiterTipsList" },
+        Operation { method: "get", path: "/reports/forecast", tag: "reports", summary: "How much to prepare for the next service", roles: Some("all staff"), parameters: vec![], request: None, response: "Forecast" }
+    ]
+}
+
+fn eur(amount_minor: i64) -> Money {
+    Money::new(amount_minor, Currency::EUR)
+}
+
+fn example<T: Serialize>(value: T) -> Value {
+    schema_of(&serde_json::to_value(value).unwrap())
+}
+
+fn list_of(name: &str) -> Value {
+    json!({ "type": "array", "items": reference(name) })
+}
+
+fn schemas() -> Value {
+    let tab_id = Uuid::from_u128(0x9b1deb4d_3b7d_4bad_9bdd_2b0d7b3dcb6d);
+    let (coffee_line, soup_line) = docs::sample_line_ids();
+    let coffee = TabItem { line_id: coffee_line, menu_number: 1, description: "Coffee".to_string(), price: eur(250) };
+
+    let mut command_response = example(CommandResponse::<Event> {
+        events: vec![],
+        warnings: vec![ApiWarning { code: "tab_nearing_max_value", message: locale::warning_message("tab_nearing_max_value", Language::English) }]
+    });
+    command_response["properties"]["events"]["items"] = reference("TabEvent");
+    let mut forecast = example(Forecast {
+        date: Date::from_ymd(2024, 5, 17).unwrap(),
+        daypart: Daypart::Lunch,
+        items: vec![PrepSuggestion { menu_number: 2, description: "Soup".to_string(), daily_average: 11.5, quantity: 12 }]
+    });
+    forecast["properties"]["daypart"]["enum"] = serde_json::to_value(vec![Daypart::Breakfast, Daypart::Lunch, Daypart::Afternoon, Daypart::Dinner]).unwrap();
+    // Either names the item; without a line id it is the first of the menu number ordered.
+    let by_line = |mut item: Value| {
+        item["required"] = json!(["reason"]);
+        item["description"] = json!("By line_id, or by menu_number for the first of it ordered.");
+        item
+    };
+    // Modifiers and notes are there only when the guest asked for something, courses only for food.
+    let courses = serde_json::to_value(vec![Course::Starter, Course::Main, Course::Dessert]).unwrap();
+    let mut new_order = example(NewOrder { items: vec![NewOrderItem { menu_number: 2, quantity: 2, modifiers: vec!["No croutons".to_string()], note: Some("One without cream".to_string()), course: Some(Course::Starter) }] });
+    new_order["properties"]["items"]["items"]["properties"]["course"]["enum"] = courses.clone();
+    new_order["properties"]["items"]["items"]["required"] = json!(["menu_number", "quantity"]);
+    let mut todo_list_group = example(TodoListGroup { tab_id, items: vec![TodoListItem { line_id: soup_line, menu_number: 2, description: "Soup".to_string(), modifiers: vec!["No croutons".to_string()], note: Some("Nut allergy".to_string()), course: Some(Course::Starter) }] });
+    todo_list_group["properties"]["items"]["items"]["properties"]["course"]["enum"] = courses.clone();
+    todo_list_group["properties"]["items"]["items"]["required"] = json!(["line_id", "menu_number", "description"]);
+    let mut fired_course = example(FiredCourse { course: Course::Main });
+    fired_course["properties"]["course"]["enum"] = courses;
+    let problem = ApiError::new(Status::UnprocessableEntity, "drinks_not_outstanding", locale::command_error_message(&CommandError::DrinksNotOutstanding(vec![LineRef::MenuNumber(1)]), Language::English))
+        .with_lines(vec![LineRef::Line(coffee_line), LineRef::MenuNumber(1)])
+        .with_incident(tab_id);
+
+    json!({
+        "NewTab": example(NewTab { tab_id, table_number: 5 }),
+        "NewOrder": new_order,
+        "ServedItems": example(ServedItems { lines: vec![ServedLine { line_id: coffee_line, quantity: 2 }], items: vec![OrderLine { menu_number: 2, quantity: 1 }], menu_numbers: None }),
+        "Handover": example(Handover { to_waiter_id: staff::legacy_id("Jane"), tab_ids: Some(vec![tab_id]) }),
+        "HandedOver": example(HandedOver { tab_ids: vec![tab_id], tables: vec![5] }),
+        "VoidedItem": by_line(example(VoidedItem { line_id: Some(coffee_line), menu_number: Some(1), reason: "Spilled".to_string() })),
+        "UnorderedItems": example(UnorderedItems { items: vec![OrderLine { menu_number: 90, quantity: 4 }] }),
+        "TabTransfer": example(TabTransfer { table_number: 7 }),
+        "FiredCourse": fired_course,
+        "CompedItem": by_line(example(CompedItem { line_id: Some(soup_line), menu_number: Some(2), reason: "Soup was cold".to_string() })),
+        "Discount": example(Discount { percent: 10, reason: "Regulars".to_string() }),
+        "Settlement": example(Settlement { amount: eur(800) }),
+        "TabEvent": { "oneOf": docs::tab_event_examples().into_iter().map(example).collect::<Vec<_>>() },
+        "TabCommandResponse": command_response,
+        "TabItem": example(coffee.clone()),
+        "TabStatus": example(TabStatus { tab_id, table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string(), to_serve: vec![coffee.clone()], in_preparation: vec![coffee.clone()], served: vec![coffee.clone()], comped: vec![coffee.clone()], discount: Some(eur(25)) }),
+        "TabStatusList": list_of("TabStatus"),
+        "TabInvoice": example(TabInvoice { tab_id, table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string(), items: vec![coffee.clone()], comped: vec![coffee.clone()], discount: Some(eur(25)), total: eur(225), tax: Some(eur(38)), has_unserved_items: false, nutrition: vec![NutritionFootnote { menu_number: 1, description: "Coffee".to_string(), servings: 1, nutrition: Nutrition { kcal: 5, protein_grams: None, carbohydrate_grams: None, fat_grams: None } }] }),
+        "WaiterTodoList": { "type": "object", "description": "By table number.", "additionalProperties": list_of("TabItem") },
+        "KitchenTodoList": list_of("TodoListGroup"),
+        "TodoListGroup": todo_list_group,
+        "DailySales": example(DailySales { date: Date::from_ymd(2024, 5, 17).unwrap(), served_value: eur(250), items: vec![ItemSales { menu_number: 1, description: "Coffee".to_string(), count: 1, value: eur(250) }], service_items: vec![ItemSales { menu_number: 90, description: "House water".to_string(), count: 4, value: eur(0) }], tabs_closed: 1 }),
+        "WaiterTipsList": list_of("WaiterTips"),
+        "WaiterTips": example(WaiterTips { waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string(), tips: eur(100), tab_count: 1 }),
+        "Forecast": forecast,
+        "Problem": example(problem)
+    })
+}
+
+// Schema of whatever the example is an example of. Fields that are null in it are taken to be
+// optional, and arrays to hold what their first element is.
+fn schema_of(example: &Value) -> Value {
+    match *example {
+        Value::Null => json!({ "nullable": true }),
+        Value::Bool(_) => json!({ "type": "boolean" }),
+        Value::Number(ref number) if number.is_f64() => json!({ "type": "number" }),
+        Value::Number(_) => json!({ "type": "integer" }),
+        Value::String(ref string) if Uuid::parse_str(string).is_ok() => json!({ "type": "string", "format": "uuid" }),
+        Value::String(ref string) if string.parse::<Date>().is_ok() => json!({ "type": "string", "format": "date" }),
+        Value::String(_) => json!({ "type": "string" }),
+        Value::Array(ref items) => json!({ "type": "array", "items": items.first().map(schema_of).unwrap_or_else(|| json!({})) }),
+        Value::Object(ref fields) => {
+            let properties: Map<String, Value> = fields.iter().map(|(name, value)| (name.clone(), schema_of(value))).collect();
+            let required: Vec<&String> = fields.iter().filter(|&(_, value)| !value.is_null()).map(|(name, _)| name).collect();
+            json!({ "type": "object", "properties": properties, "required": required })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_reference_resolves() {
+        let document = document();
+        let schemas = document["components"]["schemas"].as_object().unwrap().clone();
+        let text = serde_json::to_string(&document).unwrap();
+        for reference in text.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(schemas.contains_key(name), "{} is not defined", name);
+        }
+        assert_eq!(document["paths"]["/tabs"].as_object().map(|path| path.len()), Some(2));
+        assert_eq!(document["paths"]["/tabs/{id}/orders"]["post"]["parameters"][1]["name"], json!("dry_run"));
+        assert_eq!(schemas["NewTab"]["properties"]["tab_id"], json!({ "type": "string", "format": "uuid" }));
+        assert_eq!(schemas["TabEvent"]["oneOf"].as_array().map(Vec::len), Some(21));
+        assert_eq!(schemas["Problem"]["required"].as_array().map(Vec::len), Some(7));
+        assert_eq!(schemas["VoidedItem"]["required"], json!(["reason"]));
+        assert_eq!(schemas["NewOrder"]["properties"]["items"]["items"]["properties"]["modifiers"], json!({ "type": "array", "items": { "type": "string" } }));
+        assert_eq!(schemas["FiredCourse"]["properties"]["course"]["enum"], json!(["starter", "main", "dessert"]));
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::cqrs::{named_stream_id, Metadata};
+use crate::cqrs::store::{EventStore, StoreError};
+use crate::money::Money;
+use crate::policy::PolicyError;
+
+// What a payment provider calls back with once a tab has been paid. `event_id` is the
+// provider's own id for the callback, the same on every redelivery.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct PaymentCallback {
+    pub event_id: String,
+    pub tab_id: Uuid,
+    pub amount: Money
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureScheme {
+    // `Stripe-Signature: t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`, with more than
+    // one v1 while a secret is being rolled over.
+    Stripe
+}
+
+fn default_tolerance_seconds() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+struct ProviderSecret {
+    scheme: SignatureScheme,
+    secret: String,
+    // How far the signed timestamp may be from now, so captured callbacks cannot be replayed
+    // later on.
+    #[serde(default = "default_tolerance_seconds")]
+    tolerance_seconds: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureError {
+    UnknownProvider,
+    Missing,
+    Malformed,
+    Mismatch,
+    OutsideReplayWindow
+}
+
+// Signing secrets of the providers whose callbacks are accepted, read from TOML. Callbacks
+// from any other provider are turned away.
+//
+//     [providers.stripe]
+//     scheme = "stripe"
+//     secret = "whsec_..."
+//     tolerance_seconds = 300
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CallbackSecrets {
+    providers: BTreeMap<String, ProviderSecret>
+}
+
+impl CallbackSecrets {
+    pub fn from_toml(source: &str) -> Result<CallbackSecrets, PolicyError> {
+        toml::from_str(source).map_err(PolicyError::Parse)
+    }
+
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<CallbackSecrets, PolicyError> {
+        let mut source = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => CallbackSecrets::from_toml(&source),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(CallbackSecrets::default()),
+            Err(error) => Err(PolicyError::Io(error))
+        }
+    }
+
+    // Checks the signature header against the body exactly as it was received.
+    pub fn verify(&self, provider: &str, signature: Option<&str>, body: &[u8], now: SystemTime) -> Result<(), SignatureError> {
+        let secret = self.providers.get(provider).ok_or(SignatureError::UnknownProvider)?;
+        let signature = signature.ok_or(SignatureError::Missing)?;
+        match secret.scheme {
+            SignatureScheme::Stripe => verify_stripe(secret, signature, body, now)
+        }
+    }
+}
+
+fn verify_stripe(secret: &ProviderSecret, header: &str, body: &[u8], now: SystemTime) -> Result<(), SignatureError> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        let mut pair = part.trim().splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some("t"), Some(value)) => timestamp = value.parse::<u64>().ok(),
+            (Some("v1"), Some(value)) => signatures.push(decode_hex(value).ok_or(SignatureError::Malformed)?),
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or(SignatureError::Malformed)?;
+    if signatures.is_empty() {
+        return Err(SignatureError::Malformed);
+    }
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    if !signatures.iter().any(|signature| mac.clone().verify_slice(signature).is_ok()) {
+        return Err(SignatureError::Mismatch);
+    }
+
+    let now = now.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let skew = now.abs_diff(timestamp);
+    if skew > secret.tolerance_seconds {
+        return Err(SignatureError::OutsideReplayWindow);
+    }
+    Ok(())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok()).collect()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    CallbackReceived { provider: String, external_id: String },
+    // Handling failed for a reason worth retrying, so a redelivery may claim it again.
+    CallbackFailed
+}
+
+// Each external event gets a stream of its own, so the store's optimistic concurrency settles
+// which of several servers handles it.
+pub fn callback_id(provider: &str, external_id: &str) -> Uuid {
+    named_stream_id(provider, external_id)
+}
+
+// Remembers which provider callbacks have been handled, so a redelivered one is not turned into
+// a second command.
+pub struct Deduplicator {
+    store: Box<dyn EventStore<Event>>
+}
+
+impl Deduplicator {
+    pub fn new(store: Box<dyn EventStore<Event>>) -> Deduplicator {
+        Deduplicator { store }
+    }
+
+    // True if the caller is the one to handle the callback. False if it has been handled, or is
+    // being handled elsewhere right now.
+    pub async fn claim(&self, provider: &str, external_id: &str, metadata: &Metadata) -> Result<bool, StoreError> {
+        let stream_id = callback_id(provider, external_id);
+        let stream = self.store.read_stream(stream_id).await?;
+        match stream.events.last().map(|envelope| &envelope.payload) {
+            None | Some(&Event::CallbackFailed) => {},
+            Some(&Event::CallbackReceived { .. }) => return Ok(false)
+        }
+        let received = Event::CallbackReceived { provider: provider.to_string(), external_id: external_id.to_string() };
+        match self.store.append(stream_id, vec![received], stream.version, metadata).await {
+            Ok(_) => Ok(true),
+            Err(StoreError::Concurrency(_)) => Ok(false),
+            Err(error) => Err(error)
+        }
+    }
+
+    pub async fn release(&self, provider: &str, external_id: &str, metadata: &Metadata) -> Result<(), StoreError> {
+        let stream_id = callback_id(provider, external_id);
+        let stream = self.store.read_stream(stream_id).await?;
+        self.store.append(stream_id, vec![Event::CallbackFailed], stream.version, metadata).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::cqrs::store::InMemoryEventStore;
+    use crate::money::Currency;
+
+    #[tokio::test]
+    async fn callbacks_are_claimed_once_unless_released() {
+        let deduplicator = Deduplicator::new(Box::new(InMemoryEventStore::new()));
+        let metadata = Metadata::new();
+        assert_eq!(deduplicator.claim("stripe", "evt_1", &metadata).await, Ok(true));
+        assert_eq!(deduplicator.claim("stripe", "evt_1", &metadata).await, Ok(false));
+        assert_eq!(deduplicator.claim("stripe", "evt_2", &metadata).await, Ok(true));
+        assert_eq!(deduplicator.claim("adyen", "evt_1", &metadata).await, Ok(true));
+
+        deduplicator.release("stripe", "evt_1", &metadata).await.unwrap();
+        assert_eq!(deduplicator.claim("stripe", "evt_1", &metadata).await, Ok(true));
+        assert_eq!(deduplicator.claim("stripe", "evt_1", &metadata).await, Ok(false));
+    }
+
+    // A callback as the provider sent it, signed with "whsec_test_secret" at 1686089970.
+    const SAMPLE_BODY: &str = r#"{"event_id":"evt_1NG8Du2eZvKYlo2CUI79vXWy","tab_id":"9b1deb4d-3b7d-4bad-9bdd-2b0d7b3dcb6d","amount":{"amount_minor":1250,"currency":"EUR"}}"#;
+    const SAMPLE_SIGNATURE: &str = "t=1686089970,v1=8bb8da63c3f731195c0e8c4f91abc5c8bb1f7a0cdb9393805a387f252f2d36eb";
+
+    fn secrets() -> CallbackSecrets {
+        CallbackSecrets::from_toml("[providers.stripe]\nscheme = \"stripe\"\nsecret = \"whsec_test_secret\"\n").unwrap()
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn signed_callbacks_are_verified() {
+        let secrets = secrets();
+        assert_eq!(secrets.verify("stripe", Some(SAMPLE_SIGNATURE), SAMPLE_BODY.as_bytes(), at(1686089970 + 60)), Ok(()));
+        let rolled = format!("t=1686089970,v1={},v1={}", "00".repeat(32), &SAMPLE_SIGNATURE[16..]);
+        assert_eq!(secrets.verify("stripe", Some(&rolled), SAMPLE_BODY.as_bytes(), at(1686089970)), Ok(()));
+        let callback: PaymentCallback = serde_json::from_str(SAMPLE_BODY).unwrap();
+        assert_eq!(callback.amount, Money::new(1250, Currency::EUR));
+    }
+
+    #[test]
+    fn tampered_stale_or_unsigned_callbacks_are_rejected() {
+        let secrets = secrets();
+        let tampered = SAMPLE_BODY.replace("1250", "125");
+        assert_eq!(secrets.verify("stripe", Some(SAMPLE_SIGNATURE), tampered.as_bytes(), at(1686089970)), Err(SignatureError::Mismatch));
+        let retimed = SAMPLE_SIGNATURE.replace("t=1686089970", "t=1686099970");
+        assert_eq!(secrets.verify("stripe", Some(&retimed), SAMPLE_BODY.as_bytes(), at(1686099970)), Err(SignatureError::Mismatch));
+        assert_eq!(secrets.verify("stripe", Some(SAMPLE_SIGNATURE), SAMPLE_BODY.as_bytes(), at(1686089970 + 301)), Err(SignatureError::OutsideReplayWindow));
+        assert_eq!(secrets.verify("stripe", Some("v1=abc"), SAMPLE_BODY.as_bytes(), at(1686089970)), Err(SignatureError::Malformed));
+        assert_eq!(secrets.verify("stripe", None, SAMPLE_BODY.as_bytes(), at(1686089970)), Err(SignatureError::Missing));
+        assert_eq!(secrets.verify("adyen", Some(SAMPLE_SIGNATURE), SAMPLE_BODY.as_bytes(), at(1686089970)), Err(SignatureError::UnknownProvider));
+    }
+
+    #[test]
+    fn callback_ids_are_stable() {
+        assert_eq!(callback_id("stripe", "evt_1"), callback_id("stripe", "evt_1"));
+        assert!(callback_id("stripe", "evt_1") != callback_id("stripe", "evt_2"));
+        assert!(callback_id("stripe", "evt_1") != callback_id("stripev", "t_1"));
+    }
+}
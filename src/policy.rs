@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+
+use crate::cqrs::{Policy, Warning};
+use crate::domain::{Command, CommandError, PaymentShare, State, Tab};
+use crate::money::Money;
+
+// Tunable limits for tabs, read from TOML so operators can change them without a rebuild:
+//
+//     max_tip_percent = 50
+//     tab_value_warning_percent = 80
+//     serve_unordered = [90, 91]
+//
+//     [max_tab_value]
+//     amount_minor = 50000
+//     currency = "EUR"
+//
+// serve_unordered lists the menu numbers of items the venue serves without an order, like house
+// water or bread; without it nothing is.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TabPolicy {
+    pub max_tab_value: Option<Money>,
+    pub max_tip_percent: Option<i64>,
+    pub tab_value_warning_percent: Option<i64>,
+    pub serve_unordered: Vec<i32>
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Io(io::Error),
+    Parse(toml::de::Error)
+}
+
+impl TabPolicy {
+    pub fn from_toml(source: &str) -> Result<TabPolicy, PolicyError> {
+        toml::from_str(source).map_err(PolicyError::Parse)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TabPolicy, PolicyError> {
+        let mut source = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut source)).map_err(PolicyError::Io)?;
+        TabPolicy::from_toml(&source)
+    }
+
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<TabPolicy, PolicyError> {
+        match TabPolicy::load(path) {
+            Err(PolicyError::Io(ref error)) if error.kind() == ErrorKind::NotFound => Ok(TabPolicy::default()),
+            result => result
+        }
+    }
+
+    fn tab_value_after_order(state: &State, command: &Command) -> Option<Money> {
+        match *command {
+            Command::PlaceOrder(_, ref items) => Some(items.iter().fold(state.tab_value(), |total, item| total + item.total())),
+            _ => None
+        }
+    }
+}
+
+impl Policy<Tab> for TabPolicy {
+    fn check(&self, state: &State, command: &Command) -> Result<(), CommandError> {
+        match *command {
+            Command::PlaceOrder(..) => {
+                if let (Some(max_tab_value), Some(tab_value)) = (self.max_tab_value, TabPolicy::tab_value_after_order(state, command)) {
+                    if tab_value.currency() == max_tab_value.currency() && tab_value > max_tab_value {
+                        return Err(CommandError::TabValueLimitExceeded);
+                    }
+                }
+                Ok(())
+            },
+            Command::CloseTab(..) | Command::CloseTabSplit(..) => {
+                let order_value = state.amount_due();
+                let amount_paid = match *command {
+                    Command::CloseTabSplit(_, ref shares, _) => PaymentShare::total(shares, order_value.currency()),
+                    Command::CloseTab(_, amount_paid, _) => Some(amount_paid),
+                    _ => None
+                };
+                if let (Some(max_tip_percent), Some(amount_paid)) = (self.max_tip_percent, amount_paid) {
+                    if amount_paid.currency() == order_value.currency() && amount_paid - order_value > order_value.mul_ratio(max_tip_percent, 100) {
+                        return Err(CommandError::TipTooHigh);
+                    }
+                }
+                Ok(())
+            },
+            Command::ServeUnordered(_, ref items) => {
+                let mut not_allowed: Vec<i32> = items.iter().map(|item| item.menu_number()).filter(|menu_number| !self.serve_unordered.contains(menu_number)).collect();
+                not_allowed.sort_unstable();
+                not_allowed.dedup();
+                if not_allowed.is_empty() { Ok(()) } else { Err(CommandError::ServeUnorderedNotAllowed(not_allowed)) }
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn warnings(&self, state: &State, command: &Command) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+
+        if let (Some(max_tab_value), Some(percent), Some(tab_value)) = (self.max_tab_value, self.tab_value_warning_percent, TabPolicy::tab_value_after_order(state, command)) {
+            if tab_value.currency() == max_tab_value.currency() && tab_value > max_tab_value.mul_ratio(percent, 100) {
+                warnings.push(Warning { code: "tab_nearing_max_value" });
+            }
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Aggregate;
+    use crate::domain::{Event, OrderedItem};
+    use crate::money::Currency;
+    use crate::staff;
+    use uuid::Uuid;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    fn item(price: Money) -> OrderedItem {
+        OrderedItem::new(1, String::new(), true, price)
+    }
+
+    fn open_tab() -> State {
+        let mut state = Tab::initial_state();
+        Tab::evolve(&mut state, Event::TabOpened { table_number: 42, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        state
+    }
+
+    #[test]
+    fn can_be_read_from_toml() {
+        let policy = TabPolicy::from_toml("max_tip_percent = 50\n[max_tab_value]\namount_minor = 50000\ncurrency = \"EUR\"\n").unwrap();
+        assert_eq!(policy, TabPolicy { max_tab_value: Some(eur(50000)), max_tip_percent: Some(50), tab_value_warning_percent: None, serve_unordered: vec![] });
+        assert_eq!(TabPolicy::from_toml("").unwrap(), TabPolicy::default());
+    }
+
+    #[test]
+    fn rejects_orders_over_max_tab_value() {
+        let policy = TabPolicy { max_tab_value: Some(eur(1000)), ..TabPolicy::default() };
+        let mut state = open_tab();
+        Tab::evolve(&mut state, Event::DrinksOrdered { items: vec![item(eur(600))] });
+        assert_eq!(policy.check(&state, &Command::PlaceOrder(Uuid::new_v4(), vec![item(eur(400))])), Ok(()));
+        assert_eq!(policy.check(&state, &Command::PlaceOrder(Uuid::new_v4(), vec![item(eur(401))])), Err(CommandError::TabValueLimitExceeded));
+    }
+
+    #[test]
+    fn rejects_tips_over_max_tip_percent() {
+        let policy = TabPolicy { max_tip_percent: Some(50), ..TabPolicy::default() };
+        let mut state = open_tab();
+        let line_id = Uuid::new_v4();
+        Tab::evolve(&mut state, Event::DrinksOrdered { items: vec![item(eur(1000)).with_line_id(line_id)] });
+        Tab::evolve(&mut state, Event::DrinksServed { line_ids: vec![line_id], menu_numbers: vec![1] });
+        assert_eq!(policy.check(&state, &Command::CloseTab(Uuid::new_v4(), eur(1500), None)), Ok(()));
+        assert_eq!(policy.check(&state, &Command::CloseTab(Uuid::new_v4(), eur(1501), None)), Err(CommandError::TipTooHigh));
+    }
+
+    #[test]
+    fn only_allowed_items_are_served_without_an_order() {
+        let policy = TabPolicy::from_toml("serve_unordered = [90, 91]\n").unwrap();
+        let state = open_tab();
+        let water = OrderedItem::new(90, "House water".to_string(), true, eur(0));
+        let coke = OrderedItem::new(3, "Coke".to_string(), true, eur(300));
+        assert_eq!(policy.check(&state, &Command::ServeUnordered(Uuid::new_v4(), vec![water.clone()])), Ok(()));
+        assert_eq!(policy.check(&state, &Command::ServeUnordered(Uuid::new_v4(), vec![water.clone(), coke])), Err(CommandError::ServeUnorderedNotAllowed(vec![3])));
+        assert_eq!(TabPolicy::default().check(&state, &Command::ServeUnordered(Uuid::new_v4(), vec![water])), Err(CommandError::ServeUnorderedNotAllowed(vec![90])));
+    }
+
+    #[test]
+    fn warns_when_tab_nears_max_value() {
+        let policy = TabPolicy { max_tab_value: Some(eur(1000)), tab_value_warning_percent: Some(80), ..TabPolicy::default() };
+        let state = open_tab();
+        assert_eq!(policy.warnings(&state, &Command::PlaceOrder(Uuid::new_v4(), vec![item(eur(800))])), vec![]);
+        assert_eq!(policy.warnings(&state, &Command::PlaceOrder(Uuid::new_v4(), vec![item(eur(801))])), vec![Warning { code: "tab_nearing_max_value" }]);
+    }
+}
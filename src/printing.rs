@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use uuid::Uuid;
+
+use crate::cqrs::EventEnvelope;
+use crate::date::Date;
+use crate::domain::{Course, Event, OrderedItem};
+
+// Food as the kitchen gets it on paper, by the table it goes to. Food held for a course is
+// printed when the course is fired.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintedTicket {
+    pub tab_id: Uuid,
+    pub table_number: Option<u8>,
+    pub printed_for: SystemTime,
+    pub course: Option<Course>,
+    pub items: Vec<OrderedItem>
+}
+
+impl PrintedTicket {
+    // The ticket as plain lines: the table, the time in UTC, then each dish with how many, what
+    // to change about it and the note.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![match self.table_number {
+            Some(table_number) => format!("Table {}", table_number),
+            None => "Table ?".to_string()
+        }];
+        let minutes = self.printed_for.duration_since(Date::of(self.printed_for).start()).map(|since_midnight| since_midnight.as_secs() / 60).unwrap_or(0);
+        lines.push(format!("{:02}:{:02}", minutes / 60, minutes % 60));
+        if let Some(course) = self.course {
+            lines.push(format!("-- {} --", match course {
+                Course::Starter => "Starter",
+                Course::Main => "Main",
+                Course::Dessert => "Dessert"
+            }));
+        }
+        for item in &self.items {
+            lines.push(format!("{} x {}", item.quantity(), item.description()));
+            lines.extend(item.modifiers().iter().map(|modifier| format!("   - {}", modifier)));
+            lines.extend(item.note().map(|note| format!("   ! {}", note)));
+        }
+        lines
+    }
+}
+
+// Where kitchen tickets are printed. Printing is done off the command path, on a thread of its
+// own, so a slow or unplugged printer only holds up the tickets.
+pub trait TicketPrinter: Send + Sync {
+    fn print(&self, ticket: &PrintedTicket) -> io::Result<()>;
+}
+
+// Which printer tickets go to, from a table of the configuration:
+//
+//     [global.cafe.printer]
+//     printer = "esc_pos"
+//     address = "192.168.1.50:9100"
+//
+// ESC/POS printers are reached over the raw TCP port most network printers listen on. Stdout is
+// for trying the flow out without one.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "printer", rename_all = "snake_case")]
+pub enum PrinterSetup {
+    Stdout,
+    EscPos { address: String }
+}
+
+impl PrinterSetup {
+    pub fn open(&self) -> Arc<dyn TicketPrinter> {
+        match *self {
+            PrinterSetup::Stdout => Arc::new(StdoutPrinter),
+            PrinterSetup::EscPos { ref address } => Arc::new(EscPosPrinter::new(address.clone()))
+        }
+    }
+}
+
+pub struct StdoutPrinter;
+
+impl TicketPrinter for StdoutPrinter {
+    fn print(&self, ticket: &PrintedTicket) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for line in ticket.lines() {
+            writeln!(out, "{}", line)?;
+        }
+        writeln!(out)
+    }
+}
+
+const ESC: u8 = 0x1b;
+const GS: u8 = 0x1d;
+
+// Connects for every ticket, so a printer switched off and on again is picked up by the next
+// one.
+pub struct EscPosPrinter {
+    address: String,
+    timeout: Duration
+}
+
+impl EscPosPrinter {
+    pub fn new(address: String) -> EscPosPrinter {
+        EscPosPrinter { address, timeout: Duration::from_secs(5) }
+    }
+}
+
+// The table is printed at double size so it can be read from across the pass; the paper is fed
+// past the cutter and cut. The printers' own code page only has ASCII in common with UTF-8, so
+// other characters are printed as '?'.
+pub fn escpos(ticket: &PrintedTicket) -> Vec<u8> {
+    let mut bytes = vec![ESC, b'@'];
+    for (index, line) in ticket.lines().into_iter().enumerate() {
+        let size = if index == 0 { 0x11 } else { 0x00 };
+        bytes.extend_from_slice(&[GS, b'!', size]);
+        bytes.extend(line.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }));
+        bytes.push(b'\n');
+    }
+    bytes.extend_from_slice(&[ESC, b'd', 4, GS, b'V', 0x41, 3]);
+    bytes
+}
+
+impl TicketPrinter for EscPosPrinter {
+    fn print(&self, ticket: &PrintedTicket) -> io::Result<()> {
+        let address = self.address.parse().map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", self.address, error)))?;
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(&escpos(ticket))?;
+        stream.flush()
+    }
+}
+
+// Turns ordered food into tickets, keeping what it needs to: the table of each open tab, and food
+// held for a course that has not been fired.
+#[derive(Debug, Default)]
+pub struct KitchenPrinting {
+    tables: HashMap<Uuid, u8>,
+    held: HashMap<Uuid, Vec<OrderedItem>>,
+    fired: HashMap<Uuid, Vec<Course>>
+}
+
+impl KitchenPrinting {
+    pub fn new() -> KitchenPrinting {
+        KitchenPrinting::default()
+    }
+
+    pub fn apply(&mut self, envelope: &EventEnvelope<Event>) -> Vec<PrintedTicket> {
+        let (tab_id, table_number) = (envelope.stream_id, self.tables.get(&envelope.stream_id).cloned());
+        let ticket = |course, items| PrintedTicket { tab_id, table_number, printed_for: envelope.timestamp, course, items };
+        match envelope.payload {
+            Event::FoodOrdered { ref items } => {
+                let fired = self.fired.get(&tab_id).cloned().unwrap_or_default();
+                let (released, held): (Vec<OrderedItem>, Vec<OrderedItem>) = items.iter().cloned().partition(|item| item.course().is_none_or(|course| fired.contains(&course)));
+                let tickets = if released.is_empty() { Vec::new() } else { vec![ticket(None, released)] };
+                self.held.entry(tab_id).or_default().extend(held);
+                tickets
+            },
+            Event::CourseFired { course } => {
+                let held = self.held.remove(&tab_id).unwrap_or_default();
+                let (fired, held): (Vec<OrderedItem>, Vec<OrderedItem>) = held.into_iter().partition(|item| item.course() == Some(course));
+                let tickets = if fired.is_empty() { Vec::new() } else { vec![ticket(Some(course), fired)] };
+                self.held.insert(tab_id, held);
+                self.fired.entry(tab_id).or_default().push(course);
+                tickets
+            },
+            // Held food that is no longer wanted is not printed when its course is fired.
+            Event::ItemVoided { line_id, .. } => {
+                self.take_held(tab_id, &[line_id]);
+                Vec::new()
+            },
+            Event::FoodServed { ref line_ids, .. } => {
+                self.take_held(tab_id, line_ids);
+                Vec::new()
+            },
+            Event::TabOpened { table_number, .. } | Event::TabTransferred { table_number, .. } => {
+                self.tables.insert(tab_id, table_number);
+                Vec::new()
+            },
+            Event::TabClosed { .. } | Event::TabForceClosed { .. } | Event::TabPurged { .. } => {
+                self.tables.remove(&tab_id);
+                self.held.remove(&tab_id);
+                self.fired.remove(&tab_id);
+                Vec::new()
+            },
+            _ => Vec::new()
+        }
+    }
+
+    fn take_held(&mut self, tab_id: Uuid, line_ids: &[Uuid]) {
+        let held = match self.held.get_mut(&tab_id) {
+            Some(held) => held,
+            None => return
+        };
+        for &line_id in line_ids {
+            if let Some(index) = held.iter().position(|item| item.line_id() == line_id) {
+                let left = held[index].quantity() - 1;
+                if left == 0 {
+                    held.remove(index);
+                } else {
+                    held[index] = held[index].clone().with_quantity(left);
+                }
+            }
+        }
+    }
+}
+
+// Prints the tickets of food ordered from `since` on. The history before it is only read to know
+// the tables and the held food, so restarting the server does not print the day again.
+pub fn spawn(mut events: UnboundedReceiver<EventEnvelope<Event>>, printer: Arc<dyn TicketPrinter>, since: SystemTime) {
+    thread::spawn(move || {
+        let mut printing = KitchenPrinting::new();
+        while let Some(envelope) = events.blocking_recv() {
+            let tickets = printing.apply(&envelope);
+            if envelope.timestamp < since {
+                continue;
+            }
+            for ticket in tickets {
+                if let Err(error) = printer.print(&ticket) {
+                    eprintln!("failed to print the kitchen ticket of tab {}: {}", ticket.tab_id, error);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Metadata;
+    use crate::cqrs::store::{EventStore, InMemoryEventStore};
+    use crate::money::{Currency, Money};
+    use crate::staff;
+
+    fn food(menu_number: i32, description: &str, course: Option<Course>) -> OrderedItem {
+        OrderedItem::new(menu_number, description.to_string(), false, Money::new(450, Currency::EUR)).with_course(course).with_line_id(Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    async fn food_is_printed_for_its_table_as_it_goes_to_the_kitchen() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let soup = food(2, "Soup", None).with_quantity(2).with_modifiers(vec!["No croutons".to_string()]).with_note(Some("Nut allergy".to_string()));
+        let steak = food(7, "Steak", Some(Course::Main)).with_quantity(2);
+        let voided = Event::ItemVoided { line_id: steak.line_id(), menu_number: 7, reason: "One guest left".to_string() };
+        let events = vec![
+            Event::TabOpened { table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() },
+            Event::TabTransferred { from_table_number: 5, table_number: 7 },
+            Event::FoodOrdered { items: vec![soup, steak] },
+            Event::DrinksOrdered { items: vec![OrderedItem::new(1, "Coffee".to_string(), true, Money::new(250, Currency::EUR))] },
+            voided,
+            Event::CourseFired { course: Course::Main }
+        ];
+        let recorded = store.append(tab_id, events, 0, &Metadata::new()).await.unwrap();
+
+        let mut printing = KitchenPrinting::new();
+        let tickets: Vec<PrintedTicket> = recorded.iter().flat_map(|envelope| printing.apply(envelope)).collect();
+        assert_eq!(tickets.iter().map(|ticket| (ticket.table_number, ticket.course, ticket.items.len())).collect::<Vec<_>>(), vec![(Some(7), None, 1), (Some(7), Some(Course::Main), 1)]);
+        assert_eq!(&tickets[0].lines()[2..], ["2 x Soup", "   - No croutons", "   ! Nut allergy"]);
+        assert_eq!(&tickets[1].lines()[2..], ["-- Main --", "1 x Steak"]);
+
+        let bytes = escpos(&tickets[1]);
+        assert!(bytes.starts_with(&[ESC, b'@', GS, b'!', 0x11, b'T']));
+        assert!(bytes.ends_with(&[GS, b'V', 0x41, 3]));
+    }
+}
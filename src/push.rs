@@ -0,0 +1,320 @@
+use std::io;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::sync::mpsc::UnboundedReceiver;
+use tungstenite::{self, Message};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+
+use crate::auth::{Role, User};
+use crate::cqrs::{EventEnvelope, Span, Stage, Traces};
+use crate::domain::Event;
+use crate::read_model::{ChefTodoList, OpenTabs};
+use crate::staff::WaiterId;
+
+// What a display is showing: /ws/kitchen or /ws/waiter/<staff id>.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Topic {
+    Kitchen,
+    Waiter(WaiterId)
+}
+
+impl Topic {
+    pub fn path(&self) -> String {
+        match *self {
+            Topic::Kitchen => "/ws/kitchen".to_string(),
+            Topic::Waiter(waiter_id) => format!("/ws/waiter/{}", waiter_id)
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<Topic> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["ws", "kitchen"] => Some(Topic::Kitchen),
+            ["ws", "waiter", waiter_id] => waiter_id.parse().ok().map(Topic::Waiter),
+            _ => None
+        }
+    }
+
+    // The same as for GET /kitchen/todo and GET /waiters/<waiter_id>/todo, see access.rs, except
+    // that another waiter's list is refused rather than shown empty.
+    pub fn permits(&self, user: &User) -> bool {
+        match (self, user.role) {
+            (Topic::Kitchen, role) => role != Role::Waiter,
+            (Topic::Waiter(_), Role::Manager) => true,
+            (Topic::Waiter(waiter_id), Role::Waiter) => *waiter_id == user.staff_id,
+            (Topic::Waiter(_), Role::Chef) => false
+        }
+    }
+}
+
+const POLL: Duration = Duration::from_millis(100);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// Who the Authorization header of a request belongs to, as the REST routes would tell.
+pub type Authenticate = Box<dyn Fn(&str) -> Option<User> + Send + Sync>;
+
+enum Push {
+    Unchanged,
+    Sent,
+    // The display is no longer listening.
+    Gone
+}
+
+struct Display {
+    topic: Topic,
+    sender: Sender<String>,
+    last_sent: Option<String>
+}
+
+// The connected displays and the read models they show. Each display gets its todo list as
+// JSON when it connects and again whenever the list changes.
+pub struct Displays {
+    open_tabs: Arc<RwLock<OpenTabs>>,
+    chef_todo_list: Arc<RwLock<ChefTodoList>>,
+    displays: Mutex<Vec<Display>>
+}
+
+impl Displays {
+    pub fn new(open_tabs: Arc<RwLock<OpenTabs>>, chef_todo_list: Arc<RwLock<ChefTodoList>>) -> Displays {
+        Displays { open_tabs, chef_todo_list, displays: Mutex::new(Vec::new()) }
+    }
+
+    pub fn connect(&self, topic: Topic) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        let mut display = Display { topic, sender, last_sent: None };
+        self.push(&mut display);
+        self.displays.lock().unwrap().push(display);
+        receiver
+    }
+
+    // Returns the topics of the displays that were sent a new list. Displays whose connection
+    // has gone away are dropped here.
+    pub fn publish(&self) -> Vec<Topic> {
+        let mut displays = self.displays.lock().unwrap();
+        let mut connected = Vec::with_capacity(displays.len());
+        let mut sent = Vec::new();
+        for mut display in displays.drain(..) {
+            match self.push(&mut display) {
+                Push::Gone => continue,
+                Push::Sent => sent.push(display.topic.clone()),
+                Push::Unchanged => {}
+            }
+            connected.push(display);
+        }
+        *displays = connected;
+        sent
+    }
+
+    fn push(&self, display: &mut Display) -> Push {
+        let payload = match display.topic {
+            Topic::Kitchen => serde_json::to_string(&self.chef_todo_list.read().unwrap().todo_list()),
+            Topic::Waiter(waiter_id) => serde_json::to_string(&self.open_tabs.read().unwrap().todo_list_for_waiter_id(waiter_id))
+        }.expect("todo lists serialize to JSON");
+        if display.last_sent.as_ref() == Some(&payload) {
+            return Push::Unchanged;
+        }
+        display.last_sent = Some(payload.clone());
+        if display.sender.send(payload).is_ok() { Push::Sent } else { Push::Gone }
+    }
+}
+
+// Rocket cannot upgrade connections, so displays connect to a listener of their own. The events
+// come from EventStore::listen, which must be called after the read models are subscribed so
+// they have seen an event by the time the displays are told about it. Each event is traced with
+// the displays it changed. Displays sign in with the bearer tokens of the API.
+pub fn spawn<A: ToSocketAddrs>(address: A, mut events: UnboundedReceiver<EventEnvelope<Event>>, displays: Arc<Displays>, traces: Arc<Traces>, authenticate: Authenticate) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    let publisher = displays.clone();
+    thread::spawn(move || {
+        while let Some(envelope) = events.blocking_recv() {
+            let (started_at, started) = (SystemTime::now(), Instant::now());
+            let sent = publisher.publish();
+            if traces.is_live(&envelope) {
+                let span = Span::new(Stage::Notification, "displays", envelope.event_id, started_at, started.elapsed())
+                    .with_effects(sent.iter().map(Topic::path).collect());
+                traces.record(envelope.correlation_id, span);
+            }
+        }
+    });
+
+    let authenticate = Arc::new(authenticate);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (displays, authenticate) = (displays.clone(), authenticate.clone());
+            thread::spawn(move || serve(stream, &displays, &authenticate));
+        }
+    });
+    Ok(())
+}
+
+// Browsers cannot set headers on a websocket, so the token may come as ?token=<token> instead.
+fn topic_of(request: &Request, authenticate: &Authenticate) -> Result<Topic, StatusCode> {
+    let topic = Topic::from_path(request.uri().path()).ok_or(StatusCode::NOT_FOUND)?;
+    let header = request.headers().get("Authorization").and_then(|header| header.to_str().ok()).map(str::to_string);
+    let query_token = request.uri().query().and_then(|query| query.split('&').find_map(|pair| pair.strip_prefix("token=")));
+    let user = header.or_else(|| query_token.map(|token| format!("Bearer {}", token)))
+        .and_then(|header| authenticate(&header))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if topic.permits(&user) { Ok(topic) } else { Err(StatusCode::FORBIDDEN) }
+}
+
+// The handshake callback's error is tungstenite's own response type.
+#[allow(clippy::result_large_err)]
+fn serve(stream: TcpStream, displays: &Displays, authenticate: &Authenticate) -> io::Result<()> {
+    let mut topic = None;
+    let accept = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        match topic_of(request, authenticate) {
+            Ok(accepted) => {
+                topic = Some(accepted);
+                Ok(response)
+            }
+            Err(status) => {
+                let mut refused = ErrorResponse::new(None);
+                *refused.status_mut() = status;
+                Err(refused)
+            }
+        }
+    };
+    let mut websocket = tungstenite::accept_hdr(stream, accept).map_err(broken)?;
+    let topic = match topic {
+        Some(topic) => topic,
+        None => return Ok(())
+    };
+
+    // Reads time out so new lists go out between frames. A display that has not been heard from,
+    // not even a pong, within two pings is taken to be gone, which ends the thread.
+    websocket.get_ref().set_read_timeout(Some(POLL))?;
+    websocket.get_ref().set_write_timeout(Some(PING_INTERVAL))?;
+    let lists = displays.connect(topic);
+    let (mut heard, mut pinged) = (Instant::now(), Instant::now());
+    loop {
+        loop {
+            match lists.try_recv() {
+                Ok(payload) => websocket.send(Message::text(payload)).map_err(broken)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(())
+            }
+        }
+        // Pings are answered by tungstenite, with the pong going out on the next write or flush.
+        match websocket.read() {
+            // Sends back tungstenite's reply to the close.
+            Ok(Message::Close(_)) => {
+                let _ = websocket.flush();
+                return Ok(());
+            }
+            Ok(_) => heard = Instant::now(),
+            Err(tungstenite::Error::Io(error)) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                websocket.flush().map_err(broken)?;
+            }
+            Err(_) => return Ok(())
+        }
+        if heard.elapsed() > 2 * PING_INTERVAL {
+            return Ok(());
+        }
+        if pinged.elapsed() > PING_INTERVAL {
+            websocket.send(Message::Ping(Default::default())).map_err(broken)?;
+            pinged = Instant::now();
+        }
+    }
+}
+
+fn broken<E: ToString>(error: E) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+    use crate::cqrs::Projection;
+    use crate::domain::OrderedItem;
+    use crate::money::{Currency, Money};
+    use crate::staff;
+
+    #[test]
+    fn topics_come_from_the_path() {
+        let derek = staff::legacy_id("Derek");
+        assert_eq!(Topic::from_path("/ws/kitchen"), Some(Topic::Kitchen));
+        assert_eq!(Topic::from_path(&format!("/ws/waiter/{}", derek)), Some(Topic::Waiter(derek)));
+        assert_eq!(Topic::from_path("/ws/waiter/Derek"), None);
+        assert_eq!(Topic::from_path("/ws/waiter/"), None);
+        assert_eq!(Topic::from_path("/api/tabs"), None);
+        assert_eq!(Topic::from_path(&Topic::Waiter(derek).path()), Some(Topic::Waiter(derek)));
+    }
+
+    #[test]
+    fn displays_sign_in_like_the_api() {
+        let user = |name: &str, role| User { name: name.to_string(), role, staff_id: staff::legacy_id(name) };
+        let authenticate: Authenticate = Box::new(move |header: &str| match header {
+            "Bearer derek" => Some(user("Derek", Role::Waiter)),
+            "Bearer gordon" => Some(user("Gordon", Role::Chef)),
+            "Bearer mary" => Some(user("Mary", Role::Manager)),
+            _ => None
+        });
+        let topic = |uri: &str, header: Option<&str>| {
+            let request = header.iter().fold(Request::builder().uri(uri), |request, header| request.header("Authorization", *header));
+            topic_of(&request.body(()).unwrap(), &authenticate)
+        };
+        let (derek, jane) = (Topic::Waiter(staff::legacy_id("Derek")).path(), Topic::Waiter(staff::legacy_id("Jane")).path());
+        assert_eq!(topic(&derek, Some("Bearer derek")), Ok(Topic::Waiter(staff::legacy_id("Derek"))));
+        assert_eq!(topic(&format!("{}?token=derek", derek), None), Ok(Topic::Waiter(staff::legacy_id("Derek"))));
+        assert_eq!(topic(&jane, Some("Bearer mary")), Ok(Topic::Waiter(staff::legacy_id("Jane"))));
+        assert_eq!(topic(&jane, Some("Bearer derek")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(topic(&derek, Some("Bearer gordon")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(topic("/ws/kitchen", Some("Bearer gordon")), Ok(Topic::Kitchen));
+        assert_eq!(topic("/ws/kitchen", Some("Bearer derek")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(topic("/ws/kitchen", Some("Bearer nobody")), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(topic("/ws/kitchen", None), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(topic("/ws/bar", Some("Bearer mary")), Err(StatusCode::NOT_FOUND));
+    }
+
+    #[test]
+    fn displays_get_their_list_when_it_changes() {
+        let open_tabs = Arc::new(RwLock::new(OpenTabs::new()));
+        let chef_todo_list = Arc::new(RwLock::new(ChefTodoList::new()));
+        let displays = Displays::new(open_tabs.clone(), chef_todo_list.clone());
+        let kitchen = displays.connect(Topic::Kitchen);
+        let waiter = displays.connect(Topic::Waiter(staff::legacy_id("Derek")));
+        assert_eq!(kitchen.try_iter().collect::<Vec<_>>(), vec!["[]"]);
+        assert_eq!(waiter.try_iter().collect::<Vec<_>>(), vec!["{}"]);
+
+        let tab_id = Uuid::new_v4();
+        let soup = OrderedItem::new(1, "Soup".to_string(), false, Money::new(450, Currency::EUR)).with_line_id(Uuid::new_v4());
+        open_tabs.write().unwrap().apply(tab_id, &Event::TabOpened { table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        let soup_line = soup.line_id();
+        chef_todo_list.write().unwrap().apply(tab_id, &Event::FoodOrdered { items: vec![soup] });
+        assert_eq!(displays.publish(), vec![Topic::Kitchen]);
+        assert_eq!(kitchen.try_iter().count(), 1);
+        assert_eq!(waiter.try_iter().count(), 0);
+
+        drop(kitchen);
+        chef_todo_list.write().unwrap().apply(tab_id, &Event::FoodServed { line_ids: vec![soup_line], menu_numbers: vec![1] });
+        assert_eq!(displays.publish(), vec![]);
+        assert_eq!(displays.displays.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn displays_are_answered_until_they_close() {
+        let displays = Displays::new(Arc::new(RwLock::new(OpenTabs::new())), Arc::new(RwLock::new(ChefTodoList::new())));
+        let authenticate: Authenticate = Box::new(|_: &str| Some(User { name: "Mary".to_string(), role: Role::Manager, staff_id: staff::legacy_id("Mary") }));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &displays, &authenticate)
+        });
+
+        let (mut client, _) = tungstenite::connect(format!("ws://{}/ws/kitchen?token=mary", address)).unwrap();
+        assert_eq!(client.read().unwrap(), Message::text("[]"));
+        client.send(Message::Ping(vec![1].into())).unwrap();
+        assert_eq!(client.read().unwrap(), Message::Pong(vec![1].into()));
+        client.close(None).unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,1101 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::Bound::{Included, Unbounded};
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::auth::Role;
+use crate::cqrs::{Projection, Query, QueryHandler, TimedProjection};
+use crate::cqrs::store::{EventStore, StoreError};
+use crate::date::Date;
+use crate::domain::{Course, Event, OrderedItem};
+use crate::menu::{self, MenuItem, Nutrition};
+use crate::money::Money;
+use crate::shift;
+use crate::staff::{self, WaiterId};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TabItem {
+    pub line_id: Uuid,
+    pub menu_number: i32,
+    pub description: String,
+    pub price: Money
+}
+
+impl<'a> From<&'a OrderedItem> for TabItem {
+    fn from(item: &'a OrderedItem) -> TabItem {
+        TabItem {
+            line_id: item.line_id(),
+            menu_number: item.menu_number(),
+            description: item.description().to_string(),
+            price: item.price()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TabStatus {
+    pub tab_id: Uuid,
+    pub table_number: u8,
+    pub waiter_id: WaiterId,
+    pub waiter: String,
+    pub to_serve: Vec<TabItem>,
+    pub in_preparation: Vec<TabItem>,
+    pub served: Vec<TabItem>,
+    // Served items a manager gave away, no longer charged.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub comped: Vec<TabItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount: Option<Money>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TabInvoice {
+    pub tab_id: Uuid,
+    pub table_number: u8,
+    pub waiter_id: WaiterId,
+    pub waiter: String,
+    pub items: Vec<TabItem>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub comped: Vec<TabItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discount: Option<Money>,
+    // The items less the discount, what closing the tab takes.
+    pub total: Money,
+    // The tax included in the total, where the deployment charges any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tax: Option<Money>,
+    pub has_unserved_items: bool,
+    // Per serving of each item served, where the deployment prints nutrition on invoices.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub nutrition: Vec<NutritionFootnote>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NutritionFootnote {
+    pub menu_number: i32,
+    pub description: String,
+    pub servings: usize,
+    pub nutrition: Nutrition
+}
+
+impl TabInvoice {
+    // Prices include tax, so the tax is the part of the total over 100% + the rate.
+    pub fn with_tax(mut self, rate_basis_points: i64) -> TabInvoice {
+        if rate_basis_points > 0 {
+            self.tax = Some(self.total.mul_ratio(rate_basis_points, 10000 + rate_basis_points));
+        }
+        self
+    }
+
+    // From the menu as it is now; items without a declaration, or no longer on the menu, have no
+    // footnote.
+    pub fn with_nutrition(mut self, catalog: &Catalog) -> TabInvoice {
+        let mut footnotes: BTreeMap<i32, NutritionFootnote> = BTreeMap::new();
+        for item in &self.items {
+            if let Some(nutrition) = catalog.items.get(&item.menu_number).and_then(|on_menu| on_menu.nutrition) {
+                footnotes.entry(item.menu_number)
+                    .or_insert_with(|| NutritionFootnote { menu_number: item.menu_number, description: item.description.clone(), servings: 0, nutrition })
+                    .servings += 1;
+            }
+        }
+        self.nutrition = footnotes.into_values().collect();
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OpenTabs {
+    tabs: HashMap<Uuid, TabStatus>
+}
+
+impl OpenTabs {
+    pub fn new() -> OpenTabs {
+        OpenTabs::default()
+    }
+
+    pub fn active_table_numbers(&self) -> Vec<u8> {
+        let mut table_numbers: Vec<u8> = self.tabs.values().map(|tab| tab.table_number).collect();
+        table_numbers.sort();
+        table_numbers
+    }
+
+    pub fn tabs(&self) -> Vec<TabStatus> {
+        let mut tabs: Vec<TabStatus> = self.tabs.values().cloned().collect();
+        tabs.sort_by_key(|tab| tab.table_number);
+        tabs
+    }
+
+    pub fn tab_id_for_table(&self, table_number: u8) -> Option<Uuid> {
+        self.tab_for_table(table_number).map(|tab| tab.tab_id)
+    }
+
+    pub fn invoice_for_table(&self, table_number: u8) -> Option<TabInvoice> {
+        self.tab_for_table(table_number).map(|tab| {
+            let value: Money = tab.served.iter().map(|item| &item.price).sum();
+            TabInvoice {
+                tab_id: tab.tab_id,
+                table_number: tab.table_number,
+                waiter_id: tab.waiter_id,
+                waiter: tab.waiter.clone(),
+                items: tab.served.clone(),
+                comped: tab.comped.clone(),
+                discount: tab.discount,
+                total: match tab.discount {
+                    Some(discount) if discount > value => Money::zero(value.currency()),
+                    Some(discount) => value - discount,
+                    None => value
+                },
+                tax: None,
+                has_unserved_items: !tab.to_serve.is_empty() || !tab.in_preparation.is_empty(),
+                nutrition: Vec::new()
+            }
+        })
+    }
+
+    // The waiter's tabs, e.g. to hand over at the end of their shift.
+    pub fn tabs_of_waiter(&self, waiter_id: WaiterId) -> Vec<TabStatus> {
+        self.tabs().into_iter().filter(|tab| tab.waiter_id == waiter_id).collect()
+    }
+
+    pub fn todo_list_for_waiter(&self, waiter: &str) -> BTreeMap<u8, Vec<TabItem>> {
+        self.todo_list_where(|tab| tab.waiter == waiter)
+    }
+
+    pub fn todo_list_for_waiter_id(&self, waiter_id: WaiterId) -> BTreeMap<u8, Vec<TabItem>> {
+        self.todo_list_where(|tab| tab.waiter_id == waiter_id)
+    }
+
+    fn todo_list_where<F: Fn(&TabStatus) -> bool>(&self, of_waiter: F) -> BTreeMap<u8, Vec<TabItem>> {
+        self.tabs.values()
+            .filter(|tab| of_waiter(tab) && !tab.to_serve.is_empty())
+            .map(|tab| (tab.table_number, tab.to_serve.clone()))
+            .collect()
+    }
+
+    fn tab_for_table(&self, table_number: u8) -> Option<&TabStatus> {
+        self.tabs.values().find(|tab| tab.table_number == table_number)
+    }
+}
+
+pub struct OpenTabsQuery;
+
+impl Query for OpenTabsQuery {
+    type Result = Vec<TabStatus>;
+
+    const NAME: &'static str = "open_tabs";
+}
+
+impl QueryHandler<OpenTabsQuery> for OpenTabs {
+    fn handle(&self, _: &OpenTabsQuery) -> Vec<TabStatus> {
+        self.tabs()
+    }
+}
+
+pub struct InvoiceQuery {
+    pub table_number: u8
+}
+
+impl Query for InvoiceQuery {
+    type Result = Option<TabInvoice>;
+
+    const NAME: &'static str = "invoice";
+}
+
+impl QueryHandler<InvoiceQuery> for OpenTabs {
+    fn handle(&self, query: &InvoiceQuery) -> Option<TabInvoice> {
+        self.invoice_for_table(query.table_number)
+    }
+}
+
+pub struct WaiterTodoQuery {
+    pub waiter_id: WaiterId
+}
+
+// The waiter asked about travels with the answer so access policies can scope it.
+pub struct WaiterTodo {
+    pub waiter_id: WaiterId,
+    pub tables: BTreeMap<u8, Vec<TabItem>>
+}
+
+impl Query for WaiterTodoQuery {
+    type Result = WaiterTodo;
+
+    const NAME: &'static str = "waiter_todo";
+}
+
+impl QueryHandler<WaiterTodoQuery> for OpenTabs {
+    fn handle(&self, query: &WaiterTodoQuery) -> WaiterTodo {
+        WaiterTodo { waiter_id: query.waiter_id, tables: self.todo_list_for_waiter_id(query.waiter_id) }
+    }
+}
+
+// Modifiers and the note are what the kitchen has to know beyond the dish itself.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TodoListItem {
+    pub line_id: Uuid,
+    pub menu_number: i32,
+    pub description: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub modifiers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub course: Option<Course>
+}
+
+impl<'a> From<&'a OrderedItem> for TodoListItem {
+    fn from(item: &'a OrderedItem) -> TodoListItem {
+        TodoListItem {
+            line_id: item.line_id(),
+            menu_number: item.menu_number(),
+            description: item.description().to_string(),
+            modifiers: item.modifiers().to_vec(),
+            note: item.note().map(str::to_string),
+            course: item.course()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TodoListGroup {
+    pub tab_id: Uuid,
+    pub items: Vec<TodoListItem>
+}
+
+// Food still to be cooked, grouped by the order it came in with, oldest first. Food of a course
+// the waiter has not fired yet is held back; once fired, the course joins the list as a group of
+// its own.
+#[derive(Debug, Default)]
+pub struct ChefTodoList {
+    groups: Vec<TodoListGroup>,
+    held: Vec<TodoListGroup>,
+    fired: HashMap<Uuid, Vec<Course>>
+}
+
+impl ChefTodoList {
+    pub fn new() -> ChefTodoList {
+        ChefTodoList::default()
+    }
+
+    pub fn todo_list(&self) -> Vec<TodoListGroup> {
+        self.groups.clone()
+    }
+
+    fn remove_item(&mut self, tab_id: Uuid, line_id: Uuid) {
+        let found = self.groups.iter_mut().chain(self.held.iter_mut())
+            .filter(|group| group.tab_id == tab_id)
+            .filter_map(|group| group.items.iter().position(|item| item.line_id == line_id).map(|index| (group, index)))
+            .next();
+        if let Some((group, index)) = found {
+            group.items.remove(index);
+        }
+        self.groups.retain(|group| !group.items.is_empty());
+        self.held.retain(|group| !group.items.is_empty());
+    }
+
+    fn is_released(&self, tab_id: Uuid, item: &TodoListItem) -> bool {
+        item.course.is_none_or(|course| self.fired.get(&tab_id).is_some_and(|fired| fired.contains(&course)))
+    }
+
+    fn fire(&mut self, tab_id: Uuid, course: Course) {
+        let mut items = Vec::new();
+        for group in self.held.iter_mut().filter(|group| group.tab_id == tab_id) {
+            let (fired, held) = group.items.drain(..).partition(|item| item.course == Some(course));
+            items.extend::<Vec<_>>(fired);
+            group.items = held;
+        }
+        self.held.retain(|group| !group.items.is_empty());
+        if !items.is_empty() {
+            self.groups.push(TodoListGroup { tab_id, items });
+        }
+        self.fired.entry(tab_id).or_default().push(course);
+    }
+
+    fn forget(&mut self, tab_id: Uuid) {
+        self.groups.retain(|group| group.tab_id != tab_id);
+        self.held.retain(|group| group.tab_id != tab_id);
+        self.fired.remove(&tab_id);
+    }
+}
+
+pub struct KitchenQueueQuery;
+
+impl Query for KitchenQueueQuery {
+    type Result = Vec<TodoListGroup>;
+
+    const NAME: &'static str = "kitchen_queue";
+}
+
+impl QueryHandler<KitchenQueueQuery> for ChefTodoList {
+    fn handle(&self, _: &KitchenQueueQuery) -> Vec<TodoListGroup> {
+        self.todo_list()
+    }
+}
+
+impl Projection<Event> for ChefTodoList {
+    fn apply(&mut self, tab_id: Uuid, event: &Event) {
+        match *event {
+            Event::FoodOrdered { ref items } => {
+                let (released, held): (Vec<_>, Vec<_>) = items.iter().flat_map(OrderedItem::units)
+                    .map(|item| TodoListItem::from(&item))
+                    .partition(|item| self.is_released(tab_id, item));
+                for (groups, items) in [(&mut self.groups, released), (&mut self.held, held)] {
+                    if !items.is_empty() {
+                        groups.push(TodoListGroup { tab_id, items });
+                    }
+                }
+            },
+            Event::CourseFired { course } => self.fire(tab_id, course),
+            Event::FoodServed { ref line_ids, .. } => {
+                for line_id in line_ids {
+                    self.remove_item(tab_id, *line_id);
+                }
+            },
+            Event::ItemVoided { line_id, .. } => self.remove_item(tab_id, line_id),
+            Event::TabForceClosed { .. } | Event::TabClosed { .. } => self.forget(tab_id),
+            _ => {}
+        }
+        debug_assert!(self.groups.iter().chain(&self.held).all(|group| !group.items.is_empty()), "chef todo list has an empty group");
+    }
+}
+
+pub(crate) fn move_items(from: &mut Vec<TabItem>, to: &mut Vec<TabItem>, line_ids: &[Uuid]) {
+    for line_id in line_ids {
+        if let Some(index) = from.iter().position(|item| item.line_id == *line_id) {
+            to.push(from.remove(index));
+        }
+    }
+}
+
+// The served items charged the wrong price get the correct one, up to count of them.
+pub(crate) fn reprice(served: &mut [TabItem], menu_number: i32, charged: Money, correct: Money, count: usize) {
+    for item in served.iter_mut().filter(|item| item.menu_number == menu_number && item.price == charged).take(count) {
+        item.price = correct;
+    }
+}
+
+impl Projection<Event> for OpenTabs {
+    fn apply(&mut self, tab_id: Uuid, event: &Event) {
+        use crate::domain::Event::*;
+
+        match *event {
+            TabOpened { table_number, waiter_id, ref waiter } => {
+                self.tabs.insert(tab_id, TabStatus {
+                    tab_id,
+                    table_number,
+                    waiter_id,
+                    waiter: waiter.clone(),
+                    to_serve: Vec::new(),
+                    in_preparation: Vec::new(),
+                    served: Vec::new(),
+                    comped: Vec::new(),
+                    discount: None
+                });
+            },
+            TabClosed { .. } | TabForceClosed { .. } => {
+                self.tabs.remove(&tab_id);
+            },
+            _ => {
+                if let Some(tab) = self.tabs.get_mut(&tab_id) {
+                    match *event {
+                        DrinksOrdered { ref items } => tab.to_serve.extend(items.iter().flat_map(OrderedItem::units).map(|item| TabItem::from(&item))),
+                        FoodOrdered { ref items } => tab.in_preparation.extend(items.iter().flat_map(OrderedItem::units).map(|item| TabItem::from(&item))),
+                        DrinksServed { ref line_ids, .. } => move_items(&mut tab.to_serve, &mut tab.served, line_ids),
+                        FoodServed { ref line_ids, .. } => move_items(&mut tab.in_preparation, &mut tab.served, line_ids),
+                        UnorderedItemsServed { ref items } => tab.served.extend(items.iter().flat_map(OrderedItem::units).map(|item| TabItem::from(&item))),
+                        ServedPriceCorrected { menu_number, charged, correct, count, .. } => reprice(&mut tab.served, menu_number, charged, correct, count),
+                        TabTransferred { table_number, .. } => tab.table_number = table_number,
+                        WaiterReassigned { waiter_id, ref waiter, .. } => {
+                            tab.waiter_id = waiter_id;
+                            tab.waiter = waiter.clone();
+                        },
+                        ItemComped { line_id, .. } => move_items(&mut tab.served, &mut tab.comped, &[line_id]),
+                        DiscountApplied { amount, .. } => tab.discount = Some(tab.discount.map_or(amount, |discount| discount + amount)),
+                        ItemVoided { line_id, .. } => {
+                            if let Some(index) = tab.to_serve.iter().position(|item| item.line_id == line_id) {
+                                tab.to_serve.remove(index);
+                            } else if let Some(index) = tab.in_preparation.iter().position(|item| item.line_id == line_id) {
+                                tab.in_preparation.remove(index);
+                            }
+                        },
+                        _ => {}
+                    }
+                    debug_assert!(tab.to_serve.iter().chain(tab.in_preparation.iter()).chain(tab.served.iter()).all(|item| !item.price.is_negative()), "tab {} has an item with negative price", tab_id);
+                }
+            }
+        }
+    }
+}
+
+// An item as it was ordered, for ad-hoc searches across tabs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OrderRecord {
+    pub tab_id: Uuid,
+    pub line_id: Uuid,
+    pub table_number: u8,
+    pub waiter: String,
+    pub menu_number: i32,
+    pub description: String,
+    pub price: Money,
+    pub ordered_at: SystemTime,
+    pub voided: bool
+}
+
+// Waiter and item match case-insensitively; the dates are inclusive.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub waiter: Option<String>,
+    pub item: Option<String>,
+    pub from: Option<Date>,
+    pub to: Option<Date>
+}
+
+#[derive(Debug)]
+struct IndexedTab {
+    table_number: u8,
+    waiter: String,
+    orders: Vec<usize>
+}
+
+// Every order ever placed, indexed by waiter, item and date so searches only touch the orders
+// they return. Closed tabs stay searchable; purged ones are dropped.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    tabs: HashMap<Uuid, IndexedTab>,
+    orders: Vec<Option<OrderRecord>>,
+    by_waiter: HashMap<String, Vec<usize>>,
+    by_item: HashMap<String, Vec<usize>>,
+    by_date: BTreeMap<Date, Vec<usize>>
+}
+
+impl SearchIndex {
+    pub fn new() -> SearchIndex {
+        SearchIndex::default()
+    }
+
+    // Orders come back in the order they were placed.
+    pub fn search(&self, query: &SearchQuery) -> Vec<OrderRecord> {
+        let mut filters: Vec<BTreeSet<usize>> = Vec::new();
+        if let Some(ref waiter) = query.waiter {
+            filters.push(self.by_waiter.get(&waiter.to_lowercase()).into_iter().flatten().cloned().collect());
+        }
+        if let Some(ref item) = query.item {
+            filters.push(self.by_item.get(&item.to_lowercase()).into_iter().flatten().cloned().collect());
+        }
+        if query.from.is_some() || query.to.is_some() {
+            if let (Some(from), Some(to)) = (query.from, query.to) {
+                if from > to {
+                    return Vec::new();
+                }
+            }
+            let from = query.from.map_or(Unbounded, Included);
+            let to = query.to.map_or(Unbounded, Included);
+            filters.push(self.by_date.range((from, to)).flat_map(|(_, positions)| positions).cloned().collect());
+        }
+
+        // Walk the smallest candidate set and check the rest against it.
+        filters.sort_by_key(|filter| Reverse(filter.len()));
+        let positions: Vec<usize> = match filters.pop() {
+            Some(narrowest) => narrowest.into_iter().filter(|position| filters.iter().all(|filter| filter.contains(position))).collect(),
+            None => (0..self.orders.len()).collect()
+        };
+        positions.into_iter().filter_map(|position| self.orders[position].clone()).collect()
+    }
+
+    fn record(&mut self, tab_id: Uuid, items: &[OrderedItem], ordered_at: SystemTime) {
+        if let Some(tab) = self.tabs.get_mut(&tab_id) {
+            for item in items.iter().flat_map(OrderedItem::units) {
+                let position = self.orders.len();
+                self.by_waiter.entry(tab.waiter.to_lowercase()).or_default().push(position);
+                self.by_item.entry(item.description().to_lowercase()).or_default().push(position);
+                self.by_date.entry(Date::of(ordered_at)).or_default().push(position);
+                tab.orders.push(position);
+                self.orders.push(Some(OrderRecord {
+                    tab_id,
+                    line_id: item.line_id(),
+                    table_number: tab.table_number,
+                    waiter: tab.waiter.clone(),
+                    menu_number: item.menu_number(),
+                    description: item.description().to_string(),
+                    price: item.price(),
+                    ordered_at,
+                    voided: false
+                }));
+            }
+        }
+    }
+
+    // Voids the latest order of the line that is not voided yet.
+    fn void(&mut self, tab_id: Uuid, line_id: Uuid) {
+        if let Some(tab) = self.tabs.get(&tab_id) {
+            let orders = &mut self.orders;
+            let found = tab.orders.iter().rev()
+                .filter_map(|position| orders[*position].as_ref().map(|order| (*position, order)))
+                .find(|&(_, order)| order.line_id == line_id && !order.voided)
+                .map(|(position, _)| position);
+            if let Some(position) = found {
+                if let Some(ref mut order) = orders[position] {
+                    order.voided = true;
+                }
+            }
+        }
+    }
+
+    fn purge(&mut self, tab_id: Uuid) {
+        if let Some(tab) = self.tabs.remove(&tab_id) {
+            for position in tab.orders {
+                if let Some(order) = self.orders[position].take() {
+                    forget(self.by_waiter.get_mut(&order.waiter.to_lowercase()), position);
+                    forget(self.by_item.get_mut(&order.description.to_lowercase()), position);
+                    forget(self.by_date.get_mut(&Date::of(order.ordered_at)), position);
+                }
+            }
+        }
+    }
+}
+
+fn forget(positions: Option<&mut Vec<usize>>, position: usize) {
+    if let Some(positions) = positions {
+        positions.retain(|other| *other != position);
+    }
+}
+
+impl TimedProjection for SearchIndex {
+    type Event = Event;
+
+    fn apply_at(&mut self, tab_id: Uuid, event: &Event, timestamp: SystemTime) {
+        match *event {
+            Event::TabOpened { table_number, ref waiter, .. } => {
+                self.tabs.insert(tab_id, IndexedTab { table_number, waiter: waiter.clone(), orders: Vec::new() });
+            },
+            Event::DrinksOrdered { ref items } | Event::FoodOrdered { ref items } => self.record(tab_id, items, timestamp),
+            Event::ItemVoided { line_id, .. } => self.void(tab_id, line_id),
+            // Orders placed before the handover stay under the waiter who took them.
+            Event::WaiterReassigned { ref waiter, .. } => {
+                if let Some(tab) = self.tabs.get_mut(&tab_id) {
+                    tab.waiter = waiter.clone();
+                }
+            },
+            // Likewise for orders placed at the table the tab moved from.
+            Event::TabTransferred { table_number, .. } => {
+                if let Some(tab) = self.tabs.get_mut(&tab_id) {
+                    tab.table_number = table_number;
+                }
+            },
+            Event::TabPurged { .. } => self.purge(tab_id),
+            _ => {}
+        }
+    }
+}
+
+// What is on the menu now, at current prices. Orders are resolved against it so clients only
+// send menu numbers and never prices of their own.
+#[derive(Debug, Default)]
+pub struct Catalog {
+    items: BTreeMap<i32, MenuItem>
+}
+
+impl Catalog {
+    pub fn new() -> Catalog {
+        Catalog::default()
+    }
+
+    pub fn items(&self) -> Vec<MenuItem> {
+        self.items.values().cloned().collect()
+    }
+
+    // One ordered item per line, with its quantity. Fails with the first menu number that is not
+    // on the menu.
+    pub fn resolve(&self, lines: &[(i32, u32)]) -> Result<Vec<OrderedItem>, i32> {
+        let mut items = Vec::new();
+        for &(menu_number, quantity) in lines {
+            let item = self.items.get(&menu_number).ok_or(menu_number)?;
+            if quantity > 0 {
+                items.push(item.ordered().with_quantity(quantity));
+            }
+        }
+        Ok(items)
+    }
+}
+
+impl Projection<menu::Event> for Catalog {
+    fn apply(&mut self, _: Uuid, event: &menu::Event) {
+        match *event {
+            menu::Event::MenuItemAdded { ref item } => {
+                self.items.insert(item.menu_number, item.clone());
+            },
+            menu::Event::PriceChanged { menu_number, price } => {
+                if let Some(item) = self.items.get_mut(&menu_number) {
+                    item.price = price;
+                }
+            },
+            menu::Event::ItemRetired { menu_number } => {
+                self.items.remove(&menu_number);
+            },
+            menu::Event::NutritionDeclared { menu_number, nutrition } => {
+                if let Some(item) = self.items.get_mut(&menu_number) {
+                    item.nutrition = nutrition;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MenuChange {
+    pub version: usize,
+    pub change: menu::Event
+}
+
+// What happened to the menu after a version a device already has, for devices that were offline.
+// At most `limit` changes at a time; while `more` is set the device asks again from `version`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MenuChanges {
+    pub version: usize,
+    pub changes: Vec<MenuChange>,
+    pub more: bool
+}
+
+impl MenuChanges {
+    // None if the device claims a version the menu has not reached.
+    pub async fn since(store: &dyn EventStore<menu::Event>, menu_id: Uuid, version: usize, limit: usize) -> Result<Option<MenuChanges>, StoreError> {
+        let stream = store.read_stream_after(menu_id, version).await?;
+        if version > stream.version {
+            return Ok(None);
+        }
+
+        let more = stream.events.len() > limit;
+        let changes: Vec<MenuChange> = stream.events.into_iter()
+            .take(limit)
+            .map(|envelope| MenuChange { version: envelope.version, change: envelope.payload })
+            .collect();
+        let version = changes.last().map_or(version, |change| change.version);
+        Ok(Some(MenuChanges { version, changes, more }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WaiterOnShift {
+    pub waiter_id: WaiterId,
+    pub waiter: String,
+    pub tables: Vec<u8>
+}
+
+// Who is on shift and which tables they cover.
+#[derive(Debug, Default)]
+pub struct Roster {
+    on_shift: HashMap<Uuid, WaiterOnShift>
+}
+
+impl Roster {
+    pub fn new() -> Roster {
+        Roster::default()
+    }
+
+    pub fn waiter(&self, waiter_id: WaiterId) -> Option<&WaiterOnShift> {
+        self.on_shift.get(&waiter_id)
+    }
+
+    pub fn waiters(&self) -> Vec<WaiterOnShift> {
+        let mut waiters: Vec<WaiterOnShift> = self.on_shift.values().cloned().collect();
+        waiters.sort_by(|a, b| a.waiter.cmp(&b.waiter));
+        waiters
+    }
+}
+
+impl Projection<shift::Event> for Roster {
+    fn apply(&mut self, waiter_id: Uuid, event: &shift::Event) {
+        match *event {
+            shift::Event::ShiftStarted { ref waiter } => {
+                self.on_shift.insert(waiter_id, WaiterOnShift { waiter_id, waiter: waiter.clone(), tables: Vec::new() });
+            },
+            shift::Event::AssignedToTable { table_number } => {
+                if let Some(waiter) = self.on_shift.get_mut(&waiter_id) {
+                    if let Err(index) = waiter.tables.binary_search(&table_number) {
+                        waiter.tables.insert(index, table_number);
+                    }
+                }
+            },
+            shift::Event::ReleasedTable { table_number } => {
+                if let Some(waiter) = self.on_shift.get_mut(&waiter_id) {
+                    waiter.tables.retain(|&table| table != table_number);
+                }
+            },
+            shift::Event::ShiftEnded => {
+                self.on_shift.remove(&waiter_id);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StaffMember {
+    pub staff_id: Uuid,
+    pub name: String,
+    pub role: Role,
+    pub active: bool
+}
+
+// Everyone who ever worked here, including those who left, by staff id. The PIN hashes are kept
+// apart from the members, so they never end up in a response.
+#[derive(Debug, Default)]
+pub struct StaffRegistry {
+    members: HashMap<Uuid, StaffMember>,
+    pin_hashes: HashMap<Uuid, String>
+}
+
+impl StaffRegistry {
+    pub fn new() -> StaffRegistry {
+        StaffRegistry::default()
+    }
+
+    pub fn members(&self) -> Vec<StaffMember> {
+        let mut members: Vec<StaffMember> = self.members.values().cloned().collect();
+        members.sort_by(|a, b| (&a.name, a.staff_id).cmp(&(&b.name, b.staff_id)));
+        members
+    }
+
+    pub fn active_member(&self, staff_id: Uuid) -> Option<&StaffMember> {
+        self.members.get(&staff_id).filter(|member| member.active)
+    }
+
+    pub fn pin_hash(&self, staff_id: Uuid) -> Option<&str> {
+        self.pin_hashes.get(&staff_id).map(String::as_str)
+    }
+
+    // The first one registered under a name keeps the id they had before the registry, so
+    // whatever they did back then stays theirs. Anyone after them gets a new one.
+    pub fn id_for(&self, name: &str) -> Uuid {
+        let legacy_id = staff::legacy_id(name);
+        if self.members.contains_key(&legacy_id) {
+            Uuid::new_v4()
+        } else {
+            legacy_id
+        }
+    }
+}
+
+impl Projection<staff::Event> for StaffRegistry {
+    fn apply(&mut self, staff_id: Uuid, event: &staff::Event) {
+        match *event {
+            staff::Event::StaffRegistered { ref name, role, .. } => {
+                self.members.insert(staff_id, StaffMember { staff_id, name: name.clone(), role, active: true });
+            },
+            staff::Event::StaffDeactivated => {
+                if let Some(member) = self.members.get_mut(&staff_id) {
+                    member.active = false;
+                }
+            },
+            staff::Event::PinSet { ref pin_hash } => {
+                self.pin_hashes.insert(staff_id, pin_hash.clone());
+            }
+        }
+    }
+}
+
+// Every read model as it stood after the first `position` events of the log, for reporting
+// jobs. Built from scratch rather than copied from the live projections, which move on
+// independently while they are being read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReadModelExport {
+    pub position: usize,
+    pub tabs: Vec<TabStatus>,
+    pub kitchen_todo: Vec<TodoListGroup>
+}
+
+impl ReadModelExport {
+    // Without a position the export covers the whole log. None if the log is not that long yet.
+    pub async fn at(store: &dyn EventStore<Event>, position: Option<usize>) -> Result<Option<ReadModelExport>, StoreError> {
+        let events = store.read_all().await?;
+        let position = position.unwrap_or(events.len());
+        if position > events.len() {
+            return Ok(None);
+        }
+
+        let mut open_tabs = OpenTabs::new();
+        let mut chef_todo_list = ChefTodoList::new();
+        for envelope in &events[..position] {
+            open_tabs.apply_envelope(envelope);
+            chef_todo_list.apply_envelope(envelope);
+        }
+        Ok(Some(ReadModelExport { position, tabs: open_tabs.tabs(), kitchen_todo: chef_todo_list.todo_list() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Metadata;
+    use crate::cqrs::store::InMemoryEventStore;
+    use crate::money::Currency;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    fn open_tab(open_tabs: &mut OpenTabs, table_number: u8, waiter: &str) -> Uuid {
+        let tab_id = Uuid::new_v4();
+        open_tabs.apply(tab_id, &Event::TabOpened { table_number, waiter_id: staff::legacy_id(waiter), waiter: waiter.to_string() });
+        tab_id
+    }
+
+    #[test]
+    fn tracks_open_tables() {
+        let mut open_tabs = OpenTabs::new();
+        let tab1 = open_tab(&mut open_tabs, 5, "Derek");
+        let tab2 = open_tab(&mut open_tabs, 2, "Derek");
+        assert_eq!(open_tabs.active_table_numbers(), vec![2, 5]);
+        assert_eq!(open_tabs.tab_id_for_table(5), Some(tab1));
+        open_tabs.apply(tab2, &Event::TabClosed { amount_paid: eur(0), order_value: eur(0), tip_value: eur(0), receipt_number: None });
+        assert_eq!(open_tabs.active_table_numbers(), vec![5]);
+        assert_eq!(open_tabs.tab_id_for_table(2), None);
+    }
+
+    #[test]
+    fn waiter_todo_list_contains_items_to_serve() {
+        let mut open_tabs = OpenTabs::new();
+        let tab_id = open_tab(&mut open_tabs, 5, "Derek");
+        open_tab(&mut open_tabs, 6, "Jane");
+        let drink = OrderedItem::new(1, "Coke".to_string(), true, eur(250));
+        let food = OrderedItem::new(2, "Soup".to_string(), false, eur(450));
+        open_tabs.apply(tab_id, &Event::DrinksOrdered { items: vec![drink.clone()] });
+        open_tabs.apply(tab_id, &Event::FoodOrdered { items: vec![food] });
+        let todo = open_tabs.todo_list_for_waiter("Derek");
+        assert_eq!(todo.len(), 1);
+        assert_eq!(todo[&5], vec![TabItem::from(&drink)]);
+        assert!(open_tabs.todo_list_for_waiter("Jane").is_empty());
+
+        let (derek, jane) = (staff::legacy_id("Derek"), staff::legacy_id("Jane"));
+        open_tabs.apply(tab_id, &Event::WaiterReassigned { from_waiter_id: derek, waiter_id: jane, waiter: "Jane".to_string() });
+        assert!(open_tabs.todo_list_for_waiter("Derek").is_empty());
+        assert_eq!(open_tabs.todo_list_for_waiter("Jane")[&5], vec![TabItem::from(&drink)]);
+        assert_eq!(open_tabs.tabs_of_waiter(jane).len(), 2);
+        assert_eq!(open_tabs.todo_list_for_waiter_id(jane), open_tabs.todo_list_for_waiter("Jane"));
+        assert!(open_tabs.todo_list_for_waiter_id(derek).is_empty());
+    }
+
+    #[test]
+    fn invoice_lists_served_items() {
+        let mut open_tabs = OpenTabs::new();
+        let tab_id = open_tab(&mut open_tabs, 5, "Derek");
+        let drink = OrderedItem::new(1, "Coke".to_string(), true, eur(250)).with_line_id(Uuid::new_v4());
+        let food = OrderedItem::new(2, "Soup".to_string(), false, eur(450)).with_line_id(Uuid::new_v4());
+        open_tabs.apply(tab_id, &Event::DrinksOrdered { items: vec![drink.clone()] });
+        open_tabs.apply(tab_id, &Event::FoodOrdered { items: vec![food.clone()] });
+        open_tabs.apply(tab_id, &Event::DrinksServed { line_ids: vec![drink.line_id()], menu_numbers: vec![1] });
+        let invoice = open_tabs.invoice_for_table(5).unwrap();
+        assert_eq!(invoice.items, vec![TabItem::from(&drink)]);
+        assert_eq!(invoice.total, eur(250));
+        assert!(invoice.has_unserved_items);
+        let json = serde_json::to_value(&invoice).unwrap();
+        assert_eq!(json["total"], json!({ "amount_minor": 250, "currency": "EUR" }));
+        assert_eq!(json["items"][0]["price"], json!({ "amount_minor": 250, "currency": "EUR" }));
+        assert_eq!(open_tabs.invoice_for_table(7), None);
+        assert_eq!(invoice.clone().with_tax(0).tax, None);
+        assert_eq!(invoice.clone().with_tax(2500).tax, Some(eur(50)));
+
+        open_tabs.apply(tab_id, &Event::FoodServed { line_ids: vec![food.line_id()], menu_numbers: vec![2] });
+        open_tabs.apply(tab_id, &Event::ItemComped { line_id: food.line_id(), menu_number: 2, value: eur(450), reason: "Cold".to_string() });
+        open_tabs.apply(tab_id, &Event::DiscountApplied { percent: 10, amount: eur(25), reason: "Regulars".to_string() });
+        let discounted = open_tabs.invoice_for_table(5).unwrap();
+        assert_eq!((discounted.items.len(), discounted.comped.len(), discounted.discount, discounted.total), (1, 1, Some(eur(25)), eur(225)));
+
+        let mut catalog = Catalog::new();
+        let coke = MenuItem { menu_number: 1, description: "Coke".to_string(), is_drink: true, price: eur(250), nutrition: None };
+        catalog.apply(Uuid::nil(), &menu::Event::MenuItemAdded { item: coke });
+        assert_eq!(invoice.clone().with_nutrition(&catalog).nutrition, vec![]);
+        let nutrition = Nutrition { kcal: 139, protein_grams: None, carbohydrate_grams: Some(35), fat_grams: None };
+        catalog.apply(Uuid::nil(), &menu::Event::NutritionDeclared { menu_number: 1, nutrition: Some(nutrition) });
+        assert_eq!(invoice.with_nutrition(&catalog).nutrition, vec![NutritionFootnote { menu_number: 1, description: "Coke".to_string(), servings: 1, nutrition }]);
+    }
+
+    #[test]
+    fn chef_todo_list_groups_food_by_order() {
+        let mut todo = ChefTodoList::new();
+        let tab_id = Uuid::new_v4();
+        let soup = OrderedItem::new(1, "Soup".to_string(), false, eur(450));
+        let steak = OrderedItem::new(2, "Steak".to_string(), false, eur(1800)).with_modifiers(vec!["Medium rare".to_string()]).with_note(Some("Sauce on the side".to_string()));
+        let lines = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        todo.apply(tab_id, &Event::FoodOrdered { items: vec![soup.clone().with_line_id(lines[0])] });
+        todo.apply(tab_id, &Event::FoodOrdered { items: vec![soup.with_line_id(lines[1]), steak.with_line_id(lines[2])] });
+        assert_eq!(todo.todo_list().iter().map(|group| group.items.len()).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(serde_json::to_value(&todo.todo_list()[1].items[1]).unwrap(), json!({
+            "line_id": lines[2], "menu_number": 2, "description": "Steak", "modifiers": ["Medium rare"], "note": "Sauce on the side"
+        }));
+        // The second soup is served, the first one is still being made.
+        todo.apply(tab_id, &Event::FoodServed { line_ids: vec![lines[1], lines[2]], menu_numbers: vec![1, 2] });
+        assert_eq!(todo.todo_list(), vec![TodoListGroup { tab_id, items: vec![TodoListItem { line_id: lines[0], menu_number: 1, description: "Soup".to_string(), modifiers: vec![], note: None, course: None }] }]);
+    }
+
+    #[test]
+    fn chef_todo_list_holds_food_until_its_course_is_fired() {
+        let mut todo = ChefTodoList::new();
+        let tab_id = Uuid::new_v4();
+        let bread = OrderedItem::new(1, "Bread".to_string(), false, eur(300)).with_line_id(Uuid::new_v4());
+        let soup = OrderedItem::new(2, "Soup".to_string(), false, eur(450)).with_course(Some(Course::Starter)).with_line_id(Uuid::new_v4());
+        let steak = OrderedItem::new(3, "Steak".to_string(), false, eur(1800)).with_course(Some(Course::Main)).with_line_id(Uuid::new_v4());
+        let salad = OrderedItem::new(4, "Salad".to_string(), false, eur(500)).with_course(Some(Course::Starter)).with_line_id(Uuid::new_v4());
+        let menu_numbers = |todo: &ChefTodoList| todo.todo_list().iter().map(|group| group.items.iter().map(|item| item.menu_number).collect::<Vec<_>>()).collect::<Vec<_>>();
+        todo.apply(tab_id, &Event::FoodOrdered { items: vec![bread, soup.clone(), steak.clone()] });
+        assert_eq!(menu_numbers(&todo), vec![vec![1]]);
+        todo.apply(tab_id, &Event::CourseFired { course: Course::Starter });
+        assert_eq!(menu_numbers(&todo), vec![vec![1], vec![2]]);
+        // Food of a fired course goes to the kitchen right away.
+        todo.apply(tab_id, &Event::FoodOrdered { items: vec![salad] });
+        assert_eq!(menu_numbers(&todo), vec![vec![1], vec![2], vec![4]]);
+        todo.apply(tab_id, &Event::ItemVoided { line_id: steak.line_id(), menu_number: 3, reason: "Changed their mind".to_string() });
+        todo.apply(tab_id, &Event::CourseFired { course: Course::Main });
+        assert_eq!(menu_numbers(&todo), vec![vec![1], vec![2], vec![4]]);
+        assert_eq!(serde_json::to_value(&todo.todo_list()[1].items[0]).unwrap()["course"], json!("starter"));
+    }
+
+    #[tokio::test]
+    async fn export_is_cut_at_the_given_position() {
+        let store = InMemoryEventStore::new();
+        let metadata = Metadata::new();
+        let tab_id = Uuid::new_v4();
+        let soup = OrderedItem::new(1, "Soup".to_string(), false, eur(450));
+        store.append(tab_id, vec![Event::TabOpened { table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() }], 0, &metadata).await.unwrap();
+        store.append(tab_id, vec![Event::FoodOrdered { items: vec![soup] }], 1, &metadata).await.unwrap();
+
+        let before_order = ReadModelExport::at(&store, Some(1)).await.unwrap().unwrap();
+        assert_eq!(before_order.tabs.len(), 1);
+        assert_eq!(before_order.kitchen_todo, vec![]);
+        let now = ReadModelExport::at(&store, None).await.unwrap().unwrap();
+        assert_eq!(now.position, 2);
+        assert_eq!(now.tabs[0].in_preparation.len(), 1);
+        assert_eq!(now.kitchen_todo.len(), 1);
+        assert_eq!(ReadModelExport::at(&store, Some(3)).await, Ok(None));
+    }
+
+    fn order(index: &mut SearchIndex, tab_id: Uuid, item: &OrderedItem, date: &str) {
+        let ordered_at = date.parse::<Date>().unwrap().start();
+        index.apply_at(tab_id, &Event::DrinksOrdered { items: vec![item.clone()] }, ordered_at);
+    }
+
+    #[test]
+    fn search_combines_waiter_item_and_date() {
+        let mut index = SearchIndex::new();
+        let derek = Uuid::new_v4();
+        let jane = Uuid::new_v4();
+        index.apply(derek, &Event::TabOpened { table_number: 1, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        index.apply(jane, &Event::TabOpened { table_number: 2, waiter_id: staff::legacy_id("Jane"), waiter: "Jane".to_string() });
+        let espresso = OrderedItem::new(1, "Espresso".to_string(), true, eur(200));
+        let coke = OrderedItem::new(2, "Coke".to_string(), true, eur(250));
+        order(&mut index, derek, &espresso, "2017-06-14");
+        order(&mut index, derek, &espresso, "2017-06-15");
+        order(&mut index, derek, &coke, "2017-06-15");
+        order(&mut index, jane, &espresso, "2017-06-15");
+
+        let query = SearchQuery { waiter: Some("derek".to_string()), item: Some("espresso".to_string()), ..SearchQuery::default() };
+        assert_eq!(index.search(&query).len(), 2);
+        let from = "2017-06-15".parse().ok();
+        let found = index.search(&SearchQuery { from, to: from, ..query });
+        assert_eq!(found.iter().map(|order| (order.tab_id, order.table_number, order.menu_number)).collect::<Vec<_>>(), vec![(derek, 1, 1)]);
+        assert_eq!(index.search(&SearchQuery { item: Some("ESPRESSO".to_string()), ..SearchQuery::default() }).len(), 3);
+        assert_eq!(index.search(&SearchQuery { waiter: Some("Nobody".to_string()), ..SearchQuery::default() }), vec![]);
+        assert_eq!(index.search(&SearchQuery::default()).len(), 4);
+    }
+
+    #[test]
+    fn search_marks_voided_items_and_forgets_purged_tabs() {
+        let mut index = SearchIndex::new();
+        let tab_id = Uuid::new_v4();
+        index.apply(tab_id, &Event::TabOpened { table_number: 1, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        let espresso = OrderedItem::new(1, "Espresso".to_string(), true, eur(200));
+        let spilled = Uuid::new_v4();
+        order(&mut index, tab_id, &espresso.clone().with_line_id(Uuid::new_v4()), "2017-06-15");
+        order(&mut index, tab_id, &espresso.with_line_id(spilled), "2017-06-15");
+        index.apply(tab_id, &Event::ItemVoided { line_id: spilled, menu_number: 1, reason: "Spilled".to_string() });
+        let query = SearchQuery { item: Some("espresso".to_string()), ..SearchQuery::default() };
+        assert_eq!(index.search(&query).iter().map(|order| order.voided).collect::<Vec<_>>(), vec![false, true]);
+
+        index.apply(tab_id, &Event::TabPurged { event_count: 4, amount_paid: eur(200), order_value: eur(200), tip_value: eur(0), receipt_number: None });
+        assert_eq!(index.search(&query), vec![]);
+        assert_eq!(index.search(&SearchQuery::default()), vec![]);
+    }
+
+    #[test]
+    fn catalog_resolves_orders_at_current_prices() {
+        let mut catalog = Catalog::new();
+        let espresso = MenuItem { menu_number: 1, description: "Espresso".to_string(), is_drink: true, price: eur(200), nutrition: None };
+        let soup = MenuItem { menu_number: 2, description: "Soup".to_string(), is_drink: false, price: eur(450), nutrition: None };
+        catalog.apply(Uuid::nil(), &menu::Event::MenuItemAdded { item: espresso });
+        catalog.apply(Uuid::nil(), &menu::Event::MenuItemAdded { item: soup });
+        catalog.apply(Uuid::nil(), &menu::Event::PriceChanged { menu_number: 1, price: eur(220) });
+
+        let espresso = OrderedItem::new(1, "Espresso".to_string(), true, eur(220));
+        let soup = OrderedItem::new(2, "Soup".to_string(), false, eur(450));
+        assert_eq!(catalog.resolve(&[(1, 2), (2, 1), (2, 0)]), Ok(vec![espresso.with_quantity(2), soup]));
+        assert_eq!(catalog.resolve(&[(3, 1)]), Err(3));
+
+        catalog.apply(Uuid::nil(), &menu::Event::ItemRetired { menu_number: 2 });
+        assert_eq!(catalog.resolve(&[(2, 1)]), Err(2));
+        assert_eq!(catalog.items().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn menu_changes_come_in_pages() {
+        let store = InMemoryEventStore::new();
+        let espresso = MenuItem { menu_number: 1, description: "Espresso".to_string(), is_drink: true, price: eur(200), nutrition: None };
+        let changes = vec![
+            menu::Event::MenuItemAdded { item: espresso },
+            menu::Event::PriceChanged { menu_number: 1, price: eur(220) },
+            menu::Event::ItemRetired { menu_number: 1 }
+        ];
+        store.append(Uuid::nil(), changes, 0, &Metadata::new()).await.unwrap();
+
+        let first = MenuChanges::since(&store, Uuid::nil(), 0, 2).await.unwrap().unwrap();
+        assert_eq!((first.version, first.changes.len(), first.more), (2, 2, true));
+        let rest = MenuChanges::since(&store, Uuid::nil(), first.version, 2).await.unwrap().unwrap();
+        assert_eq!(rest.changes, vec![MenuChange { version: 3, change: menu::Event::ItemRetired { menu_number: 1 } }]);
+        assert_eq!((rest.version, rest.more), (3, false));
+        let current = MenuChanges::since(&store, Uuid::nil(), 3, 2).await.unwrap().unwrap();
+        assert_eq!((current.version, current.changes, current.more), (3, vec![], false));
+        assert_eq!(MenuChanges::since(&store, Uuid::nil(), 4, 2).await.unwrap(), None);
+    }
+
+    #[test]
+    fn roster_lists_waiters_on_shift_with_their_tables() {
+        let mut roster = Roster::new();
+        let derek = staff::legacy_id("Derek");
+        let jane = staff::legacy_id("Jane");
+        roster.apply(jane, &shift::Event::ShiftStarted { waiter: "Jane".to_string() });
+        roster.apply(derek, &shift::Event::ShiftStarted { waiter: "Derek".to_string() });
+        roster.apply(derek, &shift::Event::AssignedToTable { table_number: 5 });
+        roster.apply(derek, &shift::Event::AssignedToTable { table_number: 2 });
+        roster.apply(derek, &shift::Event::AssignedToTable { table_number: 5 });
+        assert_eq!(roster.waiters(), vec![
+            WaiterOnShift { waiter_id: derek, waiter: "Derek".to_string(), tables: vec![2, 5] },
+            WaiterOnShift { waiter_id: jane, waiter: "Jane".to_string(), tables: vec![] }
+        ]);
+
+        roster.apply(derek, &shift::Event::ReleasedTable { table_number: 5 });
+        assert_eq!(roster.waiter(derek).map(|waiter| waiter.tables.clone()), Some(vec![2]));
+
+        roster.apply(jane, &shift::Event::ShiftEnded);
+        assert_eq!(roster.waiters().len(), 1);
+    }
+
+    #[test]
+    fn staff_sharing_a_name_are_told_apart_by_id() {
+        let mut registry = StaffRegistry::new();
+        let derek = registry.id_for("Derek");
+        assert_eq!(derek, staff::legacy_id("Derek"));
+        registry.apply(derek, &staff::Event::StaffRegistered { staff_id: derek, name: "Derek".to_string(), role: Role::Waiter });
+        let other_derek = registry.id_for("Derek");
+        assert!(other_derek != derek);
+        registry.apply(other_derek, &staff::Event::StaffRegistered { staff_id: other_derek, name: "Derek".to_string(), role: Role::Waiter });
+
+        registry.apply(derek, &staff::Event::StaffDeactivated);
+        assert_eq!(registry.active_member(derek), None);
+        assert_eq!(registry.active_member(other_derek).map(|member| member.role), Some(Role::Waiter));
+        assert_eq!(registry.members().len(), 2);
+        assert!(registry.id_for("Derek") != derek);
+
+        registry.apply(other_derek, &staff::Event::PinSet { pin_hash: "hash".to_string() });
+        assert_eq!((registry.pin_hash(other_derek), registry.pin_hash(derek)), (Some("hash"), None));
+    }
+}
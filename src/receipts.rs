@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use uuid::Uuid;
+
+use crate::cqrs::{named_stream_id, Aggregate, AggregateCommand, CommandHandler, HandlerError, Metadata};
+use crate::cqrs::store::{EventStore, StoreError};
+use crate::domain;
+
+// How many times a number is asked for, or voided, again when another close changed the sequence
+// first.
+const ISSUE_ATTEMPTS: usize = 5;
+
+// How receipts are numbered, from a table of the configuration:
+//
+//     [global.cafe.receipts]
+//     location = "old-town"
+//     prefix = "OT-"
+//     digits = 6
+//
+// Every location counts from 1 on its own. Numbers are padded with zeros to digits.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ReceiptNumbering {
+    pub location: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub digits: usize
+}
+
+impl ReceiptNumbering {
+    pub fn format(&self, number: u64) -> String {
+        format!("{}{:0width$}", self.prefix, number, width = self.digits)
+    }
+}
+
+// Each location's sequence is a stream of its own, found by the location's name.
+pub fn sequence_id(location: &str) -> Uuid {
+    named_stream_id("receipts", location)
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum Command {
+    // The sequence and the tab the receipt is for.
+    IssueReceipt(Uuid, Uuid),
+    VoidReceipt(Uuid, Uuid)
+}
+
+impl AggregateCommand for Command {
+    fn aggregate_id(&self) -> Uuid {
+        match *self {
+            Command::IssueReceipt(id, _) | Command::VoidReceipt(id, _) => id
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ReceiptIssued { number: u64, tab_id: Uuid },
+    ReceiptVoided { number: u64, tab_id: Uuid }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    last_number: u64,
+    issued: HashMap<Uuid, u64>
+}
+
+impl State {
+    pub fn number_of(&self, tab_id: Uuid) -> Option<u64> {
+        self.issued.get(&tab_id).cloned()
+    }
+}
+
+// Fiscal receipt numbers, gap-free and counting up at each location. A number is the version
+// of the sequence it is issued in, so the store's concurrency check is what keeps two closes
+// from taking the same one, across restarts and servers alike. A tab keeps the number it was
+// issued: a close that fails after its number was issued gets the same one when it is retried.
+// A close that is turned down voids its number instead, so every number in the sequence is
+// either on a closed tab or voided; the tab gets a new one when it is closed after all.
+pub struct ReceiptSequence;
+
+impl Aggregate for ReceiptSequence {
+    type Command = Command;
+    type CommandError = Infallible;
+    type Event = Event;
+    type State = State;
+
+    fn initial_state() -> State {
+        State::default()
+    }
+
+    fn decide(state: &State, command: Command) -> Result<Vec<Event>, Infallible> {
+        match command {
+            Command::IssueReceipt(_, tab_id) => {
+                if state.issued.contains_key(&tab_id) {
+                    Ok(vec![])
+                } else {
+                    Ok(vec![Event::ReceiptIssued { number: state.last_number + 1, tab_id }])
+                }
+            },
+            Command::VoidReceipt(_, tab_id) => Ok(state.number_of(tab_id).map(|number| Event::ReceiptVoided { number, tab_id }).into_iter().collect())
+        }
+    }
+
+    fn evolve(state: &mut State, event: Event) {
+        match event {
+            Event::ReceiptIssued { number, tab_id } => {
+                state.last_number = number;
+                state.issued.insert(tab_id, number);
+            },
+            Event::ReceiptVoided { tab_id, .. } => {
+                state.issued.remove(&tab_id);
+            }
+        }
+    }
+}
+
+async fn handle(handler: &CommandHandler<'_, ReceiptSequence>, command: Command) -> Result<(), StoreError> {
+    let mut attempts = 1;
+    loop {
+        match handler.handle(command.clone()).await {
+            Ok(_) => return Ok(()),
+            Err(HandlerError::Concurrency(_)) if attempts < ISSUE_ATTEMPTS => attempts += 1,
+            Err(HandlerError::Concurrency(error)) => return Err(StoreError::Concurrency(error)),
+            Err(HandlerError::Store(message)) => return Err(StoreError::Backend(message)),
+            Err(HandlerError::Rejected(never)) => match never {}
+        }
+    }
+}
+
+// The tab's receipt number as printed, issued unless the tab has one already.
+pub async fn issue(store: &dyn EventStore<Event>, numbering: &ReceiptNumbering, tab_id: Uuid, metadata: Metadata) -> Result<String, StoreError> {
+    let sequence_id = sequence_id(&numbering.location);
+    let handler = CommandHandler::<ReceiptSequence>::new(store).with_metadata(metadata);
+    handle(&handler, Command::IssueReceipt(sequence_id, tab_id)).await?;
+    let (state, _) = handler.load(sequence_id).await?;
+    let number = state.number_of(tab_id).ok_or_else(|| StoreError::Backend(format!("no receipt was issued for tab {}", tab_id)))?;
+    Ok(numbering.format(number))
+}
+
+// Voids the number issued for a close that was then turned down, as the sequence would have a gap
+// otherwise. A close running alongside that took the tab with the same number is on the tab by
+// the time this one is turned down, and keeps it.
+pub async fn void_unused(store: &dyn EventStore<Event>, tabs: &dyn EventStore<domain::Event>, numbering: &ReceiptNumbering, tab_id: Uuid, receipt_number: &str, metadata: Metadata) -> Result<(), StoreError> {
+    let tab = tabs.read_stream(tab_id).await?;
+    let closed_with_it = tab.events.iter().any(|envelope| matches!(envelope.payload, domain::Event::TabClosed { receipt_number: Some(ref closed), .. } if closed == receipt_number));
+    if closed_with_it {
+        return Ok(());
+    }
+    let handler = CommandHandler::<ReceiptSequence>::new(store).with_metadata(metadata);
+    handle(&handler, Command::VoidReceipt(sequence_id(&numbering.location), tab_id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::store::InMemoryEventStore;
+    use crate::money::{Currency, Money};
+
+    fn numbering(location: &str) -> ReceiptNumbering {
+        ReceiptNumbering { location: location.to_string(), prefix: "OT-".to_string(), digits: 6 }
+    }
+
+    #[tokio::test]
+    async fn receipts_are_numbered_in_sequence_per_location() {
+        let store = InMemoryEventStore::new();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(issue(&store, &numbering("old-town"), first, Metadata::new()).await, Ok("OT-000001".to_string()));
+        assert_eq!(issue(&store, &numbering("old-town"), second, Metadata::new()).await, Ok("OT-000002".to_string()));
+        assert_eq!(issue(&store, &numbering("old-town"), first, Metadata::new()).await, Ok("OT-000001".to_string()));
+        assert_eq!(issue(&store, &numbering("harbour"), second, Metadata::new()).await, Ok("OT-000001".to_string()));
+        assert_eq!(store.read_stream(sequence_id("old-town")).await.unwrap().version, 2);
+    }
+
+    #[tokio::test]
+    async fn numbers_of_closes_turned_down_are_voided() {
+        let (store, tabs) = (InMemoryEventStore::new(), InMemoryEventStore::new());
+        let (turned_down, closed) = (Uuid::new_v4(), Uuid::new_v4());
+        let old_town = numbering("old-town");
+        assert_eq!(issue(&store, &old_town, turned_down, Metadata::new()).await, Ok("OT-000001".to_string()));
+        void_unused(&store, &tabs, &old_town, turned_down, "OT-000001", Metadata::new()).await.unwrap();
+        void_unused(&store, &tabs, &old_town, turned_down, "OT-000001", Metadata::new()).await.unwrap();
+
+        // A close alongside took the tab with the number first.
+        assert_eq!(issue(&store, &old_town, closed, Metadata::new()).await, Ok("OT-000002".to_string()));
+        let tab_closed = domain::Event::TabClosed { amount_paid: Money::zero(Currency::EUR), order_value: Money::zero(Currency::EUR), tip_value: Money::zero(Currency::EUR), receipt_number: Some("OT-000002".to_string()) };
+        tabs.append(closed, vec![tab_closed], 0, &Metadata::new()).await.unwrap();
+        void_unused(&store, &tabs, &old_town, closed, "OT-000002", Metadata::new()).await.unwrap();
+
+        assert_eq!(issue(&store, &old_town, turned_down, Metadata::new()).await, Ok("OT-000003".to_string()));
+        let events: Vec<Event> = store.read_stream(sequence_id("old-town")).await.unwrap().events.into_iter().map(|envelope| envelope.payload).collect();
+        assert_eq!(events, vec![
+            Event::ReceiptIssued { number: 1, tab_id: turned_down },
+            Event::ReceiptVoided { number: 1, tab_id: turned_down },
+            Event::ReceiptIssued { number: 2, tab_id: closed },
+            Event::ReceiptIssued { number: 3, tab_id: turned_down }
+        ]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt::Debug;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::cqrs::{Aggregate, CommandHandler, Projection};
+use crate::cqrs::store::EventStore;
+use crate::date::Date;
+use crate::domain::Tab;
+use crate::menu::Menu;
+use crate::read_model::{Catalog, ChefTodoList, OpenTabs, Roster, StaffRegistry};
+use crate::receipts::ReceiptSequence;
+use crate::reports::{SalesReport, TipsPerWaiter};
+use crate::shift::Shift;
+use crate::staff::Staff;
+use crate::table::Table;
+
+pub const USAGE: &str = "usage:
+    cafe-replay events <log> <stream id>    the stream's events, as JSON
+    cafe-replay state <log> <stream id>     the state the stream's events add up to
+    cafe-replay project <read model> [date] the read model built afresh from the whole log
+
+logs: tabs, menu, tables, shifts, staff, receipts
+read models: open_tabs, kitchen, tips, sales (of the date, YYYY-MM-DD), menu, waiters, staff";
+
+// What cafe-replay was asked for, to find out why a tab or a read model looks the way it does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Replay {
+    Events(String, Uuid),
+    State(String, Uuid),
+    Project(String, Option<Date>)
+}
+
+impl Replay {
+    pub fn parse(args: &[String]) -> Result<Replay, String> {
+        let stream_id = |id: &String| Uuid::parse_str(id).map_err(|_| format!("{} is not a stream id", id));
+        match args {
+            [command, log, id] if command == "events" => Ok(Replay::Events(log.clone(), stream_id(id)?)),
+            [command, log, id] if command == "state" => Ok(Replay::State(log.clone(), stream_id(id)?)),
+            [command, read_model] if command == "project" => Ok(Replay::Project(read_model.clone(), None)),
+            [command, read_model, date] if command == "project" => {
+                let date = date.parse().map_err(|_| format!("{} is not a date", date))?;
+                Ok(Replay::Project(read_model.clone(), Some(date)))
+            },
+            _ => Err(USAGE.to_string())
+        }
+    }
+
+    // Reads the logs the server is configured with. The file store can be read while the server
+    // is running; nothing is written.
+    pub async fn run(&self, config: &Config) -> Result<String, String> {
+        match *self {
+            Replay::Events(ref log, stream_id) => match log.as_str() {
+                "tabs" => events(config.open_tab_log().await.as_ref(), stream_id).await,
+                "menu" => events(config.open_log::<crate::menu::Event>("menu").as_ref(), stream_id).await,
+                "tables" => events(config.open_log::<crate::table::Event>("tables").as_ref(), stream_id).await,
+                "shifts" => events(config.open_log::<crate::shift::Event>("shifts").as_ref(), stream_id).await,
+                "staff" => events(config.open_log::<crate::staff::Event>("staff").as_ref(), stream_id).await,
+                "receipts" => events(config.open_log::<crate::receipts::Event>("receipts").as_ref(), stream_id).await,
+                _ => Err(format!("there is no {} log", log))
+            },
+            Replay::State(ref log, stream_id) => match log.as_str() {
+                "tabs" => state::<Tab>(config.open_tab_log().await.as_ref(), stream_id).await,
+                "menu" => state::<Menu>(config.open_log("menu").as_ref(), stream_id).await,
+                "tables" => state::<Table>(config.open_log("tables").as_ref(), stream_id).await,
+                "shifts" => state::<Shift>(config.open_log("shifts").as_ref(), stream_id).await,
+                "staff" => state::<Staff>(config.open_log("staff").as_ref(), stream_id).await,
+                "receipts" => state::<ReceiptSequence>(config.open_log("receipts").as_ref(), stream_id).await,
+                _ => Err(format!("there is no {} log", log))
+            },
+            Replay::Project(ref read_model, date) => match (read_model.as_str(), date) {
+                ("open_tabs", None) => project(config.open_tab_log().await.as_ref(), OpenTabs::new(), |tabs| tabs.tabs()).await,
+                ("kitchen", None) => project(config.open_tab_log().await.as_ref(), ChefTodoList::new(), |kitchen| kitchen.todo_list()).await,
+                ("tips", None) => project(config.open_tab_log().await.as_ref(), TipsPerWaiter::new(), |tips| tips.report(None, None)).await,
+                ("sales", Some(date)) => project(config.open_tab_log().await.as_ref(), SalesReport::new(), |sales| sales.on(date)).await,
+                ("menu", None) => project(config.open_log("menu").as_ref(), Catalog::new(), |catalog| catalog.items()).await,
+                ("waiters", None) => project(config.open_log("shifts").as_ref(), Roster::new(), |roster| roster.waiters()).await,
+                ("staff", None) => project(config.open_log("staff").as_ref(), StaffRegistry::new(), |registry| registry.members()).await,
+                ("sales", None) => Err("the sales report needs a date".to_string()),
+                _ => Err(format!("there is no {} read model, or it takes no date", read_model))
+            }
+        }
+    }
+}
+
+async fn events<T: Serialize + Send + 'static>(store: &dyn EventStore<T>, stream_id: Uuid) -> Result<String, String> {
+    let stream = store.read_stream(stream_id).await.map_err(|error| error.to_string())?;
+    serde_json::to_string_pretty(&stream.events).map_err(|error| error.to_string())
+}
+
+async fn state<A: Aggregate>(store: &dyn EventStore<A::Event>, stream_id: Uuid) -> Result<String, String>
+    where A::Command: Send, A::Event: Clone + Send + Sync + 'static, A::State: Clone + Debug + Send {
+    let (state, version) = CommandHandler::<A>::new(store).load(stream_id).await.map_err(|error| error.to_string())?;
+    Ok(format!("version {}\n{:#?}", version, state))
+}
+
+async fn project<E, P, R, F>(store: &dyn EventStore<E>, mut projection: P, report: F) -> Result<String, String>
+    where E: Send + 'static, P: Projection<E>, R: Serialize, F: Fn(&P) -> R {
+    for envelope in store.read_all().await.map_err(|error| error.to_string())? {
+        projection.apply_envelope(&envelope);
+    }
+    serde_json::to_string_pretty(&report(&projection)).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Metadata;
+    use crate::cqrs::store::InMemoryEventStore;
+    use crate::domain::Event;
+    use crate::staff;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn arguments_name_what_to_replay() {
+        let tab_id = Uuid::new_v4();
+        assert_eq!(Replay::parse(&args(&format!("state tabs {}", tab_id))), Ok(Replay::State("tabs".to_string(), tab_id)));
+        assert_eq!(Replay::parse(&args("project sales 2024-05-17")), Ok(Replay::Project("sales".to_string(), "2024-05-17".parse().ok())));
+        assert_eq!(Replay::parse(&args("events tabs 42")), Err("42 is not a stream id".to_string()));
+        assert_eq!(Replay::parse(&args("rewind")), Err(USAGE.to_string()));
+    }
+
+    #[tokio::test]
+    async fn streams_are_replayed_into_state_and_read_models() {
+        let store = InMemoryEventStore::new();
+        let tab_id = Uuid::new_v4();
+        let opened = Event::TabOpened { table_number: 5, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() };
+        store.append(tab_id, vec![opened], 0, &Metadata::new()).await.unwrap();
+
+        let dumped: serde_json::Value = serde_json::from_str(&events(&store, tab_id).await.unwrap()).unwrap();
+        assert_eq!(dumped[0]["payload"]["type"], json!("tab_opened"));
+        assert!(state::<Tab>(&store, tab_id).await.unwrap().starts_with("version 1\nState {\n    tab_open: true,"));
+        let tabs: serde_json::Value = serde_json::from_str(&project(&store, OpenTabs::new(), |tabs| tabs.tabs()).await.unwrap()).unwrap();
+        assert_eq!(tabs[0]["table_number"], json!(5));
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::Bound::{Included, Unbounded};
+use std::time::SystemTime;
+
+use uuid::Uuid;
+
+use crate::cqrs::TimedProjection;
+use crate::date::Date;
+use crate::domain::{Event, OrderedItem};
+use crate::money::{Currency, Money};
+use crate::staff::WaiterId;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WaiterTips {
+    pub waiter_id: WaiterId,
+    pub waiter: String,
+    pub tips: Money,
+    pub tab_count: usize
+}
+
+// Tips left on closed tabs, per waiter and the day the tab was closed, for settling tips at the
+// end of the day. Waiters are told apart by staff id, under the name they opened their latest
+// tab with. Tabs purged by retention take their tips out of a rebuilt report, as the tombstone
+// no longer says whose they were.
+#[derive(Debug, Default)]
+pub struct TipsPerWaiter {
+    waiters: HashMap<Uuid, (WaiterId, String)>,
+    tips: BTreeMap<Date, HashMap<(WaiterId, Currency), (String, Money, usize)>>
+}
+
+impl TipsPerWaiter {
+    pub fn new() -> TipsPerWaiter {
+        TipsPerWaiter::default()
+    }
+
+    // Both days are included; tips in different currencies are reported apart.
+    pub fn report(&self, from: Option<Date>, to: Option<Date>) -> Vec<WaiterTips> {
+        let from = from.map_or(Unbounded, Included);
+        let to = to.map_or(Unbounded, Included);
+        let mut totals: HashMap<(WaiterId, Currency), WaiterTips> = HashMap::new();
+        for day in self.tips.range((from, to)).map(|(_, day)| day) {
+            for (&(waiter_id, currency), &(ref waiter, tips, tab_count)) in day {
+                let total = totals.entry((waiter_id, currency)).or_insert_with(|| WaiterTips { waiter_id, waiter: String::new(), tips: Money::zero(currency), tab_count: 0 });
+                total.waiter = waiter.clone();
+                total.tips += tips;
+                total.tab_count += tab_count;
+            }
+        }
+        let mut report: Vec<WaiterTips> = totals.into_values().collect();
+        report.sort_by(|a, b| (&a.waiter, a.waiter_id, a.tips.currency().to_string()).cmp(&(&b.waiter, b.waiter_id, b.tips.currency().to_string())));
+        report
+    }
+}
+
+impl TimedProjection for TipsPerWaiter {
+    type Event = Event;
+
+    fn apply_at(&mut self, tab_id: Uuid, event: &Event, timestamp: SystemTime) {
+        match *event {
+            Event::TabOpened { waiter_id, ref waiter, .. } => {
+                self.waiters.insert(tab_id, (waiter_id, waiter.clone()));
+            },
+            Event::TabClosed { tip_value, .. } => {
+                if let Some((waiter_id, waiter)) = self.waiters.remove(&tab_id) {
+                    let day = self.tips.entry(Date::of(timestamp)).or_default();
+                    let total = day.entry((waiter_id, tip_value.currency())).or_insert_with(|| (String::new(), Money::zero(tip_value.currency()), 0));
+                    total.0 = waiter;
+                    total.1 += tip_value;
+                    total.2 += 1;
+                }
+            },
+            // The tip goes to whoever has the tab when it is paid.
+            Event::WaiterReassigned { waiter_id, ref waiter, .. } => {
+                if let Some(holder) = self.waiters.get_mut(&tab_id) {
+                    *holder = (waiter_id, waiter.clone());
+                }
+            },
+            Event::TabForceClosed { .. } | Event::TabPurged { .. } => {
+                self.waiters.remove(&tab_id);
+            },
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ItemSales {
+    pub menu_number: i32,
+    pub description: String,
+    pub count: usize,
+    pub value: Money
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DailySales {
+    pub date: Date,
+    pub served_value: Money,
+    pub items: Vec<ItemSales>,
+    pub service_items: Vec<ItemSales>,
+    pub tabs_closed: usize
+}
+
+#[derive(Debug, Default)]
+struct SalesDay {
+    served_value: Option<Money>,
+    items: BTreeMap<i32, ItemSales>,
+    service_items: BTreeMap<i32, ItemSales>,
+    tabs_closed: usize
+}
+
+// What was sold each day: items count as sold on the day they are served, at the price they
+// were ordered at, and tabs on the day they are closed. Comps and discounts come off the served
+// value, not the items. Items served without an order are counted apart, as service items; they
+// are worth nothing.
+#[derive(Debug, Default)]
+pub struct SalesReport {
+    unserved: HashMap<Uuid, Vec<OrderedItem>>,
+    days: BTreeMap<Date, SalesDay>
+}
+
+impl SalesReport {
+    pub fn new() -> SalesReport {
+        SalesReport::default()
+    }
+
+    pub fn on(&self, date: Date) -> DailySales {
+        match self.days.get(&date) {
+            Some(day) => DailySales {
+                date,
+                served_value: day.served_value.unwrap_or_else(|| Money::zero(Currency::default())),
+                items: day.items.values().cloned().collect(),
+                service_items: day.service_items.values().cloned().collect(),
+                tabs_closed: day.tabs_closed
+            },
+            None => DailySales { date, served_value: Money::zero(Currency::default()), items: Vec::new(), service_items: Vec::new(), tabs_closed: 0 }
+        }
+    }
+
+    fn serve(&mut self, tab_id: Uuid, line_ids: &[Uuid], drinks: bool, date: Date) {
+        for &line_id in line_ids {
+            let item = match self.unserved.get_mut(&tab_id).and_then(|unserved| take(unserved, line_id, drinks)) {
+                Some(item) => item,
+                None => continue
+            };
+            let day = self.days.entry(date).or_default();
+            let (menu_number, price) = (item.menu_number(), item.price());
+            day.served_value = Some(day.served_value.map_or(price, |value| value + price));
+            let sales = day.items.entry(menu_number).or_insert_with(|| ItemSales { menu_number, description: item.description().to_string(), count: 0, value: Money::zero(price.currency()) });
+            sales.count += 1;
+            sales.value += price;
+        }
+    }
+}
+
+fn take(unserved: &mut Vec<OrderedItem>, line_id: Uuid, drinks: bool) -> Option<OrderedItem> {
+    let index = unserved.iter().position(|item| item.line_id() == line_id && item.is_drink() == drinks)?;
+    Some(unserved.remove(index))
+}
+
+impl TimedProjection for SalesReport {
+    type Event = Event;
+
+    fn apply_at(&mut self, tab_id: Uuid, event: &Event, timestamp: SystemTime) {
+        match *event {
+            Event::DrinksOrdered { ref items } | Event::FoodOrdered { ref items } => {
+                self.unserved.entry(tab_id).or_default().extend(items.iter().flat_map(OrderedItem::units));
+            },
+            Event::DrinksServed { ref line_ids, .. } => self.serve(tab_id, line_ids, true, Date::of(timestamp)),
+            Event::FoodServed { ref line_ids, .. } => self.serve(tab_id, line_ids, false, Date::of(timestamp)),
+            Event::UnorderedItemsServed { ref items } => {
+                let day = self.days.entry(Date::of(timestamp)).or_default();
+                for item in items {
+                    let menu_number = item.menu_number();
+                    let sales = day.service_items.entry(menu_number).or_insert_with(|| ItemSales { menu_number, description: item.description().to_string(), count: 0, value: Money::zero(item.price().currency()) });
+                    sales.count += item.quantity() as usize;
+                }
+            },
+            Event::ItemVoided { line_id, .. } => {
+                if let Some(unserved) = self.unserved.get_mut(&tab_id) {
+                    if let Some(index) = unserved.iter().position(|item| item.line_id() == line_id) {
+                        unserved.remove(index);
+                    }
+                }
+            },
+            Event::TabClosed { .. } => {
+                self.unserved.remove(&tab_id);
+                self.days.entry(Date::of(timestamp)).or_default().tabs_closed += 1;
+            },
+            Event::TabForceClosed { .. } | Event::TabPurged { .. } => {
+                self.unserved.remove(&tab_id);
+            },
+            // Counted on the day of the correction, as the days before are reported already. The
+            // items sold keep the price they were charged.
+            Event::ServedPriceCorrected { charged, correct, count, .. } => {
+                let day = self.days.entry(Date::of(timestamp)).or_default();
+                let adjustment = (correct - charged) * count as i64;
+                day.served_value = Some(day.served_value.map_or(adjustment, |value| value + adjustment));
+            },
+            // Taken off the day they are given, like corrections.
+            Event::ItemComped { value: reduction, .. } | Event::DiscountApplied { amount: reduction, .. } => {
+                let day = self.days.entry(Date::of(timestamp)).or_default();
+                day.served_value = Some(day.served_value.map_or(-reduction, |value| value - reduction));
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+    use crate::cqrs::Metadata;
+    use crate::cqrs::store::{EventStore, InMemoryEventStore};
+    use crate::staff;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    fn close(tips: &mut TipsPerWaiter, waiter: &str, tip_value: Money, date: &str) {
+        let tab_id = Uuid::new_v4();
+        let closed_at = date.parse::<Date>().unwrap().start() + Duration::from_secs(20 * 60 * 60);
+        tips.apply_at(tab_id, &Event::TabOpened { table_number: 1, waiter_id: staff::legacy_id(waiter), waiter: waiter.to_string() }, closed_at);
+        tips.apply_at(tab_id, &Event::TabClosed { amount_paid: eur(1000) + tip_value, order_value: eur(1000), tip_value, receipt_number: None }, closed_at);
+    }
+
+    #[test]
+    fn tips_are_totalled_per_waiter_over_the_days_asked_for() {
+        let mut tips = TipsPerWaiter::new();
+        close(&mut tips, "Jane", eur(150), "2017-06-14");
+        close(&mut tips, "Derek", eur(100), "2017-06-15");
+        close(&mut tips, "Derek", eur(0), "2017-06-15");
+        close(&mut tips, "Derek", eur(50), "2017-06-16");
+
+        let day = "2017-06-15".parse().ok();
+        let derek = staff::legacy_id("Derek");
+        assert_eq!(tips.report(day, day), vec![WaiterTips { waiter_id: derek, waiter: "Derek".to_string(), tips: eur(100), tab_count: 2 }]);
+        assert_eq!(tips.report(day, None), vec![WaiterTips { waiter_id: derek, waiter: "Derek".to_string(), tips: eur(150), tab_count: 3 }]);
+        assert_eq!(tips.report(None, None).len(), 2);
+        assert_eq!(serde_json::to_value(tips.report(day, day)).unwrap(), json!([
+            { "waiter_id": derek, "waiter": "Derek", "tips": { "amount_minor": 100, "currency": "EUR" }, "tab_count": 2 }
+        ]));
+    }
+
+    #[tokio::test]
+    async fn sales_report_rebuilds_from_the_log() {
+        let store = InMemoryEventStore::new();
+        let live = Arc::new(RwLock::new(SalesReport::new()));
+        store.subscribe(live.clone()).await.unwrap();
+
+        let tab_id = Uuid::new_v4();
+        let coffee = OrderedItem::new(1, "Coffee".to_string(), true, eur(250));
+        let soup = OrderedItem::new(2, "Soup".to_string(), false, eur(450));
+        let lines = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        let events = vec![
+            Event::TabOpened { table_number: 1, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() },
+            Event::DrinksOrdered { items: vec![coffee.clone().with_line_id(lines[0]), coffee.with_line_id(lines[1])] },
+            Event::FoodOrdered { items: vec![soup.clone().with_line_id(lines[2]), soup.with_line_id(lines[3])] },
+            Event::DrinksServed { line_ids: vec![lines[0], lines[1]], menu_numbers: vec![1, 1] },
+            Event::ItemVoided { line_id: lines[2], menu_number: 2, reason: "Sold out".to_string() },
+            Event::FoodServed { line_ids: vec![lines[3]], menu_numbers: vec![2] },
+            Event::UnorderedItemsServed { items: vec![OrderedItem::new(90, "House water".to_string(), true, eur(0)).with_quantity(2).with_line_id(Uuid::new_v4())] },
+            Event::TabClosed { amount_paid: eur(950), order_value: eur(950), tip_value: eur(0), receipt_number: None }
+        ];
+        let recorded = store.append(tab_id, events, 0, &Metadata::new()).await.unwrap();
+
+        let today = Date::of(recorded[0].timestamp);
+        let report = live.read().unwrap().on(today);
+        assert_eq!(report.served_value, eur(950));
+        assert_eq!(report.items.iter().map(|item| (item.menu_number, item.count, item.value)).collect::<Vec<_>>(), vec![(1, 2, eur(500)), (2, 1, eur(450))]);
+        assert_eq!(report.service_items, vec![ItemSales { menu_number: 90, description: "House water".to_string(), count: 2, value: eur(0) }]);
+        assert_eq!(report.tabs_closed, 1);
+
+        let rebuilt = Arc::new(RwLock::new(SalesReport::new()));
+        store.subscribe(rebuilt.clone()).await.unwrap();
+        assert_eq!(rebuilt.read().unwrap().on(today), report);
+        assert_eq!(rebuilt.read().unwrap().on(today.next()).items, vec![]);
+    }
+}
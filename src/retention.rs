@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use uuid::Uuid;
+
+use crate::cqrs::{EventEnvelope, Metadata};
+use crate::cqrs::store::{EventStore, StoreError};
+use crate::domain::Event;
+use crate::money::Money;
+use crate::policy::PolicyError;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// How long the full history of a closed tab is kept, read from TOML:
+//
+//     keep_closed_tabs_days = 2555
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    pub keep_closed_tabs_days: u64
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy { keep_closed_tabs_days: 7 * 365 }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn from_toml(source: &str) -> Result<RetentionPolicy, PolicyError> {
+        toml::from_str(source).map_err(PolicyError::Parse)
+    }
+
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<RetentionPolicy, PolicyError> {
+        let mut source = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => RetentionPolicy::from_toml(&source),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(RetentionPolicy::default()),
+            Err(error) => Err(PolicyError::Io(error))
+        }
+    }
+
+    fn retention_period(&self) -> Duration {
+        Duration::from_secs(self.keep_closed_tabs_days * SECONDS_PER_DAY)
+    }
+}
+
+#[derive(Debug)]
+pub enum RetentionError {
+    Store(StoreError),
+    Archive(io::Error)
+}
+
+impl From<StoreError> for RetentionError {
+    fn from(error: StoreError) -> RetentionError {
+        RetentionError::Store(error)
+    }
+}
+
+impl From<io::Error> for RetentionError {
+    fn from(error: io::Error) -> RetentionError {
+        RetentionError::Archive(error)
+    }
+}
+
+impl From<serde_json::Error> for RetentionError {
+    fn from(error: serde_json::Error) -> RetentionError {
+        RetentionError::Archive(error.into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiredTab {
+    pub tab_id: Uuid,
+    pub closed_at: SystemTime
+}
+
+// Closed tabs whose last event is older than the retention period at the given time. Tabs that
+// were purged before end with TabPurged and are not picked up again.
+pub async fn expired_tabs(store: &dyn EventStore<Event>, policy: &RetentionPolicy, now: SystemTime) -> Result<Vec<ExpiredTab>, StoreError> {
+    let mut last_events: HashMap<Uuid, EventEnvelope<Event>> = HashMap::new();
+    for envelope in store.read_all().await? {
+        last_events.insert(envelope.stream_id, envelope);
+    }
+
+    let mut expired: Vec<ExpiredTab> = last_events.into_iter()
+        .filter(|(_, envelope)| match envelope.payload {
+            Event::TabClosed { .. } | Event::TabForceClosed { .. } => envelope.timestamp + policy.retention_period() <= now,
+            _ => false
+        })
+        .map(|(tab_id, envelope)| ExpiredTab { tab_id, closed_at: envelope.timestamp })
+        .collect();
+    expired.sort_by_key(|tab| tab.closed_at);
+    Ok(expired)
+}
+
+// The maintenance job. Writes the full history of every expired tab to the archive as
+// newline-delimited JSON envelopes, then replaces it with a TabPurged tombstone. All tombstones
+// of one run share a correlation id. A dry run only reports what would be purged.
+pub async fn purge_expired_tabs(store: &dyn EventStore<Event>, policy: &RetentionPolicy, now: SystemTime, archive: &mut (dyn Write + Send), dry_run: bool) -> Result<Vec<ExpiredTab>, RetentionError> {
+    let expired = expired_tabs(store, policy, now).await?;
+    if dry_run {
+        return Ok(expired);
+    }
+
+    let metadata = Metadata::new();
+    for tab in &expired {
+        let stream = store.read_stream(tab.tab_id).await?;
+        for envelope in &stream.events {
+            serde_json::to_writer(&mut *archive, envelope)?;
+            archive.write_all(b"\n")?;
+        }
+        // Nothing is removed before the archive has it.
+        archive.flush()?;
+
+        let tombstone = match stream.events.last().map(|envelope| &envelope.payload) {
+            Some(&Event::TabClosed { amount_paid, order_value, tip_value, ref receipt_number }) => Event::TabPurged { event_count: stream.events.len(), amount_paid, order_value, tip_value, receipt_number: receipt_number.clone() },
+            Some(&Event::TabForceClosed { unpaid_value, .. }) => {
+                let nothing = Money::zero(unpaid_value.currency());
+                Event::TabPurged { event_count: stream.events.len(), amount_paid: nothing, order_value: unpaid_value, tip_value: nothing, receipt_number: None }
+            },
+            _ => continue
+        };
+        store.purge_stream(tab.tab_id, stream.version, tombstone, &metadata).await?;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::CommandHandler;
+    use crate::cqrs::store::InMemoryEventStore;
+    use crate::domain::{Command, Tab};
+    use crate::money::{Currency, Money};
+    use crate::staff;
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    async fn closed_tab(store: &InMemoryEventStore<Event>) -> Uuid {
+        let tab_id = Uuid::new_v4();
+        let handler = CommandHandler::<Tab>::new(store);
+        handler.handle(Command::OpenTab(tab_id, 42, staff::legacy_id("Derek"), "Derek".to_string())).await.unwrap();
+        handler.handle(Command::CloseTab(tab_id, eur(0), None)).await.unwrap();
+        tab_id
+    }
+
+    fn years_later(years: u64) -> SystemTime {
+        SystemTime::now() + Duration::from_secs(years * 365 * SECONDS_PER_DAY)
+    }
+
+    #[tokio::test]
+    async fn only_closed_tabs_past_retention_expire() {
+        let store = InMemoryEventStore::new();
+        let closed = closed_tab(&store).await;
+        CommandHandler::<Tab>::new(&store).handle(Command::OpenTab(Uuid::new_v4(), 7, staff::legacy_id("Jane"), "Jane".to_string())).await.unwrap();
+        let policy = RetentionPolicy::default();
+        assert_eq!(expired_tabs(&store, &policy, years_later(1)).await.unwrap(), vec![]);
+        let expired = expired_tabs(&store, &policy, years_later(8)).await.unwrap();
+        assert_eq!(expired.iter().map(|tab| tab.tab_id).collect::<Vec<_>>(), vec![closed]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_the_store_untouched() {
+        let store = InMemoryEventStore::new();
+        let tab_id = closed_tab(&store).await;
+        let mut archive = Vec::new();
+        let expired = purge_expired_tabs(&store, &RetentionPolicy::default(), years_later(8), &mut archive, true).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert!(archive.is_empty());
+        assert_eq!(store.read_stream(tab_id).await.unwrap().events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn purge_archives_history_and_leaves_a_tombstone() {
+        let store = InMemoryEventStore::new();
+        let tab_id = closed_tab(&store).await;
+        let history = store.read_stream(tab_id).await.unwrap().events;
+        let mut archive = Vec::new();
+        purge_expired_tabs(&store, &RetentionPolicy::default(), years_later(8), &mut archive, false).await.unwrap();
+
+        let archived: Vec<EventEnvelope<Event>> = String::from_utf8(archive).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(archived, history);
+        let stream = store.read_stream(tab_id).await.unwrap();
+        assert_eq!(stream.version, 3);
+        assert_eq!(stream.events.into_iter().map(|envelope| envelope.payload).collect::<Vec<_>>(), vec![
+            Event::TabPurged { event_count: 2, amount_paid: eur(0), order_value: eur(0), tip_value: eur(0), receipt_number: None }
+        ]);
+        assert_eq!(expired_tabs(&store, &RetentionPolicy::default(), years_later(8)).await.unwrap(), vec![]);
+    }
+
+    #[test]
+    fn retention_is_read_from_toml() {
+        assert_eq!(RetentionPolicy::from_toml("keep_closed_tabs_days = 30").unwrap(), RetentionPolicy { keep_closed_tabs_days: 30 });
+        assert_eq!(RetentionPolicy::from_toml("").unwrap(), RetentionPolicy::default());
+    }
+}
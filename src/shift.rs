@@ -0,0 +1,150 @@
+use crate::cqrs::{Aggregate, AggregateCommand};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum Command {
+    StartShift(Uuid, String),
+    EndShift(Uuid),
+    AssignToTable(Uuid, u8),
+    // For tables handed over to another waiter before the shift ends.
+    ReleaseTable(Uuid, u8)
+}
+
+impl AggregateCommand for Command {
+    fn aggregate_id(&self) -> Uuid {
+        use self::Command::*;
+
+        match *self {
+            StartShift(id, ..) | EndShift(id) | AssignToTable(id, ..) | ReleaseTable(id, ..) => id
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum CommandError {
+    AlreadyOnShift,
+    NotOnShift
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ShiftStarted { waiter: String },
+    AssignedToTable { table_number: u8 },
+    ReleasedTable { table_number: u8 },
+    // Table assignments end with the shift.
+    ShiftEnded
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    on_shift: bool
+}
+
+impl State {
+    pub fn is_on_shift(&self) -> bool {
+        self.on_shift
+    }
+}
+
+// Each waiter's shifts are a stream of their own, under their staff id.
+pub struct Shift;
+
+impl Aggregate for Shift {
+    type Command = Command;
+    type CommandError = CommandError;
+    type Event = Event;
+    type State = State;
+
+    fn initial_state() -> State {
+        State::default()
+    }
+
+    fn decide(state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
+        use self::Command::*;
+        use self::CommandError::*;
+        use self::Event::*;
+
+        match command {
+            StartShift(_, waiter) => {
+                if state.on_shift {
+                    Err(AlreadyOnShift)
+                } else {
+                    Ok(vec![ShiftStarted { waiter }])
+                }
+            },
+            EndShift(_) => {
+                if state.on_shift {
+                    Ok(vec![ShiftEnded])
+                } else {
+                    Err(NotOnShift)
+                }
+            },
+            AssignToTable(_, table_number) => {
+                if state.on_shift {
+                    Ok(vec![AssignedToTable { table_number }])
+                } else {
+                    Err(NotOnShift)
+                }
+            },
+            ReleaseTable(_, table_number) => {
+                if state.on_shift {
+                    Ok(vec![ReleasedTable { table_number }])
+                } else {
+                    Err(NotOnShift)
+                }
+            }
+        }
+    }
+
+    fn evolve(state: &mut State, event: Event) {
+        use self::Event::*;
+
+        match event {
+            ShiftStarted { .. } => state.on_shift = true,
+            ShiftEnded => state.on_shift = false,
+            AssignedToTable { .. } | ReleasedTable { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::testing::Scenario;
+    use crate::staff;
+
+    fn derek() -> Uuid {
+        staff::legacy_id("Derek")
+    }
+
+    #[test]
+    fn shifts_start_and_end() {
+        Scenario::<Shift>::new()
+            .when(Command::StartShift(derek(), "Derek".to_string()))
+            .then(vec![Event::ShiftStarted { waiter: "Derek".to_string() }]);
+        Scenario::<Shift>::new()
+            .given(vec![Event::ShiftStarted { waiter: "Derek".to_string() }])
+            .when(Command::StartShift(derek(), "Derek".to_string()))
+            .then_err(CommandError::AlreadyOnShift);
+        Scenario::<Shift>::new()
+            .given(vec![Event::ShiftStarted { waiter: "Derek".to_string() }, Event::ShiftEnded])
+            .when(Command::EndShift(derek()))
+            .then_err(CommandError::NotOnShift);
+    }
+
+    #[test]
+    fn only_waiters_on_shift_are_assigned_tables() {
+        Scenario::<Shift>::new()
+            .when(Command::AssignToTable(derek(), 5))
+            .then_err(CommandError::NotOnShift);
+        Scenario::<Shift>::new()
+            .given(vec![Event::ShiftStarted { waiter: "Derek".to_string() }])
+            .when(Command::AssignToTable(derek(), 5))
+            .then(vec![Event::AssignedToTable { table_number: 5 }]);
+        Scenario::<Shift>::new()
+            .given(vec![Event::ShiftStarted { waiter: "Derek".to_string() }, Event::AssignedToTable { table_number: 5 }])
+            .when(Command::ReleaseTable(derek(), 5))
+            .then(vec![Event::ReleasedTable { table_number: 5 }]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::process;
+use std::sync::Arc;
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::cqrs::store::{EventStore, StoreError};
+
+type Close = Box<dyn Fn() -> BoxFuture<'static, Result<(), StoreError>> + Send + Sync>;
+
+// The event logs to close when the process is asked to stop. Read models and checkpoints are
+// rebuilt from the logs on startup, so the logs are all there is to flush.
+pub struct Shutdown {
+    stores: Vec<(&'static str, Close)>
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown { stores: Vec::new() }
+    }
+
+    // Stores are closed in the order they were added, so the tab log should go first: once it is
+    // closed commands are answered with 503 and nothing else gets written on their behalf.
+    pub fn with_store<T: Send + 'static>(mut self, name: &'static str, store: Arc<dyn EventStore<T>>) -> Shutdown {
+        self.stores.push((name, Box::new(move || {
+            let store = store.clone();
+            async move { store.close().await }.boxed()
+        })));
+        self
+    }
+
+    // Closes every store, even after one of them fails, and returns the ones that did.
+    pub async fn close(&self) -> Vec<(&'static str, StoreError)> {
+        let mut failed = Vec::new();
+        for &(name, ref close) in &self.stores {
+            if let Err(error) = close().await {
+                failed.push((name, error));
+            }
+        }
+        failed
+    }
+
+    // Once the server has stopped, for whatever reason, the stores are closed and the process
+    // exits, with 1 if the server failed or a store could not be closed cleanly.
+    pub async fn exit(self, server_error: Option<String>) -> ! {
+        if let Some(ref error) = server_error {
+            eprintln!("the server failed: {}", error);
+        }
+        let failed = self.close().await;
+        for &(name, ref error) in &failed {
+            eprintln!("failed to close the {} log: {}", name, error);
+        }
+        process::exit(if server_error.is_none() && failed.is_empty() { 0 } else { 1 });
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use uuid::Uuid;
+    use crate::cqrs::{EventEnvelope, Metadata};
+    use crate::cqrs::store::{EventStream, InMemoryEventStore, Subscriber};
+
+    struct Broken;
+
+    #[async_trait]
+    impl EventStore<i32> for Broken {
+        async fn append(&self, _: Uuid, _: Vec<i32>, _: usize, _: &Metadata) -> Result<Vec<EventEnvelope<i32>>, StoreError> {
+            Err(StoreError::Backend("disk full".to_string()))
+        }
+
+        async fn purge_stream(&self, _: Uuid, _: usize, _: i32, _: &Metadata) -> Result<EventEnvelope<i32>, StoreError> {
+            Err(StoreError::Backend("disk full".to_string()))
+        }
+
+        async fn read_stream(&self, _: Uuid) -> Result<EventStream<i32>, StoreError> {
+            Err(StoreError::Backend("disk full".to_string()))
+        }
+
+        async fn read_all(&self) -> Result<Vec<EventEnvelope<i32>>, StoreError> {
+            Err(StoreError::Backend("disk full".to_string()))
+        }
+
+        async fn subscribe(&self, _: Subscriber<i32>) -> Result<(), StoreError> {
+            Ok(())
+        }
+
+        async fn close(&self) -> Result<(), StoreError> {
+            Err(StoreError::Backend("disk full".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn every_store_is_closed_even_if_one_fails() {
+        let tabs: Arc<dyn EventStore<i32>> = Arc::new(InMemoryEventStore::new());
+        let menu: Arc<dyn EventStore<i32>> = Arc::new(InMemoryEventStore::new());
+        let shutdown = Shutdown::new()
+            .with_store("tabs", tabs.clone())
+            .with_store("payments", Arc::new(Broken))
+            .with_store("menu", menu.clone());
+
+        assert_eq!(shutdown.close().await, vec![("payments", StoreError::Backend("disk full".to_string()))]);
+        assert_eq!(tabs.append(Uuid::new_v4(), vec![1], 0, &Metadata::new()).await, Err(StoreError::Closed));
+        assert_eq!(menu.append(Uuid::new_v4(), vec![1], 0, &Metadata::new()).await, Err(StoreError::Closed));
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::time;
+use uuid::Uuid;
+
+use crate::cqrs::{named_stream_id, Metadata};
+use crate::cqrs::store::EventStore;
+use crate::policy::PolicyError;
+
+// How often the watcher works out the burn rates.
+const WATCH_INTERVAL_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+struct Objective {
+    command: String,
+    percentile: f64,
+    threshold_ms: u64
+}
+
+// Latency objectives per command type, read from TOML. An objective of p99 under 150ms leaves
+// 1% of the commands as the budget for slower ones. The burn rate is how many times faster than
+// that the budget is spent over the last window; past alert_burn_rate an alert is raised, once
+// the window has seen enough commands for the rate to mean anything.
+//
+//     window_seconds = 300
+//     alert_burn_rate = 2.0
+//     min_commands = 20
+//
+//     [[objectives]]
+//     command = "PlaceOrder"
+//     percentile = 99.0
+//     threshold_ms = 150
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LatencyObjectives {
+    objectives: Vec<Objective>,
+    window_seconds: u64,
+    alert_burn_rate: f64,
+    min_commands: usize
+}
+
+impl Default for LatencyObjectives {
+    fn default() -> LatencyObjectives {
+        LatencyObjectives { objectives: Vec::new(), window_seconds: 300, alert_burn_rate: 2.0, min_commands: 20 }
+    }
+}
+
+impl LatencyObjectives {
+    pub fn from_toml(source: &str) -> Result<LatencyObjectives, PolicyError> {
+        toml::from_str(source).map_err(PolicyError::Parse)
+    }
+
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Result<LatencyObjectives, PolicyError> {
+        let mut source = String::new();
+        match File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+            Ok(_) => LatencyObjectives::from_toml(&source),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => Ok(LatencyObjectives::default()),
+            Err(error) => Err(PolicyError::Io(error))
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_seconds)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ObjectiveStatus {
+    pub command: String,
+    pub percentile: f64,
+    pub threshold_ms: u64,
+    pub commands: usize,
+    pub slow: usize,
+    pub burn_rate: f64,
+    pub alerting: bool
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    LatencyBudgetBurning { command: String, burn_rate: f64, commands: usize, slow: usize },
+    LatencyBudgetRecovered { command: String, burn_rate: f64 }
+}
+
+// Every command type's alerts go to a stream of its own.
+pub fn objective_id(command: &str) -> Uuid {
+    named_stream_id("latency_objective", command)
+}
+
+// How long the commands with an objective took over the last window. Kept in memory only, like
+// the heartbeats; commands without an objective are not kept at all.
+pub struct CommandLatencies {
+    objectives: LatencyObjectives,
+    samples: Mutex<HashMap<String, VecDeque<(SystemTime, Duration)>>>,
+    alerting: Mutex<HashSet<String>>
+}
+
+impl CommandLatencies {
+    pub fn new(objectives: LatencyObjectives) -> CommandLatencies {
+        CommandLatencies { objectives, samples: Mutex::new(HashMap::new()), alerting: Mutex::new(HashSet::new()) }
+    }
+
+    pub fn record(&self, command: &str, elapsed: Duration, now: SystemTime) {
+        if !self.objectives.objectives.iter().any(|objective| objective.command == command) {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        let window = samples.entry(command.to_string()).or_default();
+        window.push_back((now, elapsed));
+        self.forget_before(window, now);
+    }
+
+    pub fn statuses(&self, now: SystemTime) -> Vec<ObjectiveStatus> {
+        let mut samples = self.samples.lock().unwrap();
+        let alerting = self.alerting.lock().unwrap();
+        self.objectives.objectives.iter().map(|objective| {
+            let window = samples.entry(objective.command.clone()).or_default();
+            self.forget_before(window, now);
+            let threshold = Duration::from_millis(objective.threshold_ms);
+            let commands = window.len();
+            let slow = window.iter().filter(|&&(_, elapsed)| elapsed > threshold).count();
+            let budget = 1.0 - objective.percentile / 100.0;
+            let burn_rate = if commands == 0 { 0.0 } else { slow as f64 / commands as f64 / budget };
+            ObjectiveStatus {
+                command: objective.command.clone(),
+                percentile: objective.percentile,
+                threshold_ms: objective.threshold_ms,
+                commands,
+                slow,
+                burn_rate,
+                alerting: alerting.contains(&objective.command)
+            }
+        }).collect()
+    }
+
+    // An alert when a command type starts burning its budget too fast, and another when it has
+    // stopped, each reported once.
+    pub fn alerts(&self, now: SystemTime) -> Vec<(Uuid, Event)> {
+        let statuses = self.statuses(now);
+        let mut alerting = self.alerting.lock().unwrap();
+        statuses.into_iter().filter_map(|status| {
+            let burning = status.commands >= self.objectives.min_commands && status.burn_rate >= self.objectives.alert_burn_rate;
+            let ObjectiveStatus { command, commands, slow, burn_rate, .. } = status;
+            let id = objective_id(&command);
+            if burning && alerting.insert(command.clone()) {
+                Some((id, Event::LatencyBudgetBurning { command, burn_rate, commands, slow }))
+            } else if !burning && alerting.remove(&command) {
+                Some((id, Event::LatencyBudgetRecovered { command, burn_rate }))
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    fn forget_before(&self, window: &mut VecDeque<(SystemTime, Duration)>, now: SystemTime) {
+        while window.front().is_some_and(|&(at, _)| now.duration_since(at).is_ok_and(|age| age > self.objectives.window())) {
+            window.pop_front();
+        }
+    }
+}
+
+// Records an alert in the objective's stream whenever a command type starts or stops burning
+// its latency budget too fast.
+pub fn watch(latencies: Arc<CommandLatencies>, alerts: Box<dyn EventStore<Event>>) {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(Duration::from_secs(WATCH_INTERVAL_SECONDS)).await;
+            for (objective_id, alert) in latencies.alerts(SystemTime::now()) {
+                let recorded = match alerts.read_stream(objective_id).await {
+                    Ok(stream) => alerts.append(objective_id, vec![alert], stream.version, &Metadata::new()).await,
+                    Err(error) => Err(error)
+                };
+                if let Err(error) = recorded {
+                    eprintln!("failed to record latency alert: {}", error);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn alerts_are_raised_while_the_budget_burns_too_fast() {
+        let objectives = LatencyObjectives::from_toml("window_seconds = 60\nmin_commands = 10\n\n[[objectives]]\ncommand = \"PlaceOrder\"\npercentile = 90.0\nthreshold_ms = 150\n").unwrap();
+        let latencies = CommandLatencies::new(objectives);
+        latencies.record("CloseTab", Duration::from_secs(5), at(0));
+        for i in 0..8 {
+            latencies.record("PlaceOrder", Duration::from_millis(if i < 2 { 400 } else { 20 }), at(i));
+        }
+        // Too few commands yet to tell, even at a burn rate of 2.5.
+        assert_eq!(latencies.alerts(at(10)), vec![]);
+
+        for i in 8..10 {
+            latencies.record("PlaceOrder", Duration::from_millis(20), at(i));
+        }
+        let status = latencies.statuses(at(10)).remove(0);
+        assert_eq!((status.command.as_str(), status.commands, status.slow), ("PlaceOrder", 10, 2));
+        assert!((status.burn_rate - 2.0).abs() < 1e-9);
+        let burning = latencies.alerts(at(10));
+        assert_eq!(burning.len(), 1);
+        assert_eq!(burning[0].0, objective_id("PlaceOrder"));
+        assert_eq!(latencies.alerts(at(20)), vec![]);
+        assert!(latencies.statuses(at(20))[0].alerting);
+
+        // The slow commands have left the window.
+        let recovered = latencies.alerts(at(70));
+        assert_eq!(recovered, vec![(objective_id("PlaceOrder"), Event::LatencyBudgetRecovered { command: "PlaceOrder".to_string(), burn_rate: 0.0 })]);
+    }
+}
@@ -0,0 +1,165 @@
+use uuid::Uuid;
+
+use crate::auth::Role;
+use crate::cqrs::{named_stream_id, Aggregate, AggregateCommand};
+
+// Waiters are known by their staff id, since two of them may well share a name.
+pub type WaiterId = Uuid;
+
+// The id staff had before there was a registry, when they were known by name alone. Their shift
+// streams and the tabs they opened then are filed under it, so the first member registered
+// under a name is given it too.
+pub fn legacy_id(name: &str) -> Uuid {
+    named_stream_id("waiter", name)
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum Command {
+    Register(Uuid, String, Role),
+    Deactivate(Uuid),
+    // With the PIN already hashed, see auth::hash_pin.
+    SetPin(Uuid, String)
+}
+
+impl AggregateCommand for Command {
+    fn aggregate_id(&self) -> Uuid {
+        use self::Command::*;
+
+        match *self {
+            Register(id, ..) | Deactivate(id) | SetPin(id, _) => id
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum CommandError {
+    AlreadyRegistered,
+    NotRegistered,
+    AlreadyInactive
+}
+
+// The id is in the event as well as the stream so whoever registered someone learns it from the
+// command response.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    StaffRegistered { staff_id: Uuid, name: String, role: Role },
+    // Someone who left. They stay in the registry so their history still has a name.
+    StaffDeactivated,
+    // The PIN they sign in with on shared terminals, hashed. A new one replaces the old.
+    PinSet { pin_hash: String }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    registered: bool,
+    active: bool
+}
+
+// Each member of staff is a stream of their own.
+pub struct Staff;
+
+impl Aggregate for Staff {
+    type Command = Command;
+    type CommandError = CommandError;
+    type Event = Event;
+    type State = State;
+
+    fn initial_state() -> State {
+        State::default()
+    }
+
+    fn decide(state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
+        use self::Command::*;
+        use self::CommandError::*;
+        use self::Event::*;
+
+        match command {
+            Register(staff_id, name, role) => {
+                if state.registered {
+                    Err(AlreadyRegistered)
+                } else {
+                    Ok(vec![StaffRegistered { staff_id, name, role }])
+                }
+            },
+            Deactivate(_) => {
+                if !state.registered {
+                    Err(NotRegistered)
+                } else if !state.active {
+                    Err(AlreadyInactive)
+                } else {
+                    Ok(vec![StaffDeactivated])
+                }
+            },
+            SetPin(_, pin_hash) => {
+                if !state.registered {
+                    Err(NotRegistered)
+                } else if !state.active {
+                    Err(AlreadyInactive)
+                } else {
+                    Ok(vec![PinSet { pin_hash }])
+                }
+            }
+        }
+    }
+
+    fn evolve(state: &mut State, event: Event) {
+        use self::Event::*;
+
+        match event {
+            StaffRegistered { .. } => {
+                state.registered = true;
+                state.active = true;
+            },
+            StaffDeactivated => state.active = false,
+            PinSet { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::testing::Scenario;
+
+    #[test]
+    fn staff_are_registered_once_and_deactivated_once() {
+        let derek = legacy_id("Derek");
+        let registered = Event::StaffRegistered { staff_id: derek, name: "Derek".to_string(), role: Role::Waiter };
+        Scenario::<Staff>::new()
+            .when(Command::Register(derek, "Derek".to_string(), Role::Waiter))
+            .then(vec![registered.clone()]);
+        Scenario::<Staff>::new()
+            .given(vec![registered.clone()])
+            .when(Command::Register(derek, "Derek".to_string(), Role::Manager))
+            .then_err(CommandError::AlreadyRegistered);
+        Scenario::<Staff>::new()
+            .when(Command::Deactivate(derek))
+            .then_err(CommandError::NotRegistered);
+        Scenario::<Staff>::new()
+            .given(vec![registered.clone()])
+            .when(Command::Deactivate(derek))
+            .then(vec![Event::StaffDeactivated]);
+        Scenario::<Staff>::new()
+            .given(vec![registered, Event::StaffDeactivated])
+            .when(Command::Deactivate(derek))
+            .then_err(CommandError::AlreadyInactive);
+    }
+
+    #[test]
+    fn only_active_staff_get_a_pin() {
+        let derek = legacy_id("Derek");
+        let registered = Event::StaffRegistered { staff_id: derek, name: "Derek".to_string(), role: Role::Waiter };
+        Scenario::<Staff>::new()
+            .given(vec![registered.clone()])
+            .when(Command::SetPin(derek, "hash".to_string()))
+            .then(vec![Event::PinSet { pin_hash: "hash".to_string() }]);
+        Scenario::<Staff>::new()
+            .when(Command::SetPin(derek, "hash".to_string()))
+            .then_err(CommandError::NotRegistered);
+        Scenario::<Staff>::new()
+            .given(vec![registered, Event::StaffDeactivated])
+            .when(Command::SetPin(derek, "hash".to_string()))
+            .then_err(CommandError::AlreadyInactive);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::cqrs::{Aggregate, AggregateCommand};
+use uuid::Uuid;
+
+// Each table is a stream of its own, found by its number.
+pub fn table_id(table_number: u8) -> Uuid {
+    Uuid::from_u128(u128::from(table_number))
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum Command {
+    RegisterTable(Uuid, u8),
+    SeatGuests(Uuid, Uuid),
+    ClearTable(Uuid)
+}
+
+impl AggregateCommand for Command {
+    fn aggregate_id(&self) -> Uuid {
+        use self::Command::*;
+
+        match *self {
+            RegisterTable(id, ..) | SeatGuests(id, ..) | ClearTable(id) => id
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub enum CommandError {
+    TableAlreadyRegistered,
+    UnknownTable,
+    TableInUse,
+    TableNotInUse
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    TableRegistered { table_number: u8 },
+    TableOccupied { tab_id: Uuid },
+    TableCleared
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct State {
+    registered: bool,
+    tab_id: Option<Uuid>
+}
+
+impl State {
+    // The tab of the guests seated at the table, if any.
+    pub fn tab_id(&self) -> Option<Uuid> {
+        self.tab_id
+    }
+}
+
+// Guests are seated by opening a tab for them, so a table holds at most one tab at a time.
+pub struct Table;
+
+impl Aggregate for Table {
+    type Command = Command;
+    type CommandError = CommandError;
+    type Event = Event;
+    type State = State;
+
+    fn initial_state() -> State {
+        State::default()
+    }
+
+    fn decide(state: &State, command: Command) -> Result<Vec<Event>, CommandError> {
+        use self::Command::*;
+        use self::CommandError::*;
+        use self::Event::*;
+
+        match command {
+            RegisterTable(_, table_number) => {
+                if state.registered {
+                    Err(TableAlreadyRegistered)
+                } else {
+                    Ok(vec![TableRegistered { table_number }])
+                }
+            },
+            SeatGuests(_, tab_id) => {
+                if !state.registered {
+                    Err(UnknownTable)
+                } else if state.tab_id.is_some() {
+                    Err(TableInUse)
+                } else {
+                    Ok(vec![TableOccupied { tab_id }])
+                }
+            },
+            ClearTable(_) => {
+                if !state.registered {
+                    Err(UnknownTable)
+                } else if state.tab_id.is_none() {
+                    Err(TableNotInUse)
+                } else {
+                    Ok(vec![TableCleared])
+                }
+            }
+        }
+    }
+
+    fn evolve(state: &mut State, event: Event) {
+        use self::Event::*;
+
+        match event {
+            TableRegistered { .. } => state.registered = true,
+            TableOccupied { tab_id } => state.tab_id = Some(tab_id),
+            TableCleared => state.tab_id = None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::testing::Scenario;
+
+    #[test]
+    fn tables_are_registered_once() {
+        Scenario::<Table>::new()
+            .when(Command::RegisterTable(table_id(5), 5))
+            .then(vec![Event::TableRegistered { table_number: 5 }]);
+        Scenario::<Table>::new()
+            .given(vec![Event::TableRegistered { table_number: 5 }])
+            .when(Command::RegisterTable(table_id(5), 5))
+            .then_err(CommandError::TableAlreadyRegistered);
+    }
+
+    #[test]
+    fn guests_can_only_be_seated_at_free_registered_tables() {
+        let tab_id = Uuid::new_v4();
+        Scenario::<Table>::new()
+            .when(Command::SeatGuests(table_id(5), tab_id))
+            .then_err(CommandError::UnknownTable);
+        Scenario::<Table>::new()
+            .given(vec![Event::TableRegistered { table_number: 5 }])
+            .when(Command::SeatGuests(table_id(5), tab_id))
+            .then(vec![Event::TableOccupied { tab_id }]);
+        Scenario::<Table>::new()
+            .given(vec![Event::TableRegistered { table_number: 5 }, Event::TableOccupied { tab_id }])
+            .when(Command::SeatGuests(table_id(5), Uuid::new_v4()))
+            .then_err(CommandError::TableInUse);
+        Scenario::<Table>::new()
+            .given(vec![Event::TableRegistered { table_number: 5 }, Event::TableOccupied { tab_id }, Event::TableCleared])
+            .when(Command::SeatGuests(table_id(5), tab_id))
+            .then(vec![Event::TableOccupied { tab_id }]);
+    }
+
+    #[test]
+    fn only_tables_in_use_can_be_cleared() {
+        Scenario::<Table>::new()
+            .given(vec![Event::TableRegistered { table_number: 5 }])
+            .when(Command::ClearTable(table_id(5)))
+            .then_err(CommandError::TableNotInUse);
+        Scenario::<Table>::new()
+            .given(vec![Event::TableRegistered { table_number: 5 }, Event::TableOccupied { tab_id: Uuid::new_v4() }])
+            .when(Command::ClearTable(table_id(5)))
+            .then(vec![Event::TableCleared]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use tantivy::{self, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument};
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, Value, STORED, TEXT};
+use uuid::Uuid;
+
+use crate::cqrs::Projection;
+use crate::domain::{Event, OrderedItem};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TextMatch {
+    pub menu_number: i32,
+    pub description: String,
+    pub score: f32
+}
+
+// Full-text search over the descriptions of everything that has been ordered, so staff can find
+// a menu item without knowing its number. The index lives in memory and is rebuilt on startup.
+pub struct TextIndex {
+    index: Index,
+    writer: IndexWriter,
+    reader: IndexReader,
+    menu_number: Field,
+    description: Field,
+    indexed: HashSet<(i32, String)>
+}
+
+impl TextIndex {
+    pub fn new() -> tantivy::Result<TextIndex> {
+        let mut schema = Schema::builder();
+        let menu_number = schema.add_i64_field("menu_number", STORED);
+        let description = schema.add_text_field("description", TEXT | STORED);
+        let index = Index::create_in_ram(schema.build());
+        let writer = index.writer_with_num_threads(1, 15_000_000)?;
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        Ok(TextIndex { index, writer, reader, menu_number, description, indexed: HashSet::new() })
+    }
+
+    // Best matches first. Query syntax errors are ignored rather than reported, as the query
+    // comes straight from a search box.
+    pub fn search(&self, text: &str, limit: usize) -> tantivy::Result<Vec<TextMatch>> {
+        let (query, _) = QueryParser::for_index(&self.index, vec![self.description]).parse_query_lenient(text);
+        let searcher = self.reader.searcher();
+        let mut matches = Vec::new();
+        for (score, address) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+            let document: TantivyDocument = searcher.doc(address)?;
+            matches.push(TextMatch {
+                menu_number: document.get_first(self.menu_number).and_then(|value| value.as_i64()).unwrap_or(0) as i32,
+                description: document.get_first(self.description).and_then(|value| value.as_str()).unwrap_or("").to_string(),
+                score
+            });
+        }
+        Ok(matches)
+    }
+
+    // Each menu item is indexed once, however often it is ordered.
+    fn index(&mut self, items: &[OrderedItem]) -> tantivy::Result<()> {
+        let mut added = false;
+        for item in items {
+            if self.indexed.insert((item.menu_number(), item.description().to_string())) {
+                let mut document = TantivyDocument::default();
+                document.add_i64(self.menu_number, i64::from(item.menu_number()));
+                document.add_text(self.description, item.description());
+                self.writer.add_document(document)?;
+                added = true;
+            }
+        }
+        if added {
+            self.writer.commit()?;
+            self.reader.reload()?;
+        }
+        Ok(())
+    }
+}
+
+impl Projection<Event> for TextIndex {
+    fn apply(&mut self, _: Uuid, event: &Event) {
+        match *event {
+            Event::DrinksOrdered { ref items } | Event::FoodOrdered { ref items } => {
+                self.index(items).expect("failed to update the text index");
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::{Currency, Money};
+
+    fn eur(amount_minor: i64) -> Money {
+        Money::new(amount_minor, Currency::EUR)
+    }
+
+    #[test]
+    fn finds_ordered_items_by_description() {
+        let mut index = TextIndex::new().unwrap();
+        let espresso = OrderedItem::new(1, "Double espresso".to_string(), true, eur(300));
+        let soup = OrderedItem::new(2, "Tomato soup".to_string(), false, eur(450));
+        index.apply(Uuid::new_v4(), &Event::DrinksOrdered { items: vec![espresso.clone()] });
+        index.apply(Uuid::new_v4(), &Event::DrinksOrdered { items: vec![espresso] });
+        index.apply(Uuid::new_v4(), &Event::FoodOrdered { items: vec![soup] });
+
+        let found = index.search("espresso", 10).unwrap();
+        assert_eq!(found.iter().map(|found| (found.menu_number, found.description.as_str())).collect::<Vec<_>>(), vec![(1, "Double espresso")]);
+        assert_eq!(index.search("SOUP", 10).unwrap()[0].menu_number, 2);
+        assert_eq!(index.search("pancakes", 10).unwrap(), vec![]);
+        assert_eq!(index.search("\"unclosed", 10).unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::time;
+use uuid::Uuid;
+
+use crate::cqrs::{named_stream_id, Metadata};
+use crate::cqrs::store::EventStore;
+use crate::date::Date;
+use crate::read_model::OpenTabs;
+use crate::staff::WaiterId;
+
+// How often the watchdog looks at the clock.
+const WATCH_INTERVAL_SECONDS: u64 = 60;
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct LateTab {
+    pub tab_id: Uuid,
+    pub table_number: u8,
+    pub waiter_id: WaiterId,
+    pub waiter: String
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    TabsOpenAfterClosing { closing_hour: u8, tabs: Vec<LateTab> }
+}
+
+// Every day's report goes to a stream of its own, so a restart after closing time does not
+// report the same night twice.
+pub fn night_id(date: Date) -> Uuid {
+    named_stream_id("closing_time", &date.to_string())
+}
+
+// Looks for tabs still open once closing time, a UTC hour, has passed, and reports them to the
+// managers once a day. Kept in memory only, like the heartbeats: after a restart the tabs are
+// reported again from the next check, though not recorded twice.
+pub struct Watchdog {
+    closing_hour: u8,
+    report: Mutex<Option<(Date, Vec<LateTab>)>>
+}
+
+impl Watchdog {
+    pub fn new(closing_hour: u8) -> Watchdog {
+        Watchdog { closing_hour, report: Mutex::new(None) }
+    }
+
+    // The day's report, the first time it is asked for after closing time, if any tabs are open.
+    pub fn check(&self, open_tabs: &OpenTabs, now: SystemTime) -> Option<(Uuid, Event)> {
+        let hour = now.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) % (24 * 60 * 60) / (60 * 60);
+        let today = Date::of(now);
+        let mut report = self.report.lock().unwrap();
+        if hour < u64::from(self.closing_hour) || report.as_ref().is_some_and(|&(date, _)| date == today) {
+            return None;
+        }
+        let tabs: Vec<LateTab> = open_tabs.tabs().into_iter()
+            .map(|tab| LateTab { tab_id: tab.tab_id, table_number: tab.table_number, waiter_id: tab.waiter_id, waiter: tab.waiter })
+            .collect();
+        *report = Some((today, tabs.clone()));
+        if tabs.is_empty() {
+            None
+        } else {
+            Some((night_id(today), Event::TabsOpenAfterClosing { closing_hour: self.closing_hour, tabs }))
+        }
+    }
+
+    // The tabs of the last report that are open still.
+    pub fn late_tabs(&self, open_tabs: &OpenTabs) -> Vec<LateTab> {
+        let open: Vec<Uuid> = open_tabs.tabs().into_iter().map(|tab| tab.tab_id).collect();
+        match *self.report.lock().unwrap() {
+            Some((_, ref tabs)) => tabs.iter().filter(|tab| open.contains(&tab.tab_id)).cloned().collect(),
+            None => Vec::new()
+        }
+    }
+}
+
+// Records the report in the night's stream, unless it is there already.
+pub fn watch(watchdog: Arc<Watchdog>, open_tabs: Arc<RwLock<OpenTabs>>, alerts: Box<dyn EventStore<Event>>) {
+    tokio::spawn(async move {
+        loop {
+            time::sleep(Duration::from_secs(WATCH_INTERVAL_SECONDS)).await;
+            let report = watchdog.check(&open_tabs.read().unwrap(), SystemTime::now());
+            if let Some((night_id, alert)) = report {
+                let recorded = match alerts.read_stream(night_id).await {
+                    Ok(stream) if stream.version > 0 => Ok(Vec::new()),
+                    Ok(stream) => alerts.append(night_id, vec![alert], stream.version, &Metadata::new()).await,
+                    Err(error) => Err(error)
+                };
+                if let Err(error) = recorded {
+                    eprintln!("failed to record open tabs after closing: {}", error);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::Projection;
+    use crate::domain::Event as TabEvent;
+    use crate::money::{Currency, Money};
+    use crate::staff;
+
+    fn at(hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(3 * 24 * 60 * 60 + hour * 60 * 60 + minute * 60)
+    }
+
+    #[test]
+    fn tabs_open_after_closing_are_reported_once_a_day() {
+        let mut open_tabs = OpenTabs::new();
+        let (late, paid) = (Uuid::new_v4(), Uuid::new_v4());
+        for (tab_id, table_number) in [(late, 5), (paid, 6)] {
+            open_tabs.apply(tab_id, &TabEvent::TabOpened { table_number, waiter_id: staff::legacy_id("Derek"), waiter: "Derek".to_string() });
+        }
+        let watchdog = Watchdog::new(23);
+        assert_eq!(watchdog.check(&open_tabs, at(22, 59)), None);
+        assert_eq!(watchdog.late_tabs(&open_tabs), vec![]);
+
+        let (night, Event::TabsOpenAfterClosing { closing_hour, tabs }) = watchdog.check(&open_tabs, at(23, 0)).unwrap();
+        assert_eq!((night, closing_hour, tabs.len()), (night_id(Date::of(at(23, 0))), 23, 2));
+        assert_eq!(watchdog.check(&open_tabs, at(23, 30)), None);
+
+        let eur = Money::zero(Currency::EUR);
+        open_tabs.apply(paid, &TabEvent::TabClosed { amount_paid: eur, order_value: eur, tip_value: eur, receipt_number: None });
+        assert_eq!(watchdog.late_tabs(&open_tabs).iter().map(|tab| (tab.tab_id, tab.table_number)).collect::<Vec<_>>(), vec![(late, 5)]);
+    }
+}
@@ -0,0 +1,123 @@
+// Runs every scenario in scenarios/ against the in-memory store and read models.
+//
+// A scenario is a list of steps, each a command with either the events it should record or
+// the error it should be rejected with, followed by the answers expected from read model
+// queries once all steps have run. Query names are the read model methods, with an argument
+// after a slash where the query takes one, e.g. "invoice_for_table/42".
+
+extern crate cafe;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use serde_json::Value;
+
+use cafe::cqrs::{CommandHandler, HandlerError};
+use cafe::cqrs::store::{EventStore, InMemoryEventStore};
+use cafe::domain::{Command, CommandError, Event, Tab};
+use cafe::read_model::{ChefTodoList, OpenTabs};
+
+#[derive(Deserialize)]
+struct Scenario {
+    steps: Vec<Step>,
+    #[serde(default)]
+    queries: BTreeMap<String, Value>
+}
+
+#[derive(Deserialize)]
+struct Step {
+    command: Command,
+    #[serde(default)]
+    events: Vec<Event>,
+    #[serde(default)]
+    error: Option<CommandError>
+}
+
+struct Stack {
+    store: InMemoryEventStore<Event>,
+    open_tabs: Arc<RwLock<OpenTabs>>,
+    chef_todo_list: Arc<RwLock<ChefTodoList>>
+}
+
+impl Stack {
+    async fn new() -> Stack {
+        let open_tabs = Arc::new(RwLock::new(OpenTabs::new()));
+        let chef_todo_list = Arc::new(RwLock::new(ChefTodoList::new()));
+        let store = InMemoryEventStore::new();
+        store.subscribe(open_tabs.clone()).await.unwrap();
+        store.subscribe(chef_todo_list.clone()).await.unwrap();
+        Stack { store, open_tabs, chef_todo_list }
+    }
+
+    fn query(&self, name: &str) -> Result<Value, String> {
+        let mut parts = name.splitn(2, '/');
+        let query = parts.next().unwrap_or("");
+        let argument = parts.next().unwrap_or("");
+        let open_tabs = self.open_tabs.read().unwrap();
+        let result = match query {
+            "active_table_numbers" => serde_json::to_value(open_tabs.active_table_numbers()),
+            "tabs" => serde_json::to_value(open_tabs.tabs()),
+            "tab_id_for_table" => serde_json::to_value(open_tabs.tab_id_for_table(table_number(argument)?)),
+            "invoice_for_table" => serde_json::to_value(open_tabs.invoice_for_table(table_number(argument)?)),
+            "todo_list_for_waiter" => serde_json::to_value(open_tabs.todo_list_for_waiter(argument)),
+            "chef_todo_list" => serde_json::to_value(self.chef_todo_list.read().unwrap().todo_list()),
+            _ => return Err(format!("unknown query {}", name))
+        };
+        result.map_err(|error| error.to_string())
+    }
+}
+
+fn table_number(argument: &str) -> Result<u8, String> {
+    argument.parse().map_err(|_| format!("invalid table number {:?}", argument))
+}
+
+async fn run(path: &Path) -> Result<(), String> {
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    let scenario: Scenario = serde_json::from_reader(file).map_err(|error| error.to_string())?;
+    let stack = Stack::new().await;
+
+    for (index, step) in scenario.steps.into_iter().enumerate() {
+        let expected = match step.error {
+            Some(error) => Err(HandlerError::Rejected(error)),
+            None => Ok(step.events)
+        };
+        let actual = CommandHandler::<Tab>::new(&stack.store).handle(step.command).await;
+        if actual != expected {
+            return Err(format!("step {}: expected {:?}, got {:?}", index + 1, expected, actual));
+        }
+    }
+
+    for (name, expected) in scenario.queries {
+        let actual = stack.query(&name)?;
+        if actual != expected {
+            return Err(format!("query {}: expected {}, got {}", name, expected, actual));
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn scenarios() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+    let mut paths: Vec<PathBuf> = fs::read_dir(&directory).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenarios found in {}", directory.display());
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        if let Err(error) = run(path).await {
+            failures.push(format!("{}: {}", path.display(), error));
+        }
+    }
+
+    assert!(failures.is_empty(), "failed scenarios:\n{}", failures.join("\n"));
+}